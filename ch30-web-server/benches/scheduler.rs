@@ -0,0 +1,95 @@
+//! Compares job throughput of the two `ThreadPool` schedulers.
+//!
+//! Run with `cargo bench -p ch30-web-server --bench scheduler`. Workers log
+//! each job at trace level, which the default logger skips.
+
+use ch30_web_server::{Scheduler, ThreadPool};
+use std::hint::black_box;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const JOBS: u64 = 100_000;
+
+fn pool(threads: usize, scheduler: Scheduler) -> ThreadPool {
+    ThreadPool::builder(threads)
+        .scheduler(scheduler)
+        .build()
+        .unwrap()
+}
+
+/// Many tiny jobs submitted from outside the pool.
+fn flat(threads: usize, scheduler: Scheduler) -> Duration {
+    let pool = pool(threads, scheduler);
+    let sum = Arc::new(AtomicU64::new(0));
+
+    let start = Instant::now();
+    for i in 0..JOBS {
+        let sum = Arc::clone(&sum);
+        pool.execute(move || {
+            sum.fetch_add(black_box(i), Ordering::Relaxed);
+        });
+    }
+    pool.shutdown();
+    let elapsed = start.elapsed();
+
+    assert_eq!(sum.load(Ordering::Relaxed), JOBS * (JOBS - 1) / 2);
+    elapsed
+}
+
+/// A few jobs that each submit many more from inside the pool.
+fn nested(threads: usize, scheduler: Scheduler) -> Duration {
+    const PARENTS: u64 = 100;
+    let pool = Arc::new(pool(threads, scheduler));
+    let count = Arc::new(AtomicU64::new(0));
+
+    let start = Instant::now();
+    let parents: Vec<_> = (0..PARENTS)
+        .map(|_| {
+            let inner = Arc::clone(&pool);
+            let count = Arc::clone(&count);
+            pool.spawn(move || {
+                for _ in 0..JOBS / PARENTS {
+                    let count = Arc::clone(&count);
+                    inner.execute(move || {
+                        count.fetch_add(1, Ordering::Relaxed);
+                    });
+                }
+            })
+        })
+        .collect();
+    // Wait for the parents' children before shutting down, since a parent
+    // may still be submitting when the pool stops accepting jobs.
+    while count.load(Ordering::Relaxed) < JOBS {
+        std::thread::yield_now();
+    }
+    let elapsed = start.elapsed();
+
+    // The last child can finish before its parent has dropped its handle
+    // to the pool; joining the parents makes sure they all have.
+    for parent in parents {
+        parent.join().unwrap();
+    }
+    Arc::try_unwrap(pool).ok().unwrap().shutdown();
+    elapsed
+}
+
+fn report(name: &str, threads: usize, run: fn(usize, Scheduler) -> Duration) {
+    for scheduler in [Scheduler::SharedQueue, Scheduler::WorkStealing] {
+        // Best of three to smooth out noise.
+        let best = (0..3).map(|_| run(threads, scheduler)).min().unwrap();
+        let rate = JOBS as f64 / best.as_secs_f64();
+        eprintln!(
+            "{name:<7} threads={threads:<2} {:<13} {:>9.2?} {rate:>12.0} jobs/s",
+            format!("{scheduler:?}"),
+            best
+        );
+    }
+}
+
+fn main() {
+    for threads in [2, 4, 8] {
+        report("flat", threads, flat);
+        report("nested", threads, nested);
+    }
+}
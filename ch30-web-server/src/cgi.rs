@@ -0,0 +1,509 @@
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus, Stdio};
+use std::sync::mpsc::{self, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::request::{parse_headers, percent_decode, Request, Version};
+use crate::response::Response;
+use crate::router::Handler;
+
+/// Runs CGI/1.1 scripts (RFC 3875) from a directory.
+///
+/// A request for `{prefix}/name/more/path` runs the executable `name` in
+/// the directory with `/more/path` as `PATH_INFO`. The request body is
+/// written to the script's standard input, and its standard output is read
+/// as a header block followed by the response body. A `Status` header sets
+/// the status code; a `Location` header without one redirects with `302`.
+///
+/// Scripts that run longer than the time limit are killed and the client
+/// gets `504 Gateway Timeout`; the limit also covers the script's output,
+/// which a background process it started may keep open. Output that is not
+/// a valid CGI response, or that is larger than the output limit, gives
+/// `502 Bad Gateway`. Anything a script writes to standard error is logged.
+///
+/// ```no_run
+/// use std::time::Duration;
+/// use ch30_web_server::{Cgi, Router};
+///
+/// let cgi = Cgi::new("/cgi-bin", "cgi-bin").timeout(Duration::from_secs(5));
+/// let router = Router::new().get("/cgi-bin/*", cgi);
+/// ```
+#[derive(Debug, Clone)]
+pub struct Cgi {
+    prefix: String,
+    dir: PathBuf,
+    timeout: Duration,
+    max_output: usize,
+}
+
+/// How much of a script's standard error is logged.
+const MAX_STDERR: usize = 64 * 1024;
+
+impl Cgi {
+    /// Serves the scripts in `dir` below the URL path `prefix`.
+    pub fn new(prefix: &str, dir: impl Into<PathBuf>) -> Cgi {
+        Cgi {
+            prefix: prefix.trim_end_matches('/').to_string(),
+            dir: dir.into(),
+            timeout: Duration::from_secs(10),
+            max_output: 16 * 1024 * 1024,
+        }
+    }
+
+    /// Sets how long a script may run. Defaults to 10 seconds.
+    pub fn timeout(mut self, timeout: Duration) -> Cgi {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Sets the most a script may write to standard output, headers
+    /// included. Defaults to 16 MiB.
+    pub fn max_output(mut self, bytes: usize) -> Cgi {
+        self.max_output = bytes;
+        self
+    }
+
+    /// Splits the request path into the script to run and the path info
+    /// after it.
+    fn resolve(&self, request_path: &str) -> Result<(String, PathBuf, String), Response> {
+        let not_found = || Response::text(404, "Not Found");
+        let rest = request_path
+            .strip_prefix(&self.prefix)
+            .and_then(|rest| rest.strip_prefix('/'))
+            .ok_or_else(not_found)?;
+        let (name, path_info) = match rest.find('/') {
+            Some(slash) => rest.split_at(slash),
+            None => (rest, ""),
+        };
+        let name = percent_decode(name).ok_or_else(|| Response::new(400))?;
+        let path_info = percent_decode(path_info).ok_or_else(|| Response::new(400))?;
+        // Hidden files, `..` and encoded slashes never name a script.
+        if name.is_empty() || name.starts_with('.') || name.contains(['/', '\\']) {
+            return Err(not_found());
+        }
+
+        // Absolute, since the script runs in the directory.
+        let script = fs::canonicalize(self.dir.join(&name)).map_err(|_| not_found())?;
+        let metadata = fs::metadata(&script).map_err(|_| not_found())?;
+        if !metadata.is_file() {
+            return Err(not_found());
+        }
+        if !is_executable(&metadata) {
+            return Err(Response::text(403, "Forbidden"));
+        }
+        Ok((name, script, path_info))
+    }
+
+    /// The environment described in RFC 3875 section 4.1.
+    fn environment(&self, request: &Request, name: &str, path_info: &str) -> Vec<(String, String)> {
+        let (server_name, server_port) = match request.headers.get("Host") {
+            Some(host) => match host.rsplit_once(':').filter(|(_, p)| !p.contains(']')) {
+                Some((name, port)) => (name.to_string(), port.to_string()),
+                None => (host.to_string(), "80".to_string()),
+            },
+            None => (String::new(), "80".to_string()),
+        };
+        let protocol = match request.version {
+            Version::Http10 => "HTTP/1.0",
+            Version::Http11 => "HTTP/1.1",
+        };
+
+        let mut env = vec![
+            ("GATEWAY_INTERFACE", "CGI/1.1".to_string()),
+            ("SERVER_PROTOCOL", protocol.to_string()),
+            (
+                "SERVER_SOFTWARE",
+                concat!("ch30-web-server/", env!("CARGO_PKG_VERSION")).to_string(),
+            ),
+            ("SERVER_NAME", server_name),
+            ("SERVER_PORT", server_port),
+            ("REQUEST_METHOD", request.method.as_str().to_string()),
+            ("SCRIPT_NAME", format!("{}/{name}", self.prefix)),
+            ("QUERY_STRING", request.query.clone().unwrap_or_default()),
+        ];
+        if !path_info.is_empty() {
+            env.push(("PATH_INFO", path_info.to_string()));
+        }
+        if !request.body.is_empty() {
+            env.push(("CONTENT_LENGTH", request.body.len().to_string()));
+        }
+        if let Some(content_type) = request.headers.get("Content-Type") {
+            env.push(("CONTENT_TYPE", content_type.to_string()));
+        }
+        if let Some(client) = request.remote_addr {
+            env.push(("REMOTE_ADDR", client.ip().to_string()));
+            env.push(("REMOTE_PORT", client.port().to_string()));
+        }
+        if let Ok(path) = std::env::var("PATH") {
+            env.push(("PATH", path));
+        }
+
+        let mut env: Vec<(String, String)> = env
+            .into_iter()
+            .map(|(name, value)| (name.to_string(), value))
+            .collect();
+        for (name, value) in request.headers.iter() {
+            // `Proxy` would become HTTP_PROXY, which many programs take as
+            // their proxy setting ("httpoxy").
+            let skip = ["Content-Length", "Content-Type", "Authorization", "Proxy"];
+            if skip.iter().any(|s| s.eq_ignore_ascii_case(name)) {
+                continue;
+            }
+            let name = format!("HTTP_{}", name.to_ascii_uppercase().replace('-', "_"));
+            match env.iter_mut().find(|(n, _)| *n == name) {
+                Some((_, existing)) => {
+                    existing.push_str(", ");
+                    existing.push_str(value);
+                }
+                None => env.push((name, value.to_string())),
+            }
+        }
+        env
+    }
+
+    /// Runs the script and collects its output, killing it at the deadline.
+    fn run(&self, script: &Path, env: Vec<(String, String)>, body: Vec<u8>) -> io::Result<Output> {
+        let mut child = Command::new(script)
+            .current_dir(&self.dir)
+            .env_clear()
+            .envs(env)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+
+        // Each pipe gets a thread so that a script writing a lot before it
+        // reads its input cannot deadlock with us.
+        let mut stdin = child.stdin.take().expect("stdin is piped");
+        thread::spawn(move || {
+            // A script that ignores its input closes the pipe early.
+            let _ = stdin.write_all(&body);
+        });
+        let (done, captured) = mpsc::channel();
+        let stdout = child.stdout.take().expect("stdout is piped");
+        read_in_background(stdout, Pipe::Stdout, self.max_output, done.clone());
+        let stderr = child.stderr.take().expect("stderr is piped");
+        read_in_background(stderr, Pipe::Stderr, MAX_STDERR, done);
+
+        // The script is done once it has exited and both pipes are closed.
+        // A background process it started can hold the pipes open, so the
+        // deadline covers them too.
+        let deadline = Instant::now() + self.timeout;
+        let (mut status, mut stdout, mut stderr) = (None, None, None);
+        let result = loop {
+            if status.is_none() {
+                status = child.try_wait()?;
+            }
+            while let Ok((pipe, out)) = captured.try_recv() {
+                match pipe {
+                    Pipe::Stdout => stdout = Some(out),
+                    Pipe::Stderr => stderr = Some(out),
+                }
+            }
+            if stdout
+                .as_ref()
+                .is_some_and(|out| out.len() > self.max_output)
+            {
+                break Output::TooLarge;
+            }
+            if let (Some(status), Some(stdout), Some(_)) = (status, &mut stdout, &stderr) {
+                break Output::Exited(status, std::mem::take(stdout));
+            }
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                break Output::TimedOut;
+            }
+            thread::sleep(left.min(Duration::from_millis(10)));
+        };
+        if status.is_none() {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+
+        let stderr = stderr.unwrap_or_default();
+        for line in String::from_utf8_lossy(&stderr[..stderr.len().min(MAX_STDERR)]).lines() {
+            warn!("CGI {}: {line}", script.display());
+        }
+        Ok(result)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Pipe {
+    Stdout,
+    Stderr,
+}
+
+enum Output {
+    Exited(ExitStatus, Vec<u8>),
+    TimedOut,
+    /// More output than [`Cgi::max_output`] allows.
+    TooLarge,
+}
+
+impl Handler for Cgi {
+    fn handle(&self, request: Request) -> Response {
+        let (name, script, path_info) = match self.resolve(&request.path) {
+            Ok(resolved) => resolved,
+            Err(response) => return response,
+        };
+        let env = self.environment(&request, &name, &path_info);
+
+        match self.run(&script, env, request.body) {
+            Ok(Output::Exited(status, stdout)) => {
+                if !status.success() {
+                    warn!("CGI {} exited with {status}", script.display());
+                }
+                parse_output(&stdout).unwrap_or_else(|| {
+                    error!("CGI {} sent an invalid response", script.display());
+                    Response::text(502, "Bad Gateway")
+                })
+            }
+            Ok(Output::TimedOut) => {
+                error!(
+                    "CGI {} ran longer than {:?}",
+                    script.display(),
+                    self.timeout
+                );
+                Response::text(504, "Gateway Timeout")
+            }
+            Ok(Output::TooLarge) => {
+                error!(
+                    "CGI {} wrote more than {} bytes",
+                    script.display(),
+                    self.max_output
+                );
+                Response::text(502, "Bad Gateway")
+            }
+            Err(e) => {
+                error!("Failed to run CGI {}: {e}", script.display());
+                Response::text(500, "Internal Server Error")
+            }
+        }
+    }
+}
+
+/// Reads `pipe` to its end on a thread of its own, up to one byte more
+/// than `limit`, and sends what was read to `done`.
+///
+/// The thread outlives a script that is given up on for as long as
+/// something keeps the pipe open.
+fn read_in_background(
+    pipe: impl Read + Send + 'static,
+    which: Pipe,
+    limit: usize,
+    done: Sender<(Pipe, Vec<u8>)>,
+) {
+    thread::spawn(move || {
+        let mut out = Vec::new();
+        // Closing the pipe past the limit stops a script that keeps writing.
+        let _ = pipe
+            .take(limit.saturating_add(1) as u64)
+            .read_to_end(&mut out);
+        let _ = done.send((which, out));
+    });
+}
+
+/// Turns a script's output into a response, or `None` if the output does
+/// not start with a valid header block.
+fn parse_output(output: &[u8]) -> Option<Response> {
+    let (head, body) = ["\r\n\r\n", "\n\n"]
+        .iter()
+        .filter_map(|sep| {
+            let at = output
+                .windows(sep.len())
+                .position(|w| w == sep.as_bytes())?;
+            Some((&output[..at], &output[at + sep.len()..]))
+        })
+        .min_by_key(|(head, _)| head.len())?;
+    let head = std::str::from_utf8(head).ok()?;
+    let fields = parse_headers(head.lines()).ok()?;
+    if fields.is_empty() {
+        return None;
+    }
+
+    let status = match fields.get("Status") {
+        Some(status) => {
+            let code = status.split_whitespace().next()?.parse().ok()?;
+            (200..=599).contains(&code).then_some(code)?
+        }
+        None if fields.contains("Location") => 302,
+        None => 200,
+    };
+    let mut response = Response::new(status).with_body(body);
+    for (name, value) in fields.iter() {
+        let ignored = [
+            "Status",
+            "Content-Length",
+            "Transfer-Encoding",
+            "Connection",
+        ];
+        if !ignored.iter().any(|i| i.eq_ignore_ascii_case(name)) {
+            response.headers.append(name, value);
+        }
+    }
+    Some(response)
+}
+
+#[cfg(unix)]
+fn is_executable(metadata: &fs::Metadata) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode() & 0o111 != 0
+}
+
+#[cfg(not(unix))]
+fn is_executable(_: &fs::Metadata) -> bool {
+    true
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::request::RequestParser;
+    use std::os::unix::fs::PermissionsExt;
+    use tempfile::TempDir;
+
+    fn script(dir: &TempDir, name: &str, body: &str, mode: u32) {
+        let path = dir.path().join(name);
+        fs::write(&path, format!("#!/bin/sh\n{body}\n")).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(mode)).unwrap();
+    }
+
+    fn request(head: &str) -> Request {
+        let mut parser = RequestParser::new();
+        parser.feed(head.as_bytes());
+        let mut request = parser.parse().unwrap().unwrap();
+        request.remote_addr = Some("10.0.0.7:4321".parse().unwrap());
+        request
+    }
+
+    fn get(path: &str) -> Request {
+        request(&format!("GET {path} HTTP/1.1\r\nHost: cgi.test\r\n\r\n"))
+    }
+
+    fn body(response: &Response) -> String {
+        String::from_utf8(response.body.as_bytes().unwrap().to_vec()).unwrap()
+    }
+
+    #[test]
+    fn passes_environment_and_body_to_script() {
+        let dir = TempDir::new().unwrap();
+        script(
+            &dir,
+            "env.sh",
+            "echo 'Content-Type: text/plain'\necho\n\
+             for v in GATEWAY_INTERFACE REQUEST_METHOD SCRIPT_NAME PATH_INFO QUERY_STRING \\\n\
+             CONTENT_LENGTH CONTENT_TYPE REMOTE_ADDR SERVER_NAME SERVER_PORT HTTP_X_TAG HTTP_PROXY; do\n\
+             eval \"echo $v=\\$$v\"\ndone\ncat",
+            0o755,
+        );
+        let cgi = Cgi::new("/cgi-bin/", dir.path());
+
+        let response = cgi.handle(request(
+            "POST /cgi-bin/env.sh/a%20b/c?x=1&y=2 HTTP/1.1\r\nHost: cgi.test:8080\r\n\
+             Content-Type: text/plain\r\nX-Tag: one\r\nX-Tag: two\r\nProxy: evil\r\n\
+             Content-Length: 5\r\n\r\nhello",
+        ));
+        assert_eq!(response.status, 200);
+        assert_eq!(response.headers.get("Content-Type"), Some("text/plain"));
+        assert_eq!(
+            body(&response),
+            "GATEWAY_INTERFACE=CGI/1.1\nREQUEST_METHOD=POST\nSCRIPT_NAME=/cgi-bin/env.sh\n\
+             PATH_INFO=/a b/c\nQUERY_STRING=x=1&y=2\nCONTENT_LENGTH=5\nCONTENT_TYPE=text/plain\n\
+             REMOTE_ADDR=10.0.0.7\nSERVER_NAME=cgi.test\nSERVER_PORT=8080\n\
+             HTTP_X_TAG=one, two\nHTTP_PROXY=\nhello"
+        );
+    }
+
+    #[test]
+    fn status_and_location_headers_set_the_status() {
+        let dir = TempDir::new().unwrap();
+        script(
+            &dir,
+            "created",
+            "printf 'Status: 201 Created\\r\\nX-Id: 7\\r\\nContent-Length: 99\\r\\n\\r\\nmade'",
+            0o755,
+        );
+        script(&dir, "moved", "printf 'Location: /elsewhere\\n\\n'", 0o755);
+        let cgi = Cgi::new("/cgi-bin", dir.path());
+
+        let response = cgi.handle(get("/cgi-bin/created"));
+        assert_eq!(response.status, 201);
+        assert_eq!(response.headers.get("X-Id"), Some("7"));
+        assert!(!response.headers.contains("Content-Length"));
+        assert_eq!(body(&response), "made");
+
+        let response = cgi.handle(get("/cgi-bin/moved"));
+        assert_eq!(response.status, 302);
+        assert_eq!(response.headers.get("Location"), Some("/elsewhere"));
+    }
+
+    #[test]
+    fn rejects_missing_hidden_and_non_executable_scripts() {
+        let dir = TempDir::new().unwrap();
+        script(&dir, "plain.txt", "echo", 0o644);
+        script(&dir, ".hidden", "echo", 0o755);
+        script(&dir, "garbage", "echo 'no headers here'", 0o755);
+        let cgi = Cgi::new("/cgi-bin", dir.path().join("sub"));
+        fs::create_dir(dir.path().join("sub")).unwrap();
+        script(&dir, "sub/../outside", "echo", 0o755);
+        let cgi_root = Cgi::new("/cgi-bin", dir.path());
+
+        assert_eq!(cgi.handle(get("/cgi-bin/missing")).status, 404);
+        assert_eq!(cgi.handle(get("/cgi-bin/..%2Foutside")).status, 404);
+        assert_eq!(cgi.handle(get("/cgi-bin/%2E%2E/outside")).status, 404);
+        assert_eq!(cgi_root.handle(get("/cgi-bin/.hidden")).status, 404);
+        assert_eq!(cgi_root.handle(get("/elsewhere/plain.txt")).status, 404);
+        assert_eq!(cgi_root.handle(get("/cgi-bin/plain.txt")).status, 403);
+        assert_eq!(cgi_root.handle(get("/cgi-bin/garbage")).status, 502);
+    }
+
+    #[test]
+    fn slow_scripts_are_killed() {
+        let dir = TempDir::new().unwrap();
+        script(&dir, "slow", "exec sleep 10", 0o755);
+        let cgi = Cgi::new("/cgi-bin", dir.path()).timeout(Duration::from_millis(200));
+
+        let start = Instant::now();
+        assert_eq!(cgi.handle(get("/cgi-bin/slow")).status, 504);
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        // The script exits, but a process it left behind keeps the pipes
+        // open.
+        script(
+            &dir,
+            "forks",
+            "sleep 10 &\necho 'Content-Type: text/plain'",
+            0o755,
+        );
+        let start = Instant::now();
+        assert_eq!(cgi.handle(get("/cgi-bin/forks")).status, 504);
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn output_is_limited() {
+        let dir = TempDir::new().unwrap();
+        script(
+            &dir,
+            "chatty",
+            "printf 'Content-Type: text/plain\\n\\n'\nexec yes",
+            0o755,
+        );
+        script(
+            &dir,
+            "small",
+            "printf 'Content-Type: text/plain\\n\\nfits'",
+            0o755,
+        );
+        let cgi = Cgi::new("/cgi-bin", dir.path()).max_output(1024);
+
+        assert_eq!(cgi.handle(get("/cgi-bin/chatty")).status, 502);
+        let response = cgi.handle(get("/cgi-bin/small"));
+        assert_eq!(response.status, 200);
+        assert_eq!(body(&response), "fits");
+    }
+}
@@ -0,0 +1,501 @@
+use std::io::{self, Read, Write};
+use std::mem;
+
+use flate2::write::{GzEncoder, ZlibEncoder};
+
+use crate::middleware::{Middleware, Next};
+use crate::request::{Method, Request};
+use crate::response::{Body, Response};
+
+/// A content coding this server can apply to response bodies.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Gzip,
+    /// The zlib format, which is what HTTP calls `deflate`.
+    Deflate,
+    #[cfg(feature = "brotli")]
+    Brotli,
+}
+
+impl Encoding {
+    /// Supported codings, most preferred first.
+    const ALL: &'static [Encoding] = &[
+        #[cfg(feature = "brotli")]
+        Encoding::Brotli,
+        Encoding::Gzip,
+        Encoding::Deflate,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate",
+            #[cfg(feature = "brotli")]
+            Encoding::Brotli => "br",
+        }
+    }
+
+    /// Picks the coding to use for a request's `Accept-Encoding` header.
+    ///
+    /// The coding with the highest quality value wins, ties go to the better
+    /// compression, and codings with `q=0` are never chosen. Without the
+    /// header, bodies are sent as they are.
+    pub fn negotiate(accept_encoding: Option<&str>) -> Option<Encoding> {
+        let accept_encoding = accept_encoding?;
+        let mut best: Option<(Encoding, u16)> = None;
+        for &encoding in Encoding::ALL {
+            let q = quality(accept_encoding, encoding.as_str());
+            if q > 0 && best.is_none_or(|(_, best_q)| q > best_q) {
+                best = Some((encoding, q));
+            }
+        }
+        best.map(|(encoding, _)| encoding)
+    }
+
+    fn encoder(self, level: u32) -> Encoder {
+        let out = Vec::new();
+        match self {
+            Encoding::Gzip => Encoder::Gzip(GzEncoder::new(out, flate2::Compression::new(level))),
+            Encoding::Deflate => {
+                Encoder::Deflate(ZlibEncoder::new(out, flate2::Compression::new(level)))
+            }
+            #[cfg(feature = "brotli")]
+            Encoding::Brotli => Encoder::Brotli(Box::new(brotli::CompressorWriter::new(
+                out, 4096, level, 22,
+            ))),
+        }
+    }
+}
+
+/// Returns the quality value, scaled to 0–1000, that `accept_encoding` gives
+/// `coding`. A coding that is not listed gets the value of `*`, if any.
+pub(crate) fn quality(accept_encoding: &str, coding: &str) -> u16 {
+    let mut wildcard = 0;
+    for item in accept_encoding.split(',') {
+        let mut parts = item.split(';');
+        let name = parts.next().unwrap_or("").trim();
+        let q = parts
+            .filter_map(|p| p.trim().split_once('='))
+            .find(|(k, _)| k.trim().eq_ignore_ascii_case("q"))
+            .map_or(1000, |(_, v)| parse_qvalue(v.trim()));
+
+        let is_alias = coding == "gzip" && name.eq_ignore_ascii_case("x-gzip");
+        if name.eq_ignore_ascii_case(coding) || is_alias {
+            return q;
+        }
+        if name == "*" {
+            wildcard = q;
+        }
+    }
+    wildcard
+}
+
+/// Parses a `qvalue` ("0", "0.5", "1.000") into thousandths. Malformed
+/// values count as 0.
+fn parse_qvalue(value: &str) -> u16 {
+    let (whole, fraction) = value.split_once('.').unwrap_or((value, ""));
+    if fraction.len() > 3 || !fraction.bytes().all(|b| b.is_ascii_digit()) {
+        return 0;
+    }
+    let thousandths = format!("{fraction:0<3}").parse::<u16>().unwrap_or(0);
+    match whole {
+        "0" => thousandths,
+        "1" if thousandths == 0 => 1000,
+        _ => 0,
+    }
+}
+
+/// Whether bodies of this `Content-Type` are worth compressing.
+pub(crate) fn is_compressible(content_type: &str) -> bool {
+    let essence = content_type
+        .split(';')
+        .next()
+        .unwrap_or("")
+        .trim()
+        .to_ascii_lowercase();
+    essence.starts_with("text/")
+        || essence.ends_with("+json")
+        || essence.ends_with("+xml")
+        || matches!(
+            essence.as_str(),
+            "application/json"
+                | "application/javascript"
+                | "application/xml"
+                | "application/wasm"
+                | "image/x-icon"
+        )
+}
+
+enum Encoder {
+    Gzip(GzEncoder<Vec<u8>>),
+    Deflate(ZlibEncoder<Vec<u8>>),
+    #[cfg(feature = "brotli")]
+    Brotli(Box<brotli::CompressorWriter<Vec<u8>>>),
+}
+
+impl Encoder {
+    fn writer(&mut self) -> &mut dyn Write {
+        match self {
+            Encoder::Gzip(e) => e,
+            Encoder::Deflate(e) => e,
+            #[cfg(feature = "brotli")]
+            Encoder::Brotli(e) => e.as_mut(),
+        }
+    }
+
+    /// Takes the compressed bytes produced so far.
+    fn take_output(&mut self) -> Vec<u8> {
+        let out = match self {
+            Encoder::Gzip(e) => e.get_mut(),
+            Encoder::Deflate(e) => e.get_mut(),
+            #[cfg(feature = "brotli")]
+            Encoder::Brotli(e) => e.get_mut(),
+        };
+        mem::take(out)
+    }
+
+    fn finish(self) -> io::Result<Vec<u8>> {
+        match self {
+            Encoder::Gzip(e) => e.finish(),
+            Encoder::Deflate(e) => e.finish(),
+            #[cfg(feature = "brotli")]
+            Encoder::Brotli(e) => Ok(e.into_inner()),
+        }
+    }
+}
+
+/// Where a streamed body comes from.
+enum Source {
+    Reader(Box<dyn Read + Send>),
+    /// Chunks are flushed through the encoder one by one, so each still
+    /// reaches the client as soon as it is produced.
+    Chunks(Box<dyn Iterator<Item = Vec<u8>> + Send>),
+}
+
+/// Compresses a streamed body into chunks.
+struct Compressed {
+    source: Source,
+    encoder: Option<Encoder>,
+}
+
+impl Compressed {
+    /// Feeds the next piece of input to the encoder. Returns `false` once the
+    /// input is exhausted.
+    fn feed(&mut self, encoder: &mut Encoder) -> io::Result<bool> {
+        match &mut self.source {
+            Source::Reader(reader) => {
+                let mut buf = vec![0; 64 * 1024];
+                let n = loop {
+                    match reader.read(&mut buf) {
+                        Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                        result => break result?,
+                    }
+                };
+                encoder.writer().write_all(&buf[..n])?;
+                Ok(n > 0)
+            }
+            Source::Chunks(chunks) => match chunks.next() {
+                Some(chunk) => {
+                    encoder.writer().write_all(&chunk)?;
+                    encoder.writer().flush()?;
+                    Ok(true)
+                }
+                None => Ok(false),
+            },
+        }
+    }
+}
+
+impl Iterator for Compressed {
+    type Item = Vec<u8>;
+
+    fn next(&mut self) -> Option<Vec<u8>> {
+        let mut encoder = self.encoder.take()?;
+        loop {
+            match self.feed(&mut encoder) {
+                Ok(true) => {
+                    let out = encoder.take_output();
+                    if !out.is_empty() {
+                        self.encoder = Some(encoder);
+                        return Some(out);
+                    }
+                }
+                Ok(false) => return encoder.finish().ok().filter(|out| !out.is_empty()),
+                Err(e) => {
+                    // Leaving the stream unfinished lets the client notice
+                    // that the body is cut short.
+                    warn!("Failed to compress response body: {e}");
+                    return None;
+                }
+            }
+        }
+    }
+}
+
+/// Compresses response bodies with the best coding the client accepts.
+///
+/// Only successful responses with a compressible `Content-Type` are
+/// compressed, and only if their body is at least the minimum size or of
+/// unknown length. Bodies in memory are compressed at once; files, readers
+/// and chunked bodies are compressed as they are sent. Compressed responses
+/// get a weak `ETag`, since their bytes differ from the original
+/// representation, and every compressible response gets
+/// `Vary: Accept-Encoding`.
+pub struct Compression {
+    min_size: u64,
+    level: u32,
+}
+
+impl Compression {
+    /// Compresses bodies of at least 1 KiB at level 6.
+    pub fn new() -> Compression {
+        Compression {
+            min_size: 1024,
+            level: 6,
+        }
+    }
+
+    /// Sets the smallest body worth compressing. Smaller ones gain little
+    /// and can even grow.
+    pub fn min_size(mut self, bytes: u64) -> Compression {
+        self.min_size = bytes;
+        self
+    }
+
+    /// Sets the compression level, from 0 (fastest) to 9 (smallest).
+    pub fn level(mut self, level: u32) -> Compression {
+        self.level = level.min(9);
+        self
+    }
+
+    fn compress(&self, response: &mut Response, encoding: Encoding) -> io::Result<()> {
+        let mut encoder = encoding.encoder(self.level);
+        let source = match mem::replace(&mut response.body, Body::empty()) {
+            Body::Bytes(bytes) => {
+                encoder.writer().write_all(&bytes)?;
+                response.body = Body::Bytes(encoder.finish()?);
+                return Ok(());
+            }
+            Body::File { file, len } => Source::Reader(Box::new(file.take(len))),
+            Body::Reader {
+                reader,
+                len: Some(len),
+            } => Source::Reader(Box::new(reader.take(len))),
+            Body::Reader { reader, len: None } => Source::Reader(reader),
+            Body::Chunked(chunks) => Source::Chunks(chunks),
+        };
+        response.body = Body::Chunked(Box::new(Compressed {
+            source,
+            encoder: Some(encoder),
+        }));
+        Ok(())
+    }
+}
+
+impl Default for Compression {
+    fn default() -> Compression {
+        Compression::new()
+    }
+}
+
+impl Middleware for Compression {
+    fn handle(&self, request: Request, next: Next<'_>) -> Response {
+        let encoding = Encoding::negotiate(request.headers.get("Accept-Encoding"));
+        let is_head = request.method == Method::Head;
+        let mut response = next.run(request);
+
+        let compressible = response
+            .headers
+            .get("Content-Type")
+            .is_some_and(is_compressible);
+        if !compressible || response.headers.contains("Content-Encoding") {
+            return response;
+        }
+        if !response.headers.has_token("Vary", "Accept-Encoding") {
+            response.headers.append("Vary", "Accept-Encoding");
+        }
+
+        let Some(encoding) = encoding else {
+            return response;
+        };
+        // Ranges refer to the bytes as stored, so partial responses are left
+        // alone, and a HEAD response has no body to compress.
+        if response.status != 200 || is_head || response.headers.contains("Content-Range") {
+            return response;
+        }
+        if response.body.len().is_some_and(|len| len < self.min_size) {
+            return response;
+        }
+
+        if let Err(e) = self.compress(&mut response, encoding) {
+            error!("Failed to compress response body: {e}");
+            return Response::text(500, "Internal Server Error");
+        }
+        response
+            .headers
+            .insert("Content-Encoding", encoding.as_str());
+        response.headers.remove("Content-Length");
+        response.headers.remove("Accept-Ranges");
+        if let Some(etag) = response.headers.get("ETag") {
+            if !etag.starts_with("W/") {
+                let weak = format!("W/{etag}");
+                response.headers.insert("ETag", weak);
+            }
+        }
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::middleware::Chain;
+    use crate::request::RequestParser;
+    use crate::router::Handler;
+    use flate2::read::{GzDecoder, ZlibDecoder};
+
+    fn request(headers: &str) -> Request {
+        let mut parser = RequestParser::new();
+        parser.feed(format!("GET / HTTP/1.1\r\nHost: test\r\n{headers}\r\n").as_bytes());
+        parser.parse().unwrap().unwrap()
+    }
+
+    /// Writes the response and returns the body with chunked framing removed.
+    fn sent_body(mut response: Response) -> Vec<u8> {
+        let mut out = Vec::new();
+        response.write_to(&mut out).unwrap();
+        let start = out.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4;
+        let head = String::from_utf8_lossy(&out[..start]);
+        let mut rest = &out[start..];
+        if !head.contains("Transfer-Encoding: chunked\r\n") {
+            return rest.to_vec();
+        }
+        let mut body = Vec::new();
+        loop {
+            let line_end = rest.windows(2).position(|w| w == b"\r\n").unwrap();
+            let size = std::str::from_utf8(&rest[..line_end]).unwrap();
+            let size = usize::from_str_radix(size, 16).unwrap();
+            rest = &rest[line_end + 2..];
+            if size == 0 {
+                return body;
+            }
+            body.extend_from_slice(&rest[..size]);
+            rest = &rest[size + 2..];
+        }
+    }
+
+    fn text(len: usize) -> String {
+        "hello, compression! ".repeat(len / 20)
+    }
+
+    #[test]
+    fn negotiates_by_quality() {
+        assert_eq!(Encoding::negotiate(None), None);
+        assert_eq!(Encoding::negotiate(Some("identity")), None);
+        assert_eq!(
+            Encoding::negotiate(Some("deflate, gzip;q=0.5")),
+            Some(Encoding::Deflate)
+        );
+        assert_eq!(
+            Encoding::negotiate(Some("deflate;q=0.5, x-gzip")),
+            Some(Encoding::Gzip)
+        );
+        assert_eq!(
+            Encoding::negotiate(Some("*;q=0.1, gzip;q=0, br;q=0")),
+            Some(Encoding::Deflate)
+        );
+        assert_eq!(Encoding::negotiate(Some("gzip;q=0, deflate;q=0")), None);
+        assert_eq!(parse_qvalue("0.25"), 250);
+        assert_eq!(parse_qvalue("1.5"), 0);
+    }
+
+    #[test]
+    fn compresses_large_text_bodies() {
+        let original = text(4096);
+        let chain = Chain::new({
+            let original = original.clone();
+            move |_: Request| Response::text(200, original.clone()).with_header("ETag", "\"v1\"")
+        })
+        .with(Compression::new());
+
+        let response = chain.handle(request("Accept-Encoding: gzip, deflate\r\n"));
+        assert_eq!(response.headers.get("Content-Encoding"), Some("gzip"));
+        assert_eq!(response.headers.get("ETag"), Some("W/\"v1\""));
+        assert!(response.headers.has_token("Vary", "Accept-Encoding"));
+
+        let compressed = sent_body(response);
+        assert!(compressed.len() < original.len() / 10);
+        let mut decoded = String::new();
+        GzDecoder::new(&compressed[..])
+            .read_to_string(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, original);
+    }
+
+    #[test]
+    fn leaves_small_binary_and_unaccepted_bodies_alone() {
+        let chain = Chain::new(|req: Request| match req.path.as_str() {
+            "/small" => Response::text(200, "tiny"),
+            "/image" => Response::new(200)
+                .with_header("Content-Type", "image/png")
+                .with_body(vec![0; 4096]),
+            _ => Response::text(200, text(4096)),
+        })
+        .with(Compression::new());
+
+        let mut small = request("Accept-Encoding: gzip\r\n");
+        small.path = "/small".to_string();
+        let small = chain.handle(small);
+        assert!(!small.headers.contains("Content-Encoding"));
+        assert!(small.headers.has_token("Vary", "Accept-Encoding"));
+
+        let mut image = request("Accept-Encoding: gzip\r\n");
+        image.path = "/image".to_string();
+        let image = chain.handle(image);
+        assert!(!image.headers.contains("Content-Encoding"));
+        assert!(!image.headers.contains("Vary"));
+
+        let plain = chain.handle(request(""));
+        assert!(!plain.headers.contains("Content-Encoding"));
+        assert_eq!(plain.body.len(), Some(4096 / 20 * 20));
+    }
+
+    #[test]
+    fn streams_chunked_bodies_through_the_encoder() {
+        let chain = Chain::new(|_: Request| {
+            let chunks = (0..50).map(|i| format!("line {i}\n").into_bytes());
+            Response::text(200, Body::chunked(chunks))
+        })
+        .with(Compression::new());
+
+        let response = chain.handle(request("Accept-Encoding: deflate\r\n"));
+        assert_eq!(response.headers.get("Content-Encoding"), Some("deflate"));
+
+        let mut decoded = String::new();
+        ZlibDecoder::new(&sent_body(response)[..])
+            .read_to_string(&mut decoded)
+            .unwrap();
+        let expected: String = (0..50).map(|i| format!("line {i}\n")).collect();
+        assert_eq!(decoded, expected);
+    }
+
+    #[cfg(feature = "brotli")]
+    #[test]
+    fn compresses_with_brotli_when_preferred() {
+        let original = text(4096);
+        let chain = Chain::new({
+            let original = original.clone();
+            move |_: Request| Response::text(200, original.clone())
+        })
+        .with(Compression::new());
+
+        let response = chain.handle(request("Accept-Encoding: gzip, br\r\n"));
+        assert_eq!(response.headers.get("Content-Encoding"), Some("br"));
+
+        let mut decoded = String::new();
+        brotli::Decompressor::new(&sent_body(response)[..], 4096)
+            .read_to_string(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, original);
+    }
+}
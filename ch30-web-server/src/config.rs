@@ -0,0 +1,1029 @@
+//! Server settings from a TOML file, environment variables and flags.
+//!
+//! Later sources override earlier ones: built-in defaults, then the file
+//! named by `--config` or `CH30_CONFIG`, then `CH30_*` environment
+//! variables, then command-line flags. A complete file looks like this:
+//!
+//! ```toml
+//! address = "127.0.0.1"
+//! port = 7878
+//! document_root = "."
+//! # "threads" gives each connection a worker; "event-loop" waits for all
+//! # of them on one thread and only hands requests to the workers.
+//! mode = "threads"
+//!
+//! [pool]
+//! workers = 4
+//! max_workers = 16
+//! queue = 16
+//! keep_alive = "30s"
+//!
+//! [timeouts]
+//! idle = 5
+//! header = 10
+//! body = 30
+//! write = 30
+//! drain = "10s"
+//!
+//! [limits]
+//! max_header_size = 8192
+//! max_body_size = 1048576
+//!
+//! [log]
+//! level = "info"
+//! format = "combined"
+//!
+//! # HTTPS, if built with the `tls` feature. Clients are sent the
+//! # certificate of the host name they ask for, or else the default one.
+//! [tls]
+//! cert = "certs/server.pem"
+//! key = "certs/server.key"
+//!
+//! [tls.hosts."api.example.test"]
+//! cert = "certs/api.pem"
+//! key = "certs/api.key"
+//!
+//! # Requests under a path prefix are forwarded to these servers in turn.
+//! # With a health check, servers that fail it are skipped.
+//! [proxy."/api"]
+//! upstreams = ["127.0.0.1:3001", "127.0.0.1:3002"]
+//! health_check = "/health"
+//! health_interval = "5s"
+//!
+//! # Other sites on the same port, chosen by the Host header. Requests for
+//! # any other host are served from the top-level document_root.
+//! [vhosts."blog.example.test"]
+//! document_root = "sites/blog"
+//!
+//! [vhosts."*.example.test"]
+//! document_root = "sites/www"
+//!
+//! # CGI scripts, run for requests below the prefix.
+//! [cgi]
+//! dir = "cgi-bin"
+//! prefix = "/cgi-bin"
+//! timeout = "10s"
+//! ```
+
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::connection::ConnectionConfig;
+use crate::logger::{Level, LogFormat};
+use crate::proxy::Proxy;
+use crate::request::Limits;
+#[cfg(feature = "tls")]
+use crate::tls::{TlsConfig, TlsError};
+
+/// Usage text printed for `--help`.
+pub const USAGE: &str = "\
+Usage: ch30-web-server [OPTIONS]
+
+Options:
+  --config <FILE>              Read settings from a TOML file
+  --address <IP>               Address to listen on [default: 127.0.0.1]
+  --port <PORT>                Port to listen on [default: 7878]
+  --document-root <DIR>        Directory with the pages and static/ [default: .]
+  --mode <MODE>                threads or event-loop [default: threads]
+  --workers <N>                Worker threads started up front [default: 4]
+  --max-workers <N>            Most worker threads under load [default: 4 x workers]
+  --queue <N>                  Connections waiting for a worker [default: 16]
+  --worker-keep-alive <TIME>   Idle time before a surplus worker exits [default: 30s]
+  --idle-timeout <TIME>        Keep-alive timeout between requests [default: 5s]
+  --header-timeout <TIME>      Time to receive a request's headers [default: 10s]
+  --body-timeout <TIME>        Time to receive a request's body [default: 30s]
+  --write-timeout <TIME>       Time a response write may block [default: 30s]
+  --drain-timeout <TIME>       Time given to in-flight requests on shutdown [default: 10s]
+  --max-header-size <BYTES>    Largest request line plus headers [default: 8192]
+  --max-body-size <BYTES>      Largest request body [default: 1048576]
+  --log-level <LEVEL>          error, warn, info, debug or trace [default: info]
+  --log-format <FORMAT>        common, combined or json [default: combined]
+  --tls-cert <FILE>            PEM certificate chain; serves HTTPS when set
+  --tls-key <FILE>             PEM private key for --tls-cert
+  --cgi-dir <DIR>              Directory of CGI scripts; runs them when set
+  --cgi-prefix <PATH>          URL path the CGI scripts are under [default: /cgi-bin]
+  --cgi-timeout <TIME>         Time a CGI script may run [default: 10s]
+  -h, --help                   Print this help
+
+Every option can also be set with an environment variable named after it,
+for example CH30_MAX_WORKERS=32. TIME is a number of seconds or a number
+followed by ms, s or m. Certificates for other host names, proxied paths
+and virtual hosts can only be set in the config file.
+";
+
+/// The most worker threads the pool may be configured with.
+const MAX_THREADS: usize = 4096;
+
+/// Settings names as used by flags, and the matching TOML keys.
+const SETTINGS: &[(&str, &str)] = &[
+    ("address", "address"),
+    ("port", "port"),
+    ("document-root", "document_root"),
+    ("mode", "mode"),
+    ("workers", "pool.workers"),
+    ("max-workers", "pool.max_workers"),
+    ("queue", "pool.queue"),
+    ("worker-keep-alive", "pool.keep_alive"),
+    ("idle-timeout", "timeouts.idle"),
+    ("header-timeout", "timeouts.header"),
+    ("body-timeout", "timeouts.body"),
+    ("write-timeout", "timeouts.write"),
+    ("drain-timeout", "timeouts.drain"),
+    ("max-header-size", "limits.max_header_size"),
+    ("max-body-size", "limits.max_body_size"),
+    ("log-level", "log.level"),
+    ("log-format", "log.format"),
+    ("tls-cert", "tls.cert"),
+    ("tls-key", "tls.key"),
+    ("cgi-dir", "cgi.dir"),
+    ("cgi-prefix", "cgi.prefix"),
+    ("cgi-timeout", "cgi.timeout"),
+];
+
+/// Everything `main` needs to start the server.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServerConfig {
+    pub address: IpAddr,
+    pub port: u16,
+    /// Holds `hello.html`, `404.html` and the `static/` directory.
+    pub document_root: PathBuf,
+    /// Whether connections are served from an event loop rather than a
+    /// worker each.
+    pub event_loop: bool,
+    pub workers: usize,
+    /// `None` means four times `workers`.
+    pub max_workers: Option<usize>,
+    /// Bound of the job queue.
+    pub queue: usize,
+    pub worker_keep_alive: Duration,
+    pub idle_timeout: Duration,
+    pub header_timeout: Duration,
+    pub body_timeout: Duration,
+    pub write_timeout: Duration,
+    pub drain_timeout: Duration,
+    pub limits: Limits,
+    pub log_level: Level,
+    pub log_format: LogFormat,
+    /// The default certificate chain and key for HTTPS.
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    /// Certificates for specific host names, chosen by SNI.
+    pub tls_hosts: Vec<TlsHost>,
+    /// Path prefixes forwarded to other servers.
+    pub proxies: Vec<ProxyRoute>,
+    /// Sites served instead of `document_root` for specific host names.
+    pub vhosts: Vec<VirtualHost>,
+    /// The directory of CGI scripts, if CGI is enabled.
+    pub cgi_dir: Option<PathBuf>,
+    pub cgi_prefix: String,
+    pub cgi_timeout: Duration,
+}
+
+/// A site for one host name, from `[vhosts."name"]`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VirtualHost {
+    /// A host name, or a pattern such as `*.example.test`.
+    pub name: String,
+    pub document_root: PathBuf,
+}
+
+/// A certificate for one host name, from `[tls.hosts."name"]`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TlsHost {
+    /// A host name, or a pattern such as `*.example.test`.
+    pub name: String,
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+/// A path prefix forwarded to upstream servers, from `[proxy."/prefix"]`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProxyRoute {
+    pub prefix: String,
+    /// `host:port` addresses, used in turn.
+    pub upstreams: Vec<String>,
+    /// The path to check the upstreams' health with, if any.
+    pub health_check: Option<String>,
+    pub health_interval: Duration,
+}
+
+impl ProxyRoute {
+    fn new(prefix: &str) -> ProxyRoute {
+        ProxyRoute {
+            prefix: prefix.to_string(),
+            upstreams: Vec::new(),
+            health_check: None,
+            health_interval: Duration::from_secs(10),
+        }
+    }
+
+    /// The router pattern matching the prefix and every path below it.
+    pub fn pattern(&self) -> String {
+        format!("{}/*", self.prefix.trim_end_matches('/'))
+    }
+
+    /// Builds the proxy handler. Health checks start right away.
+    pub fn proxy(&self) -> Proxy {
+        let proxy = Proxy::new(self.upstreams.iter().cloned());
+        match &self.health_check {
+            Some(path) => proxy.health_check(path, self.health_interval),
+            None => proxy,
+        }
+    }
+}
+
+impl Default for ServerConfig {
+    fn default() -> ServerConfig {
+        ServerConfig {
+            address: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            port: 7878,
+            document_root: PathBuf::from("."),
+            event_loop: false,
+            workers: 4,
+            max_workers: None,
+            queue: 16,
+            worker_keep_alive: Duration::from_secs(30),
+            idle_timeout: Duration::from_secs(5),
+            header_timeout: Duration::from_secs(10),
+            body_timeout: Duration::from_secs(30),
+            write_timeout: Duration::from_secs(30),
+            drain_timeout: Duration::from_secs(10),
+            limits: Limits::default(),
+            log_level: Level::Info,
+            log_format: LogFormat::Combined,
+            tls_cert: None,
+            tls_key: None,
+            tls_hosts: Vec::new(),
+            proxies: Vec::new(),
+            vhosts: Vec::new(),
+            cgi_dir: None,
+            cgi_prefix: "/cgi-bin".to_string(),
+            cgi_timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+impl ServerConfig {
+    /// Builds the configuration from the program arguments (including the
+    /// program name) and environment variables, then validates it.
+    pub fn build(
+        mut args: impl Iterator<Item = String>,
+        env: impl Iterator<Item = (String, String)>,
+    ) -> Result<ServerConfig, ConfigError> {
+        args.next(); // Skip the program name
+
+        let flags = parse_flags(args)?;
+        let env: Vec<(String, String)> = env.filter(|(k, _)| k.starts_with("CH30_")).collect();
+
+        let config_file = flags
+            .iter()
+            .rev()
+            .find(|(name, _)| name == "config")
+            .map(|(_, value)| value.clone())
+            .or_else(|| {
+                env.iter()
+                    .find(|(k, _)| k == "CH30_CONFIG")
+                    .map(|(_, v)| v.clone())
+            });
+        let mut config = match config_file {
+            Some(path) => ServerConfig::load(path)?,
+            None => ServerConfig::default(),
+        };
+
+        for (key, value) in &env {
+            if key == "CH30_CONFIG" {
+                continue;
+            }
+            let name = key["CH30_".len()..].to_ascii_lowercase().replace('_', "-");
+            if !SETTINGS.iter().any(|(n, _)| *n == name) {
+                return Err(ConfigError::UnknownVariable(key.clone()));
+            }
+            config.apply(&name, value, key)?;
+        }
+        for (name, value) in &flags {
+            if name != "config" {
+                config.apply(name, value, &format!("--{name}"))?;
+            }
+        }
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Reads a TOML file on top of the defaults. The result is not
+    /// validated yet.
+    pub fn load(path: impl AsRef<Path>) -> Result<ServerConfig, ConfigError> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path).map_err(|error| ConfigError::Read {
+            path: path.to_path_buf(),
+            error,
+        })?;
+        ServerConfig::from_toml(&text, &path.display().to_string())
+    }
+
+    /// Parses TOML text on top of the defaults. `source` names the text in
+    /// error messages.
+    pub fn from_toml(text: &str, source: &str) -> Result<ServerConfig, ConfigError> {
+        let table: toml::Table = text
+            .parse()
+            .map_err(|e: toml::de::Error| ConfigError::Parse {
+                origin: source.to_string(),
+                message: e.message().to_string(),
+            })?;
+
+        let mut config = ServerConfig::default();
+        let mut values = Vec::new();
+        flatten(&table, "", &mut values);
+        let mut hosts: Vec<(String, Option<PathBuf>, Option<PathBuf>)> = Vec::new();
+        for (key, value) in values {
+            let origin = format!("{key} in {source}");
+            if let Some(rest) = key.strip_prefix("tls.hosts.") {
+                let (name, field, path) = match (rest.rsplit_once('.'), value) {
+                    (Some((name, field @ ("cert" | "key"))), toml::Value::String(path)) => {
+                        (name, field, PathBuf::from(path))
+                    }
+                    (Some((_, "cert" | "key")), other) => {
+                        return Err(ConfigError::InvalidValue {
+                            origin,
+                            value: other.to_string(),
+                            expected: "a file path",
+                        })
+                    }
+                    _ => return Err(ConfigError::UnknownKey(origin)),
+                };
+                let index = match hosts.iter().position(|(n, _, _)| n == name) {
+                    Some(index) => index,
+                    None => {
+                        hosts.push((name.to_string(), None, None));
+                        hosts.len() - 1
+                    }
+                };
+                if field == "cert" {
+                    hosts[index].1 = Some(path);
+                } else {
+                    hosts[index].2 = Some(path);
+                }
+                continue;
+            }
+            if let Some(rest) = key.strip_prefix("vhosts.") {
+                let (name, root) = match (rest.rsplit_once('.'), value) {
+                    (Some((name, "document_root")), toml::Value::String(root)) => (name, root),
+                    (Some((_, "document_root")), other) => {
+                        return Err(ConfigError::InvalidValue {
+                            origin,
+                            value: other.to_string(),
+                            expected: "a directory path",
+                        })
+                    }
+                    _ => return Err(ConfigError::UnknownKey(origin)),
+                };
+                config.vhosts.push(VirtualHost {
+                    name: name.to_string(),
+                    document_root: PathBuf::from(root),
+                });
+                continue;
+            }
+            if let Some(rest) = key.strip_prefix("proxy.") {
+                let Some((prefix, field)) = rest.rsplit_once('.') else {
+                    return Err(ConfigError::UnknownKey(origin));
+                };
+                let index = match config.proxies.iter().position(|p| p.prefix == prefix) {
+                    Some(index) => index,
+                    None => {
+                        config.proxies.push(ProxyRoute::new(prefix));
+                        config.proxies.len() - 1
+                    }
+                };
+                let route = &mut config.proxies[index];
+                match (field, value) {
+                    ("upstreams", toml::Value::Array(list)) => {
+                        for upstream in list {
+                            let Some(upstream) = upstream.as_str() else {
+                                return Err(ConfigError::InvalidValue {
+                                    origin,
+                                    value: upstream.to_string(),
+                                    expected: "a host:port string",
+                                });
+                            };
+                            route.upstreams.push(upstream.to_string());
+                        }
+                    }
+                    ("health_check", toml::Value::String(path)) => {
+                        route.health_check = Some(path.clone());
+                    }
+                    ("health_interval", value) => {
+                        let text = match value {
+                            toml::Value::String(s) => s.clone(),
+                            other => other.to_string(),
+                        };
+                        route.health_interval =
+                            parse_duration(&text).ok_or_else(|| ConfigError::InvalidValue {
+                                origin,
+                                value: text.clone(),
+                                expected: "a duration like 5, 1.5s or 500ms",
+                            })?;
+                    }
+                    ("upstreams" | "health_check", other) => {
+                        return Err(ConfigError::InvalidValue {
+                            origin,
+                            value: other.to_string(),
+                            expected: if field == "upstreams" {
+                                "a list of host:port strings"
+                            } else {
+                                "a path"
+                            },
+                        })
+                    }
+                    _ => return Err(ConfigError::UnknownKey(origin)),
+                }
+                continue;
+            }
+            let Some((name, _)) = SETTINGS.iter().find(|(_, k)| *k == key) else {
+                return Err(ConfigError::UnknownKey(origin));
+            };
+            let value = match value {
+                toml::Value::String(s) => s.clone(),
+                toml::Value::Integer(n) => n.to_string(),
+                toml::Value::Float(n) => n.to_string(),
+                toml::Value::Boolean(b) => b.to_string(),
+                other => {
+                    return Err(ConfigError::InvalidValue {
+                        origin,
+                        value: other.to_string(),
+                        expected: "a string or number",
+                    })
+                }
+            };
+            config.apply(name, &value, &origin)?;
+        }
+
+        for (name, cert, key) in hosts {
+            let (Some(cert), Some(key)) = (cert, key) else {
+                return Err(ConfigError::Invalid(vec![format!(
+                    "tls.hosts.\"{name}\" in {source} needs both cert and key"
+                )]));
+            };
+            config.tls_hosts.push(TlsHost { name, cert, key });
+        }
+        Ok(config)
+    }
+
+    /// Sets the setting called `name` (as spelled in flags) from `value`.
+    fn apply(&mut self, name: &str, value: &str, origin: &str) -> Result<(), ConfigError> {
+        let invalid = |expected| ConfigError::InvalidValue {
+            origin: origin.to_string(),
+            value: value.to_string(),
+            expected,
+        };
+        let count = || {
+            value
+                .parse::<usize>()
+                .map_err(|_| invalid("a whole number"))
+        };
+        let duration =
+            || parse_duration(value).ok_or_else(|| invalid("a duration like 5, 1.5s or 500ms"));
+
+        match name {
+            "address" => self.address = value.parse().map_err(|_| invalid("an IP address"))?,
+            "port" => {
+                self.port = value
+                    .parse()
+                    .map_err(|_| invalid("a port number from 0 to 65535"))?
+            }
+            "document-root" => self.document_root = PathBuf::from(value),
+            "mode" => {
+                self.event_loop = match value.to_ascii_lowercase().as_str() {
+                    "threads" => false,
+                    "event-loop" => true,
+                    _ => return Err(invalid("one of threads, event-loop")),
+                }
+            }
+            "workers" => self.workers = count()?,
+            "max-workers" => self.max_workers = Some(count()?),
+            "queue" => self.queue = count()?,
+            "worker-keep-alive" => self.worker_keep_alive = duration()?,
+            "idle-timeout" => self.idle_timeout = duration()?,
+            "header-timeout" => self.header_timeout = duration()?,
+            "body-timeout" => self.body_timeout = duration()?,
+            "write-timeout" => self.write_timeout = duration()?,
+            "max-header-size" => self.limits.max_header_size = count()?,
+            "max-body-size" => self.limits.max_body_size = count()?,
+            "drain-timeout" => self.drain_timeout = duration()?,
+            "log-level" => {
+                self.log_level = value
+                    .parse()
+                    .map_err(|_| invalid("one of error, warn, info, debug, trace"))?
+            }
+            "log-format" => {
+                self.log_format = match value.to_ascii_lowercase().as_str() {
+                    "common" => LogFormat::Common,
+                    "combined" => LogFormat::Combined,
+                    "json" => LogFormat::Json,
+                    _ => return Err(invalid("one of common, combined, json")),
+                }
+            }
+            "tls-cert" => self.tls_cert = Some(PathBuf::from(value)),
+            "tls-key" => self.tls_key = Some(PathBuf::from(value)),
+            "cgi-dir" => self.cgi_dir = Some(PathBuf::from(value)),
+            "cgi-prefix" => {
+                if !value.starts_with('/') {
+                    return Err(invalid("a path starting with '/'"));
+                }
+                self.cgi_prefix = value.to_string();
+            }
+            "cgi-timeout" => self.cgi_timeout = duration()?,
+            _ => unreachable!("unknown setting {name}"),
+        }
+        Ok(())
+    }
+
+    /// Checks the settings against each other and the file system,
+    /// reporting every problem found.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let mut problems = Vec::new();
+        if self.workers == 0 {
+            problems.push("workers must be at least 1".to_string());
+        }
+        for (name, count) in [
+            ("workers", self.workers),
+            ("max_workers", self.max_workers()),
+        ] {
+            if count > MAX_THREADS {
+                problems.push(format!("{name} ({count}) must be at most {MAX_THREADS}"));
+            }
+        }
+        if self.max_workers() < self.workers {
+            problems.push(format!(
+                "max_workers ({}) must not be less than workers ({})",
+                self.max_workers(),
+                self.workers
+            ));
+        }
+        if self.queue == 0 {
+            problems.push("queue must be at least 1".to_string());
+        }
+        for (name, timeout) in [
+            ("idle", self.idle_timeout),
+            ("header", self.header_timeout),
+            ("body", self.body_timeout),
+            ("write", self.write_timeout),
+        ] {
+            if timeout.is_zero() {
+                problems.push(format!("{name} timeout must be greater than zero"));
+            }
+        }
+        if self.limits.max_header_size < 64 {
+            problems.push("max_header_size must be at least 64 bytes".to_string());
+        }
+        if self.worker_keep_alive.is_zero() {
+            problems.push("worker keep-alive must be greater than zero".to_string());
+        }
+        let roots = std::iter::once(&self.document_root)
+            .chain(self.vhosts.iter().map(|vhost| &vhost.document_root));
+        for root in roots {
+            if !root.is_dir() {
+                problems.push(format!(
+                    "document root {} is not a directory",
+                    root.display()
+                ));
+            }
+        }
+
+        if self.tls_cert.is_some() != self.tls_key.is_some() {
+            problems.push("tls cert and key must be given together".to_string());
+        }
+        if self.tls_enabled() && !cfg!(feature = "tls") {
+            problems.push("HTTPS needs a build with the tls feature".to_string());
+        }
+        if self.tls_enabled() && self.event_loop {
+            problems.push("HTTPS is not supported in event-loop mode".to_string());
+        }
+        let tls_files = self.tls_cert.iter().chain(&self.tls_key).chain(
+            self.tls_hosts
+                .iter()
+                .flat_map(|host| [&host.cert, &host.key]),
+        );
+        for path in tls_files {
+            if !path.is_file() {
+                problems.push(format!("TLS file {} does not exist", path.display()));
+            }
+        }
+
+        if let Some(dir) = &self.cgi_dir {
+            if !dir.is_dir() {
+                problems.push(format!(
+                    "CGI directory {} is not a directory",
+                    dir.display()
+                ));
+            }
+            if self.cgi_timeout.is_zero() {
+                problems.push("CGI timeout must be greater than zero".to_string());
+            }
+        }
+
+        for route in &self.proxies {
+            if !route.prefix.starts_with('/') {
+                problems.push(format!("proxy prefix {} must start with '/'", route.prefix));
+            }
+            if route.upstreams.is_empty() {
+                problems.push(format!("proxy {} has no upstreams", route.prefix));
+            }
+            if route.health_interval.is_zero() {
+                problems.push(format!(
+                    "proxy {} health interval must be greater than zero",
+                    route.prefix
+                ));
+            }
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigError::Invalid(problems))
+        }
+    }
+
+    pub fn max_workers(&self) -> usize {
+        self.max_workers.unwrap_or(self.workers.saturating_mul(4))
+    }
+
+    /// Whether the server should speak HTTPS.
+    pub fn tls_enabled(&self) -> bool {
+        self.tls_cert.is_some() || !self.tls_hosts.is_empty()
+    }
+
+    /// Loads the certificates for [`Server::tls`], or returns `None` if
+    /// HTTPS is not enabled.
+    ///
+    /// [`Server::tls`]: crate::Server::tls
+    #[cfg(feature = "tls")]
+    pub fn tls_config(&self) -> Result<Option<TlsConfig>, TlsError> {
+        if !self.tls_enabled() {
+            return Ok(None);
+        }
+        let mut tls = TlsConfig::new();
+        if let (Some(cert), Some(key)) = (&self.tls_cert, &self.tls_key) {
+            tls = tls.certificate(cert, key)?;
+        }
+        for host in &self.tls_hosts {
+            tls = tls.host(&host.name, &host.cert, &host.key)?;
+        }
+        Ok(Some(tls))
+    }
+
+    pub fn socket_addr(&self) -> SocketAddr {
+        SocketAddr::new(self.address, self.port)
+    }
+
+    /// The per-connection settings for [`Server::connection_config`].
+    ///
+    /// [`Server::connection_config`]: crate::Server::connection_config
+    pub fn connection_config(&self) -> ConnectionConfig {
+        ConnectionConfig {
+            idle_timeout: Some(self.idle_timeout),
+            header_timeout: Some(self.header_timeout),
+            body_timeout: Some(self.body_timeout),
+            write_timeout: Some(self.write_timeout),
+            limits: self.limits,
+            ..ConnectionConfig::default()
+        }
+    }
+}
+
+/// Why the configuration could not be built.
+#[derive(Debug)]
+pub enum ConfigError {
+    /// `--help` was given; the caller should print [`USAGE`] and exit.
+    Help,
+    Read {
+        path: PathBuf,
+        error: io::Error,
+    },
+    /// The configuration file is not valid TOML.
+    Parse {
+        origin: String,
+        message: String,
+    },
+    UnknownFlag(String),
+    UnknownVariable(String),
+    UnknownKey(String),
+    /// A flag was given without its value.
+    MissingValue(String),
+    InvalidValue {
+        /// Where the value came from, e.g. `--port` or `CH30_PORT`.
+        origin: String,
+        value: String,
+        expected: &'static str,
+    },
+    /// The settings are well-formed but do not make sense together.
+    Invalid(Vec<String>),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Help => f.write_str(USAGE),
+            ConfigError::Read { path, error } => {
+                write!(f, "cannot read config file {}: {error}", path.display())
+            }
+            ConfigError::Parse { origin, message } => {
+                write!(f, "{origin} is not valid TOML: {}", message.trim_end())
+            }
+            ConfigError::UnknownFlag(flag) => write!(f, "unknown option {flag}"),
+            ConfigError::UnknownVariable(name) => {
+                write!(f, "unknown environment variable {name}")
+            }
+            ConfigError::UnknownKey(key) => write!(f, "unknown setting {key}"),
+            ConfigError::MissingValue(flag) => write!(f, "option {flag} needs a value"),
+            ConfigError::InvalidValue {
+                origin,
+                value,
+                expected,
+            } => write!(
+                f,
+                "invalid value {value:?} for {origin}: expected {expected}"
+            ),
+            ConfigError::Invalid(problems) => {
+                f.write_str("invalid configuration:")?;
+                for problem in problems {
+                    write!(f, "\n  - {problem}")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Error for ConfigError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ConfigError::Read { error, .. } => Some(error),
+            _ => None,
+        }
+    }
+}
+
+/// Splits `--name value` and `--name=value` flags into pairs.
+fn parse_flags(
+    mut args: impl Iterator<Item = String>,
+) -> Result<Vec<(String, String)>, ConfigError> {
+    let mut flags = Vec::new();
+    while let Some(arg) = args.next() {
+        if arg == "-h" || arg == "--help" {
+            return Err(ConfigError::Help);
+        }
+        let Some(flag) = arg.strip_prefix("--") else {
+            return Err(ConfigError::UnknownFlag(arg));
+        };
+        let (name, inline) = match flag.split_once('=') {
+            Some((name, value)) => (name, Some(value.to_string())),
+            None => (flag, None),
+        };
+        if name != "config" && !SETTINGS.iter().any(|(n, _)| *n == name) {
+            return Err(ConfigError::UnknownFlag(format!("--{name}")));
+        }
+        let value = match inline {
+            Some(value) => value,
+            None => args
+                .next()
+                .ok_or_else(|| ConfigError::MissingValue(format!("--{name}")))?,
+        };
+        flags.push((name.to_string(), value));
+    }
+    Ok(flags)
+}
+
+/// Collects the leaves of `table` with dotted keys.
+fn flatten<'a>(table: &'a toml::Table, prefix: &str, out: &mut Vec<(String, &'a toml::Value)>) {
+    for (key, value) in table {
+        let key = format!("{prefix}{key}");
+        match value {
+            toml::Value::Table(inner) => flatten(inner, &format!("{key}."), out),
+            _ => out.push((key, value)),
+        }
+    }
+}
+
+/// Parses `5`, `1.5s`, `500ms` or `2m`. A bare number is in seconds.
+fn parse_duration(s: &str) -> Option<Duration> {
+    let s = s.trim();
+    let (number, scale) = if let Some(n) = s.strip_suffix("ms") {
+        (n, 0.001)
+    } else if let Some(n) = s.strip_suffix('s') {
+        (n, 1.0)
+    } else if let Some(n) = s.strip_suffix('m') {
+        (n, 60.0)
+    } else {
+        (s, 1.0)
+    };
+    let number: f64 = number.trim().parse().ok()?;
+    Duration::try_from_secs_f64(number * scale).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(list: &[&str]) -> impl Iterator<Item = String> {
+        let mut args = vec!["ch30-web-server".to_string()];
+        args.extend(list.iter().map(|s| s.to_string()));
+        args.into_iter()
+    }
+
+    fn env(list: &[(&str, &str)]) -> impl Iterator<Item = (String, String)> {
+        let vars: Vec<(String, String)> = list
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        vars.into_iter()
+    }
+
+    #[test]
+    fn module_example_parses() {
+        let example = include_str!("config.rs")
+            .lines()
+            .skip_while(|l| !l.starts_with("//! ```toml"))
+            .skip(1)
+            .take_while(|l| !l.starts_with("//! ```"))
+            .map(|l| l.trim_start_matches("//!").trim_start())
+            .collect::<Vec<_>>()
+            .join("\n");
+
+        let config = ServerConfig::from_toml(&example, "example").unwrap();
+        assert_eq!(config.max_workers, Some(16));
+        assert!(!config.event_loop);
+        assert_eq!(config.drain_timeout, Duration::from_secs(10));
+        assert_eq!(config.log_format, LogFormat::Combined);
+        assert_eq!(config.tls_key, Some(PathBuf::from("certs/server.key")));
+        assert_eq!(
+            config.tls_hosts,
+            [TlsHost {
+                name: "api.example.test".to_string(),
+                cert: PathBuf::from("certs/api.pem"),
+                key: PathBuf::from("certs/api.key"),
+            }]
+        );
+        assert_eq!(
+            config.proxies,
+            [ProxyRoute {
+                prefix: "/api".to_string(),
+                upstreams: vec!["127.0.0.1:3001".to_string(), "127.0.0.1:3002".to_string()],
+                health_check: Some("/health".to_string()),
+                health_interval: Duration::from_secs(5),
+            }]
+        );
+        assert_eq!(config.proxies[0].pattern(), "/api/*");
+        assert_eq!(config.cgi_dir, Some(PathBuf::from("cgi-bin")));
+        assert_eq!(config.cgi_timeout, Duration::from_secs(10));
+        let mut vhosts: Vec<(&str, &Path)> = config
+            .vhosts
+            .iter()
+            .map(|v| (v.name.as_str(), v.document_root.as_path()))
+            .collect();
+        vhosts.sort();
+        assert_eq!(
+            vhosts,
+            [
+                ("*.example.test", Path::new("sites/www")),
+                ("blog.example.test", Path::new("sites/blog")),
+            ]
+        );
+    }
+
+    #[test]
+    fn proxy_routes_need_upstreams() {
+        let err = ServerConfig::from_toml("[proxy.\"/api\"]\nupstreams = [1]\n", "server.toml")
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "invalid value \"1\" for proxy./api.upstreams in server.toml: \
+             expected a host:port string"
+        );
+
+        let config =
+            ServerConfig::from_toml("[proxy.\"api\"]\nhealth_check = \"/\"\n", "server.toml")
+                .unwrap();
+        let ConfigError::Invalid(problems) = config.validate().unwrap_err() else {
+            panic!("expected validation errors");
+        };
+        assert!(problems.contains(&"proxy prefix api must start with '/'".to_string()));
+        assert!(problems.contains(&"proxy api has no upstreams".to_string()));
+    }
+
+    #[test]
+    fn tls_hosts_need_cert_and_key() {
+        let err = ServerConfig::from_toml(
+            "[tls.hosts.\"*.example.test\"]\ncert = \"a.pem\"\n",
+            "server.toml",
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "invalid configuration:\n  - tls.hosts.\"*.example.test\" in server.toml needs both cert and key"
+        );
+
+        let err = ServerConfig::build(
+            args(&["--tls-cert", "missing.pem", "--mode", "event-loop"]),
+            env(&[]),
+        )
+        .unwrap_err();
+        let ConfigError::Invalid(problems) = err else {
+            panic!("expected validation errors, got {err:?}");
+        };
+        assert!(problems.contains(&"tls cert and key must be given together".to_string()));
+        assert!(problems.contains(&"TLS file missing.pem does not exist".to_string()));
+        assert!(problems.contains(&"HTTPS is not supported in event-loop mode".to_string()));
+    }
+
+    #[test]
+    fn later_sources_override_earlier_ones() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("server.toml");
+        fs::write(
+            &file,
+            "port = 8000\n[pool]\nworkers = 2\nqueue = 8\n[timeouts]\nidle = \"250ms\"\n",
+        )
+        .unwrap();
+
+        let config = ServerConfig::build(
+            args(&["--config", file.to_str().unwrap(), "--port=9000"]),
+            env(&[
+                ("CH30_PORT", "8500"),
+                ("CH30_WORKERS", "3"),
+                ("CH30_MODE", "event-loop"),
+                ("HOME", "/ignored"),
+            ]),
+        )
+        .unwrap();
+
+        assert_eq!(config.port, 9000);
+        assert_eq!(config.workers, 3);
+        assert_eq!(config.queue, 8);
+        assert_eq!(config.idle_timeout, Duration::from_millis(250));
+        assert_eq!(config.max_workers(), 12);
+        assert!(config.event_loop);
+    }
+
+    #[test]
+    fn reports_where_bad_values_come_from() {
+        let err = ServerConfig::build(args(&["--port", "http"]), env(&[])).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "invalid value \"http\" for --port: expected a port number from 0 to 65535"
+        );
+
+        let err = ServerConfig::build(args(&[]), env(&[("CH30_LOG_LEVEL", "loud")])).unwrap_err();
+        assert!(err.to_string().contains("for CH30_LOG_LEVEL"));
+
+        let err = ServerConfig::from_toml("[pool]\nworkrs = 2\n", "server.toml").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "unknown setting pool.workrs in server.toml"
+        );
+
+        assert!(matches!(
+            ServerConfig::build(args(&["--workers"]), env(&[])),
+            Err(ConfigError::MissingValue(_))
+        ));
+        assert!(matches!(
+            ServerConfig::build(args(&["--help"]), env(&[])),
+            Err(ConfigError::Help)
+        ));
+    }
+
+    #[test]
+    fn validation_lists_every_problem() {
+        let err = ServerConfig::build(
+            args(&[
+                "--workers",
+                "8",
+                "--max-workers",
+                "2",
+                "--queue",
+                "0",
+                "--document-root",
+                "/does/not/exist",
+            ]),
+            env(&[]),
+        )
+        .unwrap_err();
+
+        let ConfigError::Invalid(problems) = err else {
+            panic!("expected validation errors, got {err:?}");
+        };
+        assert_eq!(problems.len(), 3);
+
+        // Huge counts are reported, not overflowed.
+        let err = ServerConfig::build(args(&["--workers", &usize::MAX.to_string()]), env(&[]))
+            .unwrap_err();
+        let ConfigError::Invalid(problems) = err else {
+            panic!("expected validation errors, got {err:?}");
+        };
+        assert!(problems.contains(&format!("workers ({}) must be at most 4096", usize::MAX)));
+    }
+}
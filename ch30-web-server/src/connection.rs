@@ -0,0 +1,822 @@
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::logger::{self, AccessEntry, Level};
+use crate::request::{
+    BodyReader, BodySource, Limits, Method, ParseError, Request, RequestParser, Version,
+};
+use crate::response::Response;
+use crate::router::Handler;
+
+/// Settings for persistent (keep-alive) connections.
+///
+/// Every timeout can be `None` to wait forever.
+#[derive(Debug, Clone, Copy)]
+pub struct ConnectionConfig {
+    /// How long to wait for the first byte of the next request before
+    /// closing the connection.
+    pub idle_timeout: Option<Duration>,
+    /// How long a client may take to send the request line and headers,
+    /// counted from their first byte. Bounds clients that trickle in a
+    /// header a byte at a time.
+    pub header_timeout: Option<Duration>,
+    /// How long a client may take to send the body once the headers are in.
+    pub body_timeout: Option<Duration>,
+    /// How long writing a response may block.
+    pub write_timeout: Option<Duration>,
+    /// Maximum number of requests served on one connection.
+    pub max_requests: usize,
+    pub limits: Limits,
+}
+
+impl Default for ConnectionConfig {
+    fn default() -> ConnectionConfig {
+        ConnectionConfig {
+            idle_timeout: Some(Duration::from_secs(5)),
+            header_timeout: Some(Duration::from_secs(10)),
+            body_timeout: Some(Duration::from_secs(30)),
+            write_timeout: Some(Duration::from_secs(30)),
+            max_requests: 100,
+            limits: Limits::default(),
+        }
+    }
+}
+
+/// A byte stream HTTP is served over: a plain TCP connection, or TLS on top
+/// of one.
+pub trait Transport: Read + Write + Send {
+    /// The underlying socket, used for addresses and timeouts.
+    fn socket(&self) -> &TcpStream;
+
+    /// Tells the peer that nothing more will be sent.
+    fn shutdown_write(&mut self) -> io::Result<()>;
+}
+
+impl Transport for TcpStream {
+    fn socket(&self) -> &TcpStream {
+        self
+    }
+
+    fn shutdown_write(&mut self) -> io::Result<()> {
+        self.shutdown(Shutdown::Write)
+    }
+}
+
+/// A connection taken over by another protocol after
+/// `101 Switching Protocols`; see [`Response::on_upgrade`].
+///
+/// Reading yields any bytes the client sent after the request before
+/// reading from the socket. The socket has no read timeout.
+pub struct Upgraded {
+    stream: Box<dyn Transport>,
+    buffered: Vec<u8>,
+    pos: usize,
+}
+
+impl Upgraded {
+    pub(crate) fn new(stream: Box<dyn Transport>, buffered: Vec<u8>) -> Upgraded {
+        Upgraded {
+            stream,
+            buffered,
+            pos: 0,
+        }
+    }
+}
+
+impl Read for Upgraded {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos < self.buffered.len() {
+            let n = (&self.buffered[self.pos..]).read(buf)?;
+            self.pos += n;
+            return Ok(n);
+        }
+        self.stream.read(buf)
+    }
+}
+
+impl Write for Upgraded {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+impl Transport for Upgraded {
+    fn socket(&self) -> &TcpStream {
+        self.stream.socket()
+    }
+
+    fn shutdown_write(&mut self) -> io::Result<()> {
+        self.stream.shutdown_write()
+    }
+}
+
+/// Serves requests on `stream` until the client or the configuration ends
+/// the connection.
+///
+/// HTTP/1.1 connections stay open unless the client sends
+/// `Connection: close`; HTTP/1.0 connections stay open only if the client
+/// asks for `Connection: keep-alive`. Pipelined requests are answered one by
+/// one in the order they arrived.
+///
+/// A client that is too slow to send a request it has started gets
+/// `408 Request Timeout`; one that never starts the next request is
+/// disconnected silently once the idle timeout passes.
+///
+/// A `101 Switching Protocols` response with an upgrade set ends HTTP on the
+/// connection and hands it over; see [`Response::on_upgrade`].
+///
+/// A handler that [streams bodies](Handler::streams_body) gets the request
+/// as soon as its header is in, and reads the body from the connection.
+/// What it leaves unread is discarded before the next request, unless
+/// there is too much of it; then the connection is closed.
+pub fn serve_connection<T: Transport + 'static>(
+    stream: T,
+    handler: &dyn Handler,
+    config: &ConnectionConfig,
+) -> io::Result<()> {
+    stream.socket().set_write_timeout(config.write_timeout)?;
+    let parser = RequestParser::with_limits(config.limits);
+    serve_from(stream, parser, None, 0, handler, config)
+}
+
+/// Goes on serving a connection the way [`serve_connection`] does, after
+/// `served` requests. `first` is a request already taken from `parser`,
+/// which the caller has not answered yet.
+pub(crate) fn serve_from<T: Transport + 'static>(
+    mut stream: T,
+    mut parser: RequestParser,
+    mut first: Option<Request>,
+    mut served: usize,
+    handler: &dyn Handler,
+    config: &ConnectionConfig,
+) -> io::Result<()> {
+    let client = stream.socket().peer_addr().ok();
+    loop {
+        let next = match first.take() {
+            Some(request) => Ok(Some(request)),
+            None => read_request(&mut stream, &mut parser, handler, config),
+        };
+        let mut request = match next {
+            Ok(Some(request)) => request,
+            Ok(None) => return Ok(()),
+            Err(ParseError::Io(e)) => return Err(e),
+            Err(e) => {
+                if let Some(code) = e.status_code() {
+                    Response::text(code, e.to_string())
+                        .with_header("Connection", "close")
+                        .write_to(&mut stream)?;
+                }
+                lingering_close(stream);
+                return Ok(());
+            }
+        };
+        served += 1;
+        request.remote_addr = client;
+        let received = (SystemTime::now(), Instant::now());
+        let access = logger::enabled(Level::Info).then(|| RequestSummary::new(&request));
+
+        let keep_alive = wants_keep_alive(&request) && served < config.max_requests;
+        let (method, version) = (request.method, request.version);
+
+        let (mut response, body_read) = if parser.body_pending() {
+            // The handler reads the body from the connection, which it gets
+            // back once the response is ready.
+            let body = Arc::new(Mutex::new(Some(StreamedBody {
+                deadline: config.body_timeout.map(|t| Instant::now() + t),
+                stream,
+                parser,
+            })));
+            request.body_reader = Some(BodyReader::new(body.clone()));
+            let response = handler.handle(request);
+            let mut body = body
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .take()
+                .expect("only taken here");
+            let body_read = body.finish();
+            (stream, parser) = (body.stream, body.parser);
+            (response, body_read)
+        } else {
+            (handler.handle(request), true)
+        };
+        let upgrade = response.take_upgrade();
+        // On an upgrade the handler has set `Connection: Upgrade` itself.
+        let keep_alive = upgrade.is_none()
+            && set_connection_headers(
+                &mut response,
+                method,
+                version,
+                keep_alive && body_read,
+                config,
+                served,
+            );
+
+        let bytes = response.write_for(&mut stream, method, version)?;
+        if let Some(access) = access {
+            access.log(client, received, response.status, bytes);
+        }
+        if let Some(upgrade) = upgrade {
+            stream.socket().set_read_timeout(None)?;
+            upgrade(Upgraded::new(Box::new(stream), parser.into_buffered()));
+            return Ok(());
+        }
+        if !keep_alive {
+            lingering_close(stream);
+            return Ok(());
+        }
+    }
+}
+
+/// A connection lent to a [`BodyReader`] while the handler runs.
+struct StreamedBody<T> {
+    stream: T,
+    parser: RequestParser,
+    /// When the body timeout runs out.
+    deadline: Option<Instant>,
+}
+
+impl<T: Transport> StreamedBody<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut socket = Deadline {
+            stream: &mut self.stream,
+            deadline: self.deadline,
+        };
+        self.parser.read_body(&mut socket, buf)
+    }
+
+    /// Discards the rest of a body the handler did not read, unless there
+    /// is too much of it. Returns whether the next request can be read.
+    fn finish(&mut self) -> bool {
+        let mut buf = [0; 4096];
+        let mut skipped = 0;
+        while self.parser.body_pending() {
+            if skipped > 64 * 1024 {
+                return false;
+            }
+            match self.read(&mut buf) {
+                Ok(n) => skipped += n,
+                Err(_) => return false,
+            }
+        }
+        true
+    }
+}
+
+impl<T: Transport> BodySource for Mutex<Option<StreamedBody<T>>> {
+    fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut body = self.lock().unwrap_or_else(PoisonError::into_inner);
+        match body.as_mut() {
+            Some(body) => body.read(buf),
+            None => Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "the response to the request has been sent",
+            )),
+        }
+    }
+}
+
+/// Reads from a stream until a deadline, however many reads that takes.
+struct Deadline<'a, T> {
+    stream: &'a mut T,
+    deadline: Option<Instant>,
+}
+
+impl<T: Transport> Read for Deadline<'_, T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let timeout = match self.deadline {
+            Some(deadline) => {
+                let left = deadline.saturating_duration_since(Instant::now());
+                if left.is_zero() {
+                    return Err(ParseError::Timeout.into());
+                }
+                Some(left)
+            }
+            None => None,
+        };
+        self.stream.socket().set_read_timeout(timeout)?;
+        match self.stream.read(buf) {
+            Err(e) if is_timeout(&e) => Err(ParseError::Timeout.into()),
+            result => result,
+        }
+    }
+}
+
+/// Sets the `Connection` and `Keep-Alive` headers of the response to the
+/// `served`th request on a connection, and returns whether the connection
+/// stays open after it. `keep_alive` is whether the request allows that.
+pub(crate) fn set_connection_headers(
+    response: &mut Response,
+    method: Method,
+    version: Version,
+    keep_alive: bool,
+    config: &ConnectionConfig,
+    served: usize,
+) -> bool {
+    let keep_alive = keep_alive
+        && !response.headers.has_token("Connection", "close")
+        && !response.needs_close(method, version);
+    if keep_alive {
+        response.headers.insert("Connection", "keep-alive");
+        if let Some(timeout) = config.idle_timeout {
+            let remaining = config.max_requests - served;
+            response.headers.insert(
+                "Keep-Alive",
+                format!("timeout={}, max={remaining}", timeout.as_secs()),
+            );
+        }
+    } else {
+        response.headers.insert("Connection", "close");
+    }
+    keep_alive
+}
+
+/// What the access log needs from a request that is handed to the handler.
+pub(crate) struct RequestSummary {
+    method: Method,
+    target: String,
+    version: Version,
+    referer: Option<String>,
+    user_agent: Option<String>,
+}
+
+impl RequestSummary {
+    pub(crate) fn new(request: &Request) -> RequestSummary {
+        let target = match &request.query {
+            Some(query) => format!("{}?{query}", request.path),
+            None => request.path.clone(),
+        };
+        RequestSummary {
+            method: request.method,
+            target,
+            version: request.version,
+            referer: request.headers.get("Referer").map(str::to_string),
+            user_agent: request.headers.get("User-Agent").map(str::to_string),
+        }
+    }
+
+    /// Writes the access log entry for the response to the request.
+    pub(crate) fn log(
+        &self,
+        client: Option<SocketAddr>,
+        (time, started): (SystemTime, Instant),
+        status: u16,
+        bytes: u64,
+    ) {
+        logger::access(&AccessEntry {
+            client,
+            time,
+            method: self.method,
+            target: &self.target,
+            version: self.version,
+            status,
+            bytes,
+            latency: started.elapsed(),
+            referer: self.referer.as_deref(),
+            user_agent: self.user_agent.as_deref(),
+        });
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Phase {
+    Idle,
+    Header,
+    Body,
+}
+
+/// Reads the next request, enforcing the idle, header and body timeouts.
+/// A request whose body `handler` streams is returned without its body.
+///
+/// Each phase has a deadline rather than a per-read timeout, so a client
+/// cannot stretch it by sending a byte at a time. Returns `Ok(None)` if the
+/// client closes the connection or stays idle between requests.
+fn read_request<T: Transport>(
+    stream: &mut T,
+    parser: &mut RequestParser,
+    handler: &dyn Handler,
+    config: &ConnectionConfig,
+) -> Result<Option<Request>, ParseError> {
+    let deadline_after = |timeout: Option<Duration>| timeout.map(|t| Instant::now() + t);
+    let timed_out = |phase| match phase {
+        Phase::Idle => Ok(None),
+        Phase::Header | Phase::Body => Err(ParseError::Timeout),
+    };
+
+    let mut chunk = [0; 4096];
+    let mut phase = Phase::Idle;
+    let mut deadline = deadline_after(config.idle_timeout);
+    loop {
+        if let Some(request) = parser.parse_or_stream(|r| handler.streams_body(r))? {
+            return Ok(Some(request));
+        }
+        if phase == Phase::Idle && parser.buffered() > 0 {
+            phase = Phase::Header;
+            deadline = deadline_after(config.header_timeout);
+        }
+        if phase != Phase::Body && parser.header_received() {
+            phase = Phase::Body;
+            deadline = deadline_after(config.body_timeout);
+        }
+
+        let timeout = match deadline {
+            Some(deadline) => {
+                let left = deadline.saturating_duration_since(Instant::now());
+                if left.is_zero() {
+                    return timed_out(phase);
+                }
+                Some(left)
+            }
+            None => None,
+        };
+        stream.socket().set_read_timeout(timeout)?;
+
+        let n = match stream.read(&mut chunk) {
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            // A TLS peer that closes without `close_notify`.
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => 0,
+            Err(e) if is_timeout(&e) => return timed_out(phase),
+            Err(e) => return Err(ParseError::Io(e)),
+        };
+        if n == 0 {
+            return match phase {
+                Phase::Idle => Ok(None),
+                Phase::Header | Phase::Body => Err(ParseError::Incomplete),
+            };
+        }
+        parser.feed(&chunk[..n]);
+    }
+}
+
+/// Answers `503 Service Unavailable` on a connection the server has no
+/// capacity for, asking the client to retry after `retry_after`.
+///
+/// This runs on the accepting thread, so it never waits for the client.
+pub fn reject_connection(mut stream: TcpStream, retry_after: Duration) -> io::Result<()> {
+    // Discard what has already arrived so that closing does not reset the
+    // connection, but do not block waiting for more.
+    stream.set_nonblocking(true)?;
+    let mut buf = [0; 4096];
+    while let Ok(n) = stream.read(&mut buf) {
+        if n == 0 {
+            break;
+        }
+    }
+    stream.set_nonblocking(false)?;
+    stream.set_write_timeout(Some(Duration::from_secs(1)))?;
+
+    Response::text(503, "Service Unavailable")
+        .with_header("Retry-After", retry_after.as_secs().max(1).to_string())
+        .with_header("Connection", "close")
+        .write_to(&mut stream)?;
+    stream.shutdown(Shutdown::Write)
+}
+
+/// Closes our side and drains what the client still sends for a moment.
+///
+/// Closing a socket with unread data makes the kernel send a reset, which can
+/// destroy the last response before the client has read it. This happens when
+/// the client pipelined more requests than we are willing to answer.
+fn lingering_close<T: Transport>(mut stream: T) {
+    if stream.shutdown_write().is_err() {
+        return;
+    }
+    // A deadline for the whole drain, so a client that keeps trickling
+    // bytes cannot hold on to the worker.
+    let deadline = Instant::now() + Duration::from_secs(1);
+    let mut buf = [0; 4096];
+    let mut drained = 0;
+    while drained < 64 * 1024 {
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() || stream.socket().set_read_timeout(Some(left)).is_err() {
+            break;
+        }
+        match stream.read(&mut buf) {
+            Ok(0) | Err(_) => break,
+            Ok(n) => drained += n,
+        }
+    }
+}
+
+pub(crate) fn wants_keep_alive(request: &Request) -> bool {
+    match request.version {
+        Version::Http11 => !request.headers.has_token("Connection", "close"),
+        Version::Http10 => request.headers.has_token("Connection", "keep-alive"),
+    }
+}
+
+fn is_timeout(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::form::FormLimits;
+    use crate::logger::{set_logger, Logger};
+    use crate::pool::ThreadPool;
+    use crate::router::Router;
+    use std::io::Write;
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    /// Starts a server for a single connection and returns the client side.
+    fn connect(config: ConnectionConfig) -> (TcpStream, thread::JoinHandle<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let handler = |req: Request| Response::text(200, req.path);
+            serve_connection(stream, &handler, &config).unwrap();
+        });
+        (TcpStream::connect(addr).unwrap(), server)
+    }
+
+    fn read_to_close(mut stream: TcpStream) -> String {
+        let mut out = String::new();
+        stream.read_to_string(&mut out).unwrap();
+        out
+    }
+
+    #[test]
+    fn pipelined_requests_are_answered_in_order() {
+        let (mut client, server) = connect(ConnectionConfig::default());
+        client
+            .write_all(
+                b"GET /one HTTP/1.1\r\nHost: a\r\n\r\n\
+                  GET /two HTTP/1.1\r\nHost: a\r\n\r\n\
+                  GET /three HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n",
+            )
+            .unwrap();
+
+        let out = read_to_close(client);
+        server.join().unwrap();
+
+        let bodies: Vec<&str> = out
+            .split("HTTP/1.1 200 OK")
+            .skip(1)
+            .map(|r| r.rsplit("\r\n\r\n").next().unwrap())
+            .collect();
+        assert_eq!(bodies, ["/one", "/two", "/three"]);
+        assert_eq!(out.matches("Connection: keep-alive").count(), 2);
+        assert!(out.ends_with("Connection: close\r\n\r\n/three"));
+    }
+
+    #[test]
+    fn http10_closes_by_default() {
+        let (mut client, server) = connect(ConnectionConfig::default());
+        client.write_all(b"GET /old HTTP/1.0\r\n\r\n").unwrap();
+
+        let out = read_to_close(client);
+        server.join().unwrap();
+        assert!(out.contains("Connection: close"));
+    }
+
+    #[test]
+    fn max_requests_closes_connection() {
+        let (mut client, server) = connect(ConnectionConfig {
+            max_requests: 2,
+            ..ConnectionConfig::default()
+        });
+        client
+            .write_all(
+                b"GET /1 HTTP/1.1\r\nHost: a\r\n\r\n\
+                  GET /2 HTTP/1.1\r\nHost: a\r\n\r\n\
+                  GET /3 HTTP/1.1\r\nHost: a\r\n\r\n",
+            )
+            .unwrap();
+
+        let out = read_to_close(client);
+        server.join().unwrap();
+        assert_eq!(out.matches("HTTP/1.1 200 OK").count(), 2);
+    }
+
+    #[test]
+    fn rejected_connection_gets_503() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        client
+            .write_all(b"GET / HTTP/1.1\r\nHost: a\r\n\r\n")
+            .unwrap();
+
+        let (stream, _) = listener.accept().unwrap();
+        reject_connection(stream, Duration::from_secs(2)).unwrap();
+
+        let out = read_to_close(client);
+        assert!(out.starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+        assert!(out.contains("Retry-After: 2\r\n"));
+    }
+
+    #[test]
+    fn answered_requests_are_logged() {
+        struct Capture(Mutex<Vec<String>>);
+
+        impl Logger for Capture {
+            fn level(&self) -> Level {
+                Level::Info
+            }
+
+            fn log(&self, _: Level, _: std::fmt::Arguments<'_>) {}
+
+            fn access(&self, entry: &AccessEntry<'_>) {
+                self.0.lock().unwrap().push(entry.combined());
+            }
+        }
+
+        let capture = Arc::new(Capture(Mutex::new(Vec::new())));
+        set_logger(Arc::clone(&capture));
+
+        let (mut client, server) = connect(ConnectionConfig::default());
+        client
+            .write_all(
+                b"GET /logged?x=1 HTTP/1.1\r\nHost: a\r\nUser-Agent: test-agent\r\n\
+                  Connection: close\r\n\r\n",
+            )
+            .unwrap();
+        read_to_close(client);
+        server.join().unwrap();
+
+        let lines = capture.0.lock().unwrap();
+        let line = lines.iter().find(|l| l.contains("/logged")).unwrap();
+        assert!(line.starts_with("127.0.0.1 - - ["));
+        assert!(line.ends_with("\"GET /logged?x=1 HTTP/1.1\" 200 7 \"-\" \"test-agent\""));
+    }
+
+    #[test]
+    fn streamed_uploads_may_exceed_the_body_limit() {
+        let file: Vec<u8> = (0..100 * 1024).map(|i| (i % 251) as u8).collect();
+        let mut form = b"--xyz\r\n\
+            Content-Disposition: form-data; name=\"file\"; filename=\"big.bin\"\r\n\r\n"
+            .to_vec();
+        form.extend_from_slice(&file);
+        form.extend_from_slice(b"\r\n--xyz--\r\n");
+
+        let dir = tempfile::TempDir::new().unwrap();
+        let limits = FormLimits {
+            upload_dir: dir.path().to_path_buf(),
+            ..FormLimits::default()
+        };
+        let expected = file.clone();
+        let router = Router::new()
+            .streaming(Method::Post, "/upload", move |req: Request| {
+                match req.multipart(&limits) {
+                    Ok(form) => {
+                        let upload = form.file("file").unwrap();
+                        let same = std::fs::read(upload.path()).unwrap() == expected;
+                        Response::text(200, format!("{} {same}", upload.size))
+                    }
+                    Err(e) => Response::text(e.status_code(), e.to_string()),
+                }
+            })
+            .post("/small", |req: Request| Response::text(200, req.path));
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let config = ConnectionConfig {
+                limits: Limits {
+                    max_body_size: 1024,
+                    ..Limits::default()
+                },
+                ..ConnectionConfig::default()
+            };
+            serve_connection(stream, &router, &config).unwrap();
+        });
+
+        let head = "POST /upload HTTP/1.1\r\nHost: a\r\n\
+                    Content-Type: multipart/form-data; boundary=xyz\r\n";
+        let mut chunked = Vec::new();
+        for chunk in form.chunks(10_000) {
+            chunked.extend_from_slice(format!("{:x}\r\n", chunk.len()).as_bytes());
+            chunked.extend_from_slice(chunk);
+            chunked.extend_from_slice(b"\r\n");
+        }
+        chunked.extend_from_slice(b"0\r\n\r\n");
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        let mut request = format!("{head}Content-Length: {}\r\n\r\n", form.len()).into_bytes();
+        request.extend_from_slice(&form);
+        request.extend_from_slice(format!("{head}Transfer-Encoding: chunked\r\n\r\n").as_bytes());
+        request.extend_from_slice(&chunked);
+        // Other routes still get the limit.
+        request
+            .extend_from_slice(b"POST /small HTTP/1.1\r\nHost: a\r\nContent-Length: 2000\r\n\r\n");
+        client.write_all(&request).unwrap();
+
+        let out = read_to_close(client);
+        server.join().unwrap();
+        assert_eq!(out.matches("\r\n\r\n102400 true").count(), 2, "{out}");
+        assert!(out.contains("HTTP/1.1 413 Content Too Large\r\n"), "{out}");
+    }
+
+    /// Sends `head` and then one more header byte every 20ms, the way a
+    /// slowloris client keeps a connection busy without finishing it.
+    fn trickle(client: &TcpStream, head: &'static [u8]) -> thread::JoinHandle<()> {
+        let mut client = client.try_clone().unwrap();
+        thread::spawn(move || {
+            if client.write_all(head).is_err() {
+                return;
+            }
+            for _ in 0..250 {
+                thread::sleep(Duration::from_millis(20));
+                if client.write_all(b"x").is_err() {
+                    return;
+                }
+            }
+        })
+    }
+
+    fn slow_client_config() -> ConnectionConfig {
+        ConnectionConfig {
+            header_timeout: Some(Duration::from_millis(200)),
+            body_timeout: Some(Duration::from_millis(200)),
+            ..ConnectionConfig::default()
+        }
+    }
+
+    #[test]
+    fn slow_header_gets_408() {
+        let (client, server) = connect(slow_client_config());
+        let start = Instant::now();
+        let writer = trickle(&client, b"GET / HTTP/1.1\r\nHost: a\r\nX-Slow: ");
+
+        let out = read_to_close(client);
+        server.join().unwrap();
+        assert!(out.starts_with("HTTP/1.1 408 Request Timeout\r\n"));
+        assert!(start.elapsed() < Duration::from_secs(3));
+        writer.join().unwrap();
+    }
+
+    #[test]
+    fn slow_body_gets_408() {
+        let (client, server) = connect(slow_client_config());
+        let writer = trickle(
+            &client,
+            b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 100000\r\n\r\n",
+        );
+
+        let out = read_to_close(client);
+        server.join().unwrap();
+        assert!(out.starts_with("HTTP/1.1 408 Request Timeout\r\n"));
+        writer.join().unwrap();
+    }
+
+    #[test]
+    fn slow_clients_release_workers() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let pool = ThreadPool::new(1);
+            for stream in listener.incoming().take(2) {
+                let stream = stream.unwrap();
+                pool.execute(move || {
+                    let handler = |req: Request| Response::text(200, req.path);
+                    let _ = serve_connection(stream, &handler, &slow_client_config());
+                });
+            }
+        });
+
+        // The slow client takes the only worker; the next one must still be
+        // served once the slow one has timed out.
+        let slow = TcpStream::connect(addr).unwrap();
+        let writer = trickle(&slow, b"GET /slow HTTP/1.1\r\n");
+        thread::sleep(Duration::from_millis(50));
+        let mut fast = TcpStream::connect(addr).unwrap();
+        fast.write_all(b"GET /fast HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        fast.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+        let out = read_to_close(fast);
+        assert!(out.ends_with("\r\n\r\n/fast"));
+        drop(slow);
+        server.join().unwrap();
+        writer.join().unwrap();
+    }
+
+    #[test]
+    fn idle_connection_times_out() {
+        let (mut client, server) = connect(ConnectionConfig {
+            idle_timeout: Some(Duration::from_millis(100)),
+            ..ConnectionConfig::default()
+        });
+        client
+            .write_all(b"GET / HTTP/1.1\r\nHost: a\r\n\r\n")
+            .unwrap();
+
+        // The server answers, then closes the connection once it sits idle.
+        let out = read_to_close(client);
+        server.join().unwrap();
+        assert!(out.contains("Connection: keep-alive"));
+    }
+}
@@ -0,0 +1,1189 @@
+//! Serving connections from a readiness-based event loop.
+//!
+//! One thread owns every socket and waits for them with epoll (or kqueue, or
+//! whatever the platform has). Only parsed requests go to the
+//! [`ThreadPool`], so an idle keep-alive connection costs a buffer and a
+//! file descriptor instead of a worker.
+//!
+//! A response with its body in memory is rendered by the worker and written
+//! by the loop. Other bodies (files, readers, chunk iterators) are passed to
+//! the loop a piece at a time, so only a few pieces are held at once, and the
+//! worker stays with the response until its body ends.
+//!
+//! A request whose body the handler [streams](Handler::streams_body) takes
+//! its connection out of the loop: the worker reads the body from a blocking
+//! socket and serves the connection from then on, as
+//! [`serve_connection`](crate::serve_connection) does.
+
+use std::collections::{HashMap, HashSet};
+use std::io::{self, Read, Write};
+use std::net::{self, Shutdown, SocketAddr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender, SyncSender, TryRecvError};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+use mio::net::{TcpListener, TcpStream};
+use mio::{Events, Interest, Poll, Token, Waker};
+
+use crate::connection::{
+    reject_connection, serve_from, set_connection_headers, wants_keep_alive, ConnectionConfig,
+    RequestSummary, Upgraded,
+};
+use crate::logger::{self, Level};
+use crate::pool::{ShutdownReport, ThreadPool};
+use crate::request::{ParseError, Request, RequestParser};
+use crate::response::Response;
+use crate::router::Handler;
+
+const LISTENER: Token = Token(0);
+const WAKER: Token = Token(1);
+
+/// How long a closed connection is drained of what the client still sends;
+/// see `lingering_close` in the connection module.
+const LINGER: Duration = Duration::from_secs(1);
+const LINGER_BYTES: usize = 64 * 1024;
+
+/// How much of a streamed body goes to the loop at once, and how many such
+/// pieces a worker may get ahead of the socket.
+const PIECE_SIZE: usize = 64 * 1024;
+const PIECES_AHEAD: usize = 4;
+
+/// How long to wait before accepting again after accepting failed, for
+/// instance because the process ran out of file descriptors.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// What the event loop needs from the [`Server`](crate::Server).
+pub(crate) struct Settings<'a> {
+    pub connection: ConnectionConfig,
+    pub drain_timeout: Duration,
+    pub retry_after: Duration,
+    pub shutting_down: &'a AtomicBool,
+}
+
+/// Serves connections accepted on `listener` until `shutting_down` is set,
+/// then drains them the way [`Server::serve`](crate::Server::serve) does.
+///
+/// If the loop cannot run, the error is logged and the pool shut down.
+pub(crate) fn run<H: Handler>(
+    listener: net::TcpListener,
+    pool: ThreadPool,
+    handler: Arc<H>,
+    settings: Settings<'_>,
+) -> ShutdownReport {
+    let (poll, listener, waker) = match register(listener) {
+        Ok(registered) => registered,
+        Err(e) => {
+            error!("Failed to start the event loop: {e}");
+            return pool.shutdown_timeout(settings.drain_timeout);
+        }
+    };
+    let (finished, replies) = mpsc::channel();
+
+    let mut event_loop = EventLoop {
+        poll,
+        listener: Some(listener),
+        connections: HashMap::new(),
+        next_token: WAKER.0 + 1,
+        next_sweep: None,
+        retry_accept: None,
+        pool,
+        handler,
+        config: settings.connection,
+        retry_after: settings.retry_after,
+        waker: Arc::new(waker),
+        finished,
+        replies,
+        starved: HashSet::new(),
+    };
+    let mut events = Events::with_capacity(1024);
+    let mut drain_deadline = None;
+    loop {
+        if drain_deadline.is_none() && settings.shutting_down.load(Ordering::SeqCst) {
+            drain_deadline = Some(Instant::now() + settings.drain_timeout);
+            event_loop.stop_accepting();
+        }
+        if let Some(deadline) = drain_deadline {
+            // Connections waiting for a request are closed; those with a
+            // request in progress get to finish it.
+            event_loop.close_waiting();
+            if event_loop.connections.is_empty() || Instant::now() >= deadline {
+                break;
+            }
+        }
+
+        let timeout = [event_loop.next_sweep, drain_deadline]
+            .into_iter()
+            .flatten()
+            .min()
+            .map(|at| at.saturating_duration_since(Instant::now()));
+        if let Err(e) = event_loop.poll.poll(&mut events, timeout) {
+            if e.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            error!("Event loop failed: {e}");
+            break;
+        }
+        for event in &events {
+            match event.token() {
+                LISTENER => event_loop.accept(),
+                WAKER => {}
+                token => {
+                    if event.is_readable() || event.is_read_closed() || event.is_error() {
+                        event_loop.read(token);
+                    }
+                    if event.is_writable() {
+                        event_loop.write(token);
+                    }
+                }
+            }
+        }
+        while let Ok(reply) = event_loop.replies.try_recv() {
+            event_loop.reply(reply);
+        }
+        for token in std::mem::take(&mut event_loop.starved) {
+            event_loop.write(token);
+        }
+        event_loop.sweep();
+    }
+
+    let left = drain_deadline.map_or(Duration::ZERO, |deadline| {
+        deadline.saturating_duration_since(Instant::now())
+    });
+    let EventLoop {
+        pool,
+        mut connections,
+        ..
+    } = event_loop;
+    // Workers still streaming a body would otherwise wait for the loop.
+    for connection in connections.values_mut() {
+        connection.body = None;
+    }
+    let report = pool.shutdown_timeout(left);
+    for connection in connections.values() {
+        let _ = connection.stream.shutdown(Shutdown::Both);
+    }
+    report
+}
+
+fn register(listener: net::TcpListener) -> io::Result<(Poll, TcpListener, Waker)> {
+    listener.set_nonblocking(true)?;
+    let mut listener = TcpListener::from_std(listener);
+    let poll = Poll::new()?;
+    poll.registry()
+        .register(&mut listener, LISTENER, Interest::READABLE)?;
+    let waker = Waker::new(poll.registry(), WAKER)?;
+    Ok((poll, listener, waker))
+}
+
+struct EventLoop<H> {
+    poll: Poll,
+    listener: Option<TcpListener>,
+    connections: HashMap<Token, Connection>,
+    /// Tokens are never reused, so a late reply cannot reach the wrong
+    /// connection.
+    next_token: usize,
+    /// The earliest deadline of any connection, or of `retry_accept`.
+    next_sweep: Option<Instant>,
+    /// When to accept again after accepting failed. The listener is
+    /// edge-triggered, so connections already waiting would otherwise stay
+    /// there until another one arrives.
+    retry_accept: Option<Instant>,
+    pool: ThreadPool,
+    handler: Arc<H>,
+    config: ConnectionConfig,
+    retry_after: Duration,
+    waker: Arc<Waker>,
+    finished: Sender<Reply>,
+    replies: Receiver<Reply>,
+    /// Connections that have written all of their body the worker has
+    /// produced so far, and wait for more.
+    starved: HashSet<Token>,
+}
+
+struct Connection {
+    stream: TcpStream,
+    client: Option<SocketAddr>,
+    parser: RequestParser,
+    state: State,
+    /// When the current state times out.
+    deadline: Option<Instant>,
+    served: usize,
+    /// The response being written, and how much of it has been.
+    output: Vec<u8>,
+    written: usize,
+    /// The rest of a streamed body, as the worker produces it.
+    body: Option<Receiver<Vec<u8>>>,
+}
+
+enum State {
+    /// Waiting for a request, or for the rest of one.
+    Reading(Phase),
+    /// A worker has the request.
+    Handling,
+    Writing(After),
+    /// Our side is shut down; discarding what the client still sends.
+    Closing {
+        drained: usize,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Phase {
+    Idle,
+    Header,
+    Body,
+}
+
+/// What happens to a connection once its response is written.
+enum After {
+    KeepAlive,
+    Close,
+    Upgrade(Box<dyn FnOnce(Upgraded) + Send>),
+}
+
+/// A rendered response on its way back from a worker. A streamed body
+/// follows through `body`, ending with an empty piece.
+struct Reply {
+    token: Token,
+    output: Vec<u8>,
+    body: Option<Receiver<Vec<u8>>>,
+    after: After,
+}
+
+/// Sends the reply for a request to the event loop. If the handler panics
+/// before a reply is sent, dropping this closes the connection instead.
+struct ReplySender {
+    token: Token,
+    finished: Sender<Reply>,
+    waker: Arc<Waker>,
+    sent: bool,
+}
+
+impl ReplySender {
+    fn send(mut self, output: Vec<u8>, body: Option<Receiver<Vec<u8>>>, after: After) {
+        self.sent = true;
+        self.deliver(output, body, after);
+    }
+
+    fn deliver(&self, output: Vec<u8>, body: Option<Receiver<Vec<u8>>>, after: After) {
+        let reply = Reply {
+            token: self.token,
+            output,
+            body,
+            after,
+        };
+        // The loop is gone once the server has stopped; nothing to do then.
+        if self.finished.send(reply).is_ok() {
+            let _ = self.waker.wake();
+        }
+    }
+}
+
+impl Drop for ReplySender {
+    fn drop(&mut self) {
+        if !self.sent {
+            self.deliver(Vec::new(), None, After::Close);
+        }
+    }
+}
+
+/// Passes a streamed body to the event loop a piece at a time, blocking
+/// while the loop is [`PIECES_AHEAD`] pieces behind.
+struct PieceWriter {
+    pieces: SyncSender<Vec<u8>>,
+    waker: Arc<Waker>,
+    piece: Vec<u8>,
+}
+
+impl PieceWriter {
+    fn send(&mut self, piece: Vec<u8>) -> io::Result<()> {
+        // The loop drops its end when the connection closes.
+        self.pieces
+            .send(piece)
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+        let _ = self.waker.wake();
+        Ok(())
+    }
+
+    /// Sends what is left of the body and the empty piece that ends it.
+    fn end(mut self) -> io::Result<()> {
+        self.flush()?;
+        self.send(Vec::new())
+    }
+}
+
+impl Write for PieceWriter {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.piece.extend_from_slice(data);
+        if self.piece.len() >= PIECE_SIZE {
+            self.flush()?;
+        }
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.piece.is_empty() {
+            return Ok(());
+        }
+        let piece = std::mem::take(&mut self.piece);
+        self.send(piece)
+    }
+}
+
+impl Drop for PieceWriter {
+    fn drop(&mut self) {
+        // A body that fails halfway has no end marker; the loop closes the
+        // connection once it sees the channel closed.
+        let _ = self.waker.wake();
+    }
+}
+
+impl<H: Handler> EventLoop<H> {
+    fn accept(&mut self) {
+        if self.retry_accept.is_some() {
+            return;
+        }
+        loop {
+            let Some(listener) = &self.listener else {
+                return;
+            };
+            let (mut stream, client) = match listener.accept() {
+                Ok(accepted) => accepted,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    error!("Failed to accept connection: {e}");
+                    self.retry_accept = deadline(&mut self.next_sweep, Some(ACCEPT_BACKOFF));
+                    return;
+                }
+            };
+            let token = Token(self.next_token);
+            self.next_token += 1;
+            // Edge-triggered: interest in both directions stays registered
+            // for the connection's whole life.
+            let interest = Interest::READABLE | Interest::WRITABLE;
+            if let Err(e) = self.poll.registry().register(&mut stream, token, interest) {
+                error!("Failed to register connection: {e}");
+                continue;
+            }
+            let deadline = deadline(&mut self.next_sweep, self.config.idle_timeout);
+            self.connections.insert(
+                token,
+                Connection {
+                    stream,
+                    client: Some(client),
+                    parser: RequestParser::with_limits(self.config.limits),
+                    state: State::Reading(Phase::Idle),
+                    deadline,
+                    served: 0,
+                    output: Vec::new(),
+                    written: 0,
+                    body: None,
+                },
+            );
+        }
+    }
+
+    /// Stops accepting and closes the listener.
+    fn stop_accepting(&mut self) {
+        if let Some(mut listener) = self.listener.take() {
+            let _ = self.poll.registry().deregister(&mut listener);
+        }
+    }
+
+    /// Closes the connections that are not in the middle of a request.
+    fn close_waiting(&mut self) {
+        let waiting: Vec<Token> = self
+            .connections
+            .iter()
+            .filter(|(_, c)| matches!(c.state, State::Reading(Phase::Idle) | State::Closing { .. }))
+            .map(|(token, _)| *token)
+            .collect();
+        for token in waiting {
+            self.close(token);
+        }
+    }
+
+    /// Reads what the socket has, as far as the connection's state wants
+    /// it, until the socket would block.
+    fn read(&mut self, token: Token) {
+        let mut chunk = [0; 4096];
+        loop {
+            let Some(connection) = self.connections.get_mut(&token) else {
+                return;
+            };
+            if matches!(connection.state, State::Handling | State::Writing(_)) {
+                // Further requests wait in the socket until this one is
+                // answered.
+                return;
+            }
+            let n = match connection.stream.read(&mut chunk) {
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    debug!("Connection error: {e}");
+                    self.close(token);
+                    return;
+                }
+            };
+            match &mut connection.state {
+                State::Reading(_) if n > 0 => {
+                    connection.parser.feed(&chunk[..n]);
+                    self.parse(token);
+                }
+                State::Closing { drained } if n > 0 && *drained + n < LINGER_BYTES => {
+                    *drained += n;
+                }
+                // A client closing in the middle of a request gets no
+                // answer, as there is nobody left to read it.
+                _ => {
+                    self.close(token);
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Hands a complete request to the pool, or moves the connection to
+    /// the phase its partial request is in.
+    fn parse(&mut self, token: Token) {
+        let Some(connection) = self.connections.get_mut(&token) else {
+            return;
+        };
+        let State::Reading(phase) = connection.state else {
+            return;
+        };
+        let handler = &self.handler;
+        match connection
+            .parser
+            .parse_or_stream(|r| handler.streams_body(r))
+        {
+            Ok(Some(request)) if connection.parser.body_pending() => self.hand_over(token, request),
+            Ok(Some(request)) => self.dispatch(token, request),
+            Ok(None) => {
+                let next = if connection.parser.header_received() {
+                    Phase::Body
+                } else if connection.parser.buffered() > 0 {
+                    Phase::Header
+                } else {
+                    Phase::Idle
+                };
+                if next != phase {
+                    connection.state = State::Reading(next);
+                    let timeout = match next {
+                        Phase::Idle => self.config.idle_timeout,
+                        Phase::Header => self.config.header_timeout,
+                        Phase::Body => self.config.body_timeout,
+                    };
+                    let deadline = deadline(&mut self.next_sweep, timeout);
+                    if let Some(connection) = self.connections.get_mut(&token) {
+                        connection.deadline = deadline;
+                    }
+                }
+            }
+            Err(ParseError::Io(e)) => {
+                debug!("Connection error: {e}");
+                self.close(token);
+            }
+            Err(e) => match e.status_code() {
+                Some(code) => self.reject(token, Response::text(code, e.to_string())),
+                None => self.close(token),
+            },
+        }
+    }
+
+    fn dispatch(&mut self, token: Token, mut request: Request) {
+        let Some(connection) = self.connections.get_mut(&token) else {
+            return;
+        };
+        connection.served += 1;
+        connection.state = State::Handling;
+        connection.deadline = None;
+        request.remote_addr = connection.client;
+
+        let (client, served) = (connection.client, connection.served);
+        let handler = Arc::clone(&self.handler);
+        let config = self.config;
+        let waker = Arc::clone(&self.waker);
+        let reply = ReplySender {
+            token,
+            finished: self.finished.clone(),
+            waker: Arc::clone(&self.waker),
+            sent: false,
+        };
+        let result = self.pool.try_execute_nonblocking(move || {
+            let received = (SystemTime::now(), Instant::now());
+            let access = logger::enabled(Level::Info).then(|| RequestSummary::new(&request));
+            let keep_alive = wants_keep_alive(&request) && served < config.max_requests;
+            let (method, version) = (request.method, request.version);
+
+            let mut response = handler.handle(request);
+            let upgrade = response.take_upgrade();
+            // On an upgrade the handler has set `Connection: Upgrade` itself.
+            let keep_alive = upgrade.is_none()
+                && set_connection_headers(
+                    &mut response,
+                    method,
+                    version,
+                    keep_alive,
+                    &config,
+                    served,
+                );
+
+            let after = match upgrade {
+                Some(upgrade) => After::Upgrade(upgrade),
+                None if keep_alive => After::KeepAlive,
+                None => After::Close,
+            };
+            let rendered = if response.body.as_bytes().is_some() {
+                let mut output = Vec::new();
+                let rendered = response.write_for(&mut output, method, version);
+                if rendered.is_ok() {
+                    reply.send(output, None, after);
+                }
+                rendered
+            } else {
+                // Other bodies may be large or endless, so they are not
+                // rendered in full but streamed to the loop.
+                let (pieces, body) = mpsc::sync_channel(PIECES_AHEAD);
+                reply.send(Vec::new(), Some(body), after);
+                let mut writer = PieceWriter {
+                    pieces,
+                    waker,
+                    piece: Vec::new(),
+                };
+                response
+                    .write_for(&mut writer, method, version)
+                    .and_then(|bytes| writer.end().map(|()| bytes))
+            };
+            match rendered {
+                Ok(bytes) => {
+                    if let Some(access) = access {
+                        access.log(client, received, response.status, bytes);
+                    }
+                }
+                Err(e) => debug!("Failed to render response: {e}"),
+            }
+        });
+
+        if let Err(e) = result {
+            warn!("Rejecting request: {e}");
+            let response = Response::text(503, "Service Unavailable")
+                .with_header("Retry-After", self.retry_after.as_secs().max(1).to_string());
+            self.reject(token, response);
+        }
+    }
+
+    /// Answers with `response` and closes the connection.
+    fn reject(&mut self, token: Token, response: Response) {
+        let mut output = Vec::new();
+        let mut response = response.with_header("Connection", "close");
+        match response.write_to(&mut output) {
+            Ok(_) => self.respond(token, output, None, After::Close),
+            Err(_) => self.close(token),
+        }
+    }
+
+    /// Takes the reply to a request from a worker.
+    fn reply(&mut self, reply: Reply) {
+        match self.connections.get(&reply.token) {
+            Some(connection) if matches!(connection.state, State::Handling) => {
+                self.respond(reply.token, reply.output, reply.body, reply.after)
+            }
+            // The connection has moved on, for instance because the pool
+            // rejected the request and it got `503` instead.
+            _ => {}
+        }
+    }
+
+    /// Starts writing a response.
+    fn respond(
+        &mut self,
+        token: Token,
+        output: Vec<u8>,
+        body: Option<Receiver<Vec<u8>>>,
+        after: After,
+    ) {
+        let Some(connection) = self.connections.get_mut(&token) else {
+            return;
+        };
+        if output.is_empty() && body.is_none() {
+            // The worker failed before it had a response.
+            self.close(token);
+            return;
+        }
+        connection.output = output;
+        connection.written = 0;
+        connection.body = body;
+        connection.state = State::Writing(after);
+        connection.deadline = deadline(&mut self.next_sweep, self.config.write_timeout);
+        self.write(token);
+    }
+
+    /// Writes as much of the response as the socket takes, and moves on
+    /// once all of it is written.
+    fn write(&mut self, token: Token) {
+        let Some(connection) = self.connections.get_mut(&token) else {
+            return;
+        };
+        if !matches!(connection.state, State::Writing(_)) {
+            return;
+        }
+        loop {
+            while connection.written < connection.output.len() {
+                match connection
+                    .stream
+                    .write(&connection.output[connection.written..])
+                {
+                    Ok(0) => {
+                        self.close(token);
+                        return;
+                    }
+                    Ok(n) => connection.written += n,
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => return,
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                    Err(e) => {
+                        debug!("Connection error: {e}");
+                        self.close(token);
+                        return;
+                    }
+                }
+            }
+            let Some(body) = &connection.body else {
+                break;
+            };
+            match body.try_recv() {
+                Ok(piece) if piece.is_empty() => {
+                    connection.body = None;
+                    break;
+                }
+                Ok(piece) => {
+                    connection.output = piece;
+                    connection.written = 0;
+                    connection.deadline = deadline(&mut self.next_sweep, self.config.write_timeout);
+                }
+                // The worker is still producing the body; the write timeout
+                // only covers the client.
+                Err(TryRecvError::Empty) => {
+                    connection.deadline = None;
+                    self.starved.insert(token);
+                    return;
+                }
+                Err(TryRecvError::Disconnected) => {
+                    self.close(token);
+                    return;
+                }
+            }
+        }
+        connection.output = Vec::new();
+
+        let State::Writing(after) = std::mem::replace(&mut connection.state, State::Handling)
+        else {
+            unreachable!("checked above");
+        };
+        match after {
+            After::KeepAlive => {
+                connection.state = State::Reading(Phase::Idle);
+                connection.deadline = deadline(&mut self.next_sweep, self.config.idle_timeout);
+                // A pipelined request may already be buffered, or waiting in
+                // the socket without a new readiness event.
+                self.parse(token);
+                self.read(token);
+            }
+            After::Close => {
+                if connection.stream.shutdown(Shutdown::Write).is_err() {
+                    self.close(token);
+                    return;
+                }
+                connection.state = State::Closing { drained: 0 };
+                connection.deadline = deadline(&mut self.next_sweep, Some(LINGER));
+                self.read(token);
+            }
+            After::Upgrade(upgrade) => self.upgrade(token, upgrade),
+        }
+    }
+
+    /// Hands the connection over to `upgrade` on the pool, as a blocking
+    /// socket.
+    fn upgrade(&mut self, token: Token, upgrade: Box<dyn FnOnce(Upgraded) + Send>) {
+        let Some(mut connection) = self.connections.remove(&token) else {
+            return;
+        };
+        let _ = self.poll.registry().deregister(&mut connection.stream);
+        let stream = net::TcpStream::from(connection.stream);
+        if let Err(e) = stream.set_nonblocking(false) {
+            debug!("Failed to hand over upgraded connection: {e}");
+            return;
+        }
+        let upgraded = Upgraded::new(Box::new(stream), connection.parser.into_buffered());
+        if let Err(e) = self.pool.try_execute_nonblocking(move || upgrade(upgraded)) {
+            warn!("Dropping upgraded connection: {e}");
+        }
+    }
+
+    /// Hands the connection to a worker that serves it as a blocking socket,
+    /// starting with `request`, whose body the handler reads from it.
+    fn hand_over(&mut self, token: Token, request: Request) {
+        let Some(mut connection) = self.connections.remove(&token) else {
+            return;
+        };
+        let _ = self.poll.registry().deregister(&mut connection.stream);
+        let stream = net::TcpStream::from(connection.stream);
+        let fallback = match stream
+            .set_nonblocking(false)
+            .and_then(|()| stream.set_write_timeout(self.config.write_timeout))
+            .and_then(|()| stream.try_clone())
+        {
+            Ok(fallback) => fallback,
+            Err(e) => {
+                debug!("Failed to hand over connection: {e}");
+                return;
+            }
+        };
+        let (parser, served) = (connection.parser, connection.served);
+        let handler = Arc::clone(&self.handler);
+        let config = self.config;
+        let result = self.pool.try_execute_nonblocking(move || {
+            if let Err(e) = serve_from(stream, parser, Some(request), served, &*handler, &config) {
+                debug!("Connection error: {e}");
+            }
+        });
+        if let Err(e) = result {
+            warn!("Rejecting request: {e}");
+            if let Err(e) = reject_connection(fallback, self.retry_after) {
+                debug!("Failed to send 503: {e}");
+            }
+        }
+    }
+
+    /// Deals with the connections whose deadline has passed, and accepts
+    /// again once the backoff after a failed accept is over.
+    fn sweep(&mut self) {
+        let now = Instant::now();
+        if self.next_sweep.is_none_or(|next| now < next) {
+            return;
+        }
+        if self.retry_accept.is_some_and(|at| at <= now) {
+            self.retry_accept = None;
+            self.accept();
+        }
+        let mut expired = Vec::new();
+        self.next_sweep = self.retry_accept;
+        for (token, connection) in &self.connections {
+            match connection.deadline {
+                Some(deadline) if deadline <= now => expired.push(*token),
+                Some(deadline) if self.next_sweep.is_none_or(|next| deadline < next) => {
+                    self.next_sweep = Some(deadline)
+                }
+                _ => {}
+            }
+        }
+        for token in expired {
+            let Some(connection) = self.connections.get(&token) else {
+                continue;
+            };
+            match connection.state {
+                // A client too slow to finish its request is told so.
+                State::Reading(Phase::Header | Phase::Body) => {
+                    self.reject(token, Response::text(408, ParseError::Timeout.to_string()))
+                }
+                _ => self.close(token),
+            }
+        }
+    }
+
+    fn close(&mut self, token: Token) {
+        if let Some(mut connection) = self.connections.remove(&token) {
+            let _ = self.poll.registry().deregister(&mut connection.stream);
+        }
+    }
+}
+
+/// Returns the deadline `timeout` from now, and makes sure the loop wakes up
+/// for it.
+fn deadline(next_sweep: &mut Option<Instant>, timeout: Option<Duration>) -> Option<Instant> {
+    let deadline = Instant::now() + timeout?;
+    if next_sweep.is_none_or(|next| deadline < next) {
+        *next_sweep = Some(deadline);
+    }
+    Some(deadline)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pool::OverflowPolicy;
+    use crate::request::{Limits, Method};
+    use crate::response::Body;
+    use crate::router::Router;
+    use crate::server::{Server, ShutdownHandle};
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpStream;
+    use std::thread;
+
+    const LARGE: u64 = 4 * 1024 * 1024;
+
+    fn start(
+        workers: usize,
+        config: ConnectionConfig,
+    ) -> (
+        SocketAddr,
+        ShutdownHandle,
+        thread::JoinHandle<ShutdownReport>,
+    ) {
+        let server = Server::bind("127.0.0.1:0", ThreadPool::new(workers))
+            .unwrap()
+            .connection_config(config)
+            .drain_timeout(Duration::from_secs(5))
+            .event_loop(true);
+        let addr = server.local_addr().unwrap();
+        let handle = server.shutdown_handle();
+        let handler = |req: Request| {
+            if req.path == "/slow" {
+                thread::sleep(Duration::from_millis(300));
+            }
+            if req.path == "/panic" {
+                panic!("handler failed");
+            }
+            if req.path == "/large" {
+                let body = io::repeat(b'a').take(LARGE);
+                return Response::new(200).with_body(Body::reader(body, Some(LARGE)));
+            }
+            if req.path == "/endless" {
+                return Response::new(200)
+                    .with_body(Body::chunked(std::iter::repeat(vec![b'a'; 1000])));
+            }
+            Response::text(200, req.path)
+        };
+        (addr, handle, thread::spawn(move || server.serve(handler)))
+    }
+
+    fn send(client: &mut TcpStream, path: &str) {
+        client
+            .write_all(format!("GET {path} HTTP/1.1\r\nHost: a\r\n\r\n").as_bytes())
+            .unwrap();
+    }
+
+    /// Reads one response with a `Content-Length` and returns its head and
+    /// body.
+    fn read_response(reader: &mut impl BufRead) -> (String, String) {
+        let mut head = String::new();
+        loop {
+            let mut line = String::new();
+            assert!(reader.read_line(&mut line).unwrap() > 0, "closed: {head}");
+            if line == "\r\n" {
+                break;
+            }
+            head.push_str(&line);
+        }
+        let length = head
+            .lines()
+            .find_map(|l| l.strip_prefix("Content-Length: "))
+            .unwrap()
+            .parse()
+            .unwrap();
+        let mut body = vec![0; length];
+        reader.read_exact(&mut body).unwrap();
+        (head, String::from_utf8(body).unwrap())
+    }
+
+    fn get(client: &mut TcpStream, path: &str) -> (String, String) {
+        send(client, path);
+        read_response(&mut BufReader::new(client))
+    }
+
+    #[test]
+    fn serves_keep_alive_and_pipelined_requests() {
+        let (addr, handle, server) = start(1, ConnectionConfig::default());
+        let mut client = TcpStream::connect(addr).unwrap();
+        let (head, body) = get(&mut client, "/first");
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(head.contains("Connection: keep-alive\r\n"));
+        assert_eq!(body, "/first");
+
+        client
+            .write_all(
+                b"GET /one HTTP/1.1\r\nHost: a\r\n\r\n\
+                  GET /two HTTP/1.1\r\nHost: a\r\n\r\n\
+                  GET /three HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n",
+            )
+            .unwrap();
+        let mut reader = BufReader::new(&mut client);
+        for path in ["/one", "/two", "/three"] {
+            assert_eq!(read_response(&mut reader).1, path);
+        }
+        assert_eq!(reader.read(&mut [0; 16]).unwrap(), 0);
+
+        handle.shutdown();
+        assert!(server.join().unwrap().is_clean());
+    }
+
+    #[test]
+    fn slow_and_idle_clients_time_out() {
+        let (addr, handle, server) = start(
+            1,
+            ConnectionConfig {
+                idle_timeout: Some(Duration::from_millis(100)),
+                header_timeout: Some(Duration::from_millis(200)),
+                ..ConnectionConfig::default()
+            },
+        );
+        let mut slow = TcpStream::connect(addr).unwrap();
+        slow.write_all(b"GET / HTTP/1.1\r\nHost:").unwrap();
+        let mut idle = TcpStream::connect(addr).unwrap();
+        get(&mut idle, "/");
+
+        let mut out = String::new();
+        slow.read_to_string(&mut out).unwrap();
+        assert!(out.starts_with("HTTP/1.1 408 Request Timeout\r\n"));
+        assert_eq!(idle.read(&mut [0; 16]).unwrap(), 0);
+
+        handle.shutdown();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn panicking_handler_closes_its_connection() {
+        let (addr, handle, server) = start(1, ConnectionConfig::default());
+        let mut client = TcpStream::connect(addr).unwrap();
+        send(&mut client, "/panic");
+        assert_eq!(client.read(&mut [0; 16]).unwrap(), 0);
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        assert_eq!(get(&mut client, "/after").1, "/after");
+        handle.shutdown();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn shutdown_closes_idle_and_finishes_in_flight() {
+        let (addr, handle, server) = start(2, ConnectionConfig::default());
+        let mut idle = TcpStream::connect(addr).unwrap();
+        get(&mut idle, "/");
+        let mut slow = TcpStream::connect(addr).unwrap();
+        send(&mut slow, "/slow");
+        let mut partial = TcpStream::connect(addr).unwrap();
+        partial
+            .write_all(b"GET /partial HTTP/1.1\r\nHost:")
+            .unwrap();
+        thread::sleep(Duration::from_millis(100));
+
+        handle.shutdown();
+        let mut out = String::new();
+        slow.read_to_string(&mut out).unwrap();
+        assert!(out.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(out.contains("Connection: close\r\n"));
+        assert_eq!(idle.read(&mut [0; 16]).unwrap(), 0);
+
+        // A request that had started arriving is still answered.
+        partial.write_all(b" a\r\n\r\n").unwrap();
+        let mut out = String::new();
+        partial.read_to_string(&mut out).unwrap();
+        assert!(out.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(out.ends_with("Connection: close\r\n\r\n/partial"), "{out}");
+        assert!(server.join().unwrap().is_clean());
+        assert!(TcpStream::connect(addr).is_err());
+    }
+
+    #[test]
+    fn full_pool_gets_503_whatever_the_policy() {
+        let pool = ThreadPool::with_queue(1, 1, OverflowPolicy::CallerRuns);
+        let server = Server::bind("127.0.0.1:0", pool).unwrap().event_loop(true);
+        let addr = server.local_addr().unwrap();
+        let handle = server.shutdown_handle();
+        let server = thread::spawn(move || {
+            server.serve(|req: Request| {
+                thread::sleep(Duration::from_millis(300));
+                Response::text(200, req.path)
+            })
+        });
+
+        // One request runs and one waits in the queue; the loop must not
+        // run the third itself.
+        let mut clients: Vec<TcpStream> = (0..3)
+            .map(|_| {
+                let mut client = TcpStream::connect(addr).unwrap();
+                send(&mut client, "/slow");
+                thread::sleep(Duration::from_millis(50));
+                client
+            })
+            .collect();
+        let mut out = String::new();
+        clients[2].read_to_string(&mut out).unwrap();
+        assert!(
+            out.starts_with("HTTP/1.1 503 Service Unavailable\r\n"),
+            "{out}"
+        );
+        assert!(out.contains("Retry-After: 1\r\n"));
+        for client in &mut clients[..2] {
+            assert_eq!(read_response(&mut BufReader::new(client)).1, "/slow");
+        }
+
+        handle.shutdown();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn streams_bodies_a_piece_at_a_time() {
+        let (addr, handle, server) = start(1, ConnectionConfig::default());
+        let mut client = TcpStream::connect(addr).unwrap();
+        let mut reader = BufReader::new(client.try_clone().unwrap());
+        send(&mut client, "/large");
+        let (head, body) = read_response(&mut reader);
+        assert!(head.contains(&format!("Content-Length: {LARGE}\r\n")));
+        assert_eq!(body.len() as u64, LARGE);
+        assert_eq!(get(&mut client, "/after").1, "/after");
+
+        // An endless body starts arriving, and the one worker is free
+        // again once its client leaves.
+        let mut endless = TcpStream::connect(addr).unwrap();
+        send(&mut endless, "/endless");
+        let mut start = [0; 64 * 1024];
+        endless.read_exact(&mut start).unwrap();
+        assert!(start.starts_with(b"HTTP/1.1 200 OK\r\n"));
+        drop(endless);
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        assert_eq!(get(&mut client, "/free").1, "/free");
+
+        handle.shutdown();
+        assert!(server.join().unwrap().is_clean());
+    }
+
+    #[test]
+    fn upgraded_connections_leave_the_loop() {
+        let server = Server::bind("127.0.0.1:0", ThreadPool::new(1))
+            .unwrap()
+            .event_loop(true);
+        let addr = server.local_addr().unwrap();
+        let handle = server.shutdown_handle();
+        let server = thread::spawn(move || {
+            server.serve(|_: Request| {
+                Response::new(101)
+                    .with_header("Connection", "Upgrade")
+                    .with_header("Upgrade", "echo")
+                    .on_upgrade(|mut upgraded| {
+                        let mut buf = [0; 64];
+                        while let Ok(n @ 1..) = upgraded.read(&mut buf) {
+                            if upgraded.write_all(&buf[..n]).is_err() {
+                                break;
+                            }
+                        }
+                    })
+            })
+        });
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        client
+            .write_all(b"GET / HTTP/1.1\r\nHost: a\r\nUpgrade: echo\r\n\r\nearly")
+            .unwrap();
+        let mut reader = BufReader::new(client.try_clone().unwrap());
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        assert_eq!(line, "HTTP/1.1 101 Switching Protocols\r\n");
+        while line != "\r\n" {
+            line.clear();
+            reader.read_line(&mut line).unwrap();
+        }
+        client.write_all(b" late").unwrap();
+        let mut echoed = [0; 10];
+        reader.read_exact(&mut echoed).unwrap();
+        assert_eq!(&echoed, b"early late");
+
+        drop((client, reader));
+        handle.shutdown();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn streamed_bodies_leave_the_loop() {
+        let config = ConnectionConfig {
+            limits: Limits {
+                max_body_size: 1024,
+                ..Limits::default()
+            },
+            ..ConnectionConfig::default()
+        };
+        let server = Server::bind("127.0.0.1:0", ThreadPool::new(1))
+            .unwrap()
+            .connection_config(config)
+            .event_loop(true);
+        let addr = server.local_addr().unwrap();
+        let handle = server.shutdown_handle();
+        let router = Router::new()
+            .streaming(Method::Put, "/upload", |req: Request| {
+                let mut body = req.body_reader().unwrap();
+                let size = io::copy(&mut body, &mut io::sink()).unwrap();
+                Response::text(200, size.to_string())
+            })
+            .get("/after", |req: Request| Response::text(200, req.path));
+        let server = thread::spawn(move || server.serve(router));
+
+        let size = LARGE as usize;
+        let mut client = TcpStream::connect(addr).unwrap();
+        client
+            .write_all(
+                format!("PUT /upload HTTP/1.1\r\nHost: a\r\nContent-Length: {size}\r\n\r\n")
+                    .as_bytes(),
+            )
+            .unwrap();
+        client.write_all(&vec![b'a'; size]).unwrap();
+        let (head, body) = read_response(&mut BufReader::new(&mut client));
+        assert!(head.contains("Connection: keep-alive\r\n"));
+        assert_eq!(body, size.to_string());
+        assert_eq!(get(&mut client, "/after").1, "/after");
+
+        drop(client);
+        handle.shutdown();
+        assert!(server.join().unwrap().is_clean());
+    }
+
+    /// Far more idle keep-alive connections than workers stay open at once,
+    /// and every one of them is still served.
+    fn holds_idle_keep_alive_connections(count: usize) {
+        let (addr, handle, server) = start(2, ConnectionConfig::default());
+
+        let mut clients: Vec<TcpStream> = (0..count)
+            .map(|i| {
+                let mut client = TcpStream::connect(addr).unwrap();
+                client
+                    .set_read_timeout(Some(Duration::from_secs(10)))
+                    .unwrap();
+                assert_eq!(get(&mut client, &format!("/{i}")).1, format!("/{i}"));
+                client
+            })
+            .collect();
+
+        // With every connection open and idle, a new client is served
+        // right away, and so is each of the idle ones again.
+        let mut fresh = TcpStream::connect(addr).unwrap();
+        assert_eq!(get(&mut fresh, "/fresh").1, "/fresh");
+        for (i, client) in clients.iter_mut().enumerate().rev() {
+            let (head, body) = get(client, &format!("/again/{i}"));
+            assert!(head.contains("Connection: keep-alive\r\n"));
+            assert_eq!(body, format!("/again/{i}"));
+        }
+
+        handle.shutdown();
+        assert!(server.join().unwrap().is_clean());
+        for mut client in clients {
+            assert_eq!(client.read(&mut [0; 16]).unwrap(), 0);
+        }
+    }
+
+    #[test]
+    fn holds_many_idle_keep_alive_connections() {
+        holds_idle_keep_alive_connections(100);
+    }
+
+    /// Both ends of every connection are in this process, so this needs
+    /// about 4000 file descriptors: run it with a raised `ulimit -n`.
+    #[test]
+    #[ignore = "load test; needs about 4000 file descriptors"]
+    fn holds_thousands_of_idle_keep_alive_connections() {
+        holds_idle_keep_alive_connections(2000);
+    }
+}
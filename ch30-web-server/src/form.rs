@@ -0,0 +1,705 @@
+use std::error::Error;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::request::percent_decode;
+
+/// Fields of an HTML form, in the order they were sent.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Form {
+    entries: Vec<(String, String)>,
+}
+
+impl Form {
+    pub fn new() -> Form {
+        Form::default()
+    }
+
+    /// Parses `application/x-www-form-urlencoded` text, such as a query
+    /// string. Pairs without `=` get an empty value.
+    pub fn parse(text: &str) -> Result<Form, FormError> {
+        let mut form = Form::new();
+        for pair in text.split('&').filter(|p| !p.is_empty()) {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            let decode = |s: &str| {
+                percent_decode(&s.replace('+', " "))
+                    .ok_or(FormError::Malformed("invalid percent-encoding"))
+            };
+            form.insert(decode(name)?, decode(value)?);
+        }
+        Ok(form)
+    }
+
+    /// Returns the first value sent for `name`.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.entries
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Returns every value sent for `name`, as checkboxes and multiple
+    /// selects do.
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.entries
+            .iter()
+            .filter(move |(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn insert(&mut self, name: impl Into<String>, value: impl Into<String>) {
+        self.entries.push((name.into(), value.into()));
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.entries.iter().map(|(n, v)| (n.as_str(), v.as_str()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+/// Limits enforced while parsing `multipart/form-data`.
+///
+/// A body received in full is bounded by
+/// [`Limits::max_body_size`](crate::Limits::max_body_size) before any of
+/// these apply. One read from the connection as it is parsed, on a route
+/// added with [`Router::streaming`](crate::Router::streaming), is bounded by
+/// these alone.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FormLimits {
+    /// Maximum size of one uploaded file.
+    pub max_file_size: u64,
+    /// Maximum number of uploaded files.
+    pub max_files: usize,
+    /// Maximum size of one ordinary field's value.
+    pub max_field_size: usize,
+    /// Maximum number of parts, files included.
+    pub max_parts: usize,
+    /// Where uploaded files are written.
+    pub upload_dir: PathBuf,
+}
+
+impl Default for FormLimits {
+    fn default() -> FormLimits {
+        FormLimits {
+            max_file_size: 10 * 1024 * 1024,
+            max_files: 8,
+            max_field_size: 64 * 1024,
+            max_parts: 64,
+            upload_dir: std::env::temp_dir(),
+        }
+    }
+}
+
+/// A parsed `multipart/form-data` body.
+#[derive(Debug, Default)]
+pub struct Multipart {
+    /// The parts that are not files.
+    pub fields: Form,
+    pub files: Vec<UploadedFile>,
+}
+
+impl Multipart {
+    /// Parses a multipart body from `reader`, given the request's
+    /// `Content-Type`.
+    ///
+    /// Parts are handled as they are read: file contents are written to
+    /// disk chunk by chunk, so only a chunk of them at a time is held here.
+    /// On error, the files written so far are removed.
+    pub fn read_from<R: Read>(
+        reader: R,
+        content_type: &str,
+        limits: &FormLimits,
+    ) -> Result<Multipart, FormError> {
+        let boundary = boundary(content_type)?;
+        let mut parts = PartReader::new(reader, &boundary);
+        let mut multipart = Multipart::default();
+
+        parts.skip_preamble()?;
+        let mut count = 0;
+        while parts.next_part()? {
+            count += 1;
+            if count > limits.max_parts {
+                return Err(FormError::TooLarge("too many parts"));
+            }
+            let head = parts.read_head()?;
+            let disposition = head
+                .iter()
+                .find(|(name, _)| name.eq_ignore_ascii_case("Content-Disposition"))
+                .map(|(_, value)| value.as_str())
+                .ok_or(FormError::Malformed("part without Content-Disposition"))?;
+            let (kind, params) = parse_parameters(disposition);
+            if !kind.eq_ignore_ascii_case("form-data") {
+                return Err(FormError::Malformed("part is not form-data"));
+            }
+            let param = |key: &str| {
+                params
+                    .iter()
+                    .find(|(k, _)| k.eq_ignore_ascii_case(key))
+                    .map(|(_, v)| v.clone())
+            };
+            let name = param("name").ok_or(FormError::Malformed("part without a name"))?;
+
+            match param("filename") {
+                Some(filename) => {
+                    if multipart.files.len() == limits.max_files {
+                        return Err(FormError::TooLarge("too many files"));
+                    }
+                    let content_type = head
+                        .iter()
+                        .find(|(name, _)| name.eq_ignore_ascii_case("Content-Type"))
+                        .map(|(_, value)| value.clone());
+                    let (mut file, mut upload) = UploadedFile::create(&limits.upload_dir)?;
+                    upload.name = name;
+                    upload.filename = filename;
+                    upload.content_type = content_type;
+                    // Pushed before writing, so that it is removed on error.
+                    multipart.files.push(upload);
+                    let upload = multipart.files.last_mut().expect("just pushed");
+                    parts.read_body(|chunk| {
+                        upload.size += chunk.len() as u64;
+                        if upload.size > limits.max_file_size {
+                            return Err(FormError::TooLarge("file too large"));
+                        }
+                        file.write_all(chunk).map_err(FormError::Io)
+                    })?;
+                    file.flush()?;
+                }
+                None => {
+                    let mut value = Vec::new();
+                    parts.read_body(|chunk| {
+                        if value.len() + chunk.len() > limits.max_field_size {
+                            return Err(FormError::TooLarge("field too large"));
+                        }
+                        value.extend_from_slice(chunk);
+                        Ok(())
+                    })?;
+                    let value = String::from_utf8(value)
+                        .map_err(|_| FormError::Malformed("field is not UTF-8"))?;
+                    multipart.fields.insert(name, value);
+                }
+            }
+        }
+        Ok(multipart)
+    }
+
+    /// Returns the first file uploaded as field `name`.
+    pub fn file(&self, name: &str) -> Option<&UploadedFile> {
+        self.files.iter().find(|f| f.name == name)
+    }
+}
+
+/// A file received in a multipart body and stored in the upload directory.
+///
+/// The stored file is deleted when this is dropped, unless it has been
+/// moved with [`persist`](UploadedFile::persist).
+#[derive(Debug)]
+pub struct UploadedFile {
+    /// The form field the file was sent as.
+    pub name: String,
+    /// The file name the client gave; not safe to use as a path.
+    pub filename: String,
+    pub content_type: Option<String>,
+    pub size: u64,
+    path: PathBuf,
+    keep: bool,
+}
+
+impl UploadedFile {
+    /// Creates a new, uniquely named file in `dir`.
+    fn create(dir: &Path) -> io::Result<(File, UploadedFile)> {
+        static NEXT: AtomicU64 = AtomicU64::new(0);
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.subsec_nanos());
+        loop {
+            let n = NEXT.fetch_add(1, Ordering::Relaxed);
+            let path = dir.join(format!(
+                "ch30-upload-{}-{nanos:08x}-{n}",
+                std::process::id()
+            ));
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(file) => {
+                    let upload = UploadedFile {
+                        name: String::new(),
+                        filename: String::new(),
+                        content_type: None,
+                        size: 0,
+                        path,
+                        keep: false,
+                    };
+                    return Ok((file, upload));
+                }
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Where the file is stored until it is dropped.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Moves the file to `to` so that it outlives this value.
+    pub fn persist(mut self, to: impl AsRef<Path>) -> io::Result<()> {
+        let to = to.as_ref();
+        if fs::rename(&self.path, to).is_err() {
+            // Across file systems a rename fails; copy instead.
+            fs::copy(&self.path, to)?;
+            fs::remove_file(&self.path)?;
+        }
+        self.keep = true;
+        Ok(())
+    }
+}
+
+impl Drop for UploadedFile {
+    fn drop(&mut self) {
+        if !self.keep {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// Why a form body could not be parsed.
+#[derive(Debug)]
+pub enum FormError {
+    /// The request does not carry the kind of form asked for.
+    UnsupportedContentType,
+    Malformed(&'static str),
+    /// A limit in [`FormLimits`] was exceeded.
+    TooLarge(&'static str),
+    Io(io::Error),
+}
+
+impl FormError {
+    /// The status code to answer the request with.
+    pub fn status_code(&self) -> u16 {
+        match self {
+            FormError::UnsupportedContentType => 415,
+            FormError::Malformed(_) => 400,
+            FormError::TooLarge(_) => 413,
+            // Reading a body from the connection fails with these when the
+            // client is at fault.
+            FormError::Io(e) => match e.kind() {
+                io::ErrorKind::TimedOut => 408,
+                io::ErrorKind::UnexpectedEof | io::ErrorKind::InvalidData => 400,
+                _ => 500,
+            },
+        }
+    }
+}
+
+impl fmt::Display for FormError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FormError::UnsupportedContentType => f.write_str("unsupported form content type"),
+            FormError::Malformed(reason) => write!(f, "malformed form: {reason}"),
+            FormError::TooLarge(reason) => write!(f, "form too large: {reason}"),
+            FormError::Io(e) => write!(f, "I/O error: {e}"),
+        }
+    }
+}
+
+impl Error for FormError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            FormError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for FormError {
+    fn from(e: io::Error) -> FormError {
+        FormError::Io(e)
+    }
+}
+
+/// Returns the media type of a `Content-Type` value, in lower case.
+pub(crate) fn media_type(content_type: &str) -> String {
+    let (media_type, _) = parse_parameters(content_type);
+    media_type.to_ascii_lowercase()
+}
+
+fn boundary(content_type: &str) -> Result<String, FormError> {
+    let (media_type, params) = parse_parameters(content_type);
+    if !media_type.eq_ignore_ascii_case("multipart/form-data") {
+        return Err(FormError::UnsupportedContentType);
+    }
+    let boundary = params
+        .into_iter()
+        .find(|(k, _)| k.eq_ignore_ascii_case("boundary"))
+        .map(|(_, v)| v)
+        .ok_or(FormError::Malformed("missing boundary"))?;
+    // RFC 2046 section 5.1.1.
+    if boundary.is_empty() || boundary.len() > 70 {
+        return Err(FormError::Malformed("invalid boundary"));
+    }
+    Ok(boundary)
+}
+
+/// Splits `value; key=value; key="quoted value"` into the value and its
+/// parameters.
+fn parse_parameters(header: &str) -> (String, Vec<(String, String)>) {
+    let (value, mut rest) = header.split_once(';').unwrap_or((header, ""));
+    let mut params = Vec::new();
+    loop {
+        rest = rest.trim_start_matches([' ', '\t', ';']);
+        let Some((key, after)) = rest.split_once('=') else {
+            break;
+        };
+        let key = key.trim().to_string();
+        let value;
+        if let Some(quoted) = after.strip_prefix('"') {
+            let mut unquoted = String::new();
+            let mut chars = quoted.char_indices();
+            let mut end = quoted.len();
+            while let Some((i, c)) = chars.next() {
+                match c {
+                    '\\' => unquoted.extend(chars.next().map(|(_, c)| c)),
+                    '"' => {
+                        end = i + 1;
+                        break;
+                    }
+                    c => unquoted.push(c),
+                }
+            }
+            value = unquoted;
+            rest = &quoted[end..];
+        } else {
+            let end = after.find(';').unwrap_or(after.len());
+            value = after[..end].trim().to_string();
+            rest = &after[end..];
+        }
+        params.push((key, value));
+    }
+    (value.trim().to_string(), params)
+}
+
+/// Splits a multipart stream at its boundaries without buffering whole
+/// parts.
+struct PartReader<R> {
+    reader: R,
+    buf: Vec<u8>,
+    /// `\r\n--boundary`, which ends every part.
+    delimiter: Vec<u8>,
+    eof: bool,
+}
+
+/// The largest header block of a single part.
+const MAX_PART_HEAD: usize = 8 * 1024;
+
+impl<R: Read> PartReader<R> {
+    fn new(reader: R, boundary: &str) -> PartReader<R> {
+        PartReader {
+            reader,
+            // The first delimiter may come without the line break before it;
+            // starting with one lets every delimiter be found the same way.
+            buf: b"\r\n".to_vec(),
+            delimiter: format!("\r\n--{boundary}").into_bytes(),
+            eof: false,
+        }
+    }
+
+    /// Reads more input. Returns false at end of input.
+    fn fill(&mut self) -> Result<bool, FormError> {
+        if self.eof {
+            return Ok(false);
+        }
+        let mut chunk = [0; 8 * 1024];
+        let n = loop {
+            match self.reader.read(&mut chunk) {
+                Ok(n) => break n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(FormError::Io(e)),
+            }
+        };
+        self.buf.extend_from_slice(&chunk[..n]);
+        self.eof = n == 0;
+        Ok(n > 0)
+    }
+
+    /// Discards everything up to and including the first delimiter.
+    fn skip_preamble(&mut self) -> Result<(), FormError> {
+        self.read_body(|_| Ok(()))
+    }
+
+    /// Consumes what follows a delimiter. Returns false after the closing
+    /// delimiter.
+    fn next_part(&mut self) -> Result<bool, FormError> {
+        while self.buf.len() < 2 {
+            if !self.fill()? {
+                return Err(FormError::Malformed("body ends after a boundary"));
+            }
+        }
+        if self.buf.starts_with(b"--") {
+            // The epilogue after the closing delimiter is ignored.
+            return Ok(false);
+        }
+        let line_end = self.find(b"\r\n", 256)?;
+        if self.buf[..line_end]
+            .iter()
+            .any(|b| !matches!(b, b' ' | b'\t'))
+        {
+            return Err(FormError::Malformed("text after a boundary"));
+        }
+        self.buf.drain(..line_end + 2);
+        Ok(true)
+    }
+
+    /// Reads a part's header block.
+    fn read_head(&mut self) -> Result<Vec<(String, String)>, FormError> {
+        // A part without headers starts with the blank line.
+        let end = if self.starts_with(b"\r\n")? {
+            0
+        } else {
+            self.find(b"\r\n\r\n", MAX_PART_HEAD)? + 2
+        };
+        let head = std::str::from_utf8(&self.buf[..end])
+            .map_err(|_| FormError::Malformed("part headers are not UTF-8"))?;
+        let mut fields = Vec::new();
+        for line in head.split("\r\n").filter(|l| !l.is_empty()) {
+            let (name, value) = line
+                .split_once(':')
+                .ok_or(FormError::Malformed("malformed part header"))?;
+            fields.push((name.trim().to_string(), value.trim().to_string()));
+        }
+        self.buf.drain(..end + 2);
+        Ok(fields)
+    }
+
+    /// Passes the bytes up to the next delimiter to `sink`, and consumes the
+    /// delimiter.
+    fn read_body(
+        &mut self,
+        mut sink: impl FnMut(&[u8]) -> Result<(), FormError>,
+    ) -> Result<(), FormError> {
+        loop {
+            if let Some(at) = position(&self.buf, &self.delimiter) {
+                sink(&self.buf[..at])?;
+                self.buf.drain(..at + self.delimiter.len());
+                return Ok(());
+            }
+            // Everything but a possible start of the delimiter is body.
+            let safe = self.buf.len().saturating_sub(self.delimiter.len() - 1);
+            if safe > 0 {
+                sink(&self.buf[..safe])?;
+                self.buf.drain(..safe);
+            }
+            if !self.fill()? {
+                return Err(FormError::Malformed(
+                    "body ends before the closing boundary",
+                ));
+            }
+        }
+    }
+
+    fn starts_with(&mut self, prefix: &[u8]) -> Result<bool, FormError> {
+        while self.buf.len() < prefix.len() && self.fill()? {}
+        Ok(self.buf.starts_with(prefix))
+    }
+
+    /// Returns the position of `needle`, reading until it shows up within
+    /// the first `limit` bytes.
+    fn find(&mut self, needle: &[u8], limit: usize) -> Result<usize, FormError> {
+        loop {
+            if let Some(at) = position(&self.buf, needle) {
+                if at <= limit {
+                    return Ok(at);
+                }
+            }
+            if self.buf.len() > limit {
+                return Err(FormError::TooLarge("part headers too large"));
+            }
+            if !self.fill()? {
+                return Err(FormError::Malformed("body ends inside part headers"));
+            }
+        }
+    }
+}
+
+fn position(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::request::{Request, RequestParser};
+    use tempfile::TempDir;
+
+    const CONTENT_TYPE: &str = "multipart/form-data; boundary=\"----xyz\"";
+
+    fn body() -> Vec<u8> {
+        b"preamble is ignored\r\n\
+          ------xyz\r\n\
+          Content-Disposition: form-data; name=\"title\"\r\n\
+          \r\n\
+          Holiday \xe2\x98\x80\r\n\
+          ------xyz  \r\n\
+          Content-Disposition: form-data; name=\"photo\"; filename=\"a \\\"b\\\".txt\"\r\n\
+          Content-Type: text/plain\r\n\
+          \r\n\
+          line one\r\n------xy not yet\r\nline two\r\n\
+          ------xyz\r\n\
+          Content-Disposition: form-data; name=\"tag\"\r\n\
+          \r\n\
+          \r\n\
+          ------xyz--\r\n\
+          epilogue"
+            .to_vec()
+    }
+
+    fn limits(dir: &TempDir) -> FormLimits {
+        FormLimits {
+            upload_dir: dir.path().to_path_buf(),
+            ..FormLimits::default()
+        }
+    }
+
+    fn uploads(dir: &TempDir) -> usize {
+        fs::read_dir(dir.path()).unwrap().count()
+    }
+
+    /// Hands out one byte per read, so that every boundary straddles reads.
+    struct Trickle<'a>(&'a [u8]);
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = self.0.len().min(buf.len()).min(1);
+            buf[..n].copy_from_slice(&self.0[..n]);
+            self.0 = &self.0[n..];
+            Ok(n)
+        }
+    }
+
+    fn post(content_type: &str, body: &[u8]) -> Request {
+        let mut parser = RequestParser::new();
+        let head = format!(
+            "POST /upload HTTP/1.1\r\nHost: test\r\nContent-Type: {content_type}\r\n\
+             Content-Length: {}\r\n\r\n",
+            body.len()
+        );
+        parser.feed(head.as_bytes());
+        parser.feed(body);
+        parser.parse().unwrap().unwrap()
+    }
+
+    #[test]
+    fn parses_urlencoded_forms() {
+        let request = post(
+            "application/x-www-form-urlencoded; charset=UTF-8",
+            b"a=1&b=hello+world&c=%E4%BD%A0%2B&a=2&flag&&=empty",
+        );
+        let form = request.form().unwrap();
+        assert_eq!(form.get("a"), Some("1"));
+        assert_eq!(form.get_all("a").collect::<Vec<_>>(), ["1", "2"]);
+        assert_eq!(form.get("b"), Some("hello world"));
+        assert_eq!(form.get("c"), Some("你+"));
+        assert_eq!(form.get("flag"), Some(""));
+        assert_eq!(form.get(""), Some("empty"));
+
+        let json = post("application/json", b"{}");
+        assert_eq!(json.form().unwrap_err().status_code(), 415);
+        let bad = post("application/x-www-form-urlencoded", b"a=%zz");
+        assert_eq!(bad.form().unwrap_err().status_code(), 400);
+    }
+
+    #[test]
+    fn streams_file_parts_to_disk() {
+        let dir = TempDir::new().unwrap();
+        let body = body();
+        let form = Multipart::read_from(Trickle(&body), CONTENT_TYPE, &limits(&dir)).unwrap();
+
+        assert_eq!(form.fields.get("title"), Some("Holiday ☀"));
+        assert_eq!(form.fields.get("tag"), Some(""));
+        let photo = form.file("photo").unwrap();
+        assert_eq!(photo.filename, "a \"b\".txt");
+        assert_eq!(photo.content_type.as_deref(), Some("text/plain"));
+        let contents = "line one\r\n------xy not yet\r\nline two";
+        assert_eq!(photo.size, contents.len() as u64);
+        assert_eq!(fs::read_to_string(photo.path()).unwrap(), contents);
+        assert!(photo.path().starts_with(dir.path()));
+
+        // Dropping the form removes its files; persisted ones stay.
+        let kept = dir.path().join("kept.txt");
+        let mut form = form;
+        form.files.pop().unwrap().persist(&kept).unwrap();
+        drop(form);
+        assert_eq!(fs::read_to_string(&kept).unwrap(), contents);
+        assert_eq!(uploads(&dir), 1);
+    }
+
+    #[test]
+    fn request_parses_its_multipart_body() {
+        let dir = TempDir::new().unwrap();
+        let request = post(CONTENT_TYPE, &body());
+        let form = request.multipart(&limits(&dir)).unwrap();
+        assert_eq!(form.fields.len(), 2);
+        assert_eq!(form.files.len(), 1);
+
+        let plain = post("text/plain", b"hi");
+        assert_eq!(
+            plain.multipart(&limits(&dir)).unwrap_err().status_code(),
+            415
+        );
+    }
+
+    #[test]
+    fn enforces_limits_and_cleans_up() {
+        let dir = TempDir::new().unwrap();
+        let body = body();
+        let check = |limits: FormLimits, status| {
+            let err = Multipart::read_from(&body[..], CONTENT_TYPE, &limits).unwrap_err();
+            assert_eq!(err.status_code(), status, "{err}");
+        };
+
+        check(
+            FormLimits {
+                max_file_size: 10,
+                ..limits(&dir)
+            },
+            413,
+        );
+        check(
+            FormLimits {
+                max_files: 0,
+                ..limits(&dir)
+            },
+            413,
+        );
+        check(
+            FormLimits {
+                max_field_size: 4,
+                ..limits(&dir)
+            },
+            413,
+        );
+        check(
+            FormLimits {
+                max_parts: 2,
+                ..limits(&dir)
+            },
+            413,
+        );
+        assert_eq!(uploads(&dir), 0);
+
+        // A body cut off before the closing boundary.
+        let cut = &body[..body.len() - 20];
+        let err = Multipart::read_from(cut, CONTENT_TYPE, &limits(&dir)).unwrap_err();
+        assert_eq!(err.status_code(), 400);
+        assert_eq!(uploads(&dir), 0);
+    }
+}
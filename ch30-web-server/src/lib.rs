@@ -1,86 +1,52 @@
-use std::sync::{mpsc, Arc, Mutex};
-use std::thread;
-
-pub struct ThreadPool {
-    workers: Vec<Worker>,
-    sender: Option<mpsc::Sender<Job>>,
-}
-
-type Job = Box<dyn FnOnce() + Send + 'static>;
-
-impl ThreadPool {
-    /// Create a new ThreadPool.
-    ///
-    /// The size is the number of threads in the pool.
-    ///
-    /// # Panics
-    ///
-    /// The `new` function will panic if the size is zero.
-    pub fn new(size: usize) -> ThreadPool {
-        assert!(size > 0);
-
-        let (sender, receiver) = mpsc::channel();
-        let receiver = Arc::new(Mutex::new(receiver));
-        let mut workers = Vec::with_capacity(size);
-
-        for id in 0..size {
-            workers.push(Worker::new(id, Arc::clone(&receiver)));
-        }
-
-        ThreadPool {
-            workers,
-            sender: Some(sender),
-        }
-    }
-
-    pub fn execute<F>(&self, f: F)
-    where
-        F: FnOnce() + Send + 'static,
-    {
-        let job = Box::new(f);
-        self.sender.as_ref().unwrap().send(job).unwrap();
-    }
-}
-
-impl Drop for ThreadPool {
-    fn drop(&mut self) {
-        drop(self.sender.take());
-
-        for worker in &mut self.workers {
-            println!("Shutting down worker {}", worker.id);
-
-            if let Some(thread) = worker.thread.take() {
-                thread.join().unwrap();
-            }
-        }
-    }
-}
-
-struct Worker {
-    id: usize,
-    thread: Option<thread::JoinHandle<()>>,
-}
-
-impl Worker {
-    fn new(id: usize, receiver: Arc<Mutex<mpsc::Receiver<Job>>>) -> Worker {
-        let thread = thread::spawn(move || loop {
-            let message = receiver.lock().unwrap().recv();
-
-            match message {
-                Ok(job) => {
-                    println!("Worker {} got a job; executing.", id);
-                    job();
-                }
-                Err(_) => {
-                    println!("Worker {} disconnected; shutting down.", id);
-                    break;
-                }
-            }
-        });
-
-        Worker {
-            id,
-            thread: Some(thread),
-        }
-    }
-}
+// Declared first so that its logging macros are visible in the other modules.
+#[macro_use]
+mod logger;
+
+mod cgi;
+mod compression;
+mod config;
+mod connection;
+mod event_loop;
+mod form;
+mod headers;
+mod metrics;
+mod middleware;
+mod pool;
+mod proxy;
+mod request;
+mod response;
+mod router;
+mod server;
+mod static_files;
+mod status;
+#[cfg(feature = "tls")]
+mod tls;
+mod vhost;
+mod websocket;
+
+pub use cgi::Cgi;
+pub use compression::{Compression, Encoding};
+pub use config::{ConfigError, ProxyRoute, ServerConfig, TlsHost, VirtualHost, USAGE};
+pub use connection::{reject_connection, serve_connection, ConnectionConfig, Transport, Upgraded};
+pub use form::{Form, FormError, FormLimits, Multipart, UploadedFile};
+pub use headers::Headers;
+pub use logger::{
+    logger, set_logger, AccessEntry, Level, LogFormat, Logger, ParseLevelError, WriterLogger,
+};
+pub use metrics::{Histogram, HistogramSnapshot, MetricsEndpoint, RequestMetrics, RouteStats};
+pub use middleware::{BasicAuth, CatchPanic, Chain, Cors, Middleware, Next, RequestId, Timing};
+pub use pool::{
+    ExecuteError, JobHandle, JoinError, OverflowPolicy, PoolCreationError, PoolMonitor, PoolStats,
+    Scheduler, ShutdownReport, ThreadPool, ThreadPoolBuilder,
+};
+pub use proxy::Proxy;
+pub use request::{BodyReader, Limits, Method, ParseError, Request, RequestParser, Version};
+pub use response::{Body, Response};
+pub use router::{Handler, Params, Router};
+pub use server::{Server, ShutdownHandle};
+pub use static_files::{mime_type, StaticFiles};
+pub use status::reason_phrase;
+#[cfg(feature = "tls")]
+pub use tls::{TlsConfig, TlsError, TlsStream};
+pub use vhost::VirtualHosts;
+pub use websocket::{accept_key, CloseFrame, Message, WebSocket, WebSocketError, WebSocketHandler};
@@ -0,0 +1,567 @@
+use std::error::Error;
+use std::fmt::{self, Write as _};
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock, PoisonError, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::request::{Method, Version};
+
+/// Severity of a log message, from most to least severe.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Level {
+    Error = 1,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl Level {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Level::Error => "ERROR",
+            Level::Warn => "WARN",
+            Level::Info => "INFO",
+            Level::Debug => "DEBUG",
+            Level::Trace => "TRACE",
+        }
+    }
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Level {
+    type Err = ParseLevelError;
+
+    /// Parses a level name, ignoring case.
+    fn from_str(s: &str) -> Result<Level, ParseLevelError> {
+        match s.to_ascii_lowercase().as_str() {
+            "error" => Ok(Level::Error),
+            "warn" | "warning" => Ok(Level::Warn),
+            "info" => Ok(Level::Info),
+            "debug" => Ok(Level::Debug),
+            "trace" => Ok(Level::Trace),
+            _ => Err(ParseLevelError(s.to_string())),
+        }
+    }
+}
+
+/// Returned when parsing an unknown [`Level`] name.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseLevelError(String);
+
+impl fmt::Display for ParseLevelError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "unknown log level {:?}, expected error, warn, info, debug or trace",
+            self.0
+        )
+    }
+}
+
+impl Error for ParseLevelError {}
+
+/// How [`WriterLogger`] formats its lines.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    /// NCSA Common Log Format for access lines, plain text for messages.
+    #[default]
+    Common,
+    /// Common Log Format plus referer and user agent.
+    Combined,
+    /// One JSON object per line for both access lines and messages. The
+    /// only format that includes the latency.
+    Json,
+}
+
+/// One answered request, as recorded in the access log.
+#[derive(Debug, Clone)]
+pub struct AccessEntry<'a> {
+    pub client: Option<SocketAddr>,
+    /// When the request was received.
+    pub time: SystemTime,
+    pub method: Method,
+    /// The path and query as sent by the client.
+    pub target: &'a str,
+    pub version: Version,
+    pub status: u16,
+    /// Bytes of body sent.
+    pub bytes: u64,
+    /// Time from receiving the request to writing the response.
+    pub latency: Duration,
+    pub referer: Option<&'a str>,
+    pub user_agent: Option<&'a str>,
+}
+
+impl AccessEntry<'_> {
+    /// Formats the entry in Common Log Format:
+    ///
+    /// ```text
+    /// 127.0.0.1 - - [10/Oct/2000:13:55:36 +0000] "GET /index.html HTTP/1.1" 200 2326
+    /// ```
+    pub fn common(&self) -> String {
+        let client = match self.client {
+            Some(addr) => addr.ip().to_string(),
+            None => "-".to_string(),
+        };
+        let bytes = match self.bytes {
+            0 => "-".to_string(),
+            n => n.to_string(),
+        };
+        format!(
+            "{client} - - [{}] \"{} {} {}\" {} {bytes}",
+            DateTime::from(self.time).clf(),
+            self.method,
+            clf_escape(self.target),
+            self.version,
+            self.status,
+        )
+    }
+
+    /// Formats the entry in Combined Log Format, which adds the referer and
+    /// user agent to [`common`](Self::common).
+    pub fn combined(&self) -> String {
+        format!(
+            "{} \"{}\" \"{}\"",
+            self.common(),
+            clf_escape(self.referer.unwrap_or("-")),
+            clf_escape(self.user_agent.unwrap_or("-")),
+        )
+    }
+
+    /// Formats the entry as a single-line JSON object.
+    pub fn json(&self) -> String {
+        let mut out = String::from("{");
+        let _ = write!(out, "\"time\":\"{}\"", DateTime::from(self.time).rfc3339());
+        out.push_str(",\"client\":");
+        match self.client {
+            Some(addr) => json_string(&mut out, &addr.to_string()),
+            None => out.push_str("null"),
+        }
+        let _ = write!(out, ",\"method\":\"{}\"", self.method);
+        out.push_str(",\"target\":");
+        json_string(&mut out, self.target);
+        let _ = write!(
+            out,
+            ",\"version\":\"{}\",\"status\":{},\"bytes\":{},\"latency_ms\":{:.3}",
+            self.version,
+            self.status,
+            self.bytes,
+            self.latency.as_secs_f64() * 1000.0,
+        );
+        for (name, value) in [("referer", self.referer), ("user_agent", self.user_agent)] {
+            let _ = write!(out, ",\"{name}\":");
+            match value {
+                Some(value) => json_string(&mut out, value),
+                None => out.push_str("null"),
+            }
+        }
+        out.push('}');
+        out
+    }
+}
+
+/// Receives the server's log messages and access entries.
+///
+/// Install one with [`set_logger`]. Until then, a [`WriterLogger`] writing
+/// access lines to stdout and messages to stderr at [`Level::Info`] is used.
+pub trait Logger: Send + Sync {
+    /// The most verbose level this logger records. Access entries are
+    /// recorded at [`Level::Info`].
+    fn level(&self) -> Level;
+
+    fn log(&self, level: Level, message: fmt::Arguments<'_>);
+
+    fn access(&self, entry: &AccessEntry<'_>);
+}
+
+impl<L: Logger + ?Sized> Logger for Arc<L> {
+    fn level(&self) -> Level {
+        (**self).level()
+    }
+
+    fn log(&self, level: Level, message: fmt::Arguments<'_>) {
+        (**self).log(level, message)
+    }
+
+    fn access(&self, entry: &AccessEntry<'_>) {
+        (**self).access(entry)
+    }
+}
+
+/// A [`Logger`] that writes lines to a pair of writers.
+pub struct WriterLogger {
+    level: Level,
+    format: LogFormat,
+    access: Output,
+    messages: Output,
+}
+
+enum Output {
+    Stdout,
+    Stderr,
+    Writer(Mutex<Box<dyn Write + Send>>),
+}
+
+impl Output {
+    fn write_line(&self, line: &str) {
+        match self {
+            // There is nowhere left to report a failing log writer, and
+            // `println!` would panic, for instance when stdout is a closed
+            // pipe.
+            Output::Stdout => {
+                let _ = writeln!(io::stdout().lock(), "{line}");
+            }
+            Output::Stderr => {
+                let _ = writeln!(io::stderr().lock(), "{line}");
+            }
+            Output::Writer(writer) => {
+                let mut writer = writer.lock().unwrap_or_else(PoisonError::into_inner);
+                let _ = writeln!(writer, "{line}").and_then(|()| writer.flush());
+            }
+        }
+    }
+}
+
+impl WriterLogger {
+    /// Writes access lines to `access` and messages to `messages`.
+    pub fn new(
+        access: impl Write + Send + 'static,
+        messages: impl Write + Send + 'static,
+    ) -> WriterLogger {
+        WriterLogger {
+            level: Level::Info,
+            format: LogFormat::default(),
+            access: Output::Writer(Mutex::new(Box::new(access))),
+            messages: Output::Writer(Mutex::new(Box::new(messages))),
+        }
+    }
+
+    /// Writes access lines to stdout and messages to stderr.
+    pub fn stdout() -> WriterLogger {
+        WriterLogger {
+            level: Level::Info,
+            format: LogFormat::default(),
+            access: Output::Stdout,
+            messages: Output::Stderr,
+        }
+    }
+
+    pub fn level(mut self, level: Level) -> WriterLogger {
+        self.level = level;
+        self
+    }
+
+    pub fn format(mut self, format: LogFormat) -> WriterLogger {
+        self.format = format;
+        self
+    }
+}
+
+impl Logger for WriterLogger {
+    fn level(&self) -> Level {
+        self.level
+    }
+
+    fn log(&self, level: Level, message: fmt::Arguments<'_>) {
+        if level > self.level {
+            return;
+        }
+        let time = DateTime::from(SystemTime::now()).rfc3339();
+        let line = match self.format {
+            LogFormat::Json => {
+                let mut line = format!("{{\"time\":\"{time}\",\"level\":\"{level}\",\"message\":");
+                json_string(&mut line, &message.to_string());
+                line.push('}');
+                line
+            }
+            LogFormat::Common | LogFormat::Combined => format!("{time} {level:<5} {message}"),
+        };
+        self.messages.write_line(&line);
+    }
+
+    fn access(&self, entry: &AccessEntry<'_>) {
+        if Level::Info > self.level {
+            return;
+        }
+        let line = match self.format {
+            LogFormat::Common => entry.common(),
+            LogFormat::Combined => entry.combined(),
+            LogFormat::Json => entry.json(),
+        };
+        self.access.write_line(&line);
+    }
+}
+
+static LOGGER: RwLock<Option<Arc<dyn Logger>>> = RwLock::new(None);
+/// The level of the installed logger, checked before taking the lock so that
+/// disabled messages cost a single load.
+static MAX_LEVEL: AtomicUsize = AtomicUsize::new(Level::Info as usize);
+
+/// Installs `logger` for the whole process, replacing the previous one.
+pub fn set_logger(logger: impl Logger + 'static) {
+    let mut installed = LOGGER.write().unwrap_or_else(PoisonError::into_inner);
+    MAX_LEVEL.store(logger.level() as usize, Ordering::Relaxed);
+    *installed = Some(Arc::new(logger));
+}
+
+/// Returns the installed logger.
+pub fn logger() -> Arc<dyn Logger> {
+    static DEFAULT: OnceLock<Arc<dyn Logger>> = OnceLock::new();
+
+    let installed = LOGGER.read().unwrap_or_else(PoisonError::into_inner);
+    match &*installed {
+        Some(logger) => Arc::clone(logger),
+        None => Arc::clone(DEFAULT.get_or_init(|| Arc::new(WriterLogger::stdout()))),
+    }
+}
+
+pub(crate) fn enabled(level: Level) -> bool {
+    level as usize <= MAX_LEVEL.load(Ordering::Relaxed)
+}
+
+pub(crate) fn log(level: Level, message: fmt::Arguments<'_>) {
+    if enabled(level) {
+        logger().log(level, message);
+    }
+}
+
+pub(crate) fn access(entry: &AccessEntry<'_>) {
+    if enabled(Level::Info) {
+        logger().access(entry);
+    }
+}
+
+// Logging macros for the rest of the crate, made visible by `#[macro_use]`.
+
+macro_rules! error {
+    ($($arg:tt)+) => {
+        $crate::logger::log($crate::logger::Level::Error, format_args!($($arg)+))
+    };
+}
+
+macro_rules! warn {
+    ($($arg:tt)+) => {
+        $crate::logger::log($crate::logger::Level::Warn, format_args!($($arg)+))
+    };
+}
+
+macro_rules! info {
+    ($($arg:tt)+) => {
+        $crate::logger::log($crate::logger::Level::Info, format_args!($($arg)+))
+    };
+}
+
+macro_rules! debug {
+    ($($arg:tt)+) => {
+        $crate::logger::log($crate::logger::Level::Debug, format_args!($($arg)+))
+    };
+}
+
+macro_rules! trace {
+    ($($arg:tt)+) => {
+        $crate::logger::log($crate::logger::Level::Trace, format_args!($($arg)+))
+    };
+}
+
+/// Escapes `s` for a quoted field of the Common Log Format the way Apache
+/// does: `"` and `\` get a backslash, and control characters are written
+/// as `\xNN` per byte, so a client cannot break or forge log lines.
+fn clf_escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if c.is_control() => {
+                let mut buf = [0; 4];
+                for b in c.encode_utf8(&mut buf).bytes() {
+                    let _ = write!(out, "\\x{b:02x}");
+                }
+            }
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// Appends `s` as a quoted JSON string.
+fn json_string(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_control() => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+/// A UTC calendar time, enough to print log timestamps.
+struct DateTime {
+    year: i64,
+    month: u32,
+    day: u32,
+    hour: u64,
+    minute: u64,
+    second: u64,
+    millis: u32,
+}
+
+impl From<SystemTime> for DateTime {
+    fn from(time: SystemTime) -> DateTime {
+        let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let secs = since_epoch.as_secs();
+        let (year, month, day) = civil_from_days((secs / 86_400) as i64);
+        DateTime {
+            year,
+            month,
+            day,
+            hour: secs % 86_400 / 3600,
+            minute: secs % 3600 / 60,
+            second: secs % 60,
+            millis: since_epoch.subsec_millis(),
+        }
+    }
+}
+
+impl DateTime {
+    /// `10/Oct/2000:13:55:36 +0000`
+    fn clf(&self) -> String {
+        const MONTHS: [&str; 12] = [
+            "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+        ];
+        format!(
+            "{:02}/{}/{}:{:02}:{:02}:{:02} +0000",
+            self.day,
+            MONTHS[self.month as usize - 1],
+            self.year,
+            self.hour,
+            self.minute,
+            self.second
+        )
+    }
+
+    /// `2000-10-10T13:55:36.000Z`
+    fn rfc3339(&self) -> String {
+        format!(
+            "{}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+            self.year, self.month, self.day, self.hour, self.minute, self.second, self.millis
+        )
+    }
+}
+
+/// Converts days since 1970-01-01 to a (year, month, day) date, following
+/// Howard Hinnant's `civil_from_days`.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+
+    fn entry() -> AccessEntry<'static> {
+        AccessEntry {
+            client: Some("127.0.0.1:51234".parse().unwrap()),
+            // 10 Oct 2000 13:55:36 UTC
+            time: UNIX_EPOCH + Duration::from_secs(971_186_136),
+            method: Method::Get,
+            target: "/apache_pb.gif?a=1",
+            version: Version::Http10,
+            status: 200,
+            bytes: 2326,
+            latency: Duration::from_micros(1500),
+            referer: Some("http://example.com/start.html"),
+            user_agent: Some("Mozilla/4.08 \"quoted\""),
+        }
+    }
+
+    #[test]
+    fn formats_common_and_combined() {
+        assert_eq!(
+            entry().common(),
+            "127.0.0.1 - - [10/Oct/2000:13:55:36 +0000] \"GET /apache_pb.gif?a=1 HTTP/1.0\" 200 2326"
+        );
+        assert!(entry()
+            .combined()
+            .ends_with(" 2326 \"http://example.com/start.html\" \"Mozilla/4.08 \\\"quoted\\\"\""));
+
+        // Control characters cannot start a forged line.
+        let forged = AccessEntry {
+            target: "/a\\b",
+            user_agent: Some("x\n127.0.0.1 - - \"GET /\u{85}"),
+            ..entry()
+        };
+        assert!(forged.common().contains(" \"GET /a\\\\b HTTP/1.0\" "));
+        assert!(forged
+            .combined()
+            .ends_with(" \"x\\x0a127.0.0.1 - - \\\"GET /\\xc2\\x85\""));
+    }
+
+    #[test]
+    fn formats_json() {
+        assert_eq!(
+            entry().json(),
+            "{\"time\":\"2000-10-10T13:55:36.000Z\",\"client\":\"127.0.0.1:51234\",\
+             \"method\":\"GET\",\"target\":\"/apache_pb.gif?a=1\",\"version\":\"HTTP/1.0\",\
+             \"status\":200,\"bytes\":2326,\"latency_ms\":1.500,\
+             \"referer\":\"http://example.com/start.html\",\
+             \"user_agent\":\"Mozilla/4.08 \\\"quoted\\\"\"}"
+        );
+    }
+
+    #[test]
+    fn writer_logger_filters_by_level() {
+        #[derive(Clone, Default)]
+        struct Shared(Arc<Mutex<Vec<u8>>>);
+
+        impl Write for Shared {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0.lock().unwrap().write(buf)
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let messages = Shared::default();
+        let logger = WriterLogger::new(io::sink(), messages.clone())
+            .level(Level::Warn)
+            .format(LogFormat::Json);
+        logger.log(Level::Info, format_args!("hidden"));
+        logger.log(Level::Warn, format_args!("disk \"full\""));
+
+        let out = String::from_utf8(messages.0.lock().unwrap().clone()).unwrap();
+        assert_eq!(out.lines().count(), 1);
+        assert!(out.contains("\"level\":\"WARN\",\"message\":\"disk \\\"full\\\"\"}"));
+        assert_eq!("Debug".parse(), Ok(Level::Debug));
+        assert!("loud".parse::<Level>().is_err());
+    }
+}
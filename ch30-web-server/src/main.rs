@@ -1,54 +1,199 @@
-use ch30_web_server::ThreadPool;
+use ch30_web_server::{
+    logger, set_logger, CatchPanic, Cgi, Chain, Compression, ConfigError, Level, Message, Method,
+    MetricsEndpoint, OverflowPolicy, Request, RequestId, RequestMetrics, Response, Router, Server,
+    ServerConfig, StaticFiles, ThreadPool, Timing, VirtualHosts, WebSocketHandler, WriterLogger,
+    USAGE,
+};
+use std::env;
 use std::fs;
-use std::io::prelude::*;
-use std::net::TcpListener;
-use std::net::TcpStream;
+use std::path::Path;
+use std::process;
+use std::sync::Arc;
 use std::thread;
 use std::time::Duration;
 
 fn main() {
-    let listener = TcpListener::bind("127.0.0.1:7878").unwrap();
-    let pool = ThreadPool::new(4);
-
-    println!("Shutting down after 2 connections for demo purposes (or use Ctrl+C).");
-    println!("Server running on http://127.0.0.1:7878");
+    // 配置优先级：默认值 < 配置文件 < 环境变量 < 命令行参数
+    let config = match ServerConfig::build(env::args(), env::vars()) {
+        Ok(config) => config,
+        Err(ConfigError::Help) => {
+            print!("{USAGE}");
+            return;
+        }
+        Err(e) => {
+            eprintln!("{e}");
+            eprintln!("Run with --help for usage.");
+            process::exit(2);
+        }
+    };
 
-    // 为了演示优雅停机，我们在接受 2 个请求后退出循环
-    // 在真实应用中，可以使用 listener.incoming() 无限循环
-    for stream in listener.incoming().take(5) {
-        let stream = stream.unwrap();
+    // 访问日志输出到 stdout，其他日志输出到 stderr
+    set_logger(
+        WriterLogger::stdout()
+            .format(config.log_format)
+            .level(config.log_level),
+    );
 
-        pool.execute(|| {
-            handle_connection(stream);
-        });
+    // 队列满时直接拒绝，避免任务无限堆积
+    // 慢请求堆积时线程数可扩到 max_workers，空闲过久的多余线程自动退出
+    let pool = match ThreadPool::builder(config.workers)
+        .thread_name("worker")
+        .max_threads(config.max_workers())
+        .keep_alive(config.worker_keep_alive)
+        .queue(config.queue, OverflowPolicy::Reject)
+        .build()
+    {
+        Ok(pool) => pool,
+        Err(e) => {
+            eprintln!("Failed to start thread pool: {e}");
+            process::exit(1);
+        }
+    };
+    // 线程池交给 Server 之前先拿到监控句柄，供 /metrics 使用
+    let pool_monitor = pool.monitor();
+    // 停机时等待正在处理的请求完成，超过 drain_timeout 后强制关闭剩余连接
+    // event-loop 模式下由一个线程等待所有连接就绪，只把完整的请求交给线程池
+    let addr = config.socket_addr();
+    let server = match Server::bind(addr, pool) {
+        Ok(server) => server
+            .connection_config(config.connection_config())
+            .drain_timeout(config.drain_timeout)
+            .event_loop(config.event_loop),
+        Err(e) => {
+            eprintln!("Failed to bind {addr}: {e}");
+            process::exit(1);
+        }
+    };
+    // 配置了证书时改用 HTTPS，按客户端请求的主机名（SNI）选择证书
+    #[cfg(feature = "tls")]
+    let server = match config.tls_config() {
+        Ok(Some(tls)) => server.tls(tls),
+        Ok(None) => server,
+        Err(e) => {
+            eprintln!("Failed to load TLS certificates: {e}");
+            process::exit(1);
+        }
+    };
+    // 收到 SIGINT（Ctrl+C）或 SIGTERM 时优雅停机，再按一次立即退出
+    if let Err(e) = server.shutdown_on_signals() {
+        eprintln!("Failed to install signal handlers: {e}");
+        process::exit(1);
     }
 
-    println!("Shutting down.");
-}
-
-fn handle_connection(mut stream: TcpStream) {
-    let mut buffer = [0; 1024];
-    stream.read(&mut buffer).unwrap();
-
-    let get = b"GET / HTTP/1.1\r\n";
-    let sleep = b"GET /sleep HTTP/1.1\r\n";
+    // 按路由统计请求数和耗时
+    let requests = Arc::new(RequestMetrics::new());
+    let mut router = site(&config.document_root)
+        .get(
+            "/metrics",
+            MetricsEndpoint::new(pool_monitor, Arc::clone(&requests)),
+        )
+        // WebSocket 回显：连接可能长期保持，放到独立线程上，不占用线程池的 worker
+        .get(
+            "/ws/echo",
+            WebSocketHandler::new(|_, mut socket| {
+                while let Ok(message) = socket.recv() {
+                    if let Message::Text(_) | Message::Binary(_) = message {
+                        if socket.send(message).is_err() {
+                            break;
+                        }
+                    }
+                }
+            })
+            .dedicated_thread(true),
+        )
+        .metrics(Arc::clone(&requests));
+    // 配置的路径前缀转发给上游服务，所有方法都转发，多个上游轮流使用
+    for route in &config.proxies {
+        let proxy = route.proxy();
+        for method in [
+            Method::Get,
+            Method::Post,
+            Method::Put,
+            Method::Delete,
+            Method::Patch,
+            Method::Options,
+        ] {
+            router = router.route(method, &route.pattern(), proxy.clone());
+        }
+    }
+    // 配置了 CGI 目录时，前缀下的请求交给脚本处理，每次执行有时间限制
+    if let Some(dir) = &config.cgi_dir {
+        let cgi = Cgi::new(&config.cgi_prefix, dir).timeout(config.cgi_timeout);
+        let pattern = format!("{}/*", config.cgi_prefix.trim_end_matches('/'));
+        for method in [Method::Get, Method::Post] {
+            router = router.route(method, &pattern, cgi.clone());
+        }
+    }
+    // 按 Host 头选择站点：先精确匹配，再匹配通配符，其余请求交给默认站点
+    let mut sites = VirtualHosts::new();
+    for vhost in &config.vhosts {
+        let router = site(&vhost.document_root).metrics(Arc::clone(&requests));
+        sites = sites.host(&vhost.name, router);
+    }
+    let sites = sites.default_host(router);
+    // 中间件按添加顺序执行：最外层捕获 panic，返回 500 而不是直接断开连接
+    // 压缩放在最内层，其余中间件看到的都是压缩前的响应
+    let app = Chain::new(sites)
+        .with(CatchPanic)
+        .with(RequestId::new())
+        .with(Timing)
+        .with(Compression::new());
 
-    let (status_line, filename) = if buffer.starts_with(get) {
-        ("HTTP/1.1 200 OK", "hello.html")
-    } else if buffer.starts_with(sleep) {
-        thread::sleep(Duration::from_secs(5));
-        ("HTTP/1.1 200 OK", "hello.html")
+    let scheme = if config.tls_enabled() {
+        "https"
     } else {
-        ("HTTP/1.1 404 NOT FOUND", "404.html")
+        "http"
     };
+    println!("Server running on {scheme}://{addr} (press Ctrl+C to stop)");
 
-    let contents = fs::read_to_string(filename).unwrap();
-    let length = contents.len();
+    // 每个连接可以处理多个请求（keep-alive），直到客户端关闭、超时或服务器停机
+    let report = server.serve(app);
 
-    let response = format!(
-        "{status_line}\r\nContent-Length: {length}\r\n\r\n{contents}"
-    );
+    println!("Shutting down.");
+    if !report.unfinished_workers.is_empty() {
+        eprintln!(
+            "Workers still busy at the deadline: {:?}",
+            report.unfinished_workers
+        );
+    }
+    if !report.failed_workers.is_empty() {
+        eprintln!("Workers that failed: {:?}", report.failed_workers);
+    }
+    if report.panicked_jobs > 0 {
+        eprintln!("{} jobs panicked while running.", report.panicked_jobs);
+    }
+}
 
-    stream.write_all(response.as_bytes()).unwrap();
-    stream.flush().unwrap();
+// 一个站点的页面：首页、静态文件和 404 页面都在它自己的 document_root 下
+fn site(document_root: &Path) -> Router {
+    let hello = document_root.join("hello.html");
+    let not_found = document_root.join("404.html");
+    Router::new()
+        .get("/", {
+            let hello = hello.clone();
+            move |_: Request| html_file(200, &hello)
+        })
+        .get("/sleep", move |_: Request| {
+            thread::sleep(Duration::from_secs(5));
+            html_file(200, &hello)
+        })
+        .get(
+            "/static/*path",
+            StaticFiles::new(document_root.join("static")).precompressed(true),
+        )
+        .not_found(move |_: Request| html_file(404, &not_found))
+}
+
+fn html_file(status: u16, path: &Path) -> Response {
+    match fs::read_to_string(path) {
+        Ok(contents) => Response::html(status, contents),
+        Err(e) => {
+            // 走配置好的日志格式，而不是直接写 stderr
+            logger().log(
+                Level::Error,
+                format_args!("Failed to read {}: {e}", path.display()),
+            );
+            Response::new(500)
+        }
+    }
 }
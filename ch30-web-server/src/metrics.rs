@@ -0,0 +1,368 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Duration;
+
+use crate::pool::{PoolMonitor, PoolStats};
+use crate::request::{Method, Request};
+use crate::response::Response;
+use crate::router::Handler;
+
+/// Bucket bounds for latencies, from 1 ms to 10 s.
+const LATENCY_BUCKETS: [Duration; 12] = [
+    Duration::from_millis(1),
+    Duration::from_millis(5),
+    Duration::from_millis(10),
+    Duration::from_millis(25),
+    Duration::from_millis(50),
+    Duration::from_millis(100),
+    Duration::from_millis(250),
+    Duration::from_millis(500),
+    Duration::from_secs(1),
+    Duration::from_millis(2500),
+    Duration::from_secs(5),
+    Duration::from_secs(10),
+];
+
+/// Counts durations into buckets, like a Prometheus histogram. Lock-free,
+/// so it can be updated from every worker at once.
+#[derive(Debug)]
+pub struct Histogram {
+    bounds: Vec<Duration>,
+    /// One count per bound, plus one for values above the last bound.
+    counts: Vec<AtomicU64>,
+    sum_nanos: AtomicU64,
+}
+
+impl Histogram {
+    /// Creates a histogram with the given upper bucket bounds, in ascending
+    /// order.
+    pub fn new(bounds: &[Duration]) -> Histogram {
+        Histogram {
+            bounds: bounds.to_vec(),
+            counts: (0..=bounds.len()).map(|_| AtomicU64::new(0)).collect(),
+            sum_nanos: AtomicU64::new(0),
+        }
+    }
+
+    /// Creates a histogram with buckets suited to request latencies.
+    pub fn latency() -> Histogram {
+        Histogram::new(&LATENCY_BUCKETS)
+    }
+
+    pub fn observe(&self, value: Duration) {
+        let bucket = self.bounds.partition_point(|bound| *bound < value);
+        self.counts[bucket].fetch_add(1, Ordering::Relaxed);
+        let nanos = u64::try_from(value.as_nanos()).unwrap_or(u64::MAX);
+        self.sum_nanos.fetch_add(nanos, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> HistogramSnapshot {
+        let mut total = 0;
+        let mut buckets = Vec::with_capacity(self.bounds.len());
+        for (bound, count) in self.bounds.iter().zip(&self.counts) {
+            total += count.load(Ordering::Relaxed);
+            buckets.push((*bound, total));
+        }
+        total += self.counts[self.bounds.len()].load(Ordering::Relaxed);
+        HistogramSnapshot {
+            buckets,
+            count: total,
+            sum: Duration::from_nanos(self.sum_nanos.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+/// The state of a [`Histogram`] at one point in time.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HistogramSnapshot {
+    /// Upper bounds with the number of values at or below each, so the
+    /// counts are cumulative.
+    pub buckets: Vec<(Duration, u64)>,
+    /// Number of values observed.
+    pub count: u64,
+    /// Sum of the values observed.
+    pub sum: Duration,
+}
+
+/// Request counts and latencies per route.
+///
+/// Attach it to a [`Router`](crate::Router) with
+/// [`Router::metrics`](crate::Router::metrics). Routes are identified by
+/// their pattern, so `/users/1` and `/users/2` both count towards
+/// `/users/:id`.
+#[derive(Debug, Default)]
+pub struct RequestMetrics {
+    routes: Mutex<BTreeMap<(Method, String), RouteMetrics>>,
+}
+
+#[derive(Debug)]
+struct RouteMetrics {
+    statuses: BTreeMap<u16, u64>,
+    duration: Histogram,
+}
+
+/// Counts for one method and route, from [`RequestMetrics::snapshot`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RouteStats {
+    pub method: Method,
+    pub route: String,
+    /// Number of responses per status code.
+    pub statuses: BTreeMap<u16, u64>,
+    pub duration: HistogramSnapshot,
+}
+
+impl RequestMetrics {
+    pub fn new() -> RequestMetrics {
+        RequestMetrics::default()
+    }
+
+    pub fn record(&self, method: Method, route: &str, status: u16, elapsed: Duration) {
+        let mut routes = self.routes.lock().unwrap_or_else(PoisonError::into_inner);
+        let entry = routes
+            .entry((method, route.to_string()))
+            .or_insert_with(|| RouteMetrics {
+                statuses: BTreeMap::new(),
+                duration: Histogram::latency(),
+            });
+        *entry.statuses.entry(status).or_default() += 1;
+        entry.duration.observe(elapsed);
+    }
+
+    pub fn snapshot(&self) -> Vec<RouteStats> {
+        let routes = self.routes.lock().unwrap_or_else(PoisonError::into_inner);
+        routes
+            .iter()
+            .map(|((method, route), metrics)| RouteStats {
+                method: *method,
+                route: route.clone(),
+                statuses: metrics.statuses.clone(),
+                duration: metrics.duration.snapshot(),
+            })
+            .collect()
+    }
+}
+
+/// Serves pool and request metrics in the Prometheus text format.
+///
+/// ```
+/// use std::sync::Arc;
+/// use ch30_web_server::{MetricsEndpoint, RequestMetrics, Router, ThreadPool};
+///
+/// let pool = ThreadPool::new(4);
+/// let requests = Arc::new(RequestMetrics::new());
+/// let router = Router::new()
+///     .get("/metrics", MetricsEndpoint::new(pool.monitor(), Arc::clone(&requests)))
+///     .metrics(requests);
+/// ```
+pub struct MetricsEndpoint {
+    pool: PoolMonitor,
+    requests: Arc<RequestMetrics>,
+}
+
+impl MetricsEndpoint {
+    pub fn new(pool: PoolMonitor, requests: Arc<RequestMetrics>) -> MetricsEndpoint {
+        MetricsEndpoint { pool, requests }
+    }
+
+    /// Renders the current metrics.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        write_pool(&mut out, &self.pool.stats());
+        write_requests(&mut out, &self.requests.snapshot());
+        out
+    }
+}
+
+impl Handler for MetricsEndpoint {
+    fn handle(&self, _request: Request) -> Response {
+        Response::new(200)
+            .with_header("Content-Type", "text/plain; version=0.0.4; charset=utf-8")
+            .with_header("Cache-Control", "no-store")
+            .with_body(self.render())
+    }
+}
+
+fn write_pool(out: &mut String, stats: &PoolStats) {
+    let gauges = [
+        ("threads", "Worker threads alive.", stats.threads),
+        ("busy_threads", "Workers running a job.", stats.busy),
+        ("idle_threads", "Workers waiting for a job.", stats.idle),
+        ("queued_jobs", "Jobs waiting for a worker.", stats.queued),
+        (
+            "min_threads",
+            "Workers kept even when idle.",
+            stats.min_threads,
+        ),
+        (
+            "max_threads",
+            "Most workers the pool may grow to.",
+            stats.max_threads,
+        ),
+    ];
+    for (name, help, value) in gauges {
+        write_header(out, &format!("ch30_pool_{name}"), help, "gauge");
+        let _ = writeln!(out, "ch30_pool_{name} {value}");
+    }
+
+    let counters = [
+        (
+            "completed",
+            "Jobs run to completion or panic.",
+            stats.completed_jobs,
+        ),
+        ("panicked", "Jobs that panicked.", stats.panicked_jobs),
+        (
+            "rejected",
+            "Jobs the pool did not accept.",
+            stats.rejected_jobs,
+        ),
+    ];
+    for (name, help, value) in counters {
+        write_header(
+            out,
+            &format!("ch30_pool_jobs_{name}_total"),
+            help,
+            "counter",
+        );
+        let _ = writeln!(out, "ch30_pool_jobs_{name}_total {value}");
+    }
+
+    let name = "ch30_pool_queue_wait_seconds";
+    write_header(out, name, "Time jobs spent in the queue.", "histogram");
+    write_histogram(out, name, "", &stats.queue_wait);
+    let name = "ch30_pool_job_duration_seconds";
+    write_header(out, name, "Time jobs took to run.", "histogram");
+    write_histogram(out, name, "", &stats.job_duration);
+}
+
+fn write_requests(out: &mut String, routes: &[RouteStats]) {
+    let name = "ch30_http_requests_total";
+    write_header(
+        out,
+        name,
+        "Requests answered, by route and status.",
+        "counter",
+    );
+    for route in routes {
+        let labels = route_labels(route);
+        for (status, count) in &route.statuses {
+            let _ = writeln!(out, "{name}{{{labels},status=\"{status}\"}} {count}");
+        }
+    }
+
+    let name = "ch30_http_request_duration_seconds";
+    write_header(out, name, "Time taken to answer requests.", "histogram");
+    for route in routes {
+        write_histogram(out, name, &route_labels(route), &route.duration);
+    }
+}
+
+fn route_labels(route: &RouteStats) -> String {
+    format!(
+        "method=\"{}\",route=\"{}\"",
+        route.method,
+        escape_label(&route.route)
+    )
+}
+
+fn write_header(out: &mut String, name: &str, help: &str, kind: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+}
+
+/// Writes the `_bucket`, `_sum` and `_count` samples of a histogram.
+/// `labels` are added to each sample, separated by commas.
+fn write_histogram(out: &mut String, name: &str, labels: &str, snapshot: &HistogramSnapshot) {
+    let sep = if labels.is_empty() { "" } else { "," };
+    for (bound, count) in &snapshot.buckets {
+        let le = bound.as_secs_f64();
+        let _ = writeln!(out, "{name}_bucket{{{labels}{sep}le=\"{le}\"}} {count}");
+    }
+    let count = snapshot.count;
+    let _ = writeln!(out, "{name}_bucket{{{labels}{sep}le=\"+Inf\"}} {count}");
+    let braces = |labels: &str| {
+        if labels.is_empty() {
+            String::new()
+        } else {
+            format!("{{{labels}}}")
+        }
+    };
+    let sum = snapshot.sum.as_secs_f64();
+    let _ = writeln!(out, "{name}_sum{} {sum}", braces(labels));
+    let _ = writeln!(out, "{name}_count{} {count}", braces(labels));
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pool::ThreadPool;
+    use crate::request::RequestParser;
+    use crate::router::Router;
+
+    fn request(path: &str) -> Request {
+        let mut parser = RequestParser::new();
+        parser.feed(format!("GET {path} HTTP/1.1\r\nHost: test\r\n\r\n").as_bytes());
+        parser.parse().unwrap().unwrap()
+    }
+
+    #[test]
+    fn histogram_counts_are_cumulative() {
+        let histogram = Histogram::new(&[Duration::from_millis(10), Duration::from_millis(100)]);
+        for ms in [1, 10, 50, 500] {
+            histogram.observe(Duration::from_millis(ms));
+        }
+
+        let snapshot = histogram.snapshot();
+        assert_eq!(
+            snapshot.buckets,
+            [
+                (Duration::from_millis(10), 2),
+                (Duration::from_millis(100), 3)
+            ]
+        );
+        assert_eq!(snapshot.count, 4);
+        assert_eq!(snapshot.sum, Duration::from_millis(561));
+    }
+
+    #[test]
+    fn endpoint_reports_pool_and_routes() {
+        let pool = ThreadPool::new(2);
+        pool.spawn(|| ()).join().unwrap();
+        let requests = Arc::new(RequestMetrics::new());
+        let router = Router::new()
+            .get("/users/:id", |_: Request| Response::text(200, "user"))
+            .get(
+                "/metrics",
+                MetricsEndpoint::new(pool.monitor(), Arc::clone(&requests)),
+            )
+            .metrics(requests);
+
+        router.handle(request("/users/1"));
+        router.handle(request("/users/2"));
+        router.handle(request("/nope"));
+        let response = router.handle(request("/metrics"));
+        let text = String::from_utf8(response.body.as_bytes().unwrap().to_vec()).unwrap();
+
+        assert!(text.contains("# TYPE ch30_pool_threads gauge\nch30_pool_threads 2\n"));
+        assert!(text.contains("ch30_pool_jobs_completed_total 1\n"));
+        assert!(text.contains("ch30_pool_job_duration_seconds_count 1\n"));
+        assert!(text.contains(
+            "ch30_http_requests_total{method=\"GET\",route=\"/users/:id\",status=\"200\"} 2\n"
+        ));
+        assert!(text.contains(
+            "ch30_http_requests_total{method=\"GET\",route=\"(unmatched)\",status=\"404\"} 1\n"
+        ));
+        assert!(text.contains(
+            "ch30_http_request_duration_seconds_bucket{method=\"GET\",route=\"/users/:id\",le=\"+Inf\"} 2\n"
+        ));
+    }
+}
@@ -0,0 +1,619 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+
+use crate::request::{Method, Request};
+use crate::response::Response;
+use crate::router::Handler;
+
+/// Code that runs around a [`Handler`].
+///
+/// A middleware gets the request and the rest of the chain as [`Next`]. It
+/// can change the request before passing it on, change the response on the
+/// way back, or answer by itself without calling `next` at all.
+///
+/// Implemented for every `Fn(Request, Next) -> Response` closure.
+pub trait Middleware: Send + Sync + 'static {
+    fn handle(&self, request: Request, next: Next<'_>) -> Response;
+}
+
+impl<F> Middleware for F
+where
+    F: Fn(Request, Next<'_>) -> Response + Send + Sync + 'static,
+{
+    fn handle(&self, request: Request, next: Next<'_>) -> Response {
+        self(request, next)
+    }
+}
+
+/// The rest of a [`Chain`]: the middleware after the current one, then the
+/// handler.
+pub struct Next<'a> {
+    middleware: &'a [Box<dyn Middleware>],
+    handler: &'a dyn Handler,
+}
+
+impl Next<'_> {
+    /// Passes the request on and returns the response.
+    pub fn run(self, request: Request) -> Response {
+        match self.middleware.split_first() {
+            Some((first, rest)) => first.handle(
+                request,
+                Next {
+                    middleware: rest,
+                    handler: self.handler,
+                },
+            ),
+            None => self.handler.handle(request),
+        }
+    }
+}
+
+/// A handler wrapped in middleware.
+///
+/// Middleware runs in the order it was added: the first one sees the request
+/// first and the response last.
+///
+/// ```
+/// use ch30_web_server::{CatchPanic, Chain, Request, RequestId, Response, Timing};
+///
+/// let handler = Chain::new(|_: Request| Response::text(200, "hello"))
+///     .with(CatchPanic)
+///     .with(RequestId::new())
+///     .with(Timing);
+/// ```
+pub struct Chain<H> {
+    middleware: Vec<Box<dyn Middleware>>,
+    handler: H,
+}
+
+impl<H: Handler> Chain<H> {
+    pub fn new(handler: H) -> Chain<H> {
+        Chain {
+            middleware: Vec::new(),
+            handler,
+        }
+    }
+
+    /// Adds `middleware` inside the middleware added so far.
+    pub fn with(mut self, middleware: impl Middleware) -> Chain<H> {
+        self.middleware.push(Box::new(middleware));
+        self
+    }
+}
+
+impl<H: Handler> Handler for Chain<H> {
+    fn handle(&self, request: Request) -> Response {
+        Next {
+            middleware: &self.middleware,
+            handler: &self.handler,
+        }
+        .run(request)
+    }
+
+    fn streams_body(&self, request: &Request) -> bool {
+        self.handler.streams_body(request)
+    }
+}
+
+/// Tags every request with an ID, so that log lines and responses can be
+/// matched up.
+///
+/// An ID the client sent is kept if it looks sane; otherwise a new one is
+/// generated. Either way it is set on the request before it is passed on and
+/// echoed in the response.
+pub struct RequestId {
+    header: String,
+    prefix: u64,
+    counter: AtomicU64,
+}
+
+impl RequestId {
+    /// Uses the `X-Request-Id` header.
+    pub fn new() -> RequestId {
+        RequestId {
+            header: "X-Request-Id".to_string(),
+            // Random per process, so IDs from restarts do not collide.
+            prefix: RandomState::new().build_hasher().finish(),
+            counter: AtomicU64::new(0),
+        }
+    }
+
+    pub fn header(mut self, name: impl Into<String>) -> RequestId {
+        self.header = name.into();
+        self
+    }
+
+    fn generate(&self) -> String {
+        let n = self.counter.fetch_add(1, Ordering::Relaxed);
+        format!("{:016x}-{n:08x}", self.prefix)
+    }
+}
+
+impl Default for RequestId {
+    fn default() -> RequestId {
+        RequestId::new()
+    }
+}
+
+fn is_valid_request_id(id: &str) -> bool {
+    (1..=128).contains(&id.len())
+        && id
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.' | b':'))
+}
+
+impl Middleware for RequestId {
+    fn handle(&self, mut request: Request, next: Next<'_>) -> Response {
+        let id = match request.headers.get(&self.header) {
+            Some(id) if is_valid_request_id(id) => id.to_string(),
+            _ => self.generate(),
+        };
+        request.headers.insert(self.header.as_str(), id.as_str());
+        next.run(request).with_header(self.header.as_str(), id)
+    }
+}
+
+/// Reports how long the rest of the chain took in a `Server-Timing` header.
+pub struct Timing;
+
+impl Middleware for Timing {
+    fn handle(&self, request: Request, next: Next<'_>) -> Response {
+        let start = Instant::now();
+        let mut response = next.run(request);
+        let millis = start.elapsed().as_secs_f64() * 1000.0;
+        response
+            .headers
+            .append("Server-Timing", format!("app;dur={millis:.3}"));
+        response
+    }
+}
+
+/// Answers cross-origin requests according to the CORS protocol.
+///
+/// Preflight requests from allowed origins are answered with `204 No
+/// Content` and never reach the handler; those from other origins get
+/// `403 Forbidden`. Other requests are passed on, and the response gets the
+/// `Access-Control-*` headers if the origin is allowed.
+///
+/// Add it before [`BasicAuth`], since browsers send preflights without
+/// credentials.
+pub struct Cors {
+    /// `None` allows every origin.
+    origins: Option<Vec<String>>,
+    methods: Vec<Method>,
+    headers: Vec<String>,
+    expose_headers: Vec<String>,
+    credentials: bool,
+    max_age: Option<Duration>,
+}
+
+impl Cors {
+    /// Allows no origins and the `GET`, `HEAD` and `POST` methods.
+    pub fn new() -> Cors {
+        Cors {
+            origins: Some(Vec::new()),
+            methods: vec![Method::Get, Method::Head, Method::Post],
+            headers: Vec::new(),
+            expose_headers: Vec::new(),
+            credentials: false,
+            max_age: None,
+        }
+    }
+
+    pub fn allow_origin(mut self, origin: impl Into<String>) -> Cors {
+        if let Some(origins) = &mut self.origins {
+            origins.push(origin.into());
+        }
+        self
+    }
+
+    /// Allows every origin.
+    ///
+    /// # Panics
+    ///
+    /// Panics if credentials are allowed, since every site could then make
+    /// credentialed requests; list the origins instead.
+    pub fn allow_any_origin(mut self) -> Cors {
+        assert!(
+            !self.credentials,
+            "CORS credentials require a list of allowed origins"
+        );
+        self.origins = None;
+        self
+    }
+
+    pub fn allow_methods(mut self, methods: impl IntoIterator<Item = Method>) -> Cors {
+        self.methods = methods.into_iter().collect();
+        self
+    }
+
+    pub fn allow_headers<S: Into<String>>(mut self, headers: impl IntoIterator<Item = S>) -> Cors {
+        self.headers = headers.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Sets the response headers scripts may read besides the safelisted ones.
+    pub fn expose_headers<S: Into<String>>(mut self, headers: impl IntoIterator<Item = S>) -> Cors {
+        self.expose_headers = headers.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Lets requests carry cookies and `Authorization`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if every origin is allowed, since every site could then make
+    /// credentialed requests; list the origins instead.
+    pub fn allow_credentials(mut self, allow: bool) -> Cors {
+        assert!(
+            !allow || self.origins.is_some(),
+            "CORS credentials require a list of allowed origins"
+        );
+        self.credentials = allow;
+        self
+    }
+
+    /// Sets how long browsers may cache a preflight result.
+    pub fn max_age(mut self, max_age: Duration) -> Cors {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    fn allows(&self, origin: &str) -> bool {
+        match &self.origins {
+            Some(origins) => origins.iter().any(|o| o.eq_ignore_ascii_case(origin)),
+            None => true,
+        }
+    }
+
+    fn add_origin_headers(&self, response: &mut Response, origin: &str) {
+        if self.origins.is_none() {
+            response.headers.insert("Access-Control-Allow-Origin", "*");
+        } else {
+            response
+                .headers
+                .insert("Access-Control-Allow-Origin", origin);
+        }
+        if self.credentials {
+            response
+                .headers
+                .insert("Access-Control-Allow-Credentials", "true");
+        }
+    }
+
+    fn preflight(&self, origin: &str) -> Response {
+        let mut response = Response::new(204);
+        self.add_origin_headers(&mut response, origin);
+        let methods: Vec<&str> = self.methods.iter().map(Method::as_str).collect();
+        response
+            .headers
+            .insert("Access-Control-Allow-Methods", methods.join(", "));
+        if !self.headers.is_empty() {
+            response
+                .headers
+                .insert("Access-Control-Allow-Headers", self.headers.join(", "));
+        }
+        if let Some(max_age) = self.max_age {
+            response
+                .headers
+                .insert("Access-Control-Max-Age", max_age.as_secs().to_string());
+        }
+        response.headers.append(
+            "Vary",
+            "Access-Control-Request-Method, Access-Control-Request-Headers",
+        );
+        response
+    }
+}
+
+impl Default for Cors {
+    fn default() -> Cors {
+        Cors::new()
+    }
+}
+
+impl Middleware for Cors {
+    fn handle(&self, request: Request, next: Next<'_>) -> Response {
+        let mut response = self.answer(request, next);
+        // With a list of origins the answer depends on the `Origin` header,
+        // even when it is missing or refused, so caches must keep the
+        // variants apart.
+        if self.origins.is_some() {
+            response.headers.append("Vary", "Origin");
+        }
+        response
+    }
+}
+
+impl Cors {
+    fn answer(&self, request: Request, next: Next<'_>) -> Response {
+        let Some(origin) = request.headers.get("Origin").map(str::to_string) else {
+            return next.run(request);
+        };
+        let is_preflight = request.method == Method::Options
+            && request.headers.contains("Access-Control-Request-Method");
+
+        if is_preflight {
+            return if self.allows(&origin) {
+                self.preflight(&origin)
+            } else {
+                Response::text(403, "Origin not allowed")
+            };
+        }
+
+        let mut response = next.run(request);
+        if self.allows(&origin) {
+            self.add_origin_headers(&mut response, &origin);
+            if !self.expose_headers.is_empty() {
+                response.headers.insert(
+                    "Access-Control-Expose-Headers",
+                    self.expose_headers.join(", "),
+                );
+            }
+        }
+        response
+    }
+}
+
+/// Requires HTTP Basic authentication (RFC 7617) with one of a fixed set of
+/// users.
+pub struct BasicAuth {
+    realm: String,
+    users: Vec<(String, String)>,
+}
+
+impl BasicAuth {
+    pub fn new(realm: impl Into<String>) -> BasicAuth {
+        BasicAuth {
+            realm: realm.into(),
+            users: Vec::new(),
+        }
+    }
+
+    pub fn user(mut self, name: impl Into<String>, password: impl Into<String>) -> BasicAuth {
+        self.users.push((name.into(), password.into()));
+        self
+    }
+
+    fn is_authorized(&self, request: &Request) -> bool {
+        let Some((name, password)) = request
+            .headers
+            .get("Authorization")
+            .and_then(basic_credentials)
+        else {
+            return false;
+        };
+        // Check every user, so the time taken does not reveal which one
+        // came close.
+        self.users.iter().fold(false, |found, (n, p)| {
+            let matches = constant_time_eq(n.as_bytes(), name.as_bytes())
+                & constant_time_eq(p.as_bytes(), password.as_bytes());
+            found | matches
+        })
+    }
+}
+
+/// Decodes the user name and password from a `Basic` authorization header.
+fn basic_credentials(value: &str) -> Option<(String, String)> {
+    let (scheme, encoded) = value.trim().split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("Basic") {
+        return None;
+    }
+    let decoded = String::from_utf8(STANDARD.decode(encoded.trim()).ok()?).ok()?;
+    let (name, password) = decoded.split_once(':')?;
+    Some((name.to_string(), password.to_string()))
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+impl Middleware for BasicAuth {
+    fn handle(&self, request: Request, next: Next<'_>) -> Response {
+        if self.is_authorized(&request) {
+            return next.run(request);
+        }
+        let realm = self.realm.replace('\\', "\\\\").replace('"', "\\\"");
+        Response::text(401, "Unauthorized").with_header(
+            "WWW-Authenticate",
+            format!("Basic realm=\"{realm}\", charset=\"UTF-8\""),
+        )
+    }
+}
+
+/// Turns a panic in the rest of the chain into `500 Internal Server Error`,
+/// so the client gets an answer and the connection stays usable.
+///
+/// Add it first to cover the other middleware too.
+pub struct CatchPanic;
+
+impl Middleware for CatchPanic {
+    fn handle(&self, request: Request, next: Next<'_>) -> Response {
+        let target = format!("{} {}", request.method, request.path);
+        match panic::catch_unwind(AssertUnwindSafe(|| next.run(request))) {
+            Ok(response) => response,
+            Err(payload) => {
+                let message = payload
+                    .downcast_ref::<&str>()
+                    .copied()
+                    .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+                    .unwrap_or("unknown panic");
+                error!("Handler for {target} panicked: {message}");
+                Response::text(500, "Internal Server Error")
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::request::RequestParser;
+    use std::sync::{Arc, Mutex};
+
+    fn request(method: &str, headers: &str) -> Request {
+        let mut parser = RequestParser::new();
+        parser.feed(format!("{method} / HTTP/1.1\r\nHost: test\r\n{headers}\r\n").as_bytes());
+        parser.parse().unwrap().unwrap()
+    }
+
+    fn ok(_: Request) -> Response {
+        Response::text(200, "ok")
+    }
+
+    #[test]
+    fn runs_middleware_in_order() {
+        let trace = Arc::new(Mutex::new(Vec::new()));
+        let record = |name: &'static str| {
+            let trace = Arc::clone(&trace);
+            move |request: Request, next: Next<'_>| {
+                trace.lock().unwrap().push(format!("{name} in"));
+                let response = next.run(request);
+                trace.lock().unwrap().push(format!("{name} out"));
+                response
+            }
+        };
+        let chain = Chain::new(ok).with(record("a")).with(record("b"));
+
+        assert_eq!(chain.handle(request("GET", "")).status, 200);
+        assert_eq!(*trace.lock().unwrap(), ["a in", "b in", "b out", "a out"]);
+    }
+
+    #[test]
+    fn request_id_is_kept_or_generated() {
+        let chain = Chain::new(|req: Request| {
+            Response::text(200, req.headers.get("X-Request-Id").unwrap().to_string())
+        })
+        .with(RequestId::new());
+
+        let response = chain.handle(request("GET", "X-Request-Id: abc-123\r\n"));
+        assert_eq!(response.headers.get("X-Request-Id"), Some("abc-123"));
+        assert_eq!(response.body.as_bytes(), Some(&b"abc-123"[..]));
+
+        let first = chain.handle(request("GET", "X-Request-Id: bad id!\r\n"));
+        let second = chain.handle(request("GET", ""));
+        let first = first.headers.get("X-Request-Id").unwrap();
+        let second = second.headers.get("X-Request-Id").unwrap();
+        assert!(is_valid_request_id(first) && first != "bad id!");
+        assert_ne!(first, second);
+    }
+
+    #[test]
+    fn timing_adds_server_timing() {
+        let response = Chain::new(ok).with(Timing).handle(request("GET", ""));
+        let value = response.headers.get("Server-Timing").unwrap();
+        assert!(value.starts_with("app;dur="), "{value}");
+    }
+
+    #[test]
+    fn cors_answers_preflight_and_tags_responses() {
+        let chain = Chain::new(ok).with(
+            Cors::new()
+                .allow_origin("https://app.test")
+                .allow_methods([Method::Get, Method::Put])
+                .allow_headers(["Content-Type"])
+                .max_age(Duration::from_secs(600)),
+        );
+
+        let preflight = chain.handle(request(
+            "OPTIONS",
+            "Origin: https://app.test\r\nAccess-Control-Request-Method: PUT\r\n",
+        ));
+        assert_eq!(preflight.status, 204);
+        let headers = &preflight.headers;
+        assert_eq!(
+            headers.get("Access-Control-Allow-Origin"),
+            Some("https://app.test")
+        );
+        assert_eq!(
+            headers.get("Access-Control-Allow-Methods"),
+            Some("GET, PUT")
+        );
+        assert_eq!(
+            headers.get("Access-Control-Allow-Headers"),
+            Some("Content-Type")
+        );
+        assert_eq!(headers.get("Access-Control-Max-Age"), Some("600"));
+
+        let denied = chain.handle(request(
+            "OPTIONS",
+            "Origin: https://evil.test\r\nAccess-Control-Request-Method: PUT\r\n",
+        ));
+        assert_eq!(denied.status, 403);
+
+        let simple = chain.handle(request("GET", "Origin: https://app.test\r\n"));
+        assert_eq!(simple.status, 200);
+        assert_eq!(
+            simple.headers.get("Access-Control-Allow-Origin"),
+            Some("https://app.test")
+        );
+        assert!(simple.headers.has_token("Vary", "Origin"));
+
+        let other = chain.handle(request("GET", "Origin: https://evil.test\r\n"));
+        assert!(!other.headers.contains("Access-Control-Allow-Origin"));
+        assert!(other.headers.has_token("Vary", "Origin"));
+        assert!(denied.headers.has_token("Vary", "Origin"));
+        let none = chain.handle(request("GET", ""));
+        assert!(none.headers.has_token("Vary", "Origin"));
+    }
+
+    #[test]
+    fn cors_credentials_need_listed_origins() {
+        let chain = Chain::new(ok).with(
+            Cors::new()
+                .allow_origin("https://app.test")
+                .allow_credentials(true),
+        );
+        let response = chain.handle(request("GET", "Origin: https://app.test\r\n"));
+        assert_eq!(
+            response.headers.get("Access-Control-Allow-Credentials"),
+            Some("true")
+        );
+
+        let any = Chain::new(ok).with(Cors::new().allow_any_origin());
+        let response = any.handle(request("GET", "Origin: https://evil.test\r\n"));
+        assert_eq!(
+            response.headers.get("Access-Control-Allow-Origin"),
+            Some("*")
+        );
+        assert!(!response.headers.contains("Vary"));
+
+        let builds: [fn() -> Cors; 2] = [
+            || Cors::new().allow_any_origin().allow_credentials(true),
+            || Cors::new().allow_credentials(true).allow_any_origin(),
+        ];
+        for build in builds {
+            assert!(panic::catch_unwind(build).is_err());
+        }
+    }
+
+    #[test]
+    fn basic_auth_checks_credentials() {
+        let chain = Chain::new(ok).with(BasicAuth::new("admin").user("alice", "s3cret"));
+
+        // "alice:s3cret" and "alice:wrong"
+        let allowed = chain.handle(request("GET", "Authorization: Basic YWxpY2U6czNjcmV0\r\n"));
+        assert_eq!(allowed.status, 200);
+
+        for headers in ["", "Authorization: Basic YWxpY2U6d3Jvbmc=\r\n"] {
+            let denied = chain.handle(request("GET", headers));
+            assert_eq!(denied.status, 401);
+            assert_eq!(
+                denied.headers.get("WWW-Authenticate"),
+                Some("Basic realm=\"admin\", charset=\"UTF-8\"")
+            );
+        }
+    }
+
+    #[test]
+    fn catch_panic_answers_500() {
+        let chain = Chain::new(|_: Request| -> Response { panic!("boom") }).with(CatchPanic);
+        assert_eq!(chain.handle(request("GET", "")).status, 500);
+    }
+}
@@ -0,0 +1,1323 @@
+use std::any::Any;
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex, MutexGuard, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+mod shared;
+mod stealing;
+
+use shared::SharedQueue;
+use stealing::StealingQueue;
+
+use crate::metrics::{Histogram, HistogramSnapshot};
+
+/// How long a surplus worker waits for a job before it exits.
+const DEFAULT_KEEP_ALIVE: Duration = Duration::from_secs(60);
+
+pub struct ThreadPool {
+    workers: Mutex<Workers>,
+    spawner: Spawner,
+    queue: Queue,
+    policy: OverflowPolicy,
+    shared: Arc<Shared>,
+}
+
+/// State shared between the pool and its workers.
+struct Shared {
+    panics: AtomicUsize,
+    /// Workers that have been started and have not exited yet.
+    live: AtomicUsize,
+    /// Workers waiting for a job.
+    idle: AtomicUsize,
+    /// Jobs submitted but not started yet.
+    pending: AtomicUsize,
+    /// Workers asked to exit by [`ThreadPool::resize`].
+    retire: AtomicUsize,
+    min: AtomicUsize,
+    max: AtomicUsize,
+    keep_alive: Duration,
+    /// Jobs that have finished, including those that panicked.
+    completed: AtomicU64,
+    rejected: AtomicU64,
+    queue_wait: Histogram,
+    job_duration: Histogram,
+}
+
+impl Shared {
+    /// Live workers that are not about to retire.
+    fn active(&self) -> usize {
+        let live = self.live.load(Ordering::SeqCst);
+        live.saturating_sub(self.retire.load(Ordering::SeqCst))
+    }
+
+    /// Claims one pending retirement for the calling worker.
+    fn try_retire(&self) -> bool {
+        let retired = self
+            .retire
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+            .is_ok();
+        if retired {
+            self.live.fetch_sub(1, Ordering::SeqCst);
+        }
+        retired
+    }
+
+    fn stats(&self) -> PoolStats {
+        let threads = self.live.load(Ordering::SeqCst);
+        let idle = self.idle.load(Ordering::SeqCst);
+        PoolStats {
+            threads,
+            busy: threads.saturating_sub(idle),
+            idle,
+            queued: self.pending.load(Ordering::SeqCst),
+            min_threads: self.min.load(Ordering::SeqCst),
+            max_threads: self.max.load(Ordering::SeqCst),
+            completed_jobs: self.completed.load(Ordering::Relaxed),
+            panicked_jobs: self.panics.load(Ordering::Relaxed) as u64,
+            rejected_jobs: self.rejected.load(Ordering::Relaxed),
+            queue_wait: self.queue_wait.snapshot(),
+            job_duration: self.job_duration.snapshot(),
+        }
+    }
+
+    /// Lets an idle worker exit if the pool stays above its minimum size.
+    fn try_reap(&self) -> bool {
+        let min = self.min.load(Ordering::SeqCst);
+        let retire = self.retire.load(Ordering::SeqCst);
+        self.live
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |live| {
+                (live.saturating_sub(retire) > min).then(|| live - 1)
+            })
+            .is_ok()
+    }
+}
+
+/// Worker threads owned by the pool.
+struct Workers {
+    running: Vec<Worker>,
+    /// Ids of exited workers whose thread terminated abnormally.
+    failed: Vec<usize>,
+    next_id: usize,
+}
+
+impl Workers {
+    /// Joins workers that have already exited.
+    fn prune(&mut self) {
+        let failed = &mut self.failed;
+        self.running.retain_mut(|worker| {
+            let finished = worker.thread.as_ref().is_none_or(|t| t.is_finished());
+            if let Some(thread) = worker.thread.take_if(|_| finished) {
+                if thread.join().is_err() {
+                    failed.push(worker.id);
+                }
+            }
+            !finished
+        });
+    }
+}
+
+/// What a worker needs to start more workers like it.
+struct Spawner {
+    queue: Queue,
+    thread_name: Option<String>,
+    stack_size: Option<usize>,
+}
+
+impl Spawner {
+    fn spawn(&self, id: usize, shared: Arc<Shared>) -> io::Result<Worker> {
+        let mut builder = thread::Builder::new();
+        if let Some(prefix) = &self.thread_name {
+            builder = builder.name(format!("{prefix}-{id}"));
+        }
+        if let Some(bytes) = self.stack_size {
+            builder = builder.stack_size(bytes);
+        }
+        Worker::new(id, self.queue.clone(), shared, builder)
+    }
+}
+
+/// The outcome of [`ThreadPool::shutdown`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ShutdownReport {
+    /// Ids of workers whose thread terminated abnormally.
+    pub failed_workers: Vec<usize>,
+    /// Ids of workers still busy when [`ThreadPool::shutdown_timeout`] gave
+    /// up on them. Their threads are left running, detached.
+    pub unfinished_workers: Vec<usize>,
+    /// Number of jobs that panicked over the lifetime of the pool.
+    pub panicked_jobs: usize,
+}
+
+impl ShutdownReport {
+    /// Returns true if every worker shut down cleanly.
+    pub fn is_clean(&self) -> bool {
+        self.failed_workers.is_empty() && self.unfinished_workers.is_empty()
+    }
+}
+
+/// A snapshot of a pool's activity, from [`ThreadPool::stats`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PoolStats {
+    /// Worker threads alive.
+    pub threads: usize,
+    /// Workers running a job.
+    pub busy: usize,
+    /// Workers waiting for a job.
+    pub idle: usize,
+    /// Jobs submitted but not started yet.
+    pub queued: usize,
+    pub min_threads: usize,
+    pub max_threads: usize,
+    /// Jobs that have finished, including those that panicked.
+    pub completed_jobs: u64,
+    pub panicked_jobs: u64,
+    /// Jobs turned away because the queue was full or the pool shut down.
+    pub rejected_jobs: u64,
+    /// Time between submitting a job and a worker starting it.
+    pub queue_wait: HistogramSnapshot,
+    /// Time jobs took to run.
+    pub job_duration: HistogramSnapshot,
+}
+
+/// Takes [`PoolStats`] snapshots of a pool from anywhere. Cheap to clone.
+#[derive(Clone)]
+pub struct PoolMonitor {
+    shared: Arc<Shared>,
+}
+
+impl PoolMonitor {
+    pub fn stats(&self) -> PoolStats {
+        self.shared.stats()
+    }
+}
+
+/// Records a job's run time when it finishes, even if it panics.
+struct JobTimer<'a> {
+    shared: &'a Shared,
+    started: Instant,
+}
+
+impl Drop for JobTimer<'_> {
+    fn drop(&mut self) {
+        self.shared.job_duration.observe(self.started.elapsed());
+        self.shared.completed.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// How jobs are handed to workers.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Scheduler {
+    /// All workers take jobs from one queue guarded by a mutex.
+    #[default]
+    SharedQueue,
+    /// Each worker has its own deque and steals from the others when it runs
+    /// out of work. Scales better with many threads and small jobs.
+    WorkStealing,
+}
+
+/// What [`ThreadPool::try_execute`] does when a bounded queue is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Wait until a worker takes a job off the queue.
+    Block,
+    /// Give up and return [`ExecuteError::QueueFull`].
+    Reject,
+    /// Run the job on the calling thread instead.
+    CallerRuns,
+}
+
+/// Why a job was not accepted by the pool.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExecuteError {
+    /// The bounded queue is full and the policy is [`OverflowPolicy::Reject`].
+    QueueFull,
+    /// The pool no longer has any worker to receive the job.
+    ShutDown,
+}
+
+impl fmt::Display for ExecuteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExecuteError::QueueFull => f.write_str("thread pool queue is full"),
+            ExecuteError::ShutDown => f.write_str("thread pool has shut down"),
+        }
+    }
+}
+
+impl Error for ExecuteError {}
+
+/// Why a [`JobHandle`] did not produce a value.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum JoinError {
+    /// The job panicked with the given message.
+    Panicked(String),
+    /// The pool refused the job.
+    Rejected(ExecuteError),
+    /// The result will never arrive, for example because it was already
+    /// taken by an earlier call.
+    Cancelled,
+}
+
+impl fmt::Display for JoinError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JoinError::Panicked(message) => write!(f, "job panicked: {message}"),
+            JoinError::Rejected(e) => write!(f, "job rejected: {e}"),
+            JoinError::Cancelled => f.write_str("job result is no longer available"),
+        }
+    }
+}
+
+impl Error for JoinError {}
+
+/// A handle to the result of a job started with [`ThreadPool::spawn`].
+///
+/// Dropping the handle does not cancel the job; its result is discarded.
+#[derive(Debug)]
+pub struct JobHandle<T> {
+    receiver: mpsc::Receiver<Result<T, JoinError>>,
+}
+
+impl<T> JobHandle<T> {
+    /// Blocks until the job has finished and returns its result.
+    pub fn join(self) -> Result<T, JoinError> {
+        self.receiver.recv().unwrap_or(Err(JoinError::Cancelled))
+    }
+
+    /// Returns the result if the job has finished, or `None` if it is still
+    /// queued or running.
+    pub fn try_join(&mut self) -> Option<Result<T, JoinError>> {
+        match self.receiver.try_recv() {
+            Ok(result) => Some(result),
+            Err(mpsc::TryRecvError::Empty) => None,
+            Err(mpsc::TryRecvError::Disconnected) => Some(Err(JoinError::Cancelled)),
+        }
+    }
+
+    /// Waits up to `timeout` for the job to finish. Returns `None` if it is
+    /// still not done; the handle can be joined again later.
+    pub fn join_timeout(&mut self, timeout: Duration) -> Option<Result<T, JoinError>> {
+        match self.receiver.recv_timeout(timeout) {
+            Ok(result) => Some(result),
+            Err(mpsc::RecvTimeoutError::Timeout) => None,
+            Err(mpsc::RecvTimeoutError::Disconnected) => Some(Err(JoinError::Cancelled)),
+        }
+    }
+}
+
+fn panic_message(payload: Box<dyn Any + Send>) -> String {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        s.to_string()
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s.clone()
+    } else {
+        "Box<dyn Any>".to_string()
+    }
+}
+
+/// Why a [`ThreadPool`] could not be created.
+#[derive(Debug)]
+pub enum PoolCreationError {
+    /// The pool was asked to have zero threads.
+    ZeroSize,
+    /// The initial size does not lie between the minimum and maximum
+    /// number of threads.
+    InvalidBounds { min: usize, size: usize, max: usize },
+    /// The operating system refused to start a worker thread.
+    Spawn(io::Error),
+}
+
+impl fmt::Display for PoolCreationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PoolCreationError::ZeroSize => {
+                f.write_str("thread pool size must be greater than zero")
+            }
+            PoolCreationError::InvalidBounds { min, size, max } => write!(
+                f,
+                "thread pool size {size} is not between min {min} and max {max}"
+            ),
+            PoolCreationError::Spawn(e) => write!(f, "failed to spawn worker thread: {e}"),
+        }
+    }
+}
+
+impl Error for PoolCreationError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            PoolCreationError::ZeroSize | PoolCreationError::InvalidBounds { .. } => None,
+            PoolCreationError::Spawn(e) => Some(e),
+        }
+    }
+}
+
+/// The queue jobs wait in, shared by the pool and its workers.
+#[derive(Clone)]
+enum Queue {
+    Shared(Arc<SharedQueue>),
+    Stealing(Arc<StealingQueue>),
+}
+
+/// What a worker got while waiting for a job.
+enum Next {
+    Job(Job),
+    /// Nothing arrived before the timeout.
+    Empty,
+    /// The queue is closed and drained.
+    Closed,
+}
+
+impl Queue {
+    fn push(&self, job: Job, policy: OverflowPolicy) -> Result<(), ExecuteError> {
+        match self {
+            Queue::Shared(queue) => queue.push(job, policy),
+            Queue::Stealing(queue) => queue.push(job, policy),
+        }
+    }
+
+    fn next_job(&self, timeout: Duration) -> Next {
+        match self {
+            Queue::Shared(queue) => queue.next_job(timeout),
+            Queue::Stealing(queue) => queue.next_job(timeout),
+        }
+    }
+
+    fn wake_all(&self) {
+        match self {
+            Queue::Shared(queue) => queue.wake_all(),
+            Queue::Stealing(queue) => queue.wake_all(),
+        }
+    }
+
+    fn close(&self) {
+        match self {
+            Queue::Shared(queue) => queue.close(),
+            Queue::Stealing(queue) => queue.close(),
+        }
+    }
+}
+
+/// Configures a [`ThreadPool`] before it is started.
+///
+/// ```
+/// use std::time::Duration;
+///
+/// use ch30_web_server::{OverflowPolicy, Scheduler, ThreadPool};
+///
+/// let pool = ThreadPool::builder(4)
+///     .thread_name("http-worker")
+///     .stack_size(256 * 1024)
+///     .queue(64, OverflowPolicy::Reject)
+///     .scheduler(Scheduler::WorkStealing)
+///     .max_threads(16)
+///     .keep_alive(Duration::from_secs(30))
+///     .build()
+///     .unwrap();
+/// ```
+#[derive(Debug, Clone)]
+pub struct ThreadPoolBuilder {
+    size: usize,
+    min_threads: Option<usize>,
+    max_threads: Option<usize>,
+    keep_alive: Duration,
+    thread_name: Option<String>,
+    stack_size: Option<usize>,
+    queue: Option<(usize, OverflowPolicy)>,
+    scheduler: Scheduler,
+}
+
+impl ThreadPoolBuilder {
+    /// Starts `size` threads when the pool is built. Unless the bounds are
+    /// changed, the pool keeps exactly that many.
+    pub fn new(size: usize) -> ThreadPoolBuilder {
+        ThreadPoolBuilder {
+            size,
+            min_threads: None,
+            max_threads: None,
+            keep_alive: DEFAULT_KEEP_ALIVE,
+            thread_name: None,
+            stack_size: None,
+            queue: None,
+            scheduler: Scheduler::default(),
+        }
+    }
+
+    /// Lets idle workers exit until only `min` are left. Defaults to the
+    /// initial size.
+    pub fn min_threads(mut self, min: usize) -> ThreadPoolBuilder {
+        self.min_threads = Some(min);
+        self
+    }
+
+    /// Lets the pool start up to `max` workers when jobs arrive faster than
+    /// the idle workers can take them. Defaults to the initial size.
+    pub fn max_threads(mut self, max: usize) -> ThreadPoolBuilder {
+        self.max_threads = Some(max);
+        self
+    }
+
+    /// Sets how long a worker above the minimum waits for a job before it
+    /// exits. Defaults to 60 seconds.
+    pub fn keep_alive(mut self, keep_alive: Duration) -> ThreadPoolBuilder {
+        self.keep_alive = keep_alive;
+        self
+    }
+
+    /// Names worker threads `{prefix}-{id}`.
+    pub fn thread_name(mut self, prefix: impl Into<String>) -> ThreadPoolBuilder {
+        self.thread_name = Some(prefix.into());
+        self
+    }
+
+    /// Sets the stack size of each worker thread in bytes.
+    pub fn stack_size(mut self, bytes: usize) -> ThreadPoolBuilder {
+        self.stack_size = Some(bytes);
+        self
+    }
+
+    /// Bounds the job queue to `capacity` waiting jobs. By default the queue
+    /// is unbounded.
+    pub fn queue(mut self, capacity: usize, policy: OverflowPolicy) -> ThreadPoolBuilder {
+        self.queue = Some((capacity, policy));
+        self
+    }
+
+    /// Chooses how jobs are distributed to workers. The default is
+    /// [`Scheduler::SharedQueue`].
+    pub fn scheduler(mut self, scheduler: Scheduler) -> ThreadPoolBuilder {
+        self.scheduler = scheduler;
+        self
+    }
+
+    pub fn build(self) -> Result<ThreadPool, PoolCreationError> {
+        let min = self.min_threads.unwrap_or(self.size);
+        let max = self.max_threads.unwrap_or(self.size);
+        if self.size == 0 || min == 0 {
+            return Err(PoolCreationError::ZeroSize);
+        }
+        if min > self.size || self.size > max {
+            return Err(PoolCreationError::InvalidBounds {
+                min,
+                size: self.size,
+                max,
+            });
+        }
+
+        let policy = self
+            .queue
+            .map_or(OverflowPolicy::Block, |(_, policy)| policy);
+        let capacity = self.queue.map(|(capacity, _)| capacity);
+        let queue = match self.scheduler {
+            Scheduler::SharedQueue => Queue::Shared(Arc::new(SharedQueue::new(capacity))),
+            Scheduler::WorkStealing => Queue::Stealing(Arc::new(StealingQueue::new(capacity))),
+        };
+        let shared = Arc::new(Shared {
+            panics: AtomicUsize::new(0),
+            live: AtomicUsize::new(0),
+            idle: AtomicUsize::new(0),
+            pending: AtomicUsize::new(0),
+            retire: AtomicUsize::new(0),
+            min: AtomicUsize::new(min),
+            max: AtomicUsize::new(max),
+            keep_alive: self.keep_alive,
+            completed: AtomicU64::new(0),
+            rejected: AtomicU64::new(0),
+            queue_wait: Histogram::latency(),
+            job_duration: Histogram::latency(),
+        });
+
+        // If a spawn fails, dropping `pool` shuts down the workers already
+        // started.
+        let pool = ThreadPool {
+            workers: Mutex::new(Workers {
+                running: Vec::with_capacity(self.size),
+                failed: Vec::new(),
+                next_id: 0,
+            }),
+            spawner: Spawner {
+                queue: queue.clone(),
+                thread_name: self.thread_name,
+                stack_size: self.stack_size,
+            },
+            queue,
+            policy,
+            shared,
+        };
+        {
+            let mut workers = pool.lock_workers();
+            for _ in 0..self.size {
+                pool.spawn_worker(&mut workers)
+                    .map_err(PoolCreationError::Spawn)?;
+            }
+        }
+
+        Ok(pool)
+    }
+}
+
+impl ThreadPool {
+    /// Create a new ThreadPool.
+    ///
+    /// The size is the number of threads in the pool.
+    ///
+    /// # Panics
+    ///
+    /// The `new` function will panic if the size is zero.
+    pub fn new(size: usize) -> ThreadPool {
+        ThreadPool::build(size).expect("failed to create thread pool")
+    }
+
+    /// Create a new ThreadPool, returning an error instead of panicking if
+    /// the size is zero or a thread cannot be spawned.
+    pub fn build(size: usize) -> Result<ThreadPool, PoolCreationError> {
+        ThreadPoolBuilder::new(size).build()
+    }
+
+    /// Returns a [`ThreadPoolBuilder`] for a pool of `size` threads.
+    pub fn builder(size: usize) -> ThreadPoolBuilder {
+        ThreadPoolBuilder::new(size)
+    }
+
+    /// Create a new ThreadPool whose queue holds at most `capacity` waiting
+    /// jobs. `policy` decides what happens to jobs submitted while the queue
+    /// is full.
+    ///
+    /// # Panics
+    ///
+    /// The `with_queue` function will panic if the size is zero.
+    pub fn with_queue(size: usize, capacity: usize, policy: OverflowPolicy) -> ThreadPool {
+        ThreadPoolBuilder::new(size)
+            .queue(capacity, policy)
+            .build()
+            .expect("failed to create thread pool")
+    }
+
+    /// Submit a job to the pool.
+    ///
+    /// # Panics
+    ///
+    /// The `execute` function will panic if the job is rejected, see
+    /// [`try_execute`](Self::try_execute).
+    pub fn execute<F>(&self, f: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        if let Err(e) = self.try_execute(f) {
+            panic!("{e}");
+        }
+    }
+
+    /// Submit a job to the pool, applying the overflow policy if the queue
+    /// is bounded and full.
+    ///
+    /// If no worker is idle and the pool is below its maximum size, a new
+    /// worker is started first.
+    pub fn try_execute<F>(&self, f: F) -> Result<(), ExecuteError>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.submit(f, self.policy)
+    }
+
+    /// Like [`try_execute`](Self::try_execute), but rejects the job when the
+    /// queue is full whatever the overflow policy, so the caller neither
+    /// waits nor runs the job itself.
+    pub(crate) fn try_execute_nonblocking<F>(&self, f: F) -> Result<(), ExecuteError>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.submit(f, OverflowPolicy::Reject)
+    }
+
+    fn submit<F>(&self, f: F, policy: OverflowPolicy) -> Result<(), ExecuteError>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let shared = Arc::clone(&self.shared);
+        let submitted = Instant::now();
+        let job: Job = Box::new(move || {
+            shared.pending.fetch_sub(1, Ordering::SeqCst);
+            shared.queue_wait.observe(submitted.elapsed());
+            let _timer = JobTimer {
+                shared: &shared,
+                started: Instant::now(),
+            };
+            f();
+        });
+        let pending = self.shared.pending.fetch_add(1, Ordering::SeqCst) + 1;
+        if pending > self.shared.idle.load(Ordering::SeqCst)
+            && self.shared.active() < self.shared.max.load(Ordering::SeqCst)
+        {
+            self.grow();
+        }
+
+        let result = self.queue.push(job, policy);
+        if result.is_err() {
+            // The job was dropped without running.
+            self.shared.pending.fetch_sub(1, Ordering::SeqCst);
+            self.shared.rejected.fetch_add(1, Ordering::Relaxed);
+        }
+        result
+    }
+
+    /// Runs `f` on the pool and returns a handle to its result.
+    ///
+    /// If the job panics, the panic is caught and reported through
+    /// [`JobHandle::join`] as [`JoinError::Panicked`]. If the pool does not
+    /// accept the job, the handle reports [`JoinError::Rejected`].
+    pub fn spawn<F, T>(&self, f: F) -> JobHandle<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let (sender, receiver) = mpsc::channel();
+        let rejected = sender.clone();
+        let shared = Arc::clone(&self.shared);
+
+        let result = self.try_execute(move || {
+            let result = panic::catch_unwind(AssertUnwindSafe(f)).map_err(|payload| {
+                shared.panics.fetch_add(1, Ordering::Relaxed);
+                JoinError::Panicked(panic_message(payload))
+            });
+            // The handle may have been dropped; nobody wants the result then.
+            let _ = sender.send(result);
+        });
+        if let Err(e) = result {
+            let _ = rejected.send(Err(JoinError::Rejected(e)));
+        }
+
+        JobHandle { receiver }
+    }
+
+    /// Number of worker threads currently running.
+    pub fn num_threads(&self) -> usize {
+        self.shared.live.load(Ordering::SeqCst)
+    }
+
+    /// Changes the number of workers to `size` at runtime.
+    ///
+    /// New workers start immediately; surplus workers exit once they finish
+    /// their current job. If `size` lies outside the minimum and maximum
+    /// number of threads, the bound is moved to `size`. The pool still grows
+    /// and shrinks within its bounds afterwards.
+    pub fn resize(&self, size: usize) -> Result<(), PoolCreationError> {
+        if size == 0 {
+            return Err(PoolCreationError::ZeroSize);
+        }
+
+        let mut workers = self.lock_workers();
+        self.shared.min.fetch_min(size, Ordering::SeqCst);
+        self.shared.max.fetch_max(size, Ordering::SeqCst);
+
+        let active = self.shared.active();
+        if size > active {
+            for _ in active..size {
+                // Cancelling a retirement is cheaper than a new thread.
+                let cancelled = self
+                    .shared
+                    .retire
+                    .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+                    .is_ok();
+                if !cancelled {
+                    self.spawn_worker(&mut workers)
+                        .map_err(PoolCreationError::Spawn)?;
+                }
+            }
+        } else if size < active {
+            let surplus = active - size;
+            self.shared.retire.fetch_add(surplus, Ordering::SeqCst);
+            // Waiting workers check whether they should retire; busy ones
+            // notice after their current job.
+            self.queue.wake_all();
+        }
+        Ok(())
+    }
+
+    /// Starts one more worker if the pool is below its maximum size.
+    fn grow(&self) {
+        let mut workers = self.lock_workers();
+        let cancelled = self
+            .shared
+            .retire
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+            .is_ok();
+        if cancelled || self.shared.active() >= self.shared.max.load(Ordering::SeqCst) {
+            return;
+        }
+        // The existing workers still run the job, just later.
+        if let Err(e) = self.spawn_worker(&mut workers) {
+            error!("Failed to grow thread pool: {e}");
+        }
+    }
+
+    fn spawn_worker(&self, workers: &mut Workers) -> io::Result<()> {
+        workers.prune();
+        let id = workers.next_id;
+        self.shared.live.fetch_add(1, Ordering::SeqCst);
+        match self.spawner.spawn(id, Arc::clone(&self.shared)) {
+            Ok(worker) => {
+                workers.next_id += 1;
+                workers.running.push(worker);
+                Ok(())
+            }
+            Err(e) => {
+                self.shared.live.fetch_sub(1, Ordering::SeqCst);
+                Err(e)
+            }
+        }
+    }
+
+    fn lock_workers(&self) -> MutexGuard<'_, Workers> {
+        self.workers.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Takes a snapshot of the pool's gauges, counters and histograms.
+    pub fn stats(&self) -> PoolStats {
+        self.shared.stats()
+    }
+
+    /// Returns a handle that can take [`stats`](ThreadPool::stats) after the
+    /// pool has been handed to a [`Server`](crate::Server).
+    pub fn monitor(&self) -> PoolMonitor {
+        PoolMonitor {
+            shared: Arc::clone(&self.shared),
+        }
+    }
+
+    /// Number of jobs that have panicked so far. A panicking job does not
+    /// take its worker down with it.
+    pub fn panic_count(&self) -> usize {
+        self.shared.panics.load(Ordering::Relaxed)
+    }
+
+    /// Stops accepting jobs, waits for the queued ones to finish and joins
+    /// every worker.
+    ///
+    /// Unlike dropping the pool, this returns which workers failed instead of
+    /// only printing them.
+    pub fn shutdown(mut self) -> ShutdownReport {
+        self.shutdown_workers(None)
+    }
+
+    /// Like [`shutdown`](Self::shutdown), but waits at most `timeout` for the
+    /// queued and running jobs to finish.
+    ///
+    /// Workers still busy after the timeout are listed in
+    /// [`ShutdownReport::unfinished_workers`] and left to finish on their own.
+    pub fn shutdown_timeout(mut self, timeout: Duration) -> ShutdownReport {
+        self.shutdown_workers(Some(Instant::now() + timeout))
+    }
+
+    fn shutdown_workers(&mut self, deadline: Option<Instant>) -> ShutdownReport {
+        self.queue.close();
+
+        let workers = self
+            .workers
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner);
+        let mut report = ShutdownReport {
+            failed_workers: mem::take(&mut workers.failed),
+            ..ShutdownReport::default()
+        };
+        for worker in &mut workers.running {
+            if let Some(thread) = worker.thread.take() {
+                debug!("Shutting down worker {}", worker.id);
+
+                if let Some(deadline) = deadline {
+                    // `JoinHandle` has no timed join, so poll until the
+                    // thread is done or we run out of time.
+                    while !thread.is_finished() && Instant::now() < deadline {
+                        let left = deadline.saturating_duration_since(Instant::now());
+                        thread::sleep(left.min(Duration::from_millis(5)));
+                    }
+                    if !thread.is_finished() {
+                        report.unfinished_workers.push(worker.id);
+                        continue;
+                    }
+                }
+
+                if thread.join().is_err() {
+                    report.failed_workers.push(worker.id);
+                }
+            }
+        }
+        report.panicked_jobs = self.panic_count();
+        report
+    }
+}
+
+impl Drop for ThreadPool {
+    fn drop(&mut self) {
+        let report = self.shutdown_workers(None);
+        for id in &report.failed_workers {
+            error!("Worker {id} terminated abnormally");
+        }
+    }
+}
+
+struct Worker {
+    id: usize,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Worker {
+    fn new(
+        id: usize,
+        queue: Queue,
+        shared: Arc<Shared>,
+        builder: thread::Builder,
+    ) -> io::Result<Worker> {
+        let thread = builder.spawn(move || {
+            if let Queue::Stealing(queue) = &queue {
+                queue.attach(id);
+            }
+            let mut idle_since = Instant::now();
+            loop {
+                if shared.try_retire() {
+                    debug!("Worker {} no longer needed; retiring.", id);
+                    break;
+                }
+
+                let waited = idle_since.elapsed();
+                shared.idle.fetch_add(1, Ordering::SeqCst);
+                let message = queue.next_job(shared.keep_alive.saturating_sub(waited));
+                shared.idle.fetch_sub(1, Ordering::SeqCst);
+
+                match message {
+                    Next::Job(job) => {
+                        trace!("Worker {} got a job; executing.", id);
+                        if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(job)) {
+                            shared.panics.fetch_add(1, Ordering::Relaxed);
+                            warn!("Worker {} job panicked; continuing.", id);
+                            // Dropping the payload can panic too; keep that
+                            // from killing the worker.
+                            let _ = panic::catch_unwind(AssertUnwindSafe(|| drop(payload)));
+                        }
+                        idle_since = Instant::now();
+                    }
+                    Next::Empty if idle_since.elapsed() >= shared.keep_alive => {
+                        if shared.try_reap() {
+                            debug!("Worker {} idle too long; retiring.", id);
+                            break;
+                        }
+                        idle_since = Instant::now();
+                    }
+                    Next::Empty => {}
+                    Next::Closed => {
+                        shared.live.fetch_sub(1, Ordering::SeqCst);
+                        debug!("Worker {} disconnected; shutting down.", id);
+                        break;
+                    }
+                }
+            }
+            if let Queue::Stealing(queue) = &queue {
+                queue.detach(id);
+            }
+        })?;
+
+        Ok(Worker {
+            id,
+            thread: Some(thread),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Occupies the single worker of `pool` until the returned sender is
+    /// dropped or written to.
+    fn block_worker(pool: &ThreadPool) -> mpsc::Sender<()> {
+        let (started_tx, started_rx) = mpsc::channel();
+        let (release_tx, release_rx) = mpsc::channel::<()>();
+        pool.execute(move || {
+            started_tx.send(()).unwrap();
+            let _ = release_rx.recv();
+        });
+        started_rx.recv().unwrap();
+        release_tx
+    }
+
+    #[test]
+    fn reject_policy_returns_queue_full() {
+        let pool = ThreadPool::with_queue(1, 1, OverflowPolicy::Reject);
+        let release = block_worker(&pool);
+
+        assert_eq!(pool.try_execute(|| {}), Ok(()));
+        assert_eq!(pool.try_execute(|| {}), Err(ExecuteError::QueueFull));
+
+        drop(release);
+    }
+
+    #[test]
+    fn build_rejects_zero_size() {
+        assert!(matches!(
+            ThreadPool::build(0),
+            Err(PoolCreationError::ZeroSize)
+        ));
+    }
+
+    #[test]
+    fn builder_names_threads() {
+        let pool = ThreadPool::builder(2)
+            .thread_name("test-worker")
+            .stack_size(128 * 1024)
+            .build()
+            .unwrap();
+
+        let (tx, rx) = mpsc::channel();
+        pool.execute(move || {
+            tx.send(thread::current().name().map(str::to_string))
+                .unwrap()
+        });
+        let name = rx.recv().unwrap().unwrap();
+        assert!(name == "test-worker-0" || name == "test-worker-1");
+    }
+
+    #[test]
+    fn panicking_job_keeps_worker_alive() {
+        let pool = ThreadPool::new(1);
+        pool.execute(|| panic!("boom"));
+        pool.execute(|| panic!("boom again"));
+
+        let (tx, rx) = mpsc::channel();
+        pool.execute(move || tx.send(42).unwrap());
+        assert_eq!(rx.recv_timeout(Duration::from_secs(5)), Ok(42));
+        assert_eq!(pool.panic_count(), 2);
+
+        let report = pool.shutdown();
+        assert!(report.is_clean());
+        assert_eq!(report.panicked_jobs, 2);
+    }
+
+    #[test]
+    fn spawn_returns_result() {
+        let pool = ThreadPool::new(2);
+        let handles: Vec<JobHandle<u64>> = (1..=4u64).map(|n| pool.spawn(move || n * n)).collect();
+
+        let results: Vec<u64> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+        assert_eq!(results, [1, 4, 9, 16]);
+    }
+
+    #[test]
+    fn spawn_reports_panics() {
+        let pool = ThreadPool::new(1);
+        let handle = pool.spawn(|| -> u32 { panic!("bad input") });
+
+        assert_eq!(
+            handle.join(),
+            Err(JoinError::Panicked("bad input".to_string()))
+        );
+        assert_eq!(pool.panic_count(), 1);
+    }
+
+    #[test]
+    fn try_join_and_timeout() {
+        let pool = ThreadPool::new(1);
+        let release = block_worker(&pool);
+        let mut handle = pool.spawn(|| "done");
+
+        assert_eq!(handle.try_join(), None);
+        assert_eq!(handle.join_timeout(Duration::from_millis(20)), None);
+
+        drop(release);
+        assert_eq!(
+            handle.join_timeout(Duration::from_secs(5)),
+            Some(Ok("done"))
+        );
+        // The result can only be taken once.
+        assert_eq!(handle.join(), Err(JoinError::Cancelled));
+    }
+
+    #[test]
+    fn spawn_reports_rejection() {
+        let pool = ThreadPool::with_queue(1, 1, OverflowPolicy::Reject);
+        let release = block_worker(&pool);
+        pool.execute(|| {});
+
+        let handle = pool.spawn(|| 1);
+        assert_eq!(
+            handle.join(),
+            Err(JoinError::Rejected(ExecuteError::QueueFull))
+        );
+
+        drop(release);
+    }
+
+    #[test]
+    fn work_stealing_runs_all_jobs() {
+        let pool = Arc::new(
+            ThreadPool::builder(4)
+                .scheduler(Scheduler::WorkStealing)
+                .build()
+                .unwrap(),
+        );
+        let counter = Arc::new(AtomicUsize::new(0));
+
+        // Jobs that submit more jobs exercise the local deques and stealing.
+        let handles: Vec<JobHandle<()>> = (0..50)
+            .map(|_| {
+                let inner_pool = Arc::clone(&pool);
+                let counter = Arc::clone(&counter);
+                pool.spawn(move || {
+                    let nested: Vec<JobHandle<()>> = (0..10)
+                        .map(|_| {
+                            let counter = Arc::clone(&counter);
+                            inner_pool.spawn(move || {
+                                counter.fetch_add(1, Ordering::SeqCst);
+                            })
+                        })
+                        .collect();
+                    drop(nested);
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        // Shutting down waits for every queued job.
+        let pool = Arc::try_unwrap(pool).ok().unwrap();
+        assert!(pool.shutdown().is_clean());
+        assert_eq!(counter.load(Ordering::SeqCst), 500);
+    }
+
+    #[test]
+    fn work_stealing_respects_bounded_queue() {
+        let pool = ThreadPool::builder(1)
+            .scheduler(Scheduler::WorkStealing)
+            .queue(1, OverflowPolicy::Reject)
+            .build()
+            .unwrap();
+        let release = block_worker(&pool);
+
+        assert_eq!(pool.try_execute(|| {}), Ok(()));
+        assert_eq!(pool.try_execute(|| {}), Err(ExecuteError::QueueFull));
+
+        drop(release);
+    }
+
+    #[test]
+    fn caller_runs_policy_runs_inline() {
+        let pool = ThreadPool::with_queue(1, 1, OverflowPolicy::CallerRuns);
+        let release = block_worker(&pool);
+        pool.execute(|| {});
+
+        let caller = thread::current().id();
+        let (tx, rx) = mpsc::channel();
+        pool.execute(move || tx.send(thread::current().id()).unwrap());
+        assert_eq!(rx.recv().unwrap(), caller);
+
+        drop(release);
+    }
+
+    #[test]
+    fn nonblocking_submit_rejects_whatever_the_policy() {
+        for policy in [OverflowPolicy::CallerRuns, OverflowPolicy::Block] {
+            let pool = ThreadPool::with_queue(1, 1, policy);
+            let release = block_worker(&pool);
+            pool.execute(|| {});
+
+            let result = pool.try_execute_nonblocking(|| panic!("ran on the caller"));
+            assert_eq!(result, Err(ExecuteError::QueueFull));
+
+            drop(release);
+        }
+    }
+
+    /// Runs `count` jobs that wait for the returned sender, and waits until
+    /// they have all started. Fails if the pool cannot run them at once.
+    fn block_workers(pool: &ThreadPool, count: usize) -> Arc<Mutex<mpsc::Receiver<()>>> {
+        let (started_tx, started_rx) = mpsc::channel();
+        let (release_tx, release_rx) = mpsc::channel::<()>();
+        let release_rx = Arc::new(Mutex::new(release_rx));
+        for _ in 0..count {
+            let started_tx = started_tx.clone();
+            let release_rx = Arc::clone(&release_rx);
+            pool.execute(move || {
+                started_tx.send(()).unwrap();
+                let _ = release_rx.lock().unwrap().recv();
+            });
+        }
+        for _ in 0..count {
+            started_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        }
+        drop(release_tx);
+        release_rx
+    }
+
+    fn wait_for_threads(pool: &ThreadPool, count: usize) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while pool.num_threads() != count {
+            assert!(Instant::now() < deadline, "{} threads", pool.num_threads());
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn build_rejects_invalid_bounds() {
+        assert!(matches!(
+            ThreadPool::builder(4).max_threads(2).build(),
+            Err(PoolCreationError::InvalidBounds {
+                min: 4,
+                size: 4,
+                max: 2
+            })
+        ));
+        assert!(matches!(
+            ThreadPool::builder(2).min_threads(0).build(),
+            Err(PoolCreationError::ZeroSize)
+        ));
+    }
+
+    #[test]
+    fn grows_under_load_and_reaps_idle_workers() {
+        for scheduler in [Scheduler::SharedQueue, Scheduler::WorkStealing] {
+            let pool = ThreadPool::builder(1)
+                .max_threads(3)
+                .keep_alive(Duration::from_millis(50))
+                .scheduler(scheduler)
+                .build()
+                .unwrap();
+
+            // Three jobs only start together if the pool grew to three.
+            let release = block_workers(&pool, 3);
+            assert_eq!(pool.num_threads(), 3);
+
+            drop(release);
+            wait_for_threads(&pool, 1);
+            assert!(pool.shutdown().is_clean());
+        }
+    }
+
+    #[test]
+    fn resize_adds_and_retires_workers() {
+        for scheduler in [Scheduler::SharedQueue, Scheduler::WorkStealing] {
+            let pool = ThreadPool::builder(2).scheduler(scheduler).build().unwrap();
+
+            pool.resize(4).unwrap();
+            assert_eq!(pool.num_threads(), 4);
+            drop(block_workers(&pool, 4));
+
+            pool.resize(1).unwrap();
+            wait_for_threads(&pool, 1);
+            let (tx, rx) = mpsc::channel();
+            pool.execute(move || tx.send(7).unwrap());
+            assert_eq!(rx.recv_timeout(Duration::from_secs(5)), Ok(7));
+
+            assert!(matches!(pool.resize(0), Err(PoolCreationError::ZeroSize)));
+            assert!(pool.shutdown().is_clean());
+        }
+    }
+
+    #[test]
+    fn shrinking_leaves_the_queue_alone_and_reaps_workers_together() {
+        for scheduler in [Scheduler::SharedQueue, Scheduler::WorkStealing] {
+            let pool = ThreadPool::builder(4)
+                .queue(1, OverflowPolicy::Reject)
+                .scheduler(scheduler)
+                .build()
+                .unwrap();
+            pool.resize(1).unwrap();
+            assert_eq!(pool.try_execute(|| {}), Ok(()));
+            assert!(pool.shutdown().is_clean());
+
+            // Idle workers wait side by side, so they all time out at once
+            // rather than one after another.
+            let keep_alive = Duration::from_millis(300);
+            let pool = ThreadPool::builder(1)
+                .max_threads(5)
+                .keep_alive(keep_alive)
+                .scheduler(scheduler)
+                .build()
+                .unwrap();
+            drop(block_workers(&pool, 5));
+            let idle = Instant::now();
+            wait_for_threads(&pool, 1);
+            assert!(idle.elapsed() < keep_alive * 3, "{:?}", idle.elapsed());
+            assert!(pool.shutdown().is_clean());
+        }
+    }
+
+    #[test]
+    fn stats_track_queue_and_jobs() {
+        let pool = ThreadPool::builder(1)
+            .queue(1, OverflowPolicy::Reject)
+            .build()
+            .unwrap();
+        let (started_tx, started_rx) = mpsc::channel();
+        let (release_tx, release_rx) = mpsc::channel::<()>();
+        pool.execute(move || {
+            started_tx.send(()).unwrap();
+            let _ = release_rx.recv();
+        });
+        started_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        pool.execute(|| ());
+        assert!(pool.try_execute(|| ()).is_err());
+
+        let stats = pool.stats();
+        assert_eq!(
+            (stats.threads, stats.busy, stats.idle, stats.queued),
+            (1, 1, 0, 1)
+        );
+        assert_eq!(stats.rejected_jobs, 1);
+        assert_eq!(stats.completed_jobs, 0);
+
+        drop(release_tx);
+        let monitor = pool.monitor();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while monitor.stats().completed_jobs < 2 {
+            assert!(Instant::now() < deadline);
+            thread::sleep(Duration::from_millis(10));
+        }
+        let stats = monitor.stats();
+        assert_eq!(stats.queued, 0);
+        assert_eq!(stats.job_duration.count, 2);
+        assert_eq!(stats.queue_wait.count, 2);
+    }
+
+    #[test]
+    fn shutdown_timeout_reports_unfinished_workers() {
+        let pool = ThreadPool::new(2);
+        let release = block_worker(&pool);
+        pool.execute(|| {});
+
+        let start = Instant::now();
+        let report = pool.shutdown_timeout(Duration::from_millis(50));
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert_eq!(report.unfinished_workers.len(), 1);
+        assert!(!report.is_clean());
+
+        drop(release);
+    }
+
+    #[test]
+    fn block_policy_waits_for_room() {
+        let pool = Arc::new(ThreadPool::with_queue(1, 1, OverflowPolicy::Block));
+        let release = block_worker(&pool);
+        pool.execute(|| {});
+
+        let (done_tx, done_rx) = mpsc::channel();
+        let submitter = {
+            let pool = Arc::clone(&pool);
+            thread::spawn(move || {
+                pool.execute(|| {});
+                done_tx.send(()).unwrap();
+            })
+        };
+        assert!(done_rx.recv_timeout(Duration::from_millis(100)).is_err());
+
+        drop(release);
+        done_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        submitter.join().unwrap();
+    }
+}
@@ -0,0 +1,125 @@
+//! The single queue behind [`Scheduler::SharedQueue`].
+//!
+//! Jobs wait in one deque guarded by a mutex. Workers wait on a condition
+//! variable, which releases the mutex, so any number of them can wait at once
+//! and each one's timeout runs from when it started waiting.
+//!
+//! [`Scheduler::SharedQueue`]: super::Scheduler::SharedQueue
+
+use std::collections::VecDeque;
+use std::sync::{Condvar, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+
+use super::{ExecuteError, Job, Next, OverflowPolicy};
+
+pub(super) struct SharedQueue {
+    state: Mutex<State>,
+    capacity: Option<usize>,
+    work_available: Condvar,
+    space_available: Condvar,
+}
+
+struct State {
+    jobs: VecDeque<Job>,
+    closed: bool,
+    /// Bumped by [`wake_all`](SharedQueue::wake_all) so that waiting workers
+    /// return even though no job arrived.
+    wakeups: usize,
+}
+
+impl SharedQueue {
+    pub(super) fn new(capacity: Option<usize>) -> SharedQueue {
+        SharedQueue {
+            state: Mutex::new(State {
+                jobs: VecDeque::new(),
+                closed: false,
+                wakeups: 0,
+            }),
+            capacity,
+            work_available: Condvar::new(),
+            space_available: Condvar::new(),
+        }
+    }
+
+    // No job runs under the lock, so a poisoned mutex still guards a
+    // perfectly usable queue.
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub(super) fn push(&self, job: Job, policy: OverflowPolicy) -> Result<(), ExecuteError> {
+        let mut state = self.lock();
+        loop {
+            if state.closed {
+                return Err(ExecuteError::ShutDown);
+            }
+            if self
+                .capacity
+                .is_none_or(|capacity| state.jobs.len() < capacity)
+            {
+                break;
+            }
+            match policy {
+                OverflowPolicy::Reject => return Err(ExecuteError::QueueFull),
+                OverflowPolicy::CallerRuns => {
+                    drop(state);
+                    job();
+                    return Ok(());
+                }
+                OverflowPolicy::Block => {
+                    state = self
+                        .space_available
+                        .wait(state)
+                        .unwrap_or_else(PoisonError::into_inner);
+                }
+            }
+        }
+        state.jobs.push_back(job);
+        self.work_available.notify_one();
+        Ok(())
+    }
+
+    /// Waits up to `timeout` for a job. Returns [`Next::Empty`] if none
+    /// arrived in time or the workers were woken by
+    /// [`wake_all`](Self::wake_all), and [`Next::Closed`] once the queue is
+    /// closed and every job has been taken.
+    pub(super) fn next_job(&self, timeout: Duration) -> Next {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.lock();
+        let wakeups = state.wakeups;
+        loop {
+            if let Some(job) = state.jobs.pop_front() {
+                self.space_available.notify_one();
+                return Next::Job(job);
+            }
+            if state.closed {
+                return Next::Closed;
+            }
+            let left = deadline.saturating_duration_since(Instant::now());
+            if state.wakeups != wakeups || left.is_zero() {
+                return Next::Empty;
+            }
+            state = self
+                .work_available
+                .wait_timeout(state, left)
+                .unwrap_or_else(PoisonError::into_inner)
+                .0;
+        }
+    }
+
+    /// Wakes every waiting worker, for example so that surplus workers
+    /// notice they should retire.
+    pub(super) fn wake_all(&self) {
+        let mut state = self.lock();
+        state.wakeups = state.wakeups.wrapping_add(1);
+        self.work_available.notify_all();
+    }
+
+    /// Refuses new jobs and wakes every waiting thread. Jobs already queued
+    /// still run.
+    pub(super) fn close(&self) {
+        self.lock().closed = true;
+        self.work_available.notify_all();
+        self.space_available.notify_all();
+    }
+}
@@ -0,0 +1,283 @@
+//! The work-stealing scheduler behind [`Scheduler::WorkStealing`].
+//!
+//! Jobs submitted from outside the pool go to a global injector queue. Each
+//! worker owns a local deque that it refills from the injector in batches,
+//! and jobs submitted from inside a running job go straight to the local
+//! deque of that worker. A worker that runs out of work steals from the
+//! others, so no single lock is held while waiting for jobs.
+//!
+//! [`Scheduler::WorkStealing`]: super::Scheduler::WorkStealing
+
+use std::cell::RefCell;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Condvar, Mutex, MutexGuard, PoisonError, RwLock};
+use std::thread;
+use std::time::Duration;
+
+use crossbeam_deque::{Injector, Steal, Stealer, Worker as Deque};
+
+use super::{ExecuteError, Job, Next, OverflowPolicy};
+
+thread_local! {
+    // The local deque of the current worker thread, tagged with the address
+    // of the queue it belongs to.
+    static LOCAL: RefCell<Option<(usize, Deque<Job>)>> = const { RefCell::new(None) };
+}
+
+const SPIN_ROUNDS: usize = 16;
+
+pub(super) struct StealingQueue {
+    injector: Injector<Job>,
+    stealers: RwLock<Vec<(usize, Stealer<Job>)>>,
+    /// Jobs pushed but not yet taken by a worker.
+    queued: AtomicUsize,
+    capacity: Option<usize>,
+    closed: AtomicBool,
+    /// Workers waiting for jobs and submitters waiting for space. Only when
+    /// these are non-zero do we need the lock to wake someone up.
+    sleeping_workers: AtomicUsize,
+    blocked_submitters: AtomicUsize,
+    /// Bumped by [`wake_all`](Self::wake_all) so that sleeping workers
+    /// return even though no job arrived.
+    wakeups: AtomicUsize,
+    sleep: Mutex<()>,
+    work_available: Condvar,
+    space_available: Condvar,
+}
+
+impl StealingQueue {
+    pub(super) fn new(capacity: Option<usize>) -> StealingQueue {
+        StealingQueue {
+            injector: Injector::new(),
+            stealers: RwLock::new(Vec::new()),
+            queued: AtomicUsize::new(0),
+            capacity,
+            closed: AtomicBool::new(false),
+            sleeping_workers: AtomicUsize::new(0),
+            blocked_submitters: AtomicUsize::new(0),
+            wakeups: AtomicUsize::new(0),
+            sleep: Mutex::new(()),
+            work_available: Condvar::new(),
+            space_available: Condvar::new(),
+        }
+    }
+
+    fn key(&self) -> usize {
+        self as *const StealingQueue as usize
+    }
+
+    fn lock(&self) -> MutexGuard<'_, ()> {
+        self.sleep.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Gives the calling worker thread a local deque.
+    pub(super) fn attach(&self, id: usize) {
+        let deque = Deque::new_fifo();
+        self.stealers
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push((id, deque.stealer()));
+        LOCAL.with(|local| *local.borrow_mut() = Some((self.key(), deque)));
+    }
+
+    /// Removes the calling worker's deque, handing any jobs left in it back
+    /// to the other workers.
+    pub(super) fn detach(&self, id: usize) {
+        self.stealers
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .retain(|(worker, _)| *worker != id);
+        if let Some((_, deque)) = LOCAL.with(|local| local.borrow_mut().take()) {
+            let mut moved = false;
+            while let Some(job) = deque.pop() {
+                self.injector.push(job);
+                moved = true;
+            }
+            if moved {
+                let _guard = self.lock();
+                self.work_available.notify_all();
+            }
+        }
+    }
+
+    pub(super) fn push(&self, job: Job, policy: OverflowPolicy) -> Result<(), ExecuteError> {
+        if self.closed.load(Ordering::SeqCst) {
+            return Err(ExecuteError::ShutDown);
+        }
+
+        match self.capacity {
+            None => {
+                self.queued.fetch_add(1, Ordering::SeqCst);
+            }
+            Some(capacity) => {
+                let reserved = self
+                    .queued
+                    .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| {
+                        (n < capacity).then_some(n + 1)
+                    })
+                    .is_ok();
+                if !reserved {
+                    match policy {
+                        OverflowPolicy::Reject => return Err(ExecuteError::QueueFull),
+                        OverflowPolicy::CallerRuns => {
+                            job();
+                            return Ok(());
+                        }
+                        OverflowPolicy::Block => self.wait_for_space(capacity)?,
+                    }
+                }
+            }
+        }
+
+        // Jobs spawned by a job of this pool stay on the same worker.
+        let job = LOCAL.with(|local| match local.borrow().as_ref() {
+            Some((key, deque)) if *key == self.key() => {
+                deque.push(job);
+                None
+            }
+            _ => Some(job),
+        });
+        if let Some(job) = job {
+            self.injector.push(job);
+        }
+
+        // `queued` was raised before this check and a worker raises
+        // `sleeping_workers` before checking `queued`, so one of us sees the
+        // other and no wakeup is lost.
+        if self.sleeping_workers.load(Ordering::SeqCst) > 0 {
+            let _guard = self.lock();
+            self.work_available.notify_one();
+        }
+        Ok(())
+    }
+
+    fn wait_for_space(&self, capacity: usize) -> Result<(), ExecuteError> {
+        let mut guard = self.lock();
+        self.blocked_submitters.fetch_add(1, Ordering::SeqCst);
+        let result = loop {
+            if self.closed.load(Ordering::SeqCst) {
+                break Err(ExecuteError::ShutDown);
+            }
+            let reserved = self
+                .queued
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| {
+                    (n < capacity).then_some(n + 1)
+                })
+                .is_ok();
+            if reserved {
+                break Ok(());
+            }
+            guard = self
+                .space_available
+                .wait(guard)
+                .unwrap_or_else(PoisonError::into_inner);
+        };
+        self.blocked_submitters.fetch_sub(1, Ordering::SeqCst);
+        result
+    }
+
+    /// Takes a job from the local deque, the injector or another worker.
+    fn find_job(&self) -> Option<Job> {
+        let job = LOCAL.with(|local| {
+            let local = local.borrow();
+            match local.as_ref() {
+                Some((key, deque)) if *key == self.key() => {
+                    deque.pop().or_else(|| self.steal(Some(deque)))
+                }
+                _ => self.steal(None),
+            }
+        })?;
+
+        self.queued.fetch_sub(1, Ordering::SeqCst);
+        if self.blocked_submitters.load(Ordering::SeqCst) > 0 {
+            let _guard = self.lock();
+            self.space_available.notify_one();
+        }
+        Some(job)
+    }
+
+    fn steal(&self, deque: Option<&Deque<Job>>) -> Option<Job> {
+        loop {
+            let from_injector = match deque {
+                Some(deque) => self.injector.steal_batch_and_pop(deque),
+                None => self.injector.steal(),
+            };
+            let steal = from_injector.or_else(|| {
+                self.stealers
+                    .read()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .iter()
+                    .map(|(_, stealer)| stealer.steal())
+                    .collect::<Steal<Job>>()
+            });
+            match steal {
+                Steal::Success(job) => return Some(job),
+                Steal::Empty => return None,
+                Steal::Retry => continue,
+            }
+        }
+    }
+
+    /// Waits up to `timeout` for a job. Returns [`Next::Empty`] if none
+    /// arrived in time or the workers were woken by
+    /// [`wake_all`](Self::wake_all), and [`Next::Closed`] once the queue is
+    /// closed and every job has been taken.
+    pub(super) fn next_job(&self, timeout: Duration) -> Next {
+        let wakeups = self.wakeups.load(Ordering::SeqCst);
+        loop {
+            // Going to sleep and being woken costs two system calls, so look
+            // around a few more times first.
+            for _ in 0..SPIN_ROUNDS {
+                if let Some(job) = self.find_job() {
+                    return Next::Job(job);
+                }
+                thread::yield_now();
+            }
+
+            let guard = self.lock();
+            if self.wakeups.load(Ordering::SeqCst) != wakeups {
+                return Next::Empty;
+            }
+            self.sleeping_workers.fetch_add(1, Ordering::SeqCst);
+            let busy = self.queued.load(Ordering::SeqCst) > 0;
+            if busy || self.closed.load(Ordering::SeqCst) {
+                self.sleeping_workers.fetch_sub(1, Ordering::SeqCst);
+                drop(guard);
+                if !busy {
+                    return Next::Closed;
+                }
+                // A submitter has reserved a slot but not pushed yet, or the
+                // job sits in a deque we lost a race for.
+                thread::yield_now();
+                continue;
+            }
+            drop(
+                self.work_available
+                    .wait_timeout(guard, timeout)
+                    .unwrap_or_else(PoisonError::into_inner),
+            );
+            self.sleeping_workers.fetch_sub(1, Ordering::SeqCst);
+            return match self.find_job() {
+                Some(job) => Next::Job(job),
+                None => Next::Empty,
+            };
+        }
+    }
+
+    /// Wakes every sleeping worker, for example so that surplus workers
+    /// notice they should retire.
+    pub(super) fn wake_all(&self) {
+        let _guard = self.lock();
+        self.wakeups.fetch_add(1, Ordering::SeqCst);
+        self.work_available.notify_all();
+    }
+
+    /// Refuses new jobs and wakes every waiting thread. Jobs already queued
+    /// still run.
+    pub(super) fn close(&self) {
+        let _guard = self.lock();
+        self.closed.store(true, Ordering::SeqCst);
+        self.work_available.notify_all();
+        self.space_available.notify_all();
+    }
+}
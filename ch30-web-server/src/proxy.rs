@@ -0,0 +1,586 @@
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Weak};
+use std::thread;
+use std::time::Duration;
+
+use crate::headers::Headers;
+use crate::request::{parse_headers, Method, Request};
+use crate::response::{Body, Response};
+use crate::router::Handler;
+
+/// Header fields that describe a single connection rather than the message,
+/// and so are never forwarded (RFC 9110 section 7.6.1).
+const HOP_BY_HOP: &[&str] = &[
+    "Connection",
+    "Keep-Alive",
+    "Proxy-Connection",
+    "TE",
+    "Trailer",
+    "Transfer-Encoding",
+    "Upgrade",
+];
+
+/// The largest upstream status line and header block accepted.
+const MAX_HEAD_SIZE: usize = 64 * 1024;
+
+/// Forwards requests to upstream HTTP/1.1 servers, taking turns between
+/// them.
+///
+/// Each request goes to the next healthy upstream on a new connection. An
+/// upstream that refuses the connection is skipped in favour of the next
+/// one. If no upstream can be reached the client gets `502 Bad Gateway`, or
+/// `504 Gateway Timeout` if the last one did not answer in time.
+///
+/// ```no_run
+/// use std::time::Duration;
+/// use ch30_web_server::{Proxy, Router};
+///
+/// let api = Proxy::new(["127.0.0.1:3001", "127.0.0.1:3002"])
+///     .health_check("/health", Duration::from_secs(5));
+/// let router = Router::new().get("/api/*rest", api);
+/// ```
+#[derive(Clone)]
+pub struct Proxy {
+    shared: Arc<Upstreams>,
+    connect_timeout: Duration,
+    timeout: Duration,
+    health_checks: bool,
+}
+
+struct Upstreams {
+    list: Vec<Upstream>,
+    next: AtomicUsize,
+}
+
+struct Upstream {
+    /// `host:port`, resolved on every connection.
+    addr: String,
+    healthy: AtomicBool,
+}
+
+impl Upstream {
+    fn set_healthy(&self, healthy: bool) {
+        if self.healthy.swap(healthy, Ordering::Relaxed) != healthy {
+            if healthy {
+                info!("Upstream {} is healthy again", self.addr);
+            } else {
+                warn!("Upstream {} is down", self.addr);
+            }
+        }
+    }
+}
+
+/// Why forwarding to one upstream failed.
+enum Failure {
+    /// Nothing was sent, so another upstream can be tried.
+    Connect(io::Error),
+    /// The request was sent, so trying again could repeat its effects.
+    Exchange(io::Error),
+}
+
+impl Proxy {
+    /// Creates a proxy for the upstreams at the given `host:port`
+    /// addresses.
+    pub fn new<I>(upstreams: I) -> Proxy
+    where
+        I: IntoIterator,
+        I::Item: Into<String>,
+    {
+        let list = upstreams
+            .into_iter()
+            .map(|addr| Upstream {
+                addr: addr.into(),
+                healthy: AtomicBool::new(true),
+            })
+            .collect();
+        Proxy {
+            shared: Arc::new(Upstreams {
+                list,
+                next: AtomicUsize::new(0),
+            }),
+            connect_timeout: Duration::from_secs(2),
+            timeout: Duration::from_secs(30),
+            health_checks: false,
+        }
+    }
+
+    /// Sets how long connecting to an upstream may take. Defaults to 2
+    /// seconds.
+    pub fn connect_timeout(mut self, timeout: Duration) -> Proxy {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    /// Sets how long an upstream may take to start its response, and the
+    /// longest pause allowed while sending it. Defaults to 30 seconds.
+    pub fn timeout(mut self, timeout: Duration) -> Proxy {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Checks every upstream with `GET path` each `interval` on a
+    /// background thread, and sends requests only to those answering with
+    /// a 2xx or 3xx status.
+    ///
+    /// An upstream that refuses a forwarded request's connection is also
+    /// taken out of rotation until its next successful check. Without
+    /// health checks every upstream is always tried.
+    pub fn health_check(mut self, path: &str, interval: Duration) -> Proxy {
+        let shared = Arc::downgrade(&self.shared);
+        let path = path.to_string();
+        let timeout = self.connect_timeout;
+        let spawned = thread::Builder::new()
+            .name("proxy-health".to_string())
+            .spawn(move || run_health_checks(shared, &path, interval, timeout));
+        match spawned {
+            Ok(_) => self.health_checks = true,
+            Err(e) => error!("Failed to start proxy health checks: {e}"),
+        }
+        self
+    }
+
+    fn forward(&self, upstream: &Upstream, request: &Request) -> Result<Response, Failure> {
+        let stream = connect(&upstream.addr, self.connect_timeout).map_err(Failure::Connect)?;
+        let exchange = || {
+            stream.set_read_timeout(Some(self.timeout))?;
+            stream.set_write_timeout(Some(self.timeout))?;
+            write_request(&stream, request, &upstream.addr)?;
+            read_response(stream, request.method)
+        };
+        exchange().map_err(Failure::Exchange)
+    }
+}
+
+impl Handler for Proxy {
+    fn handle(&self, request: Request) -> Response {
+        let list = &self.shared.list;
+        let start = self.shared.next.fetch_add(1, Ordering::Relaxed);
+        let mut last_error = None;
+        for i in 0..list.len() {
+            let upstream = &list[(start + i) % list.len()];
+            if !upstream.healthy.load(Ordering::Relaxed) {
+                continue;
+            }
+            match self.forward(upstream, &request) {
+                Ok(response) => return response,
+                Err(Failure::Connect(e)) => {
+                    warn!("Cannot connect to upstream {}: {e}", upstream.addr);
+                    if self.health_checks {
+                        upstream.set_healthy(false);
+                    }
+                    last_error = Some(e);
+                }
+                Err(Failure::Exchange(e)) => {
+                    warn!("Upstream {} failed: {e}", upstream.addr);
+                    last_error = Some(e);
+                    break;
+                }
+            }
+        }
+        match last_error {
+            Some(e) if is_timeout(&e) => Response::text(504, "Gateway Timeout"),
+            Some(_) => Response::text(502, "Bad Gateway"),
+            None => Response::text(502, "No upstream available"),
+        }
+    }
+}
+
+fn connect(addr: &str, timeout: Duration) -> io::Result<TcpStream> {
+    let mut last_error = None;
+    for addr in addr.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, timeout) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_error = Some(e),
+        }
+    }
+    Err(last_error
+        .unwrap_or_else(|| io::Error::new(io::ErrorKind::NotFound, "address resolved to nothing")))
+}
+
+/// Sends `request` upstream with its hop-by-hop headers removed and the
+/// forwarding headers set.
+fn write_request(stream: &TcpStream, request: &Request, upstream: &str) -> io::Result<()> {
+    let mut head = format!("{} {}", request.method.as_str(), request.path);
+    if let Some(query) = &request.query {
+        head.push('?');
+        head.push_str(query);
+    }
+    head.push_str(" HTTP/1.1\r\n");
+
+    let mut headers = end_to_end(&request.headers);
+    headers.remove("Content-Length");
+    headers.insert("Host", upstream);
+    if let Some(host) = request.headers.get("Host") {
+        headers.insert("X-Forwarded-Host", host);
+    }
+    if let Some(client) = request.remote_addr {
+        let forwarded_for = match request.headers.get("X-Forwarded-For") {
+            Some(previous) => format!("{previous}, {}", client.ip()),
+            None => client.ip().to_string(),
+        };
+        headers.insert("X-Forwarded-For", forwarded_for);
+    }
+    let has_body = !request.body.is_empty()
+        || request.headers.contains("Content-Length")
+        || request.headers.contains("Transfer-Encoding");
+    if has_body {
+        headers.insert("Content-Length", request.body.len().to_string());
+    }
+    headers.insert("Connection", "close");
+    head.push_str(&headers.to_string());
+    head.push_str("\r\n");
+
+    let mut writer = BufWriter::new(stream);
+    writer.write_all(head.as_bytes())?;
+    writer.write_all(&request.body)?;
+    writer.flush()
+}
+
+/// Reads the upstream's status line and headers, and returns a response
+/// that streams the body from the upstream connection.
+fn read_response(stream: TcpStream, method: Method) -> io::Result<Response> {
+    let mut reader = BufReader::new(stream);
+    // Interim 1xx responses are not passed on.
+    let (status, headers) = loop {
+        let (status, headers) = read_head(&mut reader)?;
+        if !(100..=199).contains(&status) {
+            break (status, headers);
+        }
+    };
+
+    let mut response = Response::new(status);
+    response.headers = end_to_end(&headers);
+    if method == Method::Head || matches!(status, 204 | 304) {
+        return Ok(response);
+    }
+    response.headers.remove("Content-Length");
+    let chunked = headers.has_token("Transfer-Encoding", "chunked");
+    response.body = if chunked {
+        Body::reader(ChunkedReader::new(reader), None)
+    } else if let Some(len) = headers.get("Content-Length") {
+        let len = len.parse().map_err(|_| invalid("invalid Content-Length"))?;
+        Body::reader(reader, Some(len))
+    } else {
+        Body::reader(reader, None)
+    };
+    Ok(response)
+}
+
+fn read_head<R: BufRead>(reader: &mut R) -> io::Result<(u16, Headers)> {
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") && !head.ends_with(b"\n\n") {
+        let limit = (MAX_HEAD_SIZE - head.len()) as u64;
+        if limit == 0 {
+            return Err(invalid("response head too large"));
+        }
+        if reader.by_ref().take(limit).read_until(b'\n', &mut head)? == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "upstream closed before responding",
+            ));
+        }
+    }
+    let head = std::str::from_utf8(&head).map_err(|_| invalid("response head is not UTF-8"))?;
+    let mut lines = head.lines();
+    let status = lines
+        .next()
+        .and_then(|line| line.strip_prefix("HTTP/1."))
+        .and_then(|rest| rest.get(2..5))
+        .and_then(|code| code.parse().ok())
+        .ok_or_else(|| invalid("malformed status line"))?;
+    let headers = parse_headers(lines).map_err(|_| invalid("malformed response headers"))?;
+    Ok((status, headers))
+}
+
+/// Copies `headers` without the hop-by-hop fields, including those the
+/// `Connection` header names.
+fn end_to_end(headers: &Headers) -> Headers {
+    let listed: Vec<&str> = headers
+        .get_all("Connection")
+        .flat_map(|v| v.split(','))
+        .map(str::trim)
+        .collect();
+    let mut kept = Headers::new();
+    for (name, value) in headers.iter() {
+        let hop_by_hop = HOP_BY_HOP
+            .iter()
+            .chain(&listed)
+            .any(|h| h.eq_ignore_ascii_case(name));
+        if !hop_by_hop {
+            kept.append(name, value);
+        }
+    }
+    kept
+}
+
+/// Removes the chunked transfer coding from an upstream body.
+struct ChunkedReader<R> {
+    inner: R,
+    /// Bytes left in the current chunk.
+    remaining: u64,
+    /// Whether the CRLF after a chunk's data is still to be read.
+    chunk_end: bool,
+    done: bool,
+}
+
+impl<R: BufRead> ChunkedReader<R> {
+    fn new(inner: R) -> ChunkedReader<R> {
+        ChunkedReader {
+            inner,
+            remaining: 0,
+            chunk_end: false,
+            done: false,
+        }
+    }
+
+    fn read_line(&mut self) -> io::Result<String> {
+        let mut line = Vec::new();
+        (&mut self.inner).take(4096).read_until(b'\n', &mut line)?;
+        if !line.ends_with(b"\n") {
+            return Err(invalid("malformed chunk"));
+        }
+        String::from_utf8(line).map_err(|_| invalid("malformed chunk"))
+    }
+}
+
+impl<R: BufRead> Read for ChunkedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.done || buf.is_empty() {
+            return Ok(0);
+        }
+        if self.remaining == 0 {
+            if self.chunk_end && !self.read_line()?.trim().is_empty() {
+                return Err(invalid("missing CRLF after chunk data"));
+            }
+            let line = self.read_line()?;
+            let size = line.split(';').next().unwrap_or("").trim();
+            self.remaining =
+                u64::from_str_radix(size, 16).map_err(|_| invalid("invalid chunk size"))?;
+            if self.remaining == 0 {
+                // Trailer fields are dropped.
+                while !self.read_line()?.trim().is_empty() {}
+                self.done = true;
+                return Ok(0);
+            }
+        }
+
+        let max = buf
+            .len()
+            .min(usize::try_from(self.remaining).unwrap_or(usize::MAX));
+        let n = self.inner.read(&mut buf[..max])?;
+        if n == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "upstream closed in the middle of a chunk",
+            ));
+        }
+        self.remaining -= n as u64;
+        self.chunk_end = self.remaining == 0;
+        Ok(n)
+    }
+}
+
+fn run_health_checks(shared: Weak<Upstreams>, path: &str, interval: Duration, timeout: Duration) {
+    // Stops once the proxy has been dropped.
+    while let Some(upstreams) = shared.upgrade() {
+        for upstream in &upstreams.list {
+            let healthy = check(&upstream.addr, path, timeout).unwrap_or(false);
+            upstream.set_healthy(healthy);
+        }
+        drop(upstreams);
+        thread::sleep(interval);
+    }
+}
+
+fn check(addr: &str, path: &str, timeout: Duration) -> io::Result<bool> {
+    let mut stream = connect(addr, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    write!(
+        stream,
+        "GET {path} HTTP/1.1\r\nHost: {addr}\r\nConnection: close\r\n\r\n"
+    )?;
+    let (status, _) = read_head(&mut BufReader::new(stream))?;
+    Ok((200..=399).contains(&status))
+}
+
+fn invalid(message: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn is_timeout(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::request::RequestParser;
+    use std::net::{SocketAddr, TcpListener};
+    use std::time::Instant;
+
+    /// Starts a stand-in upstream that answers every connection with the
+    /// raw response `respond` builds from the request.
+    fn backend(respond: impl Fn(Request) -> String + Send + 'static) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                if let Ok(Some(request)) = Request::read_from(&mut stream) {
+                    let _ = stream.write_all(respond(request).as_bytes());
+                }
+            }
+        });
+        addr
+    }
+
+    /// An address nothing listens on.
+    fn closed_port() -> SocketAddr {
+        TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+    }
+
+    fn request(head: &str) -> Request {
+        let mut parser = RequestParser::new();
+        parser.feed(head.as_bytes());
+        let mut request = parser.parse().unwrap().unwrap();
+        request.remote_addr = Some("10.1.2.3:5555".parse().unwrap());
+        request
+    }
+
+    fn get(path: &str) -> Request {
+        request(&format!("GET {path} HTTP/1.1\r\nHost: site.test\r\n\r\n"))
+    }
+
+    fn body_text(response: &mut Response) -> String {
+        let mut out = String::new();
+        match &mut response.body {
+            Body::Reader { reader, .. } => reader.read_to_string(&mut out).unwrap(),
+            other => panic!("unexpected body {other:?}"),
+        };
+        out
+    }
+
+    #[test]
+    fn forwards_request_and_rewrites_headers() {
+        let upstream = backend(|req| {
+            let body = format!(
+                "{} {}?{} host={} xff={} xfh={} conn={} body={}",
+                req.method.as_str(),
+                req.path,
+                req.query.unwrap_or_default(),
+                req.headers.get("Host").unwrap(),
+                req.headers.get("X-Forwarded-For").unwrap(),
+                req.headers.get("X-Forwarded-Host").unwrap(),
+                req.headers.get("Connection").unwrap(),
+                String::from_utf8(req.body).unwrap(),
+            );
+            // A chunked response with a hop-by-hop header to strip.
+            format!(
+                "HTTP/1.1 201 Created\r\nTransfer-Encoding: chunked\r\nKeep-Alive: timeout=5\r\n\
+                 X-Backend: yes\r\n\r\n{:x};ext=1\r\n{body}\r\n0\r\nX-Trailer: t\r\n\r\n",
+                body.len()
+            )
+        });
+
+        let mut response = Proxy::new([upstream.to_string()]).handle(request(
+            "POST /items?id=7 HTTP/1.1\r\nHost: site.test\r\nX-Forwarded-For: 192.0.2.1\r\n\
+             Connection: keep-alive, X-Private\r\nX-Private: secret\r\n\
+             Transfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n0\r\n\r\n",
+        ));
+
+        assert_eq!(response.status, 201);
+        assert_eq!(response.headers.get("X-Backend"), Some("yes"));
+        assert!(!response.headers.contains("Keep-Alive"));
+        assert_eq!(
+            body_text(&mut response),
+            format!(
+                "POST /items?id=7 host={upstream} xff=192.0.2.1, 10.1.2.3 xfh=site.test \
+                 conn=close body=hello"
+            )
+        );
+    }
+
+    #[test]
+    fn private_headers_named_by_connection_are_dropped() {
+        let upstream = backend(|req| {
+            let private = req.headers.contains("X-Private");
+            format!("HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\n{private:5}")
+        });
+        let mut response = Proxy::new([upstream.to_string()]).handle(request(
+            "GET / HTTP/1.1\r\nHost: a\r\nConnection: X-Private\r\nX-Private: 1\r\n\r\n",
+        ));
+        assert_eq!(body_text(&mut response), "false");
+    }
+
+    #[test]
+    fn takes_turns_between_upstreams() {
+        let a = backend(|_| "HTTP/1.1 200 OK\r\nContent-Length: 1\r\n\r\na".to_string());
+        let b = backend(|_| "HTTP/1.1 200 OK\r\nContent-Length: 1\r\n\r\nb".to_string());
+        let proxy = Proxy::new([a.to_string(), b.to_string()]);
+
+        let seen: Vec<String> = (0..4)
+            .map(|_| body_text(&mut proxy.handle(get("/"))))
+            .collect();
+        assert_eq!(seen, ["a", "b", "a", "b"]);
+    }
+
+    #[test]
+    fn skips_upstreams_that_are_down() {
+        let live = backend(|req| {
+            let status = if req.path == "/health" { 204 } else { 200 };
+            format!("HTTP/1.1 {status} OK\r\nContent-Length: 0\r\n\r\n")
+        });
+        let dead = closed_port();
+        let proxy = Proxy::new([dead.to_string(), live.to_string()])
+            .health_check("/health", Duration::from_millis(50));
+
+        for _ in 0..4 {
+            assert_eq!(proxy.handle(get("/")).status, 200);
+        }
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while proxy.shared.list[0].healthy.load(Ordering::Relaxed) {
+            assert!(Instant::now() < deadline, "dead upstream never marked down");
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(proxy.shared.list[1].healthy.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn unreachable_upstreams_give_502() {
+        let proxy = Proxy::new([closed_port().to_string(), closed_port().to_string()]);
+        assert_eq!(proxy.handle(get("/")).status, 502);
+
+        let garbage = backend(|_| "SMTP ready\r\n\r\n".to_string());
+        assert_eq!(
+            Proxy::new([garbage.to_string()]).handle(get("/")).status,
+            502
+        );
+    }
+
+    #[test]
+    fn slow_upstream_gives_504() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let silent = thread::spawn(move || {
+            // Accept, then never answer.
+            let (stream, _) = listener.accept().unwrap();
+            thread::sleep(Duration::from_millis(500));
+            drop(stream);
+        });
+
+        let start = Instant::now();
+        let proxy = Proxy::new([addr.to_string()]).timeout(Duration::from_millis(100));
+        assert_eq!(proxy.handle(get("/")).status, 504);
+        assert!(start.elapsed() < Duration::from_millis(450));
+        silent.join().unwrap();
+    }
+}
@@ -0,0 +1,1085 @@
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Read};
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use crate::form::{self, Form, FormError, FormLimits, Multipart};
+use crate::headers::Headers;
+use crate::router::Params;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Method {
+    Get,
+    Head,
+    Post,
+    Put,
+    Delete,
+    Connect,
+    Options,
+    Trace,
+    Patch,
+}
+
+impl Method {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Method::Get => "GET",
+            Method::Head => "HEAD",
+            Method::Post => "POST",
+            Method::Put => "PUT",
+            Method::Delete => "DELETE",
+            Method::Connect => "CONNECT",
+            Method::Options => "OPTIONS",
+            Method::Trace => "TRACE",
+            Method::Patch => "PATCH",
+        }
+    }
+
+    fn parse(token: &str) -> Result<Method, ParseError> {
+        let method = match token {
+            "GET" => Method::Get,
+            "HEAD" => Method::Head,
+            "POST" => Method::Post,
+            "PUT" => Method::Put,
+            "DELETE" => Method::Delete,
+            "CONNECT" => Method::Connect,
+            "OPTIONS" => Method::Options,
+            "TRACE" => Method::Trace,
+            "PATCH" => Method::Patch,
+            _ if !token.is_empty() && token.bytes().all(is_tchar) => {
+                return Err(ParseError::NotImplemented("unknown method"));
+            }
+            _ => return Err(ParseError::BadRequest("malformed method")),
+        };
+        Ok(method)
+    }
+}
+
+impl fmt::Display for Method {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Version {
+    Http10,
+    Http11,
+}
+
+impl Version {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Version::Http10 => "HTTP/1.0",
+            Version::Http11 => "HTTP/1.1",
+        }
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// A fully received HTTP/1.x request.
+#[derive(Debug, Clone)]
+pub struct Request {
+    pub method: Method,
+    /// The path component of the request target, without the query string.
+    pub path: String,
+    /// The raw query string, without the leading `?`.
+    pub query: Option<String>,
+    pub version: Version,
+    pub headers: Headers,
+    /// The decoded body; chunked transfer coding has already been removed.
+    /// Empty if the body is left on the connection; see
+    /// [`body_reader`](Self::body_reader).
+    pub body: Vec<u8>,
+    /// Path parameters captured by the [`Router`](crate::Router).
+    pub params: Params,
+    /// The address of the client, if the request came from a socket.
+    pub remote_addr: Option<SocketAddr>,
+    pub(crate) body_reader: Option<BodyReader>,
+}
+
+impl Request {
+    /// Reads a single request from `reader`.
+    ///
+    /// Returns `Ok(None)` if the reader is closed before any byte arrives.
+    pub fn read_from<R: Read>(reader: &mut R) -> Result<Option<Request>, ParseError> {
+        RequestParser::new().read_request(reader)
+    }
+
+    /// Returns the path parameter captured as `name`, if any.
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params.get(name)
+    }
+
+    /// Parses an `application/x-www-form-urlencoded` body.
+    pub fn form(&self) -> Result<Form, FormError> {
+        let content_type = self.headers.get("Content-Type").unwrap_or("");
+        if form::media_type(content_type) != "application/x-www-form-urlencoded" {
+            return Err(FormError::UnsupportedContentType);
+        }
+        let body = std::str::from_utf8(&self.body)
+            .map_err(|_| FormError::Malformed("form is not UTF-8"))?;
+        Form::parse(body)
+    }
+
+    /// Returns a reader for the body if it is still on the connection,
+    /// as it is for a route added with
+    /// [`Router::streaming`](crate::Router::streaming).
+    ///
+    /// Every reader returned reads from the same place; what is not read by
+    /// the time the response is sent is discarded.
+    pub fn body_reader(&self) -> Option<BodyReader> {
+        self.body_reader.clone()
+    }
+
+    /// Parses a `multipart/form-data` body, writing file parts to
+    /// [`FormLimits::upload_dir`].
+    ///
+    /// A body still on the connection is read from it as the parts are
+    /// parsed, so only a chunk of it is in memory at a time and only
+    /// `limits` bound it. Otherwise the body has been received in full, and
+    /// is bounded by [`Limits::max_body_size`] as well.
+    pub fn multipart(&self, limits: &FormLimits) -> Result<Multipart, FormError> {
+        let content_type = self.headers.get("Content-Type").unwrap_or("");
+        match self.body_reader() {
+            Some(reader) => Multipart::read_from(reader, content_type, limits),
+            None => Multipart::read_from(&self.body[..], content_type, limits),
+        }
+    }
+}
+
+/// Reads the body of a request from its connection; see
+/// [`Request::body_reader`].
+///
+/// A read fails with [`io::ErrorKind::TimedOut`] if the client takes longer
+/// than the body timeout, with [`io::ErrorKind::UnexpectedEof`] if it closes
+/// the connection early, and with [`io::ErrorKind::InvalidData`] if the
+/// chunked framing is malformed.
+#[derive(Clone)]
+pub struct BodyReader {
+    source: Arc<dyn BodySource>,
+}
+
+/// Where a [`BodyReader`] gets the body from.
+pub(crate) trait BodySource: Send + Sync {
+    fn read(&self, buf: &mut [u8]) -> io::Result<usize>;
+}
+
+impl BodyReader {
+    pub(crate) fn new(source: Arc<dyn BodySource>) -> BodyReader {
+        BodyReader { source }
+    }
+}
+
+impl Read for BodyReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.source.read(buf)
+    }
+}
+
+impl fmt::Debug for BodyReader {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BodyReader").finish_non_exhaustive()
+    }
+}
+
+/// Why a request could not be parsed.
+#[derive(Debug)]
+pub enum ParseError {
+    /// The request is malformed.
+    BadRequest(&'static str),
+    /// The body is larger than [`Limits::max_body_size`].
+    PayloadTooLarge,
+    /// The request line and headers are larger than [`Limits::max_header_size`].
+    HeaderFieldsTooLarge,
+    /// The request uses a method or transfer coding this server does not support.
+    NotImplemented(&'static str),
+    VersionNotSupported,
+    /// The peer closed the connection in the middle of a request.
+    Incomplete,
+    /// The peer took too long to send the request.
+    Timeout,
+    Io(io::Error),
+}
+
+impl ParseError {
+    /// The status code that should be sent back to the client, or `None` if
+    /// the connection is unusable and no response can be written.
+    pub fn status_code(&self) -> Option<u16> {
+        match self {
+            ParseError::BadRequest(_) | ParseError::Incomplete => Some(400),
+            ParseError::PayloadTooLarge => Some(413),
+            ParseError::Timeout => Some(408),
+            ParseError::HeaderFieldsTooLarge => Some(431),
+            ParseError::NotImplemented(_) => Some(501),
+            ParseError::VersionNotSupported => Some(505),
+            ParseError::Io(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::BadRequest(reason) => write!(f, "bad request: {reason}"),
+            ParseError::PayloadTooLarge => f.write_str("request body too large"),
+            ParseError::HeaderFieldsTooLarge => f.write_str("request header fields too large"),
+            ParseError::NotImplemented(reason) => write!(f, "not implemented: {reason}"),
+            ParseError::VersionNotSupported => f.write_str("HTTP version not supported"),
+            ParseError::Incomplete => f.write_str("connection closed before request was complete"),
+            ParseError::Timeout => f.write_str("timed out waiting for the request"),
+            ParseError::Io(e) => write!(f, "I/O error: {e}"),
+        }
+    }
+}
+
+impl Error for ParseError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ParseError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ParseError {
+    fn from(e: io::Error) -> ParseError {
+        ParseError::Io(e)
+    }
+}
+
+impl From<ParseError> for io::Error {
+    fn from(e: ParseError) -> io::Error {
+        let kind = match e {
+            ParseError::Io(e) => return e,
+            ParseError::Incomplete => io::ErrorKind::UnexpectedEof,
+            ParseError::Timeout => io::ErrorKind::TimedOut,
+            _ => io::ErrorKind::InvalidData,
+        };
+        io::Error::new(kind, e)
+    }
+}
+
+/// Size limits enforced while parsing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    /// Maximum size of the request line plus all header fields.
+    pub max_header_size: usize,
+    /// Maximum size of the body. The framing of a chunked body counts too,
+    /// and its trailer fields are bounded by `max_header_size` instead.
+    pub max_body_size: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            max_header_size: 8 * 1024,
+            max_body_size: 1024 * 1024,
+        }
+    }
+}
+
+/// An incremental HTTP/1.x request parser.
+///
+/// Bytes can arrive in arbitrary pieces: feed them with [`feed`](Self::feed)
+/// and call [`parse`](Self::parse) until it yields a request. Bytes after the
+/// end of a request stay buffered for the next one.
+#[derive(Debug, Default)]
+pub struct RequestParser {
+    buf: Vec<u8>,
+    limits: Limits,
+    // How far the search for the end of the header block has got.
+    scanned: usize,
+    // The request whose header has been parsed, waiting for its body.
+    pending: Option<(Request, BodyKind)>,
+    // The body of the last request, if it is read as the handler asks.
+    streaming: Option<StreamedBody>,
+}
+
+#[derive(Debug)]
+struct StreamedBody {
+    kind: BodyKind,
+    /// Decoded bytes of a chunked body not read yet.
+    decoded: Vec<u8>,
+    /// Whether the decoder has seen the end of a chunked body.
+    ended: bool,
+}
+
+#[derive(Debug)]
+enum BodyKind {
+    Empty,
+    Length(usize),
+    Chunked(ChunkedDecoder),
+}
+
+impl RequestParser {
+    pub fn new() -> RequestParser {
+        RequestParser::default()
+    }
+
+    pub fn with_limits(limits: Limits) -> RequestParser {
+        RequestParser {
+            limits,
+            ..RequestParser::default()
+        }
+    }
+
+    pub fn limits(&self) -> &Limits {
+        &self.limits
+    }
+
+    pub fn feed(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+
+    /// Number of bytes received but not yet consumed by a parsed request.
+    pub fn buffered(&self) -> usize {
+        self.buf.len()
+    }
+
+    /// Returns the bytes received but not yet consumed, for a connection
+    /// that stops speaking HTTP.
+    pub fn into_buffered(self) -> Vec<u8> {
+        self.buf
+    }
+
+    /// Returns true if the whole header block of the next request has been
+    /// buffered, so that only its body is still missing.
+    pub fn header_received(&self) -> bool {
+        self.pending.is_some() || find_header_end(&self.buf, self.scanned).is_some()
+    }
+
+    /// Tries to parse one request from the buffered bytes.
+    ///
+    /// Returns `Ok(None)` if more input is needed. The header is parsed
+    /// once, and a chunked body is decoded as it arrives, so calling this
+    /// after every read does not go over the same bytes again.
+    pub fn parse(&mut self) -> Result<Option<Request>, ParseError> {
+        self.parse_or_stream(|_| false)
+    }
+
+    /// Like [`parse`](Self::parse), but returns a request with a body as
+    /// soon as its header is in if `stream` says so. Its body is then read
+    /// with [`read_body`](Self::read_body), and is not bounded by
+    /// [`Limits::max_body_size`].
+    pub(crate) fn parse_or_stream(
+        &mut self,
+        stream: impl FnOnce(&Request) -> bool,
+    ) -> Result<Option<Request>, ParseError> {
+        debug_assert!(self.streaming.is_none(), "the last body is unread");
+        if self.pending.is_none() {
+            let Some((request, kind)) = self.parse_head()? else {
+                return Ok(None);
+            };
+            if !matches!(kind, BodyKind::Empty) && stream(&request) {
+                self.streaming = Some(StreamedBody {
+                    kind,
+                    decoded: Vec::new(),
+                    ended: false,
+                });
+                return Ok(Some(request));
+            }
+            if matches!(kind, BodyKind::Length(len) if len > self.limits.max_body_size) {
+                return Err(ParseError::PayloadTooLarge);
+            }
+            self.pending = Some((request, kind));
+        }
+        let (request, kind) = self.pending.as_mut().expect("set above");
+        let consumed = match kind {
+            BodyKind::Empty => 0,
+            BodyKind::Length(len) => {
+                if self.buf.len() < *len {
+                    return Ok(None);
+                }
+                request.body = self.buf[..*len].to_vec();
+                *len
+            }
+            BodyKind::Chunked(decoder) => {
+                match decoder.decode(&self.buf, &mut request.body, &self.limits)? {
+                    Some(used) => used,
+                    None => return Ok(None),
+                }
+            }
+        };
+        self.buf.drain(..consumed);
+        Ok(self.pending.take().map(|(request, _)| request))
+    }
+
+    /// Parses the request line and headers once they have all arrived, and
+    /// removes them from the buffer.
+    fn parse_head(&mut self) -> Result<Option<(Request, BodyKind)>, ParseError> {
+        // RFC 9112 section 2.2: ignore empty lines before the request line.
+        let leading = self
+            .buf
+            .iter()
+            .take_while(|&&b| b == b'\r' || b == b'\n')
+            .count();
+        if leading > 0 {
+            self.buf.drain(..leading);
+            self.scanned = 0;
+        }
+
+        let header_end = match find_header_end(&self.buf, self.scanned) {
+            Some(end) => end,
+            None => {
+                if self.buf.len() > self.limits.max_header_size {
+                    return Err(ParseError::HeaderFieldsTooLarge);
+                }
+                self.scanned = self.buf.len();
+                return Ok(None);
+            }
+        };
+        if header_end > self.limits.max_header_size {
+            return Err(ParseError::HeaderFieldsTooLarge);
+        }
+
+        let head = std::str::from_utf8(&self.buf[..header_end])
+            .map_err(|_| ParseError::BadRequest("header is not valid UTF-8"))?;
+        let mut lines = head.split('\n').map(|l| l.strip_suffix('\r').unwrap_or(l));
+
+        let (method, path, query, version) = parse_request_line(lines.next().unwrap_or(""))?;
+        let headers = parse_headers(lines)?;
+
+        if version == Version::Http11 && !headers.contains("Host") {
+            return Err(ParseError::BadRequest("missing Host header"));
+        }
+
+        let kind = body_kind(&headers)?;
+        self.buf.drain(..header_end);
+        self.scanned = 0;
+
+        let request = Request {
+            method,
+            path,
+            query,
+            version,
+            headers,
+            body: Vec::new(),
+            params: Params::new(),
+            remote_addr: None,
+            body_reader: None,
+        };
+        Ok(Some((request, kind)))
+    }
+
+    /// Returns true while the body of the request returned last is being
+    /// read with [`read_body`](Self::read_body) and has not ended.
+    pub(crate) fn body_pending(&self) -> bool {
+        self.streaming.is_some()
+    }
+
+    /// Reads the body of the request returned last by
+    /// [`parse_or_stream`](Self::parse_or_stream), taking what is buffered
+    /// first and then reading from `reader`. Returns 0 at the end of the
+    /// body.
+    pub(crate) fn read_body<R: Read + ?Sized>(
+        &mut self,
+        reader: &mut R,
+        out: &mut [u8],
+    ) -> io::Result<usize> {
+        loop {
+            let Some(streamed) = &mut self.streaming else {
+                return Ok(0);
+            };
+            match &mut streamed.kind {
+                BodyKind::Empty => {}
+                BodyKind::Length(left) => {
+                    let want = out.len().min(*left);
+                    let n = if self.buf.is_empty() {
+                        match reader.read(&mut out[..want])? {
+                            0 if want > 0 => return Err(ParseError::Incomplete.into()),
+                            n => n,
+                        }
+                    } else {
+                        let n = want.min(self.buf.len());
+                        out[..n].copy_from_slice(&self.buf[..n]);
+                        self.buf.drain(..n);
+                        n
+                    };
+                    *left -= n;
+                    if *left == 0 {
+                        self.streaming = None;
+                    }
+                    return Ok(n);
+                }
+                BodyKind::Chunked(decoder) => {
+                    if !streamed.decoded.is_empty() {
+                        let n = out.len().min(streamed.decoded.len());
+                        out[..n].copy_from_slice(&streamed.decoded[..n]);
+                        streamed.decoded.drain(..n);
+                        if streamed.ended && streamed.decoded.is_empty() {
+                            self.streaming = None;
+                        }
+                        return Ok(n);
+                    }
+                    if !streamed.ended {
+                        // Only the trailers stay bounded; the handler bounds
+                        // the body.
+                        let limits = Limits {
+                            max_body_size: usize::MAX,
+                            ..self.limits
+                        };
+                        streamed.ended = decoder
+                            .decode(&self.buf, &mut streamed.decoded, &limits)?
+                            .is_some();
+                        self.buf.drain(..decoder.pos);
+                        decoder.pos = 0;
+                        if !streamed.ended && streamed.decoded.is_empty() {
+                            let mut chunk = [0; 8 * 1024];
+                            match reader.read(&mut chunk)? {
+                                0 => return Err(ParseError::Incomplete.into()),
+                                n => self.buf.extend_from_slice(&chunk[..n]),
+                            }
+                        }
+                        continue;
+                    }
+                }
+            }
+            self.streaming = None;
+        }
+    }
+
+    /// Reads from `reader` until a whole request has been parsed.
+    ///
+    /// Returns `Ok(None)` if the reader reaches end of file with nothing
+    /// buffered, which is how a client normally closes a connection.
+    pub fn read_request<R: Read>(&mut self, reader: &mut R) -> Result<Option<Request>, ParseError> {
+        let mut chunk = [0; 4096];
+        loop {
+            if let Some(request) = self.parse()? {
+                return Ok(Some(request));
+            }
+
+            let n = match reader.read(&mut chunk) {
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(ParseError::Io(e)),
+            };
+            if n == 0 {
+                return if self.buf.is_empty() {
+                    Ok(None)
+                } else {
+                    Err(ParseError::Incomplete)
+                };
+            }
+            self.feed(&chunk[..n]);
+        }
+    }
+}
+
+/// Finds the index just past the blank line ending the header block.
+fn find_header_end(buf: &[u8], from: usize) -> Option<usize> {
+    let start = from.saturating_sub(3);
+    buf[start..]
+        .iter()
+        .enumerate()
+        .filter(|&(_, &b)| b == b'\n')
+        .find_map(|(i, _)| {
+            let i = start + i;
+            match &buf[i + 1..] {
+                [b'\n', ..] => Some(i + 2),
+                [b'\r', b'\n', ..] => Some(i + 3),
+                _ => None,
+            }
+        })
+}
+
+fn parse_request_line(line: &str) -> Result<(Method, String, Option<String>, Version), ParseError> {
+    let mut parts = line.split(' ');
+    let (method, target, version) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(m), Some(t), Some(v), None) => (m, t, v),
+        _ => return Err(ParseError::BadRequest("malformed request line")),
+    };
+
+    let method = Method::parse(method)?;
+    let version = match version {
+        "HTTP/1.1" => Version::Http11,
+        "HTTP/1.0" => Version::Http10,
+        v if v.starts_with("HTTP/") => return Err(ParseError::VersionNotSupported),
+        _ => return Err(ParseError::BadRequest("malformed HTTP version")),
+    };
+
+    let target = if target.starts_with('/') || (target == "*" && method == Method::Options) {
+        target
+    } else if let Some(rest) = target
+        .strip_prefix("http://")
+        .or_else(|| target.strip_prefix("https://"))
+    {
+        // absolute-form: keep only the path and query.
+        rest.find('/').map_or("/", |i| &rest[i..])
+    } else {
+        return Err(ParseError::BadRequest("malformed request target"));
+    };
+    if target.bytes().any(|b| b.is_ascii_control()) {
+        return Err(ParseError::BadRequest("malformed request target"));
+    }
+
+    let (path, query) = match target.split_once('?') {
+        Some((path, query)) => (path.to_string(), Some(query.to_string())),
+        None => (target.to_string(), None),
+    };
+
+    Ok((method, path, query, version))
+}
+
+pub(crate) fn parse_headers<'a>(
+    lines: impl Iterator<Item = &'a str>,
+) -> Result<Headers, ParseError> {
+    let mut headers = Headers::new();
+    for line in lines {
+        if line.is_empty() {
+            break;
+        }
+        if line.starts_with(' ') || line.starts_with('\t') {
+            return Err(ParseError::BadRequest("obsolete header line folding"));
+        }
+        let (name, value) = line
+            .split_once(':')
+            .ok_or(ParseError::BadRequest("malformed header line"))?;
+        if name.is_empty() || !name.bytes().all(is_tchar) {
+            return Err(ParseError::BadRequest("malformed header name"));
+        }
+        headers.append(name, value.trim_matches(|c| c == ' ' || c == '\t'));
+    }
+    Ok(headers)
+}
+
+fn body_kind(headers: &Headers) -> Result<BodyKind, ParseError> {
+    if headers.contains("Transfer-Encoding") {
+        // A message with both framings is a request smuggling vector.
+        if headers.contains("Content-Length") {
+            return Err(ParseError::BadRequest(
+                "both Transfer-Encoding and Content-Length",
+            ));
+        }
+        let codings: Vec<&str> = headers
+            .get_all("Transfer-Encoding")
+            .flat_map(|v| v.split(','))
+            .map(str::trim)
+            .filter(|c| !c.is_empty())
+            .collect();
+        return match codings.as_slice() {
+            [c] if c.eq_ignore_ascii_case("chunked") => {
+                Ok(BodyKind::Chunked(ChunkedDecoder::default()))
+            }
+            [.., last] if last.eq_ignore_ascii_case("chunked") => {
+                Err(ParseError::NotImplemented("unsupported transfer coding"))
+            }
+            _ => Err(ParseError::BadRequest(
+                "chunked must be the final transfer coding",
+            )),
+        };
+    }
+
+    let mut length = None;
+    for value in headers.get_all("Content-Length").flat_map(|v| v.split(',')) {
+        let value = value.trim();
+        if value.is_empty() || !value.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(ParseError::BadRequest("invalid Content-Length"));
+        }
+        // Too many digits to fit in memory is simply too large.
+        let n: usize = value.parse().map_err(|_| ParseError::PayloadTooLarge)?;
+        if length.is_some_and(|l| l != n) {
+            return Err(ParseError::BadRequest("conflicting Content-Length"));
+        }
+        length = Some(n);
+    }
+
+    Ok(match length {
+        Some(0) | None => BodyKind::Empty,
+        Some(n) => BodyKind::Length(n),
+    })
+}
+
+/// Decodes a chunked body as it arrives, remembering where it got to.
+#[derive(Debug, Default)]
+struct ChunkedDecoder {
+    /// How much of the buffer has been decoded.
+    pos: usize,
+    state: ChunkState,
+    /// Bytes of chunk framing seen so far.
+    framing: usize,
+}
+
+#[derive(Debug, Default)]
+enum ChunkState {
+    /// Expecting a chunk size line.
+    #[default]
+    Size,
+    /// Inside a chunk, with this many bytes of it to come.
+    Data(usize),
+    /// Expecting the line break after a chunk's data.
+    DataEnd,
+    /// After the last chunk, with this many bytes of trailer fields so far.
+    Trailers(usize),
+}
+
+impl ChunkedDecoder {
+    /// Decodes what `buf` holds of the body, appending it to `body`.
+    /// Returns the number of bytes the body took up once it is complete, or
+    /// `None` if more is needed. `buf` must start where it did on the
+    /// previous call.
+    fn decode(
+        &mut self,
+        buf: &[u8],
+        body: &mut Vec<u8>,
+        limits: &Limits,
+    ) -> Result<Option<usize>, ParseError> {
+        const MAX_LINE: usize = 1024;
+
+        loop {
+            match self.state {
+                ChunkState::Size => {
+                    let Some(line_len) = buf[self.pos..].iter().position(|&b| b == b'\n') else {
+                        if buf.len() - self.pos > MAX_LINE {
+                            return Err(ParseError::BadRequest("chunk size line too long"));
+                        }
+                        return Ok(None);
+                    };
+                    let line = &buf[self.pos..self.pos + line_len];
+                    let line = line.strip_suffix(b"\r").unwrap_or(line);
+
+                    // Chunk extensions after ';' are allowed and ignored.
+                    let size = line.split(|&b| b == b';').next().unwrap_or_default();
+                    let size = std::str::from_utf8(size)
+                        .ok()
+                        .map(str::trim)
+                        .filter(|s| !s.is_empty() && s.bytes().all(|b| b.is_ascii_hexdigit()))
+                        .ok_or(ParseError::BadRequest("invalid chunk size"))?;
+                    let size =
+                        usize::from_str_radix(size, 16).map_err(|_| ParseError::PayloadTooLarge)?;
+
+                    // Size lines count, so that tiny chunks with long
+                    // extensions cannot buffer many times the limit. The
+                    // size comes from the client; no sum with it may
+                    // overflow.
+                    self.framing += line_len + 1;
+                    match body
+                        .len()
+                        .checked_add(self.framing)
+                        .and_then(|total| total.checked_add(size))
+                    {
+                        Some(total) if total <= limits.max_body_size => {}
+                        _ => return Err(ParseError::PayloadTooLarge),
+                    }
+                    self.pos += line_len + 1;
+                    self.state = match size {
+                        0 => ChunkState::Trailers(0),
+                        size => ChunkState::Data(size),
+                    };
+                }
+                ChunkState::Data(left) => {
+                    let n = left.min(buf.len() - self.pos);
+                    body.extend_from_slice(&buf[self.pos..self.pos + n]);
+                    self.pos += n;
+                    if n < left {
+                        self.state = ChunkState::Data(left - n);
+                        return Ok(None);
+                    }
+                    self.state = ChunkState::DataEnd;
+                }
+                ChunkState::DataEnd => {
+                    let end = match &buf[self.pos..] {
+                        [b'\r', b'\n', ..] => 2,
+                        [b'\n', ..] => 1,
+                        [] | [b'\r'] => return Ok(None),
+                        _ => return Err(ParseError::BadRequest("missing CRLF after chunk data")),
+                    };
+                    self.framing += end;
+                    self.pos += end;
+                    self.state = ChunkState::Size;
+                }
+                // The trailer fields are ignored, up to the final empty line.
+                ChunkState::Trailers(size) => {
+                    let Some(line_len) = buf[self.pos..].iter().position(|&b| b == b'\n') else {
+                        if size + buf.len() - self.pos > limits.max_header_size {
+                            return Err(ParseError::HeaderFieldsTooLarge);
+                        }
+                        return Ok(None);
+                    };
+                    let size = size + line_len + 1;
+                    if size > limits.max_header_size {
+                        return Err(ParseError::HeaderFieldsTooLarge);
+                    }
+                    let line = &buf[self.pos..self.pos + line_len];
+                    self.pos += line_len + 1;
+                    if line.is_empty() || line == b"\r" {
+                        return Ok(Some(self.pos));
+                    }
+                    self.state = ChunkState::Trailers(size);
+                }
+            }
+        }
+    }
+}
+
+/// Decodes `%XX` escapes. Returns `None` for malformed escapes or if the
+/// result is not valid UTF-8.
+pub(crate) fn percent_decode(s: &str) -> Option<String> {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = bytes
+                .get(i + 1..i + 3)
+                .filter(|h| h.iter().all(u8::is_ascii_hexdigit))?;
+            let hex = std::str::from_utf8(hex).ok()?;
+            out.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(out).ok()
+}
+
+fn is_tchar(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_all(input: &[u8]) -> Result<Option<Request>, ParseError> {
+        let mut parser = RequestParser::new();
+        parser.feed(input);
+        parser.parse()
+    }
+
+    #[test]
+    fn parses_request_line_and_query() {
+        let req = parse_all(b"GET /search?q=rust&page=2 HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(req.method, Method::Get);
+        assert_eq!(req.path, "/search");
+        assert_eq!(req.query.as_deref(), Some("q=rust&page=2"));
+        assert_eq!(req.version, Version::Http11);
+        assert_eq!(req.headers.get("host"), Some("localhost"));
+        assert!(req.body.is_empty());
+    }
+
+    #[test]
+    fn handles_partial_reads() {
+        let input = b"POST /submit HTTP/1.1\r\nHost: a\r\nContent-Length: 5\r\n\r\nhello";
+        let mut parser = RequestParser::new();
+
+        for (i, byte) in input[..input.len() - 1].iter().enumerate() {
+            parser.feed(&[*byte]);
+            assert!(parser.parse().unwrap().is_none());
+            assert_eq!(parser.header_received(), i >= input.len() - 6);
+        }
+        parser.feed(&input[input.len() - 1..]);
+
+        let req = parser.parse().unwrap().unwrap();
+        assert_eq!(req.body, b"hello");
+        assert_eq!(parser.buffered(), 0);
+    }
+
+    #[test]
+    fn decodes_chunked_body() {
+        let input = b"POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n\
+                      4;ext=1\r\nWiki\r\n5\r\npedia\r\n0\r\nX-Trailer: y\r\n\r\n";
+        let req = parse_all(input).unwrap().unwrap();
+
+        assert_eq!(req.body, b"Wikipedia");
+    }
+
+    #[test]
+    fn huge_chunk_size_is_too_large() {
+        let input = b"POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n\
+                      1\r\na\r\nffffffffffffffff\r\n";
+        let err = parse_all(input).unwrap_err();
+        assert_eq!(err.status_code(), Some(413));
+
+        // The sum with the framing must not wrap around either.
+        let limits = Limits {
+            max_body_size: usize::MAX,
+            ..Limits::default()
+        };
+        let err = ChunkedDecoder::default()
+            .decode(b"ffffffffffffffff\r\n", &mut Vec::new(), &limits)
+            .unwrap_err();
+        assert_eq!(err.status_code(), Some(413));
+    }
+
+    #[test]
+    fn chunk_framing_and_trailers_are_bounded() {
+        let head = b"POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n";
+        let mut parser = RequestParser::with_limits(Limits {
+            max_header_size: 256,
+            max_body_size: 4096,
+        });
+        parser.feed(head);
+        let mut extension = vec![b'x'; 1000];
+        extension.extend_from_slice(b"\r\na\r\n");
+        for _ in 0..4 {
+            parser.feed(b"1;");
+            parser.feed(&extension);
+            assert!(parser.parse().unwrap().is_none());
+        }
+        parser.feed(b"1;");
+        parser.feed(&extension);
+        assert_eq!(parser.parse().unwrap_err().status_code(), Some(413));
+
+        // Trailer lines cannot go on forever.
+        let mut parser = RequestParser::with_limits(Limits {
+            max_header_size: 256,
+            max_body_size: 4096,
+        });
+        parser.feed(head);
+        parser.feed(b"0\r\n");
+        let err = loop {
+            parser.feed(b"X-Trailer: y\r\n");
+            match parser.parse() {
+                Ok(None) => {}
+                Ok(Some(_)) => panic!("trailers ended"),
+                Err(e) => break e,
+            }
+        };
+        assert_eq!(err.status_code(), Some(431));
+    }
+
+    #[test]
+    fn decodes_chunked_body_byte_by_byte() {
+        let input = b"POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n\
+                      4\r\nWiki\r\n5\r\npedia\r\n0\r\nX-Trailer: y\r\n\r\nGET";
+        let mut parser = RequestParser::new();
+        for byte in &input[..input.len() - 4] {
+            parser.feed(&[*byte]);
+            assert!(parser.parse().unwrap().is_none());
+        }
+        parser.feed(&input[input.len() - 4..]);
+
+        assert_eq!(parser.parse().unwrap().unwrap().body, b"Wikipedia");
+        assert_eq!(parser.buffered(), 3);
+    }
+
+    #[test]
+    fn streams_a_body_past_the_limit() {
+        let limits = Limits {
+            max_body_size: 4,
+            ..Limits::default()
+        };
+        let bodies: [(&str, &[u8]); 2] = [
+            ("Content-Length: 11", b"hello world"),
+            (
+                "Transfer-Encoding: chunked",
+                b"5\r\nhello\r\n6;ext\r\n world\r\n0\r\nX-Trailer: y\r\n\r\n",
+            ),
+        ];
+        for (framing, body) in bodies {
+            let head = format!("POST / HTTP/1.1\r\nHost: a\r\n{framing}\r\n\r\n");
+            let mut parser = RequestParser::with_limits(limits);
+            parser.feed(head.as_bytes());
+            parser.feed(&body[..3]);
+            assert_eq!(parser.parse().unwrap_err().status_code(), Some(413));
+
+            let mut parser = RequestParser::with_limits(limits);
+            parser.feed(head.as_bytes());
+            parser.feed(&body[..3]);
+            let request = parser.parse_or_stream(|_| true).unwrap().unwrap();
+            assert!(request.body.is_empty());
+
+            let next = b"GET /next HTTP/1.1\r\nHost: a\r\n\r\n";
+            let rest = [&body[3..], next].concat();
+            let mut reader = &rest[..];
+            let (mut out, mut buf) = (Vec::new(), [0; 2]);
+            while parser.body_pending() {
+                let n = parser.read_body(&mut reader, &mut buf).unwrap();
+                out.extend_from_slice(&buf[..n]);
+            }
+            assert_eq!(out, b"hello world");
+            assert_eq!(
+                parser.read_request(&mut reader).unwrap().unwrap().path,
+                "/next"
+            );
+
+            // A body cut short is an error.
+            let mut parser = RequestParser::new();
+            parser.feed(head.as_bytes());
+            parser.parse_or_stream(|_| true).unwrap().unwrap();
+            let mut cut = &body[..body.len() - 4];
+            let err = loop {
+                match parser.read_body(&mut cut, &mut buf) {
+                    Ok(n) => assert!(n > 0),
+                    Err(e) => break e,
+                }
+            };
+            assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        }
+    }
+
+    #[test]
+    fn keeps_following_request_buffered() {
+        let mut parser = RequestParser::new();
+        parser.feed(b"GET /a HTTP/1.1\r\nHost: a\r\n\r\nGET /b HTTP/1.1\r\nHost: a\r\n\r\n");
+
+        assert_eq!(parser.parse().unwrap().unwrap().path, "/a");
+        assert_eq!(parser.parse().unwrap().unwrap().path, "/b");
+        assert!(parser.parse().unwrap().is_none());
+    }
+
+    #[test]
+    fn reads_from_stream() {
+        let mut input: &[u8] = b"GET / HTTP/1.0\r\n\r\n";
+        let req = Request::read_from(&mut input).unwrap().unwrap();
+        assert_eq!(req.version, Version::Http10);
+
+        let mut empty: &[u8] = b"";
+        assert!(Request::read_from(&mut empty).unwrap().is_none());
+
+        let mut truncated: &[u8] = b"GET / HTTP/1.1\r\nHo";
+        let err = Request::read_from(&mut truncated).unwrap_err();
+        assert_eq!(err.status_code(), Some(400));
+    }
+
+    #[test]
+    fn error_status_codes() {
+        let status = |input: &[u8]| parse_all(input).unwrap_err().status_code();
+
+        assert_eq!(status(b"GET / HTTP/1.1\r\n\r\n"), Some(400));
+        assert_eq!(status(b"BREW / HTTP/1.1\r\nHost: a\r\n\r\n"), Some(501));
+        assert_eq!(status(b"GET / HTTP/2.0\r\nHost: a\r\n\r\n"), Some(505));
+        assert_eq!(
+            status(b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 1\r\nContent-Length: 2\r\n\r\n"),
+            Some(400)
+        );
+        assert_eq!(
+            status(b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 99999999\r\n\r\n"),
+            Some(413)
+        );
+    }
+
+    #[test]
+    fn percent_decoding() {
+        assert_eq!(percent_decode("a%20b%2Fc").as_deref(), Some("a b/c"));
+        assert_eq!(percent_decode("%E4%BD%A0").as_deref(), Some("你"));
+        assert_eq!(percent_decode("bad%2"), None);
+        assert_eq!(percent_decode("%zz"), None);
+    }
+
+    #[test]
+    fn rejects_oversized_header() {
+        let mut parser = RequestParser::with_limits(Limits {
+            max_header_size: 64,
+            ..Limits::default()
+        });
+        parser.feed(b"GET / HTTP/1.1\r\nHost: a\r\nX-Padding: ");
+        assert!(parser.parse().unwrap().is_none());
+
+        parser.feed(&[b'x'; 64]);
+        let err = parser.parse().unwrap_err();
+        assert_eq!(err.status_code(), Some(431));
+    }
+}
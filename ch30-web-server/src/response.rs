@@ -0,0 +1,527 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+
+use crate::connection::Upgraded;
+use crate::headers::Headers;
+use crate::request::{Method, Version};
+use crate::status::reason_phrase;
+
+/// The payload of a [`Response`].
+pub enum Body {
+    Bytes(Vec<u8>),
+    /// `len` bytes read from the file's current position.
+    File {
+        file: File,
+        len: u64,
+    },
+    /// Bytes read from `reader` until end of file, or `len` bytes if the
+    /// length is known up front.
+    Reader {
+        reader: Box<dyn Read + Send>,
+        len: Option<u64>,
+    },
+    /// Chunks produced one at a time, each sent as soon as it is ready.
+    Chunked(Box<dyn Iterator<Item = Vec<u8>> + Send>),
+}
+
+impl Body {
+    pub fn empty() -> Body {
+        Body::Bytes(Vec::new())
+    }
+
+    pub fn reader(reader: impl Read + Send + 'static, len: Option<u64>) -> Body {
+        Body::Reader {
+            reader: Box::new(reader),
+            len,
+        }
+    }
+
+    pub fn chunked<I>(chunks: I) -> Body
+    where
+        I: IntoIterator<Item = Vec<u8>>,
+        I::IntoIter: Send + 'static,
+    {
+        Body::Chunked(Box::new(chunks.into_iter()))
+    }
+
+    /// Returns the length of the body, or `None` if it is only known once
+    /// the body has been sent.
+    pub fn len(&self) -> Option<u64> {
+        match self {
+            Body::Bytes(bytes) => Some(bytes.len() as u64),
+            Body::File { len, .. } => Some(*len),
+            Body::Reader { len, .. } => *len,
+            Body::Chunked(_) => None,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == Some(0)
+    }
+
+    /// Returns the body if it is held in memory.
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            Body::Bytes(bytes) => Some(bytes),
+            _ => None,
+        }
+    }
+
+    /// Writes the body as is, or as a series of chunks if `chunked` is set,
+    /// and returns the number of body bytes written.
+    fn write_to<W: Write>(&mut self, writer: &mut W, chunked: bool) -> io::Result<u64> {
+        let mut out = BodyWriter {
+            writer,
+            chunked,
+            written: 0,
+        };
+        match self {
+            Body::Bytes(bytes) => out.write_chunk(bytes)?,
+            Body::File { file, len } => out.copy_exact(file, *len)?,
+            Body::Reader {
+                reader,
+                len: Some(len),
+            } => out.copy_exact(reader, *len)?,
+            Body::Reader { reader, len: None } => {
+                let mut buf = vec![0; 8 * 1024];
+                loop {
+                    let n = match reader.read(&mut buf) {
+                        Ok(0) => break,
+                        Ok(n) => n,
+                        Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                        Err(e) => return Err(e),
+                    };
+                    out.write_chunk(&buf[..n])?;
+                }
+            }
+            Body::Chunked(chunks) => {
+                for chunk in chunks {
+                    out.write_chunk(&chunk)?;
+                }
+            }
+        }
+        out.finish()
+    }
+}
+
+/// Frames body bytes as they are written.
+struct BodyWriter<'a, W> {
+    writer: &'a mut W,
+    chunked: bool,
+    written: u64,
+}
+
+impl<W: Write> BodyWriter<'_, W> {
+    fn write_chunk(&mut self, data: &[u8]) -> io::Result<()> {
+        // An empty chunk would end the body early.
+        if data.is_empty() {
+            return Ok(());
+        }
+        if self.chunked {
+            write!(self.writer, "{:x}\r\n", data.len())?;
+            self.writer.write_all(data)?;
+            self.writer.write_all(b"\r\n")?;
+            // Streamed chunks go out as they are produced.
+            self.writer.flush()?;
+        } else {
+            self.writer.write_all(data)?;
+        }
+        self.written += data.len() as u64;
+        Ok(())
+    }
+
+    /// Copies exactly `len` bytes from `reader`.
+    fn copy_exact<R: Read + ?Sized>(&mut self, reader: &mut R, len: u64) -> io::Result<()> {
+        let mut reader = reader.take(len);
+        let mut buf = vec![0; 64 * 1024];
+        loop {
+            let n = match reader.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            self.write_chunk(&buf[..n])?;
+        }
+        if self.written < len {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "body ended before its declared length",
+            ));
+        }
+        Ok(())
+    }
+
+    fn finish(self) -> io::Result<u64> {
+        if self.chunked {
+            self.writer.write_all(b"0\r\n\r\n")?;
+        }
+        Ok(self.written)
+    }
+}
+
+impl fmt::Debug for Body {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Body::Bytes(bytes) => f.debug_tuple("Bytes").field(bytes).finish(),
+            Body::File { file, len } => f
+                .debug_struct("File")
+                .field("file", file)
+                .field("len", len)
+                .finish(),
+            Body::Reader { len, .. } => f
+                .debug_struct("Reader")
+                .field("len", len)
+                .finish_non_exhaustive(),
+            Body::Chunked(_) => f.write_str("Chunked(..)"),
+        }
+    }
+}
+
+impl From<Vec<u8>> for Body {
+    fn from(bytes: Vec<u8>) -> Body {
+        Body::Bytes(bytes)
+    }
+}
+
+impl From<&[u8]> for Body {
+    fn from(bytes: &[u8]) -> Body {
+        Body::Bytes(bytes.to_vec())
+    }
+}
+
+impl From<String> for Body {
+    fn from(s: String) -> Body {
+        Body::Bytes(s.into_bytes())
+    }
+}
+
+impl From<&str> for Body {
+    fn from(s: &str) -> Body {
+        Body::Bytes(s.as_bytes().to_vec())
+    }
+}
+
+/// How the end of a response body is marked on the wire.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Framing {
+    /// The status code does not allow a body.
+    NoBody,
+    /// The handler set `Content-Length` itself for a `HEAD` response, whose
+    /// body is not sent.
+    Declared,
+    Length(u64),
+    Chunked,
+    /// HTTP/1.0 clients cannot read chunks, so the end of a body of unknown
+    /// length is marked by closing the connection.
+    Close,
+}
+
+/// Takes over the connection once a `101 Switching Protocols` response has
+/// been sent.
+struct Upgrade(Box<dyn FnOnce(Upgraded) + Send>);
+
+impl fmt::Debug for Upgrade {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Upgrade(..)")
+    }
+}
+
+/// An HTTP response ready to be written to a client.
+#[derive(Debug)]
+pub struct Response {
+    pub status: u16,
+    pub headers: Headers,
+    pub body: Body,
+    upgrade: Option<Upgrade>,
+}
+
+impl Response {
+    /// Creates an empty response with the given status code.
+    pub fn new(status: u16) -> Response {
+        Response {
+            status,
+            headers: Headers::new(),
+            body: Body::empty(),
+            upgrade: None,
+        }
+    }
+
+    pub fn html(status: u16, body: impl Into<Body>) -> Response {
+        Response::new(status)
+            .with_header("Content-Type", "text/html; charset=utf-8")
+            .with_body(body)
+    }
+
+    pub fn text(status: u16, body: impl Into<Body>) -> Response {
+        Response::new(status)
+            .with_header("Content-Type", "text/plain; charset=utf-8")
+            .with_body(body)
+    }
+
+    /// Sets a header, replacing any previous value.
+    pub fn with_header(mut self, name: impl Into<String>, value: impl Into<String>) -> Response {
+        self.headers.insert(name, value);
+        self
+    }
+
+    /// Adds a header, keeping any previous values.
+    pub fn with_appended_header(
+        mut self,
+        name: impl Into<String>,
+        value: impl Into<String>,
+    ) -> Response {
+        self.headers.append(name, value);
+        self
+    }
+
+    pub fn with_body(mut self, body: impl Into<Body>) -> Response {
+        self.body = body.into();
+        self
+    }
+
+    /// Hands the connection to `upgrade` after this response is sent,
+    /// instead of reading the next request from it.
+    ///
+    /// Only takes effect on a `101 Switching Protocols` response. `upgrade`
+    /// runs on the thread that served the request.
+    pub fn on_upgrade(mut self, upgrade: impl FnOnce(Upgraded) + Send + 'static) -> Response {
+        self.upgrade = Some(Upgrade(Box::new(upgrade)));
+        self
+    }
+
+    /// Removes the upgrade set with [`Response::on_upgrade`], if this
+    /// response switches protocols.
+    pub(crate) fn take_upgrade(&mut self) -> Option<Box<dyn FnOnce(Upgraded) + Send>> {
+        if self.status != 101 {
+            return None;
+        }
+        self.upgrade.take().map(|upgrade| upgrade.0)
+    }
+
+    fn framing(&self, method: Method, version: Version) -> Framing {
+        if matches!(self.status, 100..=199 | 204 | 304) {
+            Framing::NoBody
+        } else if method == Method::Head
+            && self.body.is_empty()
+            && self.headers.contains("Content-Length")
+        {
+            // Nothing follows the head, so the length of the body a `GET`
+            // would get cannot put the connection out of step.
+            Framing::Declared
+        } else if let Some(len) = self.body.len() {
+            Framing::Length(len)
+        } else if version == Version::Http11 {
+            Framing::Chunked
+        } else {
+            Framing::Close
+        }
+    }
+
+    /// Whether the connection must be closed after this response to show
+    /// the client where its body ends.
+    pub fn needs_close(&self, method: Method, version: Version) -> bool {
+        method != Method::Head && self.framing(method, version) == Framing::Close
+    }
+
+    /// Writes the response to an HTTP/1.1 `GET` request. See
+    /// [`Response::write_for`].
+    pub fn write_to<W: Write>(&mut self, writer: &mut W) -> io::Result<u64> {
+        self.write_for(writer, Method::Get, Version::Http11)
+    }
+
+    /// Writes the status line, headers and body framed for a request with
+    /// the given method and version, and returns the number of body bytes
+    /// sent.
+    ///
+    /// `Content-Length` is added when the body's length is known and
+    /// `Transfer-Encoding: chunked` when it is not. Responses to `HEAD` get
+    /// the same headers as the `GET` would, but no body. Bodies of unknown
+    /// length sent to HTTP/1.0 clients are delimited by closing the
+    /// connection; see [`Response::needs_close`]. A `Content-Length` set by
+    /// the handler is only kept on an empty response to `HEAD`; otherwise
+    /// the body's own length replaces it.
+    pub fn write_for<W: Write>(
+        &mut self,
+        writer: &mut W,
+        method: Method,
+        version: Version,
+    ) -> io::Result<u64> {
+        let framing = self.framing(method, version);
+        // Framing is the writer's job; a stale header would contradict it.
+        self.headers.remove("Transfer-Encoding");
+        if matches!(
+            framing,
+            Framing::Length(_) | Framing::Chunked | Framing::Close
+        ) {
+            self.headers.remove("Content-Length");
+        }
+
+        let mut head = format!(
+            "HTTP/1.1 {} {}\r\n",
+            self.status,
+            reason_phrase(self.status)
+        );
+        match framing {
+            Framing::Length(len) => head.push_str(&format!("Content-Length: {len}\r\n")),
+            Framing::Chunked => head.push_str("Transfer-Encoding: chunked\r\n"),
+            Framing::NoBody | Framing::Declared | Framing::Close => {}
+        }
+        head.push_str(&self.headers.to_string());
+        head.push_str("\r\n");
+
+        let mut writer = BufWriter::new(writer);
+        writer.write_all(head.as_bytes())?;
+        let written = if framing == Framing::NoBody || method == Method::Head {
+            0
+        } else {
+            self.body
+                .write_to(&mut writer, framing == Framing::Chunked)?
+        };
+        writer.flush()?;
+        Ok(written)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writes_status_headers_and_body() {
+        let mut out = Vec::new();
+        Response::text(200, "hi")
+            .with_header("X-Test", "1")
+            .write_to(&mut out)
+            .unwrap();
+
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\
+             Content-Type: text/plain; charset=utf-8\r\nX-Test: 1\r\n\r\nhi"
+        );
+    }
+
+    #[test]
+    fn not_modified_has_no_body() {
+        let mut out = Vec::new();
+        Response::new(304)
+            .with_body("ignored")
+            .write_to(&mut out)
+            .unwrap();
+
+        assert_eq!(out, b"HTTP/1.1 304 Not Modified\r\n\r\n");
+    }
+
+    #[test]
+    fn head_response_keeps_framing_headers() {
+        let mut out = Vec::new();
+        let written = Response::new(200)
+            .with_body("hello")
+            .write_for(&mut out, Method::Head, Version::Http11)
+            .unwrap();
+        assert_eq!(written, 0);
+        assert_eq!(out, b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\n");
+
+        let mut out = Vec::new();
+        Response::new(200)
+            .with_body(Body::chunked(vec![b"hello".to_vec()]))
+            .write_for(&mut out, Method::Head, Version::Http11)
+            .unwrap();
+        assert_eq!(
+            out,
+            b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn declared_length_must_match_the_body() {
+        let mut out = Vec::new();
+        Response::new(200)
+            .with_header("Content-Length", "100")
+            .with_body("hi")
+            .write_to(&mut out)
+            .unwrap();
+        assert_eq!(out, b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nhi");
+
+        let mut out = Vec::new();
+        Response::new(200)
+            .with_header("Content-Length", "100")
+            .write_to(&mut out)
+            .unwrap();
+        assert_eq!(out, b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n");
+
+        let mut out = Vec::new();
+        Response::new(200)
+            .with_header("Content-Length", "3")
+            .with_body(Body::reader(&b"abc"[..], None))
+            .write_to(&mut out)
+            .unwrap();
+        assert!(!String::from_utf8(out).unwrap().contains("Content-Length"));
+
+        // A `HEAD` response tells the length of the body it leaves out.
+        let mut out = Vec::new();
+        Response::new(200)
+            .with_header("Content-Length", "100")
+            .write_for(&mut out, Method::Head, Version::Http11)
+            .unwrap();
+        assert_eq!(out, b"HTTP/1.1 200 OK\r\nContent-Length: 100\r\n\r\n");
+    }
+
+    #[test]
+    fn streams_unknown_length_as_chunks() {
+        let chunks = vec![b"hello".to_vec(), Vec::new(), b", chunked world".to_vec()];
+        let mut out = Vec::new();
+        let written = Response::new(200)
+            .with_header("Transfer-Encoding", "gzip")
+            .with_body(Body::chunked(chunks))
+            .write_to(&mut out)
+            .unwrap();
+
+        assert_eq!(written, 20);
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+             5\r\nhello\r\nf\r\n, chunked world\r\n0\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn reader_bodies_use_their_length_when_known() {
+        let mut out = Vec::new();
+        Response::new(200)
+            .with_body(Body::reader(&b"abcdef"[..], Some(3)))
+            .write_to(&mut out)
+            .unwrap();
+        assert_eq!(out, b"HTTP/1.1 200 OK\r\nContent-Length: 3\r\n\r\nabc");
+
+        let mut out = Vec::new();
+        let err = Response::new(200)
+            .with_body(Body::reader(&b"ab"[..], Some(3)))
+            .write_to(&mut out)
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+
+        let mut out = Vec::new();
+        Response::new(200)
+            .with_body(Body::reader(&b"abc"[..], None))
+            .write_to(&mut out)
+            .unwrap();
+        assert!(out.ends_with(b"chunked\r\n\r\n3\r\nabc\r\n0\r\n\r\n"));
+    }
+
+    #[test]
+    fn http10_bodies_of_unknown_length_end_with_the_connection() {
+        let mut response = Response::new(200).with_body(Body::reader(&b"abc"[..], None));
+        assert!(response.needs_close(Method::Get, Version::Http10));
+        assert!(!response.needs_close(Method::Head, Version::Http10));
+        assert!(!response.needs_close(Method::Get, Version::Http11));
+
+        let mut out = Vec::new();
+        response
+            .write_for(&mut out, Method::Get, Version::Http10)
+            .unwrap();
+        assert_eq!(out, b"HTTP/1.1 200 OK\r\n\r\nabc");
+    }
+}
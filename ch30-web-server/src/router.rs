@@ -0,0 +1,437 @@
+use std::sync::Arc;
+use std::time::Instant;
+
+use crate::metrics::RequestMetrics;
+use crate::request::{percent_decode, Method, Request};
+use crate::response::Response;
+
+/// Something that turns a request into a response.
+///
+/// Implemented for every `Fn(Request) -> Response` closure, so plain
+/// functions can be registered directly.
+pub trait Handler: Send + Sync + 'static {
+    fn handle(&self, request: Request) -> Response;
+
+    /// Whether the body of `request`, whose header has just arrived, is
+    /// left on the connection for the handler to read; see
+    /// [`Request::body_reader`].
+    fn streams_body(&self, _request: &Request) -> bool {
+        false
+    }
+}
+
+impl<F> Handler for F
+where
+    F: Fn(Request) -> Response + Send + Sync + 'static,
+{
+    fn handle(&self, request: Request) -> Response {
+        self(request)
+    }
+}
+
+/// Path parameters captured while matching a route.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Params {
+    entries: Vec<(String, String)>,
+}
+
+impl Params {
+    pub fn new() -> Params {
+        Params::default()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.entries
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn insert(&mut self, name: impl Into<String>, value: impl Into<String>) {
+        self.entries.push((name.into(), value.into()));
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.entries.iter().map(|(n, v)| (n.as_str(), v.as_str()))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Segment {
+    Static(String),
+    Param(String),
+    Wildcard(String),
+}
+
+#[derive(Debug, Clone)]
+struct Pattern {
+    source: String,
+    segments: Vec<Segment>,
+}
+
+impl Pattern {
+    fn parse(pattern: &str) -> Pattern {
+        assert!(
+            pattern.starts_with('/'),
+            "route pattern must start with '/': {pattern}"
+        );
+
+        let parts: Vec<&str> = pattern[1..].split('/').collect();
+        let mut segments = Vec::with_capacity(parts.len());
+        for (i, part) in parts.iter().enumerate() {
+            let segment = if let Some(name) = part.strip_prefix(':') {
+                assert!(!name.is_empty(), "empty parameter name in {pattern}");
+                Segment::Param(name.to_string())
+            } else if let Some(name) = part.strip_prefix('*') {
+                assert!(
+                    i == parts.len() - 1,
+                    "wildcard must be the last segment in {pattern}"
+                );
+                Segment::Wildcard(name.to_string())
+            } else {
+                Segment::Static(part.to_string())
+            };
+            segments.push(segment);
+        }
+        Pattern {
+            source: pattern.to_string(),
+            segments,
+        }
+    }
+
+    /// Matches `path` against the pattern, returning the captured params.
+    fn matches(&self, path: &str) -> Option<Params> {
+        let mut params = Params::new();
+        let mut parts = path.strip_prefix('/')?.split('/');
+
+        for segment in &self.segments {
+            match segment {
+                Segment::Wildcard(name) => {
+                    let rest: Vec<&str> = parts.by_ref().collect();
+                    let rest = percent_decode(&rest.join("/"))?;
+                    if !name.is_empty() {
+                        params.insert(name.as_str(), rest);
+                    }
+                    return Some(params);
+                }
+                Segment::Static(s) => {
+                    if parts.next()? != s {
+                        return None;
+                    }
+                }
+                Segment::Param(name) => {
+                    let part = parts.next().filter(|p| !p.is_empty())?;
+                    params.insert(name.as_str(), percent_decode(part)?);
+                }
+            }
+        }
+
+        match parts.next() {
+            None => Some(params),
+            Some(_) => None,
+        }
+    }
+
+    /// Ranks how specific the pattern is: static segments beat parameters,
+    /// which beat wildcards.
+    fn rank(&self) -> Vec<u8> {
+        self.segments
+            .iter()
+            .map(|s| match s {
+                Segment::Static(_) => 2,
+                Segment::Param(_) => 1,
+                Segment::Wildcard(_) => 0,
+            })
+            .collect()
+    }
+}
+
+struct Route {
+    method: Method,
+    pattern: Pattern,
+    handler: Box<dyn Handler>,
+    streams_body: bool,
+}
+
+/// Dispatches requests to handlers by method and path pattern.
+///
+/// Patterns are made of `/`-separated segments. A segment starting with `:`
+/// captures one path segment, and a final segment starting with `*` captures
+/// the rest of the path:
+///
+/// ```
+/// use ch30_web_server::{Request, Response, Router};
+///
+/// let router = Router::new()
+///     .get("/users/:id", |req: Request| {
+///         Response::text(200, format!("user {}", req.param("id").unwrap()))
+///     })
+///     .get("/static/*path", |req: Request| {
+///         Response::text(200, req.param("path").unwrap().to_string())
+///     });
+/// ```
+///
+/// `HEAD` requests fall back to the `GET` route for the same path. When the
+/// path matches a route but the method does not, the router answers
+/// `405 Method Not Allowed` with an `Allow` header listing the methods that
+/// would have matched.
+pub struct Router {
+    routes: Vec<Route>,
+    not_found: Box<dyn Handler>,
+    metrics: Option<Arc<RequestMetrics>>,
+}
+
+impl Router {
+    pub fn new() -> Router {
+        Router {
+            routes: Vec::new(),
+            not_found: Box::new(|_: Request| Response::text(404, "Not Found")),
+            metrics: None,
+        }
+    }
+
+    /// Registers `handler` for `method` requests matching `pattern`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the pattern does not start with `/`, has an empty parameter
+    /// name, or has a wildcard that is not the last segment.
+    pub fn route(mut self, method: Method, pattern: &str, handler: impl Handler) -> Router {
+        self.routes.push(Route {
+            method,
+            pattern: Pattern::parse(pattern),
+            handler: Box::new(handler),
+            streams_body: false,
+        });
+        self
+    }
+
+    /// Registers `handler` like [`route`](Self::route), but leaves the
+    /// request body on the connection for the handler to read with
+    /// [`Request::body_reader`] or [`Request::multipart`].
+    ///
+    /// Such a body is never held in memory as a whole, and is not bounded by
+    /// [`Limits::max_body_size`](crate::Limits::max_body_size): the handler
+    /// bounds what it reads, as [`FormLimits`](crate::FormLimits) does for
+    /// uploads. It still has to arrive within the body timeout.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the pattern is invalid, as [`route`](Self::route) does.
+    pub fn streaming(mut self, method: Method, pattern: &str, handler: impl Handler) -> Router {
+        self = self.route(method, pattern, handler);
+        self.routes.last_mut().expect("just added").streams_body = true;
+        self
+    }
+
+    pub fn get(self, pattern: &str, handler: impl Handler) -> Router {
+        self.route(Method::Get, pattern, handler)
+    }
+
+    pub fn post(self, pattern: &str, handler: impl Handler) -> Router {
+        self.route(Method::Post, pattern, handler)
+    }
+
+    pub fn put(self, pattern: &str, handler: impl Handler) -> Router {
+        self.route(Method::Put, pattern, handler)
+    }
+
+    pub fn delete(self, pattern: &str, handler: impl Handler) -> Router {
+        self.route(Method::Delete, pattern, handler)
+    }
+
+    /// Sets the handler used when no route matches the path.
+    pub fn not_found(mut self, handler: impl Handler) -> Router {
+        self.not_found = Box::new(handler);
+        self
+    }
+
+    /// Records the status and latency of every response in `metrics`, by
+    /// route pattern. Requests that match no route count as `(unmatched)`.
+    pub fn metrics(mut self, metrics: Arc<RequestMetrics>) -> Router {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Finds the best route for `request`. Without one, returns the methods
+    /// that routes for its path allow.
+    fn find(&self, request: &Request) -> Result<(&Route, Params), Vec<Method>> {
+        let mut best: Option<(&Route, Params)> = None;
+        let mut allowed: Vec<Method> = Vec::new();
+
+        for route in &self.routes {
+            let Some(params) = route.pattern.matches(&request.path) else {
+                continue;
+            };
+            // HEAD is answered by the GET handler unless one is registered.
+            let head_via_get = request.method == Method::Head && route.method == Method::Get;
+            if route.method != request.method && !head_via_get {
+                let methods: &[Method] = match route.method {
+                    Method::Get => &[Method::Get, Method::Head],
+                    _ => &[route.method],
+                };
+                for method in methods {
+                    if !allowed.contains(method) {
+                        allowed.push(*method);
+                    }
+                }
+                continue;
+            }
+            let key = |r: &Route| (r.pattern.rank(), r.method == request.method);
+            let better = match &best {
+                Some((current, _)) => key(route) > key(current),
+                None => true,
+            };
+            if better {
+                best = Some((route, params));
+            }
+        }
+        best.ok_or(allowed)
+    }
+
+    /// Runs the handler of the best matching route and returns its response
+    /// with the route's pattern.
+    fn dispatch(&self, mut request: Request) -> (Response, &str) {
+        match self.find(&request) {
+            Ok((route, params)) => {
+                request.params = params;
+                (route.handler.handle(request), &route.pattern.source)
+            }
+            Err(allowed) if !allowed.is_empty() => {
+                let allow: Vec<&str> = allowed.iter().map(Method::as_str).collect();
+                let response = Response::text(405, "Method Not Allowed")
+                    .with_header("Allow", allow.join(", "));
+                (response, UNMATCHED)
+            }
+            Err(_) => (self.not_found.handle(request), UNMATCHED),
+        }
+    }
+}
+
+impl Default for Router {
+    fn default() -> Router {
+        Router::new()
+    }
+}
+
+/// The route label for requests that match no route.
+const UNMATCHED: &str = "(unmatched)";
+
+impl Handler for Router {
+    fn handle(&self, request: Request) -> Response {
+        let Some(metrics) = &self.metrics else {
+            return self.dispatch(request).0;
+        };
+        let (method, start) = (request.method, Instant::now());
+        let (response, route) = self.dispatch(request);
+        metrics.record(method, route, response.status, start.elapsed());
+        response
+    }
+
+    fn streams_body(&self, request: &Request) -> bool {
+        self.find(request)
+            .is_ok_and(|(route, _)| route.streams_body || route.handler.streams_body(request))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::request::RequestParser;
+
+    fn request(method: &str, path: &str) -> Request {
+        let mut parser = RequestParser::new();
+        parser.feed(format!("{method} {path} HTTP/1.1\r\nHost: test\r\n\r\n").as_bytes());
+        parser.parse().unwrap().unwrap()
+    }
+
+    fn body(response: Response) -> String {
+        String::from_utf8(response.body.as_bytes().unwrap().to_vec()).unwrap()
+    }
+
+    fn echo(name: &'static str) -> impl Handler {
+        move |req: Request| {
+            let params: Vec<String> = req.params.iter().map(|(k, v)| format!("{k}={v}")).collect();
+            Response::text(200, format!("{name} {}", params.join(",")))
+        }
+    }
+
+    #[test]
+    fn captures_params_and_wildcards() {
+        let router = Router::new()
+            .get("/users/:id", echo("user"))
+            .get("/users/:id/posts/:post", echo("post"))
+            .get("/static/*path", echo("static"));
+
+        assert_eq!(
+            body(router.handle(request("GET", "/users/42"))),
+            "user id=42"
+        );
+        assert_eq!(
+            body(router.handle(request("GET", "/users/7/posts/hello%20world"))),
+            "post id=7,post=hello world"
+        );
+        assert_eq!(
+            body(router.handle(request("GET", "/static/css/site.css"))),
+            "static path=css/site.css"
+        );
+        assert_eq!(router.handle(request("GET", "/users/")).status, 404);
+        assert_eq!(router.handle(request("GET", "/users/1/extra")).status, 404);
+    }
+
+    #[test]
+    fn prefers_static_segments() {
+        let router = Router::new()
+            .get("/users/:id", echo("param"))
+            .get("/users/me", echo("me"))
+            .get("/*rest", echo("fallback"));
+
+        assert_eq!(body(router.handle(request("GET", "/users/me"))), "me ");
+        assert_eq!(
+            body(router.handle(request("GET", "/users/5"))),
+            "param id=5"
+        );
+        assert_eq!(
+            body(router.handle(request("GET", "/about"))),
+            "fallback rest=about"
+        );
+    }
+
+    #[test]
+    fn method_not_allowed_lists_methods() {
+        let router = Router::new()
+            .get("/items/:id", echo("get"))
+            .delete("/items/:id", echo("delete"));
+
+        let response = router.handle(request("POST", "/items/1"));
+        assert_eq!(response.status, 405);
+        assert_eq!(response.headers.get("Allow"), Some("GET, HEAD, DELETE"));
+    }
+
+    #[test]
+    fn head_falls_back_to_get() {
+        let router = Router::new().get("/", echo("get"));
+
+        assert_eq!(body(router.handle(request("HEAD", "/"))), "get ");
+    }
+
+    #[test]
+    fn custom_not_found() {
+        let router = Router::new().not_found(|_: Request| Response::text(404, "nothing here"));
+
+        assert_eq!(
+            body(router.handle(request("GET", "/missing"))),
+            "nothing here"
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "wildcard must be the last segment")]
+    fn wildcard_must_be_last() {
+        let _ = Router::new().get("/*rest/more", echo("bad"));
+    }
+}
@@ -0,0 +1,430 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::process;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::thread;
+use std::time::Duration;
+
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
+
+use crate::connection::{reject_connection, serve_connection, ConnectionConfig};
+use crate::event_loop;
+use crate::pool::{ShutdownReport, ThreadPool};
+use crate::request::Request;
+use crate::response::Response;
+use crate::router::Handler;
+#[cfg(feature = "tls")]
+use crate::tls::{TlsAcceptor, TlsConfig};
+
+/// Accepts connections and serves each one on a [`ThreadPool`] until it is
+/// told to shut down.
+///
+/// Shutting down stops accepting, closes connections that are waiting for
+/// their next request and lets requests already being handled finish. Once
+/// the drain timeout has passed, the connections still open are closed by
+/// force.
+///
+/// ```no_run
+/// use ch30_web_server::{Request, Response, Server, ThreadPool};
+///
+/// let server = Server::bind("127.0.0.1:7878", ThreadPool::new(4)).unwrap();
+/// server.shutdown_on_signals().unwrap();
+/// let report = server.serve(|_: Request| Response::text(200, "hello"));
+/// ```
+pub struct Server {
+    listener: TcpListener,
+    pool: ThreadPool,
+    connection: ConnectionConfig,
+    drain_timeout: Duration,
+    retry_after: Duration,
+    event_loop: bool,
+    state: Arc<ServerState>,
+    #[cfg(feature = "tls")]
+    tls: Option<TlsAcceptor>,
+}
+
+struct ServerState {
+    shutting_down: AtomicBool,
+    /// Where to connect to wake up the accept loop.
+    wake_addr: SocketAddr,
+    /// Clones of the open connections, so they can be closed from outside.
+    open: Mutex<HashMap<u64, TcpStream>>,
+    next_id: AtomicU64,
+}
+
+impl ServerState {
+    fn open(&self) -> MutexGuard<'_, HashMap<u64, TcpStream>> {
+        self.open.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Tells a running [`Server`] to shut down. Cheap to clone and usable from
+/// any thread.
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    state: Arc<ServerState>,
+}
+
+impl ShutdownHandle {
+    /// Starts a graceful shutdown. Calling it again has no effect.
+    pub fn shutdown(&self) {
+        if self.state.shutting_down.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        // The accept loop is blocked in `accept`; a connection of our own
+        // wakes it up so it can see the flag.
+        let _ = TcpStream::connect_timeout(&self.state.wake_addr, Duration::from_secs(1));
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        self.state.shutting_down.load(Ordering::SeqCst)
+    }
+}
+
+impl Server {
+    /// Creates a server for an already bound listener.
+    pub fn new(listener: TcpListener, pool: ThreadPool) -> io::Result<Server> {
+        let mut wake_addr = listener.local_addr()?;
+        if wake_addr.ip().is_unspecified() {
+            let loopback = match wake_addr.ip() {
+                IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::LOCALHOST),
+                IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::LOCALHOST),
+            };
+            wake_addr.set_ip(loopback);
+        }
+
+        Ok(Server {
+            listener,
+            pool,
+            connection: ConnectionConfig::default(),
+            drain_timeout: Duration::from_secs(30),
+            retry_after: Duration::from_secs(1),
+            event_loop: false,
+            state: Arc::new(ServerState {
+                shutting_down: AtomicBool::new(false),
+                wake_addr,
+                open: Mutex::new(HashMap::new()),
+                next_id: AtomicU64::new(0),
+            }),
+            #[cfg(feature = "tls")]
+            tls: None,
+        })
+    }
+
+    /// Binds a listener to `addr` and creates a server for it.
+    pub fn bind(addr: impl std::net::ToSocketAddrs, pool: ThreadPool) -> io::Result<Server> {
+        Server::new(TcpListener::bind(addr)?, pool)
+    }
+
+    pub fn connection_config(mut self, config: ConnectionConfig) -> Server {
+        self.connection = config;
+        self
+    }
+
+    /// Sets how long shutdown waits for in-flight requests before closing
+    /// their connections. Defaults to 30 seconds.
+    pub fn drain_timeout(mut self, timeout: Duration) -> Server {
+        self.drain_timeout = timeout;
+        self
+    }
+
+    /// Sets the `Retry-After` sent with `503` when the pool has no room for
+    /// a connection. Defaults to 1 second.
+    pub fn retry_after(mut self, retry_after: Duration) -> Server {
+        self.retry_after = retry_after;
+        self
+    }
+
+    /// Drives every connection from one thread waiting on socket readiness
+    /// (epoll on Linux) and only hands complete requests to the pool, instead
+    /// of giving each connection a worker for its whole life. This lets a
+    /// few workers hold thousands of idle keep-alive connections.
+    ///
+    /// Bodies other than bytes are passed to the loop a piece at a time, and
+    /// keep their worker busy until they end. The loop never waits for the
+    /// pool: a request it has no room for gets `503 Service Unavailable`
+    /// whatever its [`OverflowPolicy`](crate::OverflowPolicy). HTTPS is not
+    /// supported in this mode; with [`Server::tls`] set, connections get a
+    /// worker each as usual.
+    pub fn event_loop(mut self, enabled: bool) -> Server {
+        self.event_loop = enabled;
+        self
+    }
+
+    /// Serves HTTPS instead of plain HTTP. The handshake runs on the worker
+    /// and must finish within the connection's header timeout.
+    #[cfg(feature = "tls")]
+    pub fn tls(mut self, config: TlsConfig) -> Server {
+        self.tls = Some(config.into_acceptor());
+        self
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle {
+            state: Arc::clone(&self.state),
+        }
+    }
+
+    /// Shuts the server down gracefully on `SIGINT` or `SIGTERM`. A second
+    /// signal exits the process immediately.
+    pub fn shutdown_on_signals(&self) -> io::Result<()> {
+        let mut signals = Signals::new([SIGINT, SIGTERM])?;
+        let handle = self.shutdown_handle();
+        thread::Builder::new()
+            .name("signals".to_string())
+            .spawn(move || {
+                for signal in signals.forever() {
+                    if handle.is_shutting_down() {
+                        warn!("Received signal {signal} again; exiting now.");
+                        process::exit(128 + signal);
+                    }
+                    info!("Received signal {signal}; shutting down gracefully.");
+                    handle.shutdown();
+                }
+            })?;
+        Ok(())
+    }
+
+    /// Serves connections until [`ShutdownHandle::shutdown`] is called, then
+    /// drains the pool and reports how its workers shut down.
+    pub fn serve(self, handler: impl Handler) -> ShutdownReport {
+        let handler = Arc::new(Draining {
+            inner: handler,
+            state: Arc::clone(&self.state),
+        });
+
+        if self.event_loop {
+            #[cfg(feature = "tls")]
+            let tls = self.tls.is_some();
+            #[cfg(not(feature = "tls"))]
+            let tls = false;
+            if tls {
+                warn!("The event loop does not support TLS; giving each connection a worker.");
+            } else {
+                return self.serve_event_loop(handler);
+            }
+        }
+
+        for stream in self.listener.incoming() {
+            if self.state.shutting_down.load(Ordering::SeqCst) {
+                break;
+            }
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    error!("Failed to accept connection: {e}");
+                    continue;
+                }
+            };
+            self.dispatch(stream, &handler);
+        }
+        drop(self.listener);
+
+        // Connections waiting for a request see end of file and close;
+        // handlers that are running can still write their response.
+        for stream in self.state.open().values() {
+            let _ = stream.shutdown(Shutdown::Read);
+        }
+        let report = self.pool.shutdown_timeout(self.drain_timeout);
+        for stream in self.state.open().values() {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+        report
+    }
+
+    fn serve_event_loop<H: Handler>(self, handler: Arc<Draining<H>>) -> ShutdownReport {
+        let settings = event_loop::Settings {
+            connection: self.connection,
+            drain_timeout: self.drain_timeout,
+            retry_after: self.retry_after,
+            shutting_down: &self.state.shutting_down,
+        };
+        event_loop::run(self.listener, self.pool, handler, settings)
+    }
+
+    fn dispatch<H: Handler>(&self, stream: TcpStream, handler: &Arc<Draining<H>>) {
+        // Keep a handle to answer 503 with if the pool rejects the job.
+        let (fallback, tracked) = match (stream.try_clone(), stream.try_clone()) {
+            (Ok(fallback), Ok(tracked)) => (fallback, tracked),
+            (Err(e), _) | (_, Err(e)) => {
+                error!("Failed to clone connection: {e}");
+                return;
+            }
+        };
+        let id = self.state.next_id.fetch_add(1, Ordering::Relaxed);
+        self.state.open().insert(id, tracked);
+
+        let state = Arc::clone(&self.state);
+        let handler = Arc::clone(handler);
+        let config = self.connection;
+        #[cfg(feature = "tls")]
+        let tls = self.tls.clone();
+        let result = self.pool.try_execute(move || {
+            #[cfg(feature = "tls")]
+            let result = match tls {
+                Some(tls) => match tls.accept(stream, config.header_timeout) {
+                    Ok(stream) => serve_connection(stream, &*handler, &config),
+                    Err(e) => {
+                        debug!("TLS handshake failed: {e}");
+                        Ok(())
+                    }
+                },
+                None => serve_connection(stream, &*handler, &config),
+            };
+            #[cfg(not(feature = "tls"))]
+            let result = serve_connection(stream, &*handler, &config);
+            if let Err(e) = result {
+                debug!("Connection error: {e}");
+            }
+            state.open().remove(&id);
+        });
+
+        if let Err(e) = result {
+            self.state.open().remove(&id);
+            warn!("Rejecting connection: {e}");
+            // A TLS client cannot read a plain-text 503; just close.
+            #[cfg(feature = "tls")]
+            if self.tls.is_some() {
+                return;
+            }
+            if let Err(e) = reject_connection(fallback, self.retry_after) {
+                debug!("Failed to send 503: {e}");
+            }
+        }
+    }
+}
+
+/// Asks clients to close their connection once shutdown has started.
+struct Draining<H> {
+    inner: H,
+    state: Arc<ServerState>,
+}
+
+impl<H: Handler> Handler for Draining<H> {
+    fn handle(&self, request: Request) -> Response {
+        let response = self.inner.handle(request);
+        // A connection switching protocols is no longer HTTP's to close.
+        if self.state.shutting_down.load(Ordering::SeqCst) && response.status != 101 {
+            response.with_header("Connection", "close")
+        } else {
+            response
+        }
+    }
+
+    fn streams_body(&self, request: &Request) -> bool {
+        self.inner.streams_body(request)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::sync::mpsc;
+    use std::time::Instant;
+
+    /// Starts a server whose `/slow` route waits for `release` and returns
+    /// its address, shutdown handle and the thread running it.
+    fn start(
+        drain_timeout: Duration,
+        started: mpsc::Sender<()>,
+        release: mpsc::Receiver<()>,
+    ) -> (
+        SocketAddr,
+        ShutdownHandle,
+        thread::JoinHandle<ShutdownReport>,
+    ) {
+        let server = Server::bind("127.0.0.1:0", ThreadPool::new(2))
+            .unwrap()
+            .drain_timeout(drain_timeout);
+        let addr = server.local_addr().unwrap();
+        let handle = server.shutdown_handle();
+
+        let started = Mutex::new(started);
+        let release = Mutex::new(release);
+        let handler = move |req: Request| {
+            if req.path == "/slow" {
+                started.lock().unwrap().send(()).unwrap();
+                let _ = release
+                    .lock()
+                    .unwrap()
+                    .recv_timeout(Duration::from_secs(10));
+            }
+            Response::text(200, req.path)
+        };
+        (addr, handle, thread::spawn(move || server.serve(handler)))
+    }
+
+    fn request(addr: SocketAddr, path: &str) -> TcpStream {
+        let mut client = TcpStream::connect(addr).unwrap();
+        client
+            .write_all(format!("GET {path} HTTP/1.1\r\nHost: a\r\n\r\n").as_bytes())
+            .unwrap();
+        client
+    }
+
+    fn read_to_close(mut stream: TcpStream) -> String {
+        let mut out = Vec::new();
+        let _ = stream.read_to_end(&mut out);
+        String::from_utf8_lossy(&out).into_owned()
+    }
+
+    #[test]
+    fn shutdown_lets_in_flight_requests_finish() {
+        let (started_tx, started_rx) = mpsc::channel();
+        let (release_tx, release_rx) = mpsc::channel();
+        let (addr, handle, server) = start(Duration::from_secs(5), started_tx, release_rx);
+
+        let slow = request(addr, "/slow");
+        started_rx.recv().unwrap();
+        handle.shutdown();
+        release_tx.send(()).unwrap();
+
+        let out = read_to_close(slow);
+        assert!(out.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(out.contains("Connection: close\r\n"));
+        assert!(server.join().unwrap().is_clean());
+        assert!(TcpStream::connect(addr).is_err());
+    }
+
+    #[test]
+    fn shutdown_closes_idle_connections() {
+        let (started_tx, _started_rx) = mpsc::channel();
+        let (_release_tx, release_rx) = mpsc::channel();
+        let (addr, handle, server) = start(Duration::from_secs(5), started_tx, release_rx);
+
+        // A keep-alive connection that has been answered and sits idle.
+        let mut idle = request(addr, "/");
+        let mut buf = [0; 1024];
+        assert!(idle.read(&mut buf).unwrap() > 0);
+
+        let start = Instant::now();
+        handle.shutdown();
+        assert!(server.join().unwrap().is_clean());
+        assert!(start.elapsed() < Duration::from_secs(2));
+        assert_eq!(idle.read(&mut buf).unwrap(), 0);
+    }
+
+    #[test]
+    fn stragglers_are_closed_after_drain_timeout() {
+        let (started_tx, started_rx) = mpsc::channel();
+        let (release_tx, release_rx) = mpsc::channel();
+        let (addr, handle, server) = start(Duration::from_millis(100), started_tx, release_rx);
+
+        let slow = request(addr, "/slow");
+        started_rx.recv().unwrap();
+        handle.shutdown();
+
+        let report = server.join().unwrap();
+        assert_eq!(report.unfinished_workers.len(), 1);
+        assert_eq!(read_to_close(slow), "");
+        drop(release_tx);
+    }
+}
@@ -0,0 +1,498 @@
+use std::fs::{self, File, Metadata};
+use std::io::{Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::compression;
+use crate::request::{percent_decode, Method, Request};
+use crate::response::{Body, Response};
+use crate::router::Handler;
+
+/// Serves files from a directory on disk.
+///
+/// The file path is taken from a `*path` wildcard parameter when the handler
+/// is mounted on a [`Router`](crate::Router) route such as `/static/*path`,
+/// and from the whole request path otherwise.
+///
+/// Files are streamed rather than read into memory. The handler sets
+/// `Content-Type` from the file extension, answers conditional requests
+/// (`If-None-Match`, `If-Modified-Since`) with `304 Not Modified`, serves
+/// single byte ranges with `206 Partial Content`, and falls back to an index
+/// file for directories. Paths that try to leave the root with `..` are
+/// rejected with `403 Forbidden`.
+///
+/// With [`StaticFiles::precompressed`] enabled, a `.gz` file next to the
+/// requested one is sent instead to clients that accept gzip.
+pub struct StaticFiles {
+    root: PathBuf,
+    index: Option<String>,
+    precompressed: bool,
+}
+
+impl StaticFiles {
+    pub fn new(root: impl Into<PathBuf>) -> StaticFiles {
+        StaticFiles {
+            root: root.into(),
+            index: Some("index.html".to_string()),
+            precompressed: false,
+        }
+    }
+
+    /// Serves `name.gz`, if it exists, to clients asking for `name` that
+    /// accept gzip. Disabled by default.
+    pub fn precompressed(mut self, enabled: bool) -> StaticFiles {
+        self.precompressed = enabled;
+        self
+    }
+
+    /// Sets the file served for directory requests, or disables it with `None`.
+    pub fn index_file(mut self, name: Option<&str>) -> StaticFiles {
+        self.index = name.map(str::to_string);
+        self
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Maps a percent-decoded request path onto a file below the root.
+    fn resolve(&self, decoded: &str) -> Result<PathBuf, Response> {
+        let mut path = self.root.clone();
+        for segment in decoded.split('/') {
+            match segment {
+                "" | "." => {}
+                ".." => return Err(Response::text(403, "Forbidden")),
+                s if s.contains(['\\', '\0']) || Path::new(s).is_absolute() => {
+                    return Err(Response::text(403, "Forbidden"));
+                }
+                s => path.push(s),
+            }
+        }
+
+        // Symlinks must not lead outside the root either.
+        let root = self.root.canonicalize().map_err(|_| not_found())?;
+        let canonical = path.canonicalize().map_err(|_| not_found())?;
+        if !canonical.starts_with(&root) {
+            return Err(Response::text(403, "Forbidden"));
+        }
+        Ok(canonical)
+    }
+
+    /// Finds the gzip sidecar of `path`, if there is one inside the root.
+    fn gzip_sidecar(&self, path: &Path) -> Option<(PathBuf, Metadata)> {
+        let mut name = path.file_name()?.to_os_string();
+        name.push(".gz");
+        let sidecar = path.with_file_name(name).canonicalize().ok()?;
+        let root = self.root.canonicalize().ok()?;
+        let metadata = fs::metadata(&sidecar).ok()?;
+        (sidecar.starts_with(root) && metadata.is_file()).then_some((sidecar, metadata))
+    }
+
+    fn serve(&self, request: &Request) -> Result<Response, Response> {
+        // The router has already decoded the wildcard parameter; decoding
+        // it again would turn `%25` into an escape of its own.
+        let request_path = match request.param("path") {
+            Some(path) => path.to_string(),
+            None => percent_decode(&request.path).ok_or_else(|| Response::new(400))?,
+        };
+        let mut path = self.resolve(&request_path)?;
+
+        let mut metadata = fs::metadata(&path).map_err(|_| not_found())?;
+        if metadata.is_dir() {
+            // Redirect so that relative links inside the index page work.
+            if !request.path.ends_with('/') {
+                let location = match &request.query {
+                    Some(query) => format!("{}/?{query}", request.path),
+                    None => format!("{}/", request.path),
+                };
+                return Ok(Response::new(301).with_header("Location", location));
+            }
+            let index = self.index.as_ref().ok_or_else(not_found)?;
+            path.push(index);
+            metadata = fs::metadata(&path).map_err(|_| not_found())?;
+            if !metadata.is_file() {
+                return Err(not_found());
+            }
+        }
+
+        let content_type = mime_type(&path);
+        let mut gzipped = false;
+        let sidecar = self
+            .precompressed
+            .then(|| self.gzip_sidecar(&path))
+            .flatten();
+        let has_sidecar = sidecar.is_some();
+        if let Some((sidecar, sidecar_metadata)) = sidecar {
+            let accepts_gzip = request
+                .headers
+                .get("Accept-Encoding")
+                .is_some_and(|accept| compression::quality(accept, "gzip") > 0);
+            if accepts_gzip {
+                path = sidecar;
+                metadata = sidecar_metadata;
+                gzipped = true;
+            }
+        }
+
+        let len = metadata.len();
+        let modified = metadata.modified().ok().map(truncate_to_seconds);
+        let mut etag = etag(len, modified);
+        if gzipped {
+            // The two representations must not share an entity tag.
+            etag.insert_str(etag.len() - 1, "-gz");
+        }
+
+        let mut response = Response::new(200)
+            .with_header("Content-Type", content_type)
+            .with_header("Accept-Ranges", "bytes")
+            .with_header("ETag", etag.as_str());
+        if gzipped {
+            response.headers.insert("Content-Encoding", "gzip");
+        }
+        if has_sidecar {
+            response.headers.append("Vary", "Accept-Encoding");
+        }
+        if let Some(modified) = modified {
+            response = response.with_header("Last-Modified", httpdate::fmt_http_date(modified));
+        }
+
+        if is_not_modified(request, &etag, modified) {
+            response.status = 304;
+            response.headers.remove("Content-Type");
+            return Ok(response);
+        }
+
+        let range = match request.headers.get("Range") {
+            Some(range) if if_range_matches(request, &etag, modified) => {
+                match parse_range(range, len) {
+                    Some(Ok(range)) => Some(range),
+                    Some(Err(())) => {
+                        return Ok(Response::text(416, "Range Not Satisfiable")
+                            .with_header("Content-Range", format!("bytes */{len}")));
+                    }
+                    None => None,
+                }
+            }
+            _ => None,
+        };
+
+        let mut file = File::open(&path).map_err(|_| not_found())?;
+        let (start, body_len) = match range {
+            Some((start, end)) => {
+                response.status = 206;
+                response
+                    .headers
+                    .insert("Content-Range", format!("bytes {start}-{end}/{len}"));
+                (start, end - start + 1)
+            }
+            None => (0, len),
+        };
+
+        if request.method == Method::Head {
+            response
+                .headers
+                .insert("Content-Length", body_len.to_string());
+            return Ok(response);
+        }
+
+        file.seek(SeekFrom::Start(start))
+            .map_err(|_| Response::new(500))?;
+        response.body = Body::File {
+            file,
+            len: body_len,
+        };
+        Ok(response)
+    }
+}
+
+impl Handler for StaticFiles {
+    fn handle(&self, request: Request) -> Response {
+        if !matches!(request.method, Method::Get | Method::Head) {
+            return Response::text(405, "Method Not Allowed").with_header("Allow", "GET, HEAD");
+        }
+        match self.serve(&request) {
+            Ok(response) | Err(response) => response,
+        }
+    }
+}
+
+fn not_found() -> Response {
+    Response::text(404, "Not Found")
+}
+
+/// Returns the `Content-Type` for a file based on its extension.
+pub fn mime_type(path: &Path) -> &'static str {
+    let ext = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(str::to_ascii_lowercase)
+        .unwrap_or_default();
+
+    match ext.as_str() {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "js" | "mjs" => "text/javascript; charset=utf-8",
+        "json" => "application/json",
+        "txt" => "text/plain; charset=utf-8",
+        "csv" => "text/csv; charset=utf-8",
+        "xml" => "application/xml",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "ico" => "image/x-icon",
+        "wasm" => "application/wasm",
+        "pdf" => "application/pdf",
+        "zip" => "application/zip",
+        "gz" => "application/gzip",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "mp4" => "video/mp4",
+        "mp3" => "audio/mpeg",
+        _ => "application/octet-stream",
+    }
+}
+
+// HTTP dates only have one second resolution.
+fn truncate_to_seconds(time: SystemTime) -> SystemTime {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    UNIX_EPOCH + Duration::from_secs(secs)
+}
+
+fn etag(len: u64, modified: Option<SystemTime>) -> String {
+    let secs = modified
+        .and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    format!("\"{len:x}-{secs:x}\"")
+}
+
+fn etag_matches(list: &str, etag: &str) -> bool {
+    list.split(',').map(str::trim).any(|tag| {
+        // If-None-Match uses weak comparison.
+        tag == "*" || tag.strip_prefix("W/").unwrap_or(tag) == etag
+    })
+}
+
+fn is_not_modified(request: &Request, etag: &str, modified: Option<SystemTime>) -> bool {
+    // If-None-Match takes precedence over If-Modified-Since (RFC 9110 13.2.2).
+    if let Some(list) = request.headers.get("If-None-Match") {
+        return etag_matches(list, etag);
+    }
+    match (request.headers.get("If-Modified-Since"), modified) {
+        (Some(since), Some(modified)) => httpdate::parse_http_date(since)
+            .map(|since| modified <= since)
+            .unwrap_or(false),
+        _ => false,
+    }
+}
+
+/// A range is only honoured if `If-Range` is absent or still matches.
+fn if_range_matches(request: &Request, etag: &str, modified: Option<SystemTime>) -> bool {
+    match request.headers.get("If-Range") {
+        None => true,
+        Some(value) if value.starts_with('"') => value == etag,
+        Some(value) => match (httpdate::parse_http_date(value), modified) {
+            (Ok(date), Some(modified)) => date == modified,
+            _ => false,
+        },
+    }
+}
+
+/// Parses a single `bytes=` range into inclusive offsets.
+///
+/// Returns `None` if the header should be ignored (unknown unit, several
+/// ranges, or malformed) and `Some(Err(()))` if it cannot be satisfied.
+fn parse_range(header: &str, len: u64) -> Option<Result<(u64, u64), ()>> {
+    let spec = header.trim().strip_prefix("bytes=")?;
+    if spec.contains(',') {
+        return None;
+    }
+    let (start, end) = spec.split_once('-')?;
+    let (start, end) = (start.trim(), end.trim());
+
+    let range = if start.is_empty() {
+        let suffix: u64 = end.parse().ok()?;
+        if suffix == 0 || len == 0 {
+            return Some(Err(()));
+        }
+        (len.saturating_sub(suffix), len - 1)
+    } else {
+        let start: u64 = start.parse().ok()?;
+        let end = match end {
+            "" => len.saturating_sub(1),
+            end => end.parse::<u64>().ok()?.min(len.saturating_sub(1)),
+        };
+        if start >= len || start > end {
+            return Some(Err(()));
+        }
+        (start, end)
+    };
+    Some(Ok(range))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::request::RequestParser;
+    use crate::router::Router;
+    use std::io::Read;
+    use tempfile::TempDir;
+
+    fn request(path: &str, headers: &str) -> Request {
+        let mut parser = RequestParser::new();
+        parser.feed(format!("GET {path} HTTP/1.1\r\nHost: test\r\n{headers}\r\n").as_bytes());
+        parser.parse().unwrap().unwrap()
+    }
+
+    fn read_body(response: Response) -> Vec<u8> {
+        match response.body {
+            Body::Bytes(bytes) => bytes,
+            Body::File { file, len } => {
+                let mut out = Vec::new();
+                file.take(len).read_to_end(&mut out).unwrap();
+                out
+            }
+            other => panic!("unexpected body {other:?}"),
+        }
+    }
+
+    fn site() -> (TempDir, StaticFiles) {
+        let dir = TempDir::new().unwrap();
+        fs::create_dir(dir.path().join("docs")).unwrap();
+        fs::write(dir.path().join("hello.txt"), "hello, world").unwrap();
+        fs::write(dir.path().join("docs/index.html"), "<h1>docs</h1>").unwrap();
+        let files = StaticFiles::new(dir.path());
+        (dir, files)
+    }
+
+    #[test]
+    fn serves_file_with_mime_type() {
+        let (_dir, files) = site();
+        let response = files.handle(request("/hello.txt", ""));
+
+        assert_eq!(response.status, 200);
+        assert_eq!(
+            response.headers.get("Content-Type"),
+            Some("text/plain; charset=utf-8")
+        );
+        assert_eq!(read_body(response), b"hello, world");
+    }
+
+    #[test]
+    fn directory_index_and_redirect() {
+        let (_dir, files) = site();
+
+        let response = files.handle(request("/docs", ""));
+        assert_eq!(response.status, 301);
+        assert_eq!(response.headers.get("Location"), Some("/docs/"));
+
+        let response = files.handle(request("/docs/", ""));
+        assert_eq!(read_body(response), b"<h1>docs</h1>");
+    }
+
+    #[test]
+    fn rejects_traversal() {
+        let (_dir, files) = site();
+
+        assert_eq!(files.handle(request("/../etc/passwd", "")).status, 403);
+        assert_eq!(
+            files.handle(request("/docs/%2e%2e/%2e%2e/x", "")).status,
+            403
+        );
+        assert_eq!(files.handle(request("/missing.txt", "")).status, 404);
+    }
+
+    #[test]
+    fn decodes_the_path_once() {
+        let (dir, files) = site();
+        fs::write(dir.path().join("100%.txt"), "percent").unwrap();
+        fs::write(dir.path().join("a%2Fb.txt"), "escaped").unwrap();
+
+        let response = files.handle(request("/100%25.txt", ""));
+        assert_eq!(read_body(response), b"percent");
+
+        let router = Router::new().get("/static/*path", files);
+        let response = router.handle(request("/static/100%25.txt", ""));
+        assert_eq!(read_body(response), b"percent");
+        let response = router.handle(request("/static/a%252Fb.txt", ""));
+        assert_eq!(read_body(response), b"escaped");
+    }
+
+    #[test]
+    fn byte_ranges() {
+        let (_dir, files) = site();
+
+        let response = files.handle(request("/hello.txt", "Range: bytes=7-\r\n"));
+        assert_eq!(response.status, 206);
+        assert_eq!(response.headers.get("Content-Range"), Some("bytes 7-11/12"));
+        assert_eq!(read_body(response), b"world");
+
+        let response = files.handle(request("/hello.txt", "Range: bytes=-5\r\n"));
+        assert_eq!(read_body(response), b"world");
+
+        let response = files.handle(request("/hello.txt", "Range: bytes=50-60\r\n"));
+        assert_eq!(response.status, 416);
+        assert_eq!(response.headers.get("Content-Range"), Some("bytes */12"));
+    }
+
+    #[test]
+    fn conditional_requests() {
+        let (_dir, files) = site();
+        let first = files.handle(request("/hello.txt", ""));
+        let etag = first.headers.get("ETag").unwrap().to_string();
+        let modified = first.headers.get("Last-Modified").unwrap().to_string();
+
+        let response = files.handle(request("/hello.txt", &format!("If-None-Match: {etag}\r\n")));
+        assert_eq!(response.status, 304);
+
+        let response = files.handle(request(
+            "/hello.txt",
+            &format!("If-Modified-Since: {modified}\r\n"),
+        ));
+        assert_eq!(response.status, 304);
+
+        let response = files.handle(request("/hello.txt", "If-None-Match: \"other\"\r\n"));
+        assert_eq!(response.status, 200);
+    }
+
+    #[test]
+    fn serves_gzip_sidecar_to_clients_that_accept_it() {
+        let (dir, files) = site();
+        fs::write(dir.path().join("hello.txt.gz"), b"gzipped bytes").unwrap();
+        let files = files.precompressed(true);
+
+        let response = files.handle(request("/hello.txt", "Accept-Encoding: gzip\r\n"));
+        assert_eq!(response.headers.get("Content-Encoding"), Some("gzip"));
+        assert_eq!(
+            response.headers.get("Content-Type"),
+            Some("text/plain; charset=utf-8")
+        );
+        assert_eq!(response.headers.get("Vary"), Some("Accept-Encoding"));
+        let gz_etag = response.headers.get("ETag").unwrap().to_string();
+        assert_eq!(read_body(response), b"gzipped bytes");
+
+        let response = files.handle(request("/hello.txt", "Accept-Encoding: gzip;q=0\r\n"));
+        assert!(!response.headers.contains("Content-Encoding"));
+        assert_eq!(response.headers.get("Vary"), Some("Accept-Encoding"));
+        assert_ne!(response.headers.get("ETag"), Some(gz_etag.as_str()));
+        assert_eq!(read_body(response), b"hello, world");
+
+        let response = files.handle(request("/docs/", "Accept-Encoding: gzip\r\n"));
+        assert!(!response.headers.contains("Vary"));
+    }
+
+    #[test]
+    fn range_parsing() {
+        assert_eq!(parse_range("bytes=0-4", 10), Some(Ok((0, 4))));
+        assert_eq!(parse_range("bytes=5-100", 10), Some(Ok((5, 9))));
+        assert_eq!(parse_range("bytes=-3", 10), Some(Ok((7, 9))));
+        assert_eq!(parse_range("bytes=10-", 10), Some(Err(())));
+        assert_eq!(parse_range("bytes=0-1,3-4", 10), None);
+        assert_eq!(parse_range("items=0-1", 10), None);
+    }
+}
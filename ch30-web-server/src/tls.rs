@@ -0,0 +1,331 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Write};
+use std::net::{Shutdown, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use rustls::crypto::ring;
+use rustls::pki_types::pem::{self, PemObject};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use rustls::{ServerConnection, StreamOwned};
+
+use crate::connection::Transport;
+
+/// A TLS connection accepted by the server.
+pub type TlsStream = StreamOwned<ServerConnection, TcpStream>;
+
+impl Transport for TlsStream {
+    fn socket(&self) -> &TcpStream {
+        &self.sock
+    }
+
+    fn shutdown_write(&mut self) -> io::Result<()> {
+        self.conn.send_close_notify();
+        self.flush()?;
+        self.sock.shutdown(Shutdown::Write)
+    }
+}
+
+/// Certificates for an HTTPS listener, chosen by the name the client asks
+/// for (SNI).
+///
+/// A host name may start with `*.` to match any single label in its place.
+/// Clients asking for a name without a certificate of its own, or for no
+/// name at all, get the default certificate; without one, their handshake
+/// fails.
+///
+/// ```no_run
+/// use ch30_web_server::TlsConfig;
+///
+/// let tls = TlsConfig::new()
+///     .certificate("certs/default.pem", "certs/default.key")?
+///     .host("*.example.test", "certs/example.pem", "certs/example.key")?;
+/// # Ok::<(), ch30_web_server::TlsError>(())
+/// ```
+#[derive(Debug, Default)]
+pub struct TlsConfig {
+    default: Option<Arc<CertifiedKey>>,
+    hosts: HashMap<String, Arc<CertifiedKey>>,
+}
+
+impl TlsConfig {
+    pub fn new() -> TlsConfig {
+        TlsConfig::default()
+    }
+
+    /// Loads the default certificate chain and private key from PEM files.
+    pub fn certificate(
+        mut self,
+        cert: impl AsRef<Path>,
+        key: impl AsRef<Path>,
+    ) -> Result<TlsConfig, TlsError> {
+        self.default = Some(load_certified_key(cert.as_ref(), key.as_ref())?);
+        Ok(self)
+    }
+
+    /// Loads the certificate chain and private key for `name` from PEM
+    /// files.
+    pub fn host(
+        mut self,
+        name: &str,
+        cert: impl AsRef<Path>,
+        key: impl AsRef<Path>,
+    ) -> Result<TlsConfig, TlsError> {
+        let key = load_certified_key(cert.as_ref(), key.as_ref())?;
+        self.hosts.insert(name.to_ascii_lowercase(), key);
+        Ok(self)
+    }
+
+    /// Builds the rustls configuration shared by all connections.
+    pub(crate) fn into_acceptor(self) -> TlsAcceptor {
+        let mut config =
+            rustls::ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+                .with_safe_default_protocol_versions()
+                .expect("the ring provider supports the default protocol versions")
+                .with_no_client_auth()
+                .with_cert_resolver(Arc::new(self));
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+        TlsAcceptor {
+            config: Arc::new(config),
+        }
+    }
+}
+
+impl ResolvesServerCert for TlsConfig {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let name = client_hello.server_name().map(str::to_ascii_lowercase);
+        let by_name = name.and_then(|name| {
+            self.hosts.get(&name).or_else(|| {
+                let (_, parent) = name.split_once('.')?;
+                self.hosts.get(&format!("*.{parent}"))
+            })
+        });
+        by_name.or(self.default.as_ref()).cloned()
+    }
+}
+
+fn load_certified_key(cert: &Path, key: &Path) -> Result<Arc<CertifiedKey>, TlsError> {
+    let pem_error = |path: &Path| {
+        let path = path.to_path_buf();
+        move |error| TlsError::Pem { path, error }
+    };
+
+    let chain = CertificateDer::pem_file_iter(cert)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(pem_error(cert))?;
+    if chain.is_empty() {
+        return Err(TlsError::NoCertificates(cert.to_path_buf()));
+    }
+    let private_key = PrivateKeyDer::from_pem_file(key).map_err(pem_error(key))?;
+
+    let certified =
+        CertifiedKey::from_der(chain, private_key, &ring::default_provider()).map_err(|error| {
+            TlsError::Rustls {
+                path: key.to_path_buf(),
+                error,
+            }
+        })?;
+    Ok(Arc::new(certified))
+}
+
+/// Performs TLS handshakes for the server.
+#[derive(Clone)]
+pub(crate) struct TlsAcceptor {
+    config: Arc<rustls::ServerConfig>,
+}
+
+impl TlsAcceptor {
+    /// Completes the handshake on `stream` within `timeout`.
+    pub(crate) fn accept(
+        &self,
+        mut stream: TcpStream,
+        timeout: Option<Duration>,
+    ) -> io::Result<TlsStream> {
+        let mut conn = ServerConnection::new(Arc::clone(&self.config)).map_err(io::Error::other)?;
+        let deadline = timeout.map(|t| Instant::now() + t);
+        while conn.is_handshaking() {
+            let left = match deadline {
+                Some(deadline) => {
+                    let left = deadline.saturating_duration_since(Instant::now());
+                    if left.is_zero() {
+                        return Err(io::Error::new(
+                            io::ErrorKind::TimedOut,
+                            "TLS handshake timed out",
+                        ));
+                    }
+                    Some(left)
+                }
+                None => None,
+            };
+            stream.set_read_timeout(left)?;
+            stream.set_write_timeout(left)?;
+            conn.complete_io(&mut stream)?;
+        }
+        Ok(StreamOwned::new(conn, stream))
+    }
+}
+
+/// Why TLS certificates could not be loaded.
+#[derive(Debug)]
+pub enum TlsError {
+    /// A PEM file could not be read or parsed.
+    Pem { path: PathBuf, error: pem::Error },
+    /// The certificate file holds no certificates.
+    NoCertificates(PathBuf),
+    /// The private key is unusable or does not match the certificate.
+    Rustls { path: PathBuf, error: rustls::Error },
+}
+
+impl fmt::Display for TlsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TlsError::Pem { path, error } => write!(f, "cannot load {}: {error}", path.display()),
+            TlsError::NoCertificates(path) => {
+                write!(f, "no certificates found in {}", path.display())
+            }
+            TlsError::Rustls { path, error } => {
+                write!(f, "unusable private key {}: {error}", path.display())
+            }
+        }
+    }
+}
+
+impl Error for TlsError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            TlsError::Pem { error, .. } => Some(error),
+            TlsError::NoCertificates(_) => None,
+            TlsError::Rustls { error, .. } => Some(error),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pool::ThreadPool;
+    use crate::request::Request;
+    use crate::response::Response;
+    use crate::server::Server;
+    use rustls::pki_types::ServerName;
+    use rustls::{ClientConfig, ClientConnection, RootCertStore};
+    use std::fs;
+    use std::io::Read;
+    use std::net::SocketAddr;
+    use std::thread;
+    use tempfile::TempDir;
+
+    /// Writes a self-signed certificate for `name` and returns its paths and
+    /// DER bytes.
+    fn self_signed(dir: &Path, name: &str) -> (PathBuf, PathBuf, CertificateDer<'static>) {
+        let generated = rcgen::generate_simple_self_signed(vec![name.to_string()]).unwrap();
+        let file = name.replace('*', "wildcard");
+        let cert = dir.join(format!("{file}.pem"));
+        let key = dir.join(format!("{file}.key"));
+        fs::write(&cert, generated.cert.pem()).unwrap();
+        fs::write(&key, generated.key_pair.serialize_pem()).unwrap();
+        (cert, key, generated.cert.der().clone())
+    }
+
+    /// Sends a request to `addr` over TLS with `server_name` as SNI and
+    /// returns the certificate the server presented and its response.
+    fn get(
+        addr: SocketAddr,
+        roots: &RootCertStore,
+        server_name: &str,
+    ) -> (CertificateDer<'static>, String) {
+        let config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots.clone())
+            .with_no_client_auth();
+        let name = ServerName::try_from(server_name.to_string()).unwrap();
+        let conn = ClientConnection::new(Arc::new(config), name).unwrap();
+        let mut stream = StreamOwned::new(conn, TcpStream::connect(addr).unwrap());
+
+        write!(
+            stream,
+            "GET / HTTP/1.1\r\nHost: {server_name}\r\nConnection: close\r\n\r\n"
+        )
+        .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        let cert = stream.conn.peer_certificates().unwrap()[0].clone();
+        (cert, response)
+    }
+
+    #[test]
+    fn serves_https_with_certificate_chosen_by_sni() {
+        let dir = TempDir::new().unwrap();
+        let (cert, key, default_der) = self_signed(dir.path(), "localhost");
+        let (host_cert, host_key, host_der) = self_signed(dir.path(), "*.example.test");
+        let tls = TlsConfig::new()
+            .certificate(&cert, &key)
+            .unwrap()
+            .host("*.example.test", &host_cert, &host_key)
+            .unwrap();
+
+        let server = Server::bind("127.0.0.1:0", ThreadPool::new(2))
+            .unwrap()
+            .tls(tls);
+        let addr = server.local_addr().unwrap();
+        let handle = server.shutdown_handle();
+        let running = thread::spawn(move || {
+            server.serve(|req: Request| {
+                Response::text(200, format!("hello {}", req.headers.get("Host").unwrap()))
+            })
+        });
+
+        let mut roots = RootCertStore::empty();
+        roots.add(default_der.clone()).unwrap();
+        roots.add(host_der.clone()).unwrap();
+
+        let (presented, response) = get(addr, &roots, "www.example.test");
+        assert_eq!(presented, host_der);
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+        assert!(response.ends_with("hello www.example.test"));
+
+        let (presented, response) = get(addr, &roots, "localhost");
+        assert_eq!(presented, default_der);
+        assert!(response.ends_with("hello localhost"));
+
+        // Plain HTTP on the TLS port fails the handshake without a response.
+        let mut plain = TcpStream::connect(addr).unwrap();
+        plain
+            .write_all(b"GET / HTTP/1.1\r\nHost: a\r\n\r\n")
+            .unwrap();
+        let mut out = Vec::new();
+        let _ = plain.read_to_end(&mut out);
+        assert!(!out.starts_with(b"HTTP/1.1 200"));
+
+        handle.shutdown();
+        assert!(running.join().unwrap().is_clean());
+    }
+
+    #[test]
+    fn rejects_unusable_pem_files() {
+        let dir = TempDir::new().unwrap();
+        let (cert, _, _) = self_signed(dir.path(), "localhost");
+        let (_, other_key, _) = self_signed(dir.path(), "other.test");
+        let empty = dir.path().join("empty.pem");
+        fs::write(&empty, "").unwrap();
+
+        assert!(matches!(
+            TlsConfig::new().certificate(&cert, &other_key),
+            Err(TlsError::Rustls { .. })
+        ));
+        assert!(matches!(
+            TlsConfig::new().certificate(&empty, &other_key),
+            Err(TlsError::NoCertificates(_))
+        ));
+        assert!(matches!(
+            TlsConfig::new().certificate(&cert, dir.path().join("missing.key")),
+            Err(TlsError::Pem { .. })
+        ));
+    }
+}
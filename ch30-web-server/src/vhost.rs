@@ -0,0 +1,139 @@
+use std::collections::HashMap;
+
+use crate::request::Request;
+use crate::response::Response;
+use crate::router::Handler;
+
+/// Picks the site that serves a request by its `Host` header.
+///
+/// A host name may start with `*.` to match any single label in its place,
+/// so `*.example.test` matches `www.example.test` but neither
+/// `example.test` nor `a.b.example.test`. Exact names win over wildcards.
+/// Requests for any other host, or without a `Host` header, go to the
+/// default site; without one they get `404 Not Found`.
+///
+/// ```
+/// use ch30_web_server::{Request, Response, VirtualHosts};
+///
+/// let sites = VirtualHosts::new()
+///     .host("api.example.test", |_: Request| Response::text(200, "api"))
+///     .host("*.example.test", |_: Request| Response::text(200, "www"))
+///     .default_host(|_: Request| Response::text(200, "default"));
+/// ```
+#[derive(Default)]
+pub struct VirtualHosts {
+    hosts: HashMap<String, Box<dyn Handler>>,
+    default: Option<Box<dyn Handler>>,
+}
+
+impl VirtualHosts {
+    pub fn new() -> VirtualHosts {
+        VirtualHosts::default()
+    }
+
+    /// Serves requests for `name` with `handler`. Names are compared
+    /// without regard to case.
+    pub fn host(mut self, name: &str, handler: impl Handler) -> VirtualHosts {
+        let name = name.trim_end_matches('.').to_ascii_lowercase();
+        self.hosts.insert(name, Box::new(handler));
+        self
+    }
+
+    /// Serves requests that match no host with `handler`.
+    pub fn default_host(mut self, handler: impl Handler) -> VirtualHosts {
+        self.default = Some(Box::new(handler));
+        self
+    }
+
+    fn select(&self, host: Option<&str>) -> Option<&dyn Handler> {
+        let by_name = host.map(host_name).and_then(|name| {
+            self.hosts.get(&name).or_else(|| {
+                let (_, parent) = name.split_once('.')?;
+                self.hosts.get(&format!("*.{parent}"))
+            })
+        });
+        by_name.or(self.default.as_ref()).map(|handler| &**handler)
+    }
+}
+
+impl Handler for VirtualHosts {
+    fn handle(&self, request: Request) -> Response {
+        match self.select(request.headers.get("Host")) {
+            Some(handler) => handler.handle(request),
+            None => Response::text(404, "Not Found"),
+        }
+    }
+
+    fn streams_body(&self, request: &Request) -> bool {
+        self.select(request.headers.get("Host"))
+            .is_some_and(|handler| handler.streams_body(request))
+    }
+}
+
+/// Returns the host name of a `Host` header value, without the port and in
+/// lower case.
+fn host_name(host: &str) -> String {
+    let host = host.trim();
+    let name = match host.find(']') {
+        // An IPv6 literal keeps its brackets.
+        Some(end) if host.starts_with('[') => &host[..=end],
+        _ => host.rsplit_once(':').map_or(host, |(name, _)| name),
+    };
+    name.trim_end_matches('.').to_ascii_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::request::RequestParser;
+
+    fn get(host: Option<&str>) -> Request {
+        let mut parser = RequestParser::new();
+        let head = match host {
+            Some(host) => format!("GET / HTTP/1.1\r\nHost: {host}\r\n\r\n"),
+            None => "GET / HTTP/1.0\r\n\r\n".to_string(),
+        };
+        parser.feed(head.as_bytes());
+        parser.parse().unwrap().unwrap()
+    }
+
+    fn site(name: &'static str) -> impl Handler {
+        move |_: Request| Response::text(200, name)
+    }
+
+    fn served_by(hosts: &VirtualHosts, host: Option<&str>) -> String {
+        let response = hosts.handle(get(host));
+        String::from_utf8(response.body.as_bytes().unwrap().to_vec()).unwrap()
+    }
+
+    #[test]
+    fn selects_exact_then_wildcard_then_default() {
+        let hosts = VirtualHosts::new()
+            .host("api.example.test", site("api"))
+            .host("*.Example.test", site("wildcard"))
+            .host("[::1]", site("ipv6"))
+            .default_host(site("default"));
+
+        let cases = [
+            (Some("api.example.test"), "api"),
+            (Some("API.Example.Test:8080"), "api"),
+            (Some("api.example.test."), "api"),
+            (Some("www.example.test"), "wildcard"),
+            (Some("example.test"), "default"),
+            (Some("a.b.example.test"), "default"),
+            (Some("[::1]:7878"), "ipv6"),
+            (Some("other.test"), "default"),
+            (None, "default"),
+        ];
+        for (host, expected) in cases {
+            assert_eq!(served_by(&hosts, host), expected, "{host:?}");
+        }
+    }
+
+    #[test]
+    fn unknown_host_without_default_is_not_found() {
+        let hosts = VirtualHosts::new().host("a.test", site("a"));
+        assert_eq!(hosts.handle(get(Some("b.test"))).status, 404);
+        assert_eq!(served_by(&hosts, Some("a.test")), "a");
+    }
+}
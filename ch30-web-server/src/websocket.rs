@@ -0,0 +1,793 @@
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+
+use crate::connection::{Transport, Upgraded};
+use crate::request::{Method, Request, Version};
+use crate::response::Response;
+use crate::router::Handler;
+
+/// Appended to the client's key before hashing it (RFC 6455 section 1.3).
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+const OP_CONTINUATION: u8 = 0x0;
+const OP_TEXT: u8 = 0x1;
+const OP_BINARY: u8 = 0x2;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xa;
+
+/// How long [`WebSocket::close`] waits for the peer's close frame.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Computes `Sec-WebSocket-Accept` for a client's `Sec-WebSocket-Key`.
+pub fn accept_key(key: &str) -> String {
+    let digest = sha1_smol::Sha1::from(format!("{key}{GUID}")).digest();
+    STANDARD.encode(digest.bytes())
+}
+
+/// Accepts WebSocket upgrades and runs `on_socket` for each connection.
+///
+/// By default the socket runs on the pool worker that served the upgrade
+/// request, which it occupies until `on_socket` returns. Long-lived sockets
+/// can get a thread of their own with
+/// [`dedicated_thread`](WebSocketHandler::dedicated_thread) instead; those
+/// are not closed by [`Server`](crate::Server) shutdown.
+///
+/// ```no_run
+/// use ch30_web_server::{Message, Router, WebSocketHandler};
+///
+/// let echo = WebSocketHandler::new(|_, mut socket| {
+///     while let Ok(message) = socket.recv() {
+///         if let Message::Text(_) | Message::Binary(_) = message {
+///             if socket.send(message).is_err() {
+///                 break;
+///             }
+///         }
+///     }
+/// });
+/// let router = Router::new().get("/echo", echo);
+/// ```
+pub struct WebSocketHandler {
+    on_socket: Arc<dyn Fn(Request, WebSocket) + Send + Sync>,
+    protocols: Vec<String>,
+    max_message_size: usize,
+    dedicated_thread: bool,
+}
+
+impl WebSocketHandler {
+    pub fn new(on_socket: impl Fn(Request, WebSocket) + Send + Sync + 'static) -> WebSocketHandler {
+        WebSocketHandler {
+            on_socket: Arc::new(on_socket),
+            protocols: Vec::new(),
+            max_message_size: 16 * 1024 * 1024,
+            dedicated_thread: false,
+        }
+    }
+
+    /// Sets the subprotocols the server speaks. The first one the client
+    /// offers is chosen; see [`WebSocket::protocol`].
+    pub fn protocols(mut self, protocols: &[&str]) -> WebSocketHandler {
+        self.protocols = protocols.iter().map(|p| p.to_string()).collect();
+        self
+    }
+
+    /// Sets the largest message accepted, after reassembling fragments.
+    /// Larger messages close the connection with code 1009. Defaults to
+    /// 16 MiB.
+    pub fn max_message_size(mut self, size: usize) -> WebSocketHandler {
+        self.max_message_size = size;
+        self
+    }
+
+    /// Runs each socket on a new thread instead of the pool worker.
+    pub fn dedicated_thread(mut self, dedicated: bool) -> WebSocketHandler {
+        self.dedicated_thread = dedicated;
+        self
+    }
+
+    /// Checks the opening handshake and builds the `101` response, or the
+    /// error response if the request is not a valid upgrade.
+    fn handshake(&self, request: &Request) -> Result<(Response, Option<String>), Response> {
+        if request.method != Method::Get {
+            return Err(Response::text(405, "Method Not Allowed").with_header("Allow", "GET"));
+        }
+        if request.version != Version::Http11
+            || !request.headers.has_token("Upgrade", "websocket")
+            || !request.headers.has_token("Connection", "upgrade")
+        {
+            return Err(Response::text(426, "Expected a WebSocket upgrade")
+                .with_header("Upgrade", "websocket")
+                .with_header("Connection", "Upgrade"));
+        }
+        if request.headers.get("Sec-WebSocket-Version") != Some("13") {
+            return Err(Response::text(426, "Unsupported WebSocket version")
+                .with_header("Sec-WebSocket-Version", "13"));
+        }
+        let key = request
+            .headers
+            .get("Sec-WebSocket-Key")
+            .unwrap_or("")
+            .trim();
+        if STANDARD.decode(key).map(|k| k.len()) != Ok(16) {
+            return Err(Response::text(400, "Invalid Sec-WebSocket-Key"));
+        }
+
+        let protocol = request
+            .headers
+            .get_all("Sec-WebSocket-Protocol")
+            .flat_map(|v| v.split(','))
+            .map(str::trim)
+            .find(|offered| self.protocols.iter().any(|p| p == offered))
+            .map(str::to_string);
+
+        let mut response = Response::new(101)
+            .with_header("Upgrade", "websocket")
+            .with_header("Connection", "Upgrade")
+            .with_header("Sec-WebSocket-Accept", accept_key(key));
+        if let Some(protocol) = &protocol {
+            response
+                .headers
+                .insert("Sec-WebSocket-Protocol", protocol.as_str());
+        }
+        Ok((response, protocol))
+    }
+}
+
+impl Handler for WebSocketHandler {
+    fn handle(&self, request: Request) -> Response {
+        let (response, protocol) = match self.handshake(&request) {
+            Ok(accepted) => accepted,
+            Err(response) => return response,
+        };
+        let on_socket = Arc::clone(&self.on_socket);
+        let max_message_size = self.max_message_size;
+        let dedicated_thread = self.dedicated_thread;
+        response.on_upgrade(move |stream| {
+            let socket = WebSocket::new(stream, protocol, max_message_size);
+            if !dedicated_thread {
+                on_socket(request, socket);
+                return;
+            }
+            let spawned = thread::Builder::new()
+                .name("websocket".to_string())
+                .spawn(move || on_socket(request, socket));
+            if let Err(e) = spawned {
+                error!("Failed to spawn WebSocket thread: {e}");
+            }
+        })
+    }
+}
+
+/// A message received from or sent to a WebSocket peer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+    /// Received pings have already been answered with a pong.
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
+    Close(Option<CloseFrame>),
+}
+
+impl From<String> for Message {
+    fn from(text: String) -> Message {
+        Message::Text(text)
+    }
+}
+
+impl From<&str> for Message {
+    fn from(text: &str) -> Message {
+        Message::Text(text.to_string())
+    }
+}
+
+impl From<Vec<u8>> for Message {
+    fn from(data: Vec<u8>) -> Message {
+        Message::Binary(data)
+    }
+}
+
+/// The status code and reason of a close frame.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CloseFrame {
+    pub code: u16,
+    pub reason: String,
+}
+
+impl CloseFrame {
+    pub const NORMAL: u16 = 1000;
+    pub const GOING_AWAY: u16 = 1001;
+    pub const PROTOCOL_ERROR: u16 = 1002;
+    pub const UNSUPPORTED_DATA: u16 = 1003;
+    pub const INVALID_DATA: u16 = 1007;
+    pub const POLICY_VIOLATION: u16 = 1008;
+    pub const MESSAGE_TOO_BIG: u16 = 1009;
+    pub const INTERNAL_ERROR: u16 = 1011;
+
+    /// Whether `code` may appear in a close frame on the wire.
+    fn is_valid_code(code: u16) -> bool {
+        matches!(code, 1000..=1003 | 1007..=1014 | 3000..=4999)
+    }
+}
+
+/// A server-side WebSocket connection (RFC 6455).
+///
+/// [`recv`](WebSocket::recv) reassembles fragmented messages, answers pings
+/// and completes the closing handshake. A peer that breaks the protocol is
+/// sent a close frame with the matching code and the connection ends.
+pub struct WebSocket {
+    stream: Upgraded,
+    protocol: Option<String>,
+    max_message_size: usize,
+    /// Opcode and payload of a message whose final fragment is missing.
+    fragmented: Option<(u8, Vec<u8>)>,
+    sent_close: bool,
+    received_close: bool,
+}
+
+struct Frame {
+    fin: bool,
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+impl WebSocket {
+    fn new(stream: Upgraded, protocol: Option<String>, max_message_size: usize) -> WebSocket {
+        WebSocket {
+            stream,
+            protocol,
+            max_message_size,
+            fragmented: None,
+            sent_close: false,
+            received_close: false,
+        }
+    }
+
+    /// The subprotocol agreed on during the handshake.
+    pub fn protocol(&self) -> Option<&str> {
+        self.protocol.as_deref()
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.stream.socket().peer_addr()
+    }
+
+    /// Bounds how long [`recv`](WebSocket::recv) waits for data. `None`,
+    /// the default, waits forever.
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.stream.socket().set_read_timeout(timeout)
+    }
+
+    /// Waits for the next message.
+    ///
+    /// When the peer closes the connection this returns its
+    /// [`Message::Close`] once, after answering it; after that it returns
+    /// [`WebSocketError::Closed`].
+    pub fn recv(&mut self) -> Result<Message, WebSocketError> {
+        if self.received_close {
+            return Err(WebSocketError::Closed);
+        }
+        loop {
+            let frame = self.read_frame()?;
+            match frame.opcode {
+                OP_CONTINUATION => match &mut self.fragmented {
+                    Some((_, data)) => data.extend_from_slice(&frame.payload),
+                    None => {
+                        return Err(self.fail(CloseFrame::PROTOCOL_ERROR, "unexpected continuation"))
+                    }
+                },
+                OP_TEXT | OP_BINARY => {
+                    if self.fragmented.is_some() {
+                        return Err(self.fail(CloseFrame::PROTOCOL_ERROR, "expected continuation"));
+                    }
+                    self.fragmented = Some((frame.opcode, frame.payload));
+                }
+                OP_CLOSE => return self.closed_by_peer(&frame.payload),
+                OP_PING => {
+                    self.write_frame(OP_PONG, &frame.payload)?;
+                    return Ok(Message::Ping(frame.payload));
+                }
+                OP_PONG => return Ok(Message::Pong(frame.payload)),
+                _ => return Err(self.fail(CloseFrame::PROTOCOL_ERROR, "unknown opcode")),
+            }
+
+            if !frame.fin {
+                continue;
+            }
+            let Some((opcode, data)) = self.fragmented.take() else {
+                continue;
+            };
+            if opcode == OP_BINARY {
+                return Ok(Message::Binary(data));
+            }
+            return match String::from_utf8(data) {
+                Ok(text) => Ok(Message::Text(text)),
+                Err(_) => Err(self.fail(CloseFrame::INVALID_DATA, "text is not UTF-8")),
+            };
+        }
+    }
+
+    /// Sends a message. Sending [`Message::Close`] closes the connection as
+    /// [`close`](WebSocket::close) does.
+    pub fn send(&mut self, message: Message) -> Result<(), WebSocketError> {
+        if self.sent_close {
+            return Err(WebSocketError::Closed);
+        }
+        match message {
+            Message::Text(text) => self.write_frame(OP_TEXT, text.as_bytes()),
+            Message::Binary(data) => self.write_frame(OP_BINARY, &data),
+            Message::Ping(data) => self.write_frame(OP_PING, &data),
+            Message::Pong(data) => self.write_frame(OP_PONG, &data),
+            Message::Close(None) => self.close(CloseFrame::NORMAL, ""),
+            Message::Close(Some(frame)) => self.close(frame.code, &frame.reason),
+        }
+    }
+
+    /// Starts the closing handshake and waits a few seconds for the peer to
+    /// answer it. Messages that arrive in the meantime are dropped.
+    pub fn close(&mut self, code: u16, reason: &str) -> Result<(), WebSocketError> {
+        if self.sent_close {
+            return Err(WebSocketError::Closed);
+        }
+        self.send_close(Some(code), reason)?;
+        self.stream.socket().set_read_timeout(Some(CLOSE_TIMEOUT))?;
+        while !self.received_close {
+            match self.read_frame() {
+                Ok(frame) if frame.opcode == OP_CLOSE => self.received_close = true,
+                Ok(_) => {}
+                Err(_) => break,
+            }
+        }
+        self.received_close = true;
+        let _ = self.stream.shutdown_write();
+        Ok(())
+    }
+
+    /// Answers the peer's close frame and ends the connection.
+    fn closed_by_peer(&mut self, payload: &[u8]) -> Result<Message, WebSocketError> {
+        let frame = match payload {
+            [] => None,
+            [_] => return Err(self.fail(CloseFrame::PROTOCOL_ERROR, "truncated close code")),
+            [hi, lo, reason @ ..] => {
+                let code = u16::from_be_bytes([*hi, *lo]);
+                if !CloseFrame::is_valid_code(code) {
+                    return Err(self.fail(CloseFrame::PROTOCOL_ERROR, "invalid close code"));
+                }
+                let Ok(reason) = std::str::from_utf8(reason) else {
+                    return Err(self.fail(CloseFrame::INVALID_DATA, "close reason is not UTF-8"));
+                };
+                Some(CloseFrame {
+                    code,
+                    reason: reason.to_string(),
+                })
+            }
+        };
+        self.received_close = true;
+        if !self.sent_close {
+            self.send_close(frame.as_ref().map(|f| f.code), "")?;
+        }
+        // The server closes the TCP connection first (section 5.5.1).
+        let _ = self.stream.shutdown_write();
+        Ok(Message::Close(frame))
+    }
+
+    /// Closes the connection after the peer broke the protocol.
+    fn fail(&mut self, code: u16, reason: &'static str) -> WebSocketError {
+        debug!("Closing WebSocket with {code}: {reason}");
+        if !self.sent_close {
+            let _ = self.send_close(Some(code), reason);
+        }
+        self.received_close = true;
+        let _ = self.stream.shutdown_write();
+        WebSocketError::Protocol { code, reason }
+    }
+
+    fn send_close(&mut self, code: Option<u16>, reason: &str) -> Result<(), WebSocketError> {
+        let mut payload = Vec::new();
+        if let Some(code) = code {
+            payload.extend_from_slice(&code.to_be_bytes());
+            // Control frames carry at most 125 bytes.
+            let mut end = reason.len().min(123);
+            while !reason.is_char_boundary(end) {
+                end -= 1;
+            }
+            payload.extend_from_slice(&reason.as_bytes()[..end]);
+        }
+        self.sent_close = true;
+        self.write_frame(OP_CLOSE, &payload)
+    }
+
+    fn read_frame(&mut self) -> Result<Frame, WebSocketError> {
+        let mut head = [0; 2];
+        self.stream.read_exact(&mut head)?;
+        let fin = head[0] & 0x80 != 0;
+        let opcode = head[0] & 0x0f;
+        if head[0] & 0x70 != 0 {
+            return Err(self.fail(CloseFrame::PROTOCOL_ERROR, "reserved bits set"));
+        }
+        if head[1] & 0x80 == 0 {
+            return Err(self.fail(CloseFrame::PROTOCOL_ERROR, "client frames must be masked"));
+        }
+
+        let len = match head[1] & 0x7f {
+            126 => {
+                let mut len = [0; 2];
+                self.stream.read_exact(&mut len)?;
+                u64::from(u16::from_be_bytes(len))
+            }
+            127 => {
+                let mut len = [0; 8];
+                self.stream.read_exact(&mut len)?;
+                let len = u64::from_be_bytes(len);
+                // RFC 6455 section 5.2: the most significant bit must be 0.
+                if len >> 63 != 0 {
+                    return Err(self.fail(CloseFrame::PROTOCOL_ERROR, "invalid frame length"));
+                }
+                len
+            }
+            len => u64::from(len),
+        };
+        if opcode >= OP_CLOSE {
+            if !fin || len > 125 {
+                return Err(self.fail(CloseFrame::PROTOCOL_ERROR, "invalid control frame"));
+            }
+        } else {
+            let pending = self.fragmented.as_ref().map_or(0, |(_, data)| data.len());
+            // Compared against what is left, so a huge length cannot wrap.
+            let room = self.max_message_size.saturating_sub(pending);
+            if len > room as u64 {
+                return Err(self.fail(CloseFrame::MESSAGE_TOO_BIG, "message too big"));
+            }
+        }
+
+        let mut mask = [0; 4];
+        self.stream.read_exact(&mut mask)?;
+        let mut payload = vec![0; len as usize];
+        self.stream.read_exact(&mut payload)?;
+        for (i, byte) in payload.iter_mut().enumerate() {
+            *byte ^= mask[i % 4];
+        }
+        Ok(Frame {
+            fin,
+            opcode,
+            payload,
+        })
+    }
+
+    /// Writes a single unmasked, final frame.
+    fn write_frame(&mut self, opcode: u8, payload: &[u8]) -> Result<(), WebSocketError> {
+        let mut frame = Vec::with_capacity(payload.len() + 10);
+        frame.push(0x80 | opcode);
+        match payload.len() {
+            len @ 0..=125 => frame.push(len as u8),
+            len @ 126..=0xffff => {
+                frame.push(126);
+                frame.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+            len => {
+                frame.push(127);
+                frame.extend_from_slice(&(len as u64).to_be_bytes());
+            }
+        }
+        frame.extend_from_slice(payload);
+        self.stream.write_all(&frame)?;
+        self.stream.flush()?;
+        Ok(())
+    }
+}
+
+/// Why a WebSocket could not receive or send a message.
+#[derive(Debug)]
+pub enum WebSocketError {
+    Io(io::Error),
+    /// The peer broke the protocol, and the connection was closed with
+    /// `code`.
+    Protocol {
+        code: u16,
+        reason: &'static str,
+    },
+    /// The closing handshake has already taken place.
+    Closed,
+}
+
+impl fmt::Display for WebSocketError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WebSocketError::Io(e) => write!(f, "WebSocket I/O error: {e}"),
+            WebSocketError::Protocol { code, reason } => {
+                write!(f, "WebSocket protocol error ({code}): {reason}")
+            }
+            WebSocketError::Closed => f.write_str("WebSocket is closed"),
+        }
+    }
+}
+
+impl Error for WebSocketError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            WebSocketError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for WebSocketError {
+    fn from(e: io::Error) -> WebSocketError {
+        WebSocketError::Io(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection::{serve_connection, ConnectionConfig};
+    use crate::pool::ThreadPool;
+    use crate::request::RequestParser;
+    use crate::server::Server;
+    use std::net::{TcpListener, TcpStream};
+
+    const KEY: &str = "dGhlIHNhbXBsZSBub25jZQ==";
+
+    fn request(headers: &str) -> Request {
+        let mut parser = RequestParser::new();
+        parser.feed(format!("GET /ws HTTP/1.1\r\nHost: test\r\n{headers}\r\n").as_bytes());
+        parser.parse().unwrap().unwrap()
+    }
+
+    fn upgrade_headers() -> String {
+        format!(
+            "Upgrade: websocket\r\nConnection: keep-alive, Upgrade\r\n\
+             Sec-WebSocket-Version: 13\r\nSec-WebSocket-Key: {KEY}\r\n"
+        )
+    }
+
+    /// A masked frame, as a client sends it.
+    fn client_frame(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [0x37, 0xfa, 0x21, 0x3d];
+        let mut frame = vec![if fin { 0x80 } else { 0 } | opcode];
+        if payload.len() < 126 {
+            frame.push(0x80 | payload.len() as u8);
+        } else {
+            frame.push(0x80 | 126);
+            frame.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+        }
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        frame
+    }
+
+    fn read_frame(stream: &mut TcpStream) -> (u8, Vec<u8>) {
+        let mut head = [0; 2];
+        stream.read_exact(&mut head).unwrap();
+        assert_eq!(head[0] & 0x80, 0x80, "server frames are final");
+        assert_eq!(head[1] & 0x80, 0, "server frames are not masked");
+        let len = match head[1] {
+            126 => {
+                let mut len = [0; 2];
+                stream.read_exact(&mut len).unwrap();
+                u16::from_be_bytes(len) as usize
+            }
+            len => len as usize,
+        };
+        let mut payload = vec![0; len];
+        stream.read_exact(&mut payload).unwrap();
+        (head[0] & 0x0f, payload)
+    }
+
+    fn read_head(stream: &mut TcpStream) -> String {
+        let mut head = Vec::new();
+        while !head.ends_with(b"\r\n\r\n") {
+            let mut byte = [0];
+            stream.read_exact(&mut byte).unwrap();
+            head.push(byte[0]);
+        }
+        String::from_utf8(head).unwrap()
+    }
+
+    fn echo(_: Request, mut socket: WebSocket) {
+        while let Ok(message) = socket.recv() {
+            if let Message::Text(_) | Message::Binary(_) = message {
+                socket.send(message).unwrap();
+            }
+        }
+    }
+
+    /// Serves one connection with `handler` and sends `data` on it.
+    fn open(handler: WebSocketHandler, data: &[u8]) -> (TcpStream, thread::JoinHandle<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            serve_connection(stream, &handler, &ConnectionConfig::default()).unwrap();
+        });
+        let mut client = TcpStream::connect(addr).unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        client.write_all(data).unwrap();
+        (client, server)
+    }
+
+    fn handshake_and(frames: &[Vec<u8>]) -> Vec<u8> {
+        let mut data =
+            format!("GET /ws HTTP/1.1\r\nHost: a\r\n{}\r\n", upgrade_headers()).into_bytes();
+        for frame in frames {
+            data.extend_from_slice(frame);
+        }
+        data
+    }
+
+    #[test]
+    fn accept_key_matches_rfc_example() {
+        assert_eq!(accept_key(KEY), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+    }
+
+    #[test]
+    fn rejects_invalid_handshakes() {
+        let handler = WebSocketHandler::new(echo).protocols(&["superchat"]);
+
+        let response = handler.handle(request(""));
+        assert_eq!(response.status, 426);
+        assert_eq!(response.headers.get("Upgrade"), Some("websocket"));
+
+        let old = upgrade_headers().replace("Version: 13", "Version: 8");
+        let response = handler.handle(request(&old));
+        assert_eq!(response.status, 426);
+        assert_eq!(response.headers.get("Sec-WebSocket-Version"), Some("13"));
+
+        let short_key = upgrade_headers().replace(KEY, "c2hvcnQ=");
+        assert_eq!(handler.handle(request(&short_key)).status, 400);
+
+        let offered = format!(
+            "{}Sec-WebSocket-Protocol: chat, superchat\r\n",
+            upgrade_headers()
+        );
+        let response = handler.handle(request(&offered));
+        assert_eq!(response.status, 101);
+        assert_eq!(
+            response.headers.get("Sec-WebSocket-Accept"),
+            Some("s3pPLMBiTxaQ9kYGzzhZRbK+xOo=")
+        );
+        assert_eq!(
+            response.headers.get("Sec-WebSocket-Protocol"),
+            Some("superchat")
+        );
+    }
+
+    #[test]
+    fn echoes_fragmented_messages_and_answers_pings() {
+        // Frames sent together with the handshake must not be lost.
+        let data = handshake_and(&[
+            client_frame(false, OP_TEXT, b"Hel"),
+            client_frame(true, OP_PING, b"are you there"),
+            client_frame(true, OP_CONTINUATION, "lo, wörld".as_bytes()),
+            client_frame(true, OP_BINARY, &[7; 300]),
+        ]);
+        let (mut client, server) = open(WebSocketHandler::new(echo), &data);
+
+        let head = read_head(&mut client);
+        assert!(
+            head.starts_with("HTTP/1.1 101 Switching Protocols\r\n"),
+            "{head}"
+        );
+        assert!(head.contains("Connection: Upgrade\r\n"));
+        assert!(head.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+
+        assert_eq!(
+            read_frame(&mut client),
+            (OP_PONG, b"are you there".to_vec())
+        );
+        assert_eq!(
+            read_frame(&mut client),
+            (OP_TEXT, "Hello, wörld".as_bytes().to_vec())
+        );
+        assert_eq!(read_frame(&mut client), (OP_BINARY, vec![7; 300]));
+
+        let mut close = 1001u16.to_be_bytes().to_vec();
+        close.extend_from_slice(b"bye");
+        client
+            .write_all(&client_frame(true, OP_CLOSE, &close))
+            .unwrap();
+        assert_eq!(
+            read_frame(&mut client),
+            (OP_CLOSE, 1001u16.to_be_bytes().to_vec())
+        );
+        assert_eq!(client.read(&mut [0; 16]).unwrap(), 0);
+        server.join().unwrap();
+    }
+
+    /// Sends `frames` after the handshake and returns the code the server
+    /// closes the connection with.
+    fn close_code(handler: WebSocketHandler, frames: &[Vec<u8>]) -> u16 {
+        let (mut client, server) = open(handler, &handshake_and(frames));
+        read_head(&mut client);
+        let (opcode, payload) = read_frame(&mut client);
+        assert_eq!(opcode, OP_CLOSE);
+        server.join().unwrap();
+        u16::from_be_bytes([payload[0], payload[1]])
+    }
+
+    #[test]
+    fn protocol_violations_close_with_matching_code() {
+        let mut unmasked = client_frame(true, OP_TEXT, b"hi");
+        unmasked[1] &= 0x7f;
+        assert_eq!(close_code(WebSocketHandler::new(echo), &[unmasked]), 1002);
+
+        let stray = client_frame(true, OP_CONTINUATION, b"x");
+        assert_eq!(close_code(WebSocketHandler::new(echo), &[stray]), 1002);
+
+        let long_ping = client_frame(true, OP_PING, &[0; 126]);
+        assert_eq!(close_code(WebSocketHandler::new(echo), &[long_ping]), 1002);
+
+        let latin1 = client_frame(true, OP_TEXT, &[0x77, 0xf6, 0x72]);
+        assert_eq!(close_code(WebSocketHandler::new(echo), &[latin1]), 1007);
+
+        let small = WebSocketHandler::new(echo).max_message_size(4);
+        let frames = [
+            client_frame(false, OP_BINARY, b"abc"),
+            client_frame(true, OP_CONTINUATION, b"de"),
+        ];
+        assert_eq!(close_code(small, &frames), 1009);
+
+        // A continuation whose 64-bit length would overflow the total.
+        let huge = |len: u64| {
+            let mut frame = vec![OP_CONTINUATION, 0x80 | 127];
+            frame.extend_from_slice(&len.to_be_bytes());
+            [client_frame(false, OP_TEXT, b"abc"), frame]
+        };
+        let frames = huge(u64::MAX >> 1);
+        assert_eq!(close_code(WebSocketHandler::new(echo), &frames), 1009);
+        let frames = huge(u64::MAX - 1);
+        assert_eq!(close_code(WebSocketHandler::new(echo), &frames), 1002);
+    }
+
+    #[test]
+    fn dedicated_thread_frees_the_worker() {
+        let server = Server::bind("127.0.0.1:0", ThreadPool::new(1)).unwrap();
+        let addr = server.local_addr().unwrap();
+        let handle = server.shutdown_handle();
+        let ws = WebSocketHandler::new(echo).dedicated_thread(true);
+        let running = thread::spawn(move || {
+            server.serve(move |req: Request| match req.path.as_str() {
+                "/ws" => ws.handle(req),
+                _ => Response::text(200, "plain"),
+            })
+        });
+
+        let mut socket = TcpStream::connect(addr).unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        socket.write_all(&handshake_and(&[])).unwrap();
+        assert!(read_head(&mut socket).starts_with("HTTP/1.1 101 "));
+
+        // The only worker is free again while the socket stays open.
+        let mut plain = TcpStream::connect(addr).unwrap();
+        plain
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        plain
+            .write_all(b"GET / HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        let mut out = String::new();
+        plain.read_to_string(&mut out).unwrap();
+        assert!(out.ends_with("\r\n\r\nplain"), "{out}");
+
+        socket
+            .write_all(&client_frame(true, OP_TEXT, b"still here"))
+            .unwrap();
+        assert_eq!(read_frame(&mut socket), (OP_TEXT, b"still here".to_vec()));
+
+        drop(socket);
+        handle.shutdown();
+        assert!(running.join().unwrap().is_clean());
+    }
+}